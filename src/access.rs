@@ -0,0 +1,118 @@
+/*
+    Unix permission checks. ffs runs every operation on behalf of a set of
+    Credentials and checks them against the owner, group and mode bits of
+    the inodes involved, the way the kernel does for a local filesystem.
+*/
+
+use crate::core::acl::{Acl, AclTag};
+use crate::core::inode::{FileType, Inode};
+
+// what access(2) calls R_OK, W_OK and X_OK
+pub const MAY_READ: u32 = 4;
+pub const MAY_WRITE: u32 = 2;
+pub const MAY_EXEC: u32 = 1;
+
+pub const S_ISUID: u16 = 0o4000;
+pub const S_ISGID: u16 = 0o2000;
+pub const S_ISVTX: u16 = 0o1000;
+// everything an inode's mode can hold; the file type is stored apart
+pub const MODE_MASK: u16 = 0o7777;
+
+/*
+    Who an operation runs as. uid 0 is root, which passes every check
+    except executing a file nobody may execute.
+*/
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Credentials {
+    pub uid: u32,
+    pub gid: u32,
+    // supplementary groups
+    pub groups: Vec<u32>,
+}
+
+impl Credentials {
+    pub fn new(uid: u32, gid: u32) -> Self {
+        Self { uid, gid, groups: Vec::new() }
+    }
+
+    pub fn root() -> Self {
+        Self::new(0, 0)
+    }
+
+    pub fn with_groups(mut self, groups: Vec<u32>) -> Self {
+        self.groups = groups;
+        self
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.uid == 0
+    }
+
+    pub fn in_group(&self, gid: u32) -> bool {
+        self.gid == gid || self.groups.contains(&gid)
+    }
+
+    /*
+        Whether the caller owns `inode`, or is root and may act as if it did.
+    */
+    pub fn owns(&self, inode: &Inode) -> bool {
+        self.is_root() || self.uid == inode.uid
+    }
+
+    /*
+        Whether every access in `mask` (MAY_READ | MAY_WRITE | MAY_EXEC) is
+        granted on `inode`, whose access ACL is `acl`. Only one class of bits
+        applies: the owner's if the caller owns the inode, else the group's
+        if it is in the group, else everybody else's.
+    */
+    pub fn may(&self, inode: &Inode, acl: Option<&Acl>, mask: u32) -> bool {
+        if self.is_root() {
+            let executable = inode.file_type == FileType::Directory || inode.mode & 0o111 != 0;
+            return mask & MAY_EXEC == 0 || executable;
+        }
+        if let Some(acl) = acl.filter(|_| self.uid != inode.uid) {
+            return self.acl_permits(inode, acl, mask);
+        }
+        let shift = if self.uid == inode.uid {
+            6
+        } else if self.in_group(inode.gid) {
+            3
+        } else {
+            0
+        };
+        (inode.mode as u32 >> shift) & mask == mask
+    }
+
+    /*
+        The ACL check for anybody but the owner, like posix_acl_permission:
+        an entry naming the caller decides, then the group entries, of which
+        any one granting everything will do, then the other entry. What a
+        named or group entry grants is limited by the mask.
+    */
+    fn acl_permits(&self, inode: &Inode, acl: &Acl, mask: u32) -> bool {
+        let mask = mask as u16;
+        let masked = |perm: u16| perm & acl.mask().unwrap_or(7) & mask == mask;
+        let entries = acl.entries();
+        if let Some(entry) = entries.iter().find(|entry| entry.tag == AclTag::User && entry.id == self.uid) {
+            return masked(entry.perm);
+        }
+        let mut groups = entries.iter().filter(|entry| match entry.tag {
+            AclTag::GroupObj => self.in_group(inode.gid),
+            AclTag::Group => self.in_group(entry.id),
+            _ => false,
+        }).peekable();
+        if groups.peek().is_some() {
+            return groups.any(|entry| masked(entry.perm));
+        }
+        entries.iter().any(|entry| entry.tag == AclTag::Other && entry.perm & mask == mask)
+    }
+
+    /*
+        Whether the caller may remove or rename the entry for `inode` out of
+        `directory`, given it may write there. In a sticky directory only the
+        owner of the entry or of the directory may.
+    */
+    pub fn may_unlink(&self, directory: &Inode, inode: &Inode) -> bool {
+        directory.mode & S_ISVTX == 0 || self.owns(inode) || self.owns(directory)
+    }
+}
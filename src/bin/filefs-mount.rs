@@ -0,0 +1,74 @@
+/*
+    filefs-mount <image> <mountpoint> [-o option[,option...]]
+
+    Mounts a filefs image through FUSE and serves it until it is unmounted
+    (for example with `fusermount -u <mountpoint>`).
+*/
+
+use std::process::ExitCode;
+
+use filefs::AtimeMode;
+use fuser::MountOption;
+
+const USAGE: &str = "usage: filefs-mount <image> <mountpoint> \
+[-o ro,allow_other,allow_root,auto_unmount,default_permissions,strictatime|relatime|noatime]";
+
+/*
+    Adds the options in a comma separated list to `options`. The atime ones
+    are handled by filefs itself rather than passed on; the last one wins.
+*/
+fn parse_options(list: &str, options: &mut Vec<MountOption>, atime: &mut AtimeMode) -> Result<(), String> {
+    for option in list.split(',').filter(|o| !o.is_empty()) {
+        match option {
+            "ro" => options.push(MountOption::RO),
+            "rw" => options.push(MountOption::RW),
+            "allow_other" => options.push(MountOption::AllowOther),
+            "allow_root" => options.push(MountOption::AllowRoot),
+            "auto_unmount" => options.push(MountOption::AutoUnmount),
+            "default_permissions" => options.push(MountOption::DefaultPermissions),
+            "strictatime" => *atime = AtimeMode::Strict,
+            "relatime" => *atime = AtimeMode::Relatime,
+            "noatime" => *atime = AtimeMode::NoAtime,
+            other => return Err(format!("unknown mount option {:?}", other)),
+        }
+    }
+    Ok(())
+}
+
+fn run(args: &[String]) -> Result<(), String> {
+    let mut positional = Vec::new();
+    let mut options = Vec::new();
+    let mut atime = AtimeMode::default();
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return Ok(());
+            }
+            "-o" => {
+                let list = iter.next().ok_or("-o needs an argument")?;
+                parse_options(list, &mut options, &mut atime)?;
+            }
+            _ => positional.push(arg.as_str()),
+        }
+    }
+
+    let [image, mountpoint] = positional[..] else {
+        return Err(USAGE.to_string());
+    };
+    filefs::fuse::mount(image, mountpoint, &options, atime)
+        .map_err(|e| format!("cannot mount {} on {}: {}", image, mountpoint, e))
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(message) => {
+            eprintln!("filefs-mount: {}", message);
+            ExitCode::FAILURE
+        }
+    }
+}
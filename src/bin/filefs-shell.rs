@@ -0,0 +1,421 @@
+/*
+    filefs-shell <image> [--upgrade] [--script <file>]
+
+    A small command interpreter for poking at a filefs image. Commands are
+    read from the terminal with a prompt, from a script file given with
+    --script, or from stdin when it is not a terminal. In the last two
+    cases the first failing command stops the run with a non-zero exit
+    status, which keeps test fixtures honest. An image from an older
+    release has to be converted with --upgrade before it can be used.
+*/
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, IsTerminal, Read, Write};
+use std::process::ExitCode;
+
+use filefs::{major, makedev, minor, FileMedium, FileType, Filesystem, MAY_READ, MAY_WRITE};
+
+const USAGE: &str = "usage: filefs-shell <image> [--upgrade] [--script <file>]";
+
+const HELP: &str = "\
+cd [dir]              change the current directory (default /)
+pwd                   print the current directory
+ls [dir]              list a directory
+mkdir <dir>...        create directories
+touch <file>...       create empty files that do not exist yet
+cat <file>...         print file contents
+write <file> <text>   replace a file's contents with text and a newline
+rm <file>...          remove files
+rmdir <dir>...        remove empty directories
+mv <from> <to>        rename or move an entry
+ln [-s] <file> <link> give a file another name, or with -s make a
+                      symbolic link to it
+readlink <link>...    print where symbolic links point
+mknod <path> p|s      create a FIFO or a socket
+mknod <path> c|b <major> <minor>
+                      create a character or block device node
+chmod <mode> <path>   set the permission bits, in octal
+chown <uid>[:<gid>] <path>
+                      set the owner and optionally the group
+stat <path>...        show inode details
+getfattr <path>       list extended attributes and their values
+setfattr <path> <name> <value>
+                      set an extended attribute
+setfattr -x <name> <path>
+                      remove an extended attribute
+df                    show block and inode usage
+import <archive> [dir]
+                      unpack a tar archive from the host into dir (default .)
+export <archive> [dir]
+                      write dir (default .) to a tar archive on the host
+help                  show this help
+exit                  leave the shell";
+
+/*
+    Splits a command line into words. Words are separated by whitespace;
+    single quotes keep everything literally, double quotes keep whitespace
+    and a backslash escapes the next character outside single quotes.
+*/
+fn tokenize(line: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut current = String::new();
+    let mut in_word = false;
+    let mut chars = line.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => current.push(c),
+                        None => return Err("unterminated single quote".to_string()),
+                    }
+                }
+            }
+            '"' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => current.push(chars.next().ok_or("unterminated double quote")?),
+                        Some(c) => current.push(c),
+                        None => return Err("unterminated double quote".to_string()),
+                    }
+                }
+            }
+            '\\' => {
+                in_word = true;
+                current.push(chars.next().ok_or("trailing backslash")?);
+            }
+            c if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut current));
+                    in_word = false;
+                }
+            }
+            c => {
+                in_word = true;
+                current.push(c);
+            }
+        }
+    }
+    if in_word {
+        words.push(current);
+    }
+    Ok(words)
+}
+
+struct Shell {
+    fs: Filesystem<FileMedium>,
+}
+
+impl Shell {
+    fn prompt(&self) -> String {
+        format!("filefs:{}> ", self.fs.current_dir().map_or_else(|_| "?".into(), |dir| dir.to_string_lossy().into_owned()))
+    }
+
+    /*
+        Runs one command line. Returns Ok(false) when the shell should exit.
+    */
+    fn execute(&mut self, line: &str, out: &mut dyn Write) -> Result<bool, String> {
+        let words = tokenize(line)?;
+        let Some((command, args)) = words.split_first() else {
+            return Ok(true);
+        };
+        if command.starts_with('#') {
+            return Ok(true);
+        }
+
+        let need_args = |count: usize| {
+            if args.len() < count {
+                Err(format!("{}: missing operand, see help", command))
+            } else {
+                Ok(())
+            }
+        };
+        // filesystem errors already name the path they failed on
+        let io = |e: std::io::Error| format!("{}: {}", command, e);
+
+        match command.as_str() {
+            "exit" | "quit" => return Ok(false),
+            "help" => writeln!(out, "{}", HELP).map_err(io)?,
+            "pwd" => writeln!(out, "{}", self.fs.current_dir().map_err(io)?.to_string_lossy()).map_err(io)?,
+            "cd" => {
+                let path = args.first().map_or("/", |p| p.as_str());
+                self.fs.chdir(path).map_err(io)?;
+            }
+            "ls" => {
+                let path = args.first().map_or(".", |p| p.as_str());
+                for entry in self.fs.read_dir(path).map_err(io)? {
+                    let stat = self.fs.stat(entry.inode_number).map_err(io)?;
+                    let suffix = match stat.file_type {
+                        FileType::Directory => "/",
+                        FileType::Symlink => "@",
+                        FileType::Fifo => "|",
+                        FileType::Socket => "=",
+                        FileType::File | FileType::CharDevice | FileType::BlockDevice => "",
+                    };
+                    writeln!(out, "{}{}", entry.name.to_string_lossy(), suffix).map_err(io)?;
+                }
+            }
+            "mkdir" => {
+                need_args(1)?;
+                for path in args {
+                    self.fs.create_dir(path.as_str()).map_err(io)?;
+                }
+            }
+            "touch" => {
+                need_args(1)?;
+                for path in args {
+                    if self.fs.lookup_path(path.as_str()).is_err() {
+                        self.fs.create_file(path.as_str()).map_err(io)?;
+                    }
+                }
+            }
+            "cat" => {
+                need_args(1)?;
+                for path in args {
+                    let mut contents = Vec::new();
+                    self.fs.open(path.as_str(), MAY_READ)
+                        .and_then(|mut handle| handle.read_to_end(&mut contents))
+                        .map_err(io)?;
+                    out.write_all(&contents).map_err(io)?;
+                }
+            }
+            "write" => {
+                need_args(2)?;
+                let path = args[0].as_str();
+                let text = format!("{}\n", args[1..].join(" "));
+                let mut handle = if self.fs.lookup_path(path).is_ok() {
+                    self.fs.open(path, MAY_WRITE)
+                } else {
+                    self.fs.create_file(path)
+                }.map_err(io)?;
+                handle.set_len(0)
+                    .and_then(|_| handle.write_all(text.as_bytes()))
+                    .map_err(io)?;
+            }
+            "rm" => {
+                need_args(1)?;
+                for path in args {
+                    self.fs.remove_file(path.as_str()).map_err(io)?;
+                }
+            }
+            "rmdir" => {
+                need_args(1)?;
+                for path in args {
+                    self.fs.remove_dir(path.as_str()).map_err(io)?;
+                }
+            }
+            "mv" => {
+                need_args(2)?;
+                self.fs.rename_path(args[0].as_str(), args[1].as_str())
+                    .map_err(io)?;
+            }
+            "ln" => {
+                match args {
+                    [flag, target, link] if flag == "-s" => self.fs.symlink_path(target.as_str(), link.as_str()),
+                    _ => {
+                        need_args(2)?;
+                        self.fs.link_path(args[0].as_str(), args[1].as_str())
+                    }
+                }.map_err(io)?;
+            }
+            "readlink" => {
+                need_args(1)?;
+                for path in args {
+                    writeln!(out, "{}", self.fs.readlink_path(path.as_str()).map_err(io)?.to_string_lossy()).map_err(io)?;
+                }
+            }
+            "getfattr" => {
+                need_args(1)?;
+                let inode_number = self.fs.lookup_path(args[0].as_str()).map_err(io)?;
+                for name in self.fs.listxattr(inode_number).map_err(io)? {
+                    let value = self.fs.getxattr(inode_number, name.as_str()).map_err(io)?;
+                    writeln!(out, "{}={:?}", name, String::from_utf8_lossy(&value)).map_err(io)?;
+                }
+            }
+            "setfattr" => {
+                need_args(3)?;
+                if args[0] == "-x" {
+                    let inode_number = self.fs.lookup_path(args[2].as_str()).map_err(io)?;
+                    self.fs.removexattr(inode_number, args[1].as_str()).map_err(io)?;
+                } else {
+                    let inode_number = self.fs.lookup_path(args[0].as_str()).map_err(io)?;
+                    self.fs.setxattr(inode_number, args[1].as_str(), args[2].as_bytes(), 0).map_err(io)?;
+                }
+            }
+            "mknod" => {
+                need_args(2)?;
+                let file_type = match args[1].as_str() {
+                    "p" => FileType::Fifo,
+                    "s" => FileType::Socket,
+                    "c" => FileType::CharDevice,
+                    "b" => FileType::BlockDevice,
+                    other => return Err(format!("mknod: invalid type {:?}", other)),
+                };
+                let rdev = if file_type.is_device() {
+                    need_args(4)?;
+                    let number = |n: &String| n.parse::<u32>().map_err(|_| format!("mknod: invalid device number {:?}", n));
+                    makedev(number(&args[2])?, number(&args[3])?)
+                } else {
+                    0
+                };
+                self.fs.mknod_path(args[0].as_str(), file_type, 0o666, rdev).map_err(io)?;
+            }
+            "import" => {
+                need_args(1)?;
+                let archive = File::open(&args[0]).map_err(|e| format!("import: {}: {}", args[0], e))?;
+                let directory = args.get(1).map_or(".", |d| d.as_str());
+                filefs::tar::import(&mut self.fs, directory, BufReader::new(archive)).map_err(io)?;
+            }
+            "export" => {
+                need_args(1)?;
+                let archive = File::create(&args[0]).map_err(|e| format!("export: {}: {}", args[0], e))?;
+                let directory = args.get(1).map_or(".", |d| d.as_str());
+                filefs::tar::export(&mut self.fs, directory, std::io::BufWriter::new(archive)).map_err(io)?;
+            }
+            "chmod" => {
+                need_args(2)?;
+                let mode = u16::from_str_radix(args[0].as_str(), 8)
+                    .map_err(|_| format!("chmod: invalid mode {:?}", args[0]))?;
+                let inode_number = self.fs.lookup_path(args[1].as_str()).map_err(io)?;
+                self.fs.chmod(inode_number, mode).map_err(io)?;
+            }
+            "chown" => {
+                need_args(2)?;
+                let (uid, gid) = match args[0].split_once(':') {
+                    Some((uid, gid)) => (uid, Some(gid)),
+                    None => (args[0].as_str(), None),
+                };
+                let number = |id: &str| id.parse::<u32>().map_err(|_| format!("chown: invalid id {:?}", id));
+                let uid = number(uid)?;
+                let gid = gid.map(number).transpose()?;
+                let inode_number = self.fs.lookup_path(args[1].as_str()).map_err(io)?;
+                self.fs.chown(inode_number, Some(uid), gid).map_err(io)?;
+            }
+            "stat" => {
+                need_args(1)?;
+                for path in args {
+                    let stat = self.fs.lstat_path(path.as_str()).map_err(io)?;
+                    let (kind, name) = match stat.file_type {
+                        FileType::File => ("regular file", path.clone()),
+                        FileType::Directory => ("directory", path.clone()),
+                        FileType::Symlink => ("symbolic link",
+                                              format!("{} -> {}", path, self.fs.readlink(stat.inode_number).map_err(io)?.to_string_lossy())),
+                        FileType::Fifo => ("fifo", path.clone()),
+                        FileType::Socket => ("socket", path.clone()),
+                        FileType::CharDevice => ("character special file", path.clone()),
+                        FileType::BlockDevice => ("block special file", path.clone()),
+                    };
+                    writeln!(out, "  File: {}\n  Type: {}\n Inode: {}\n Links: {}\n  Size: {}\nBlocks: {} of {} bytes",
+                             name, kind, stat.inode_number, stat.nlink, stat.size, stat.blocks, stat.block_size).map_err(io)?;
+                    if stat.file_type.is_device() {
+                        writeln!(out, "Device: {},{}", major(stat.rdev), minor(stat.rdev)).map_err(io)?;
+                    }
+                    writeln!(out, "  Mode: {:04o}\n   Uid: {}\n   Gid: {}", stat.mode, stat.uid, stat.gid).map_err(io)?;
+                    writeln!(out, "Access: {}\nModify: {}\nChange: {}\n Birth: {}",
+                             stat.atime, stat.mtime, stat.ctime, stat.crtime).map_err(io)?;
+                }
+            }
+            "df" => {
+                let stat = self.fs.statfs();
+                let used = stat.total_blocks - stat.free_blocks;
+                writeln!(out, "{:>10} {:>10} {:>10} {:>10}", "", "total", "used", "free").map_err(io)?;
+                writeln!(out, "{:>10} {:>10} {:>10} {:>10}", "blocks", stat.total_blocks, used, stat.free_blocks).map_err(io)?;
+                writeln!(out, "{:>10} {:>10} {:>10} {:>10}", "inodes", stat.total_inodes,
+                         stat.total_inodes - stat.free_inodes, stat.free_inodes).map_err(io)?;
+                writeln!(out, "block size {} bytes", stat.block_size).map_err(io)?;
+            }
+            other => return Err(format!("{}: command not found, try help", other)),
+        }
+        Ok(true)
+    }
+}
+
+fn run(args: &[String]) -> Result<(), String> {
+    let upgrade = args.iter().any(|arg| arg == "--upgrade");
+    let args: Vec<&String> = args.iter().filter(|arg| *arg != "--upgrade").collect();
+    let (image, script) = match args[..] {
+        [image] => (image, None),
+        [image, flag, script] if flag == "--script" || flag == "-s" => (image, Some(script)),
+        _ => return Err(USAGE.to_string()),
+    };
+
+    let load: fn(FileMedium) -> std::io::Result<Filesystem<FileMedium>> = if upgrade { Filesystem::upgrade } else { Filesystem::load };
+    let fs = FileMedium::load(image.as_str())
+        .and_then(load)
+        .map_err(|e| format!("cannot load {}: {}", image, e))?;
+    let mut shell = Shell { fs };
+
+    let interactive = script.is_none() && std::io::stdin().is_terminal();
+    let input: Box<dyn BufRead> = match script {
+        Some(path) => Box::new(BufReader::new(File::open(path).map_err(|e| format!("cannot open {}: {}", path, e))?)),
+        None => Box::new(std::io::stdin().lock()),
+    };
+
+    let mut stdout = std::io::stdout();
+    let mut lines = input.lines();
+    loop {
+        if interactive {
+            print!("{}", shell.prompt());
+            stdout.flush().map_err(|e| e.to_string())?;
+        }
+        let Some(line) = lines.next() else {
+            break;
+        };
+        let line = line.map_err(|e| e.to_string())?;
+
+        match shell.execute(&line, &mut stdout) {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(message) if interactive => eprintln!("{}", message),
+            Err(message) => return Err(message),
+        }
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(message) => {
+            eprintln!("filefs-shell: {}", message);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokenize() {
+        assert_eq!(tokenize("  ls   /a/b ").unwrap(), vec!["ls", "/a/b"]);
+        assert_eq!(tokenize(r#"write "my file" 'it''s' a\ b "q\"uote""#).unwrap(),
+                   vec!["write", "my file", "its", "a b", "q\"uote"]);
+        assert!(tokenize("cat 'open").is_err());
+    }
+
+    #[test]
+    fn test_script_session() {
+        let image = std::env::temp_dir().join("test_shell_session.dat").to_string_lossy().into_owned();
+        Filesystem::new(FileMedium::new(image.as_str()).unwrap(), 1 << 20, 1024, 4096).unwrap();
+        let mut shell = Shell { fs: Filesystem::load(FileMedium::load(image.as_str()).unwrap()).unwrap() };
+
+        let mut out = Vec::new();
+        for line in ["mkdir docs", "cd docs", "write readme hello world", "touch empty",
+                     "mv readme ../top", "ls", "cat /top", "pwd"] {
+            assert!(shell.execute(line, &mut out).unwrap());
+        }
+        assert_eq!(String::from_utf8(out).unwrap(), "empty\nhello world\n/docs\n");
+
+        assert!(shell.execute("rmdir /docs", &mut Vec::new()).is_err());
+        assert!(shell.execute("frobnicate", &mut Vec::new()).is_err());
+        assert!(!shell.execute("exit", &mut Vec::new()).unwrap());
+    }
+}
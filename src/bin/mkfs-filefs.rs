@@ -0,0 +1,155 @@
+/*
+    mkfs.filefs [options] <image> <size>
+
+    Formats <image> as a filefs filesystem of <size> bytes and prints where
+    each on-disk structure ended up. Sizes accept K, M and G suffixes
+    (powers of 1024). `-O extents` maps files through extent trees instead
+    of block pointers.
+
+    Cargo does not allow dots in binary names, so this builds as
+    mkfs-filefs; link it as mkfs.filefs to make `mkfs -t filefs` find it.
+*/
+
+use std::fs::OpenOptions;
+use std::process::ExitCode;
+
+use filefs::{FileMedium, FormatOptions};
+
+const USAGE: &str = "usage: mkfs.filefs [-b block-size] [-i bytes-per-inode] [-L label] [-O extents] [-f] <image> <size>";
+
+/*
+    Parses a byte count such as "4096", "4K", "10M", "1G" or "2T".
+*/
+fn parse_size(value: &str) -> Result<u64, String> {
+    let (digits, shift) = match value.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&value[..value.len() - 1], 10),
+        Some('M') => (&value[..value.len() - 1], 20),
+        Some('G') => (&value[..value.len() - 1], 30),
+        Some('T') => (&value[..value.len() - 1], 40),
+        _ => (value, 0),
+    };
+    let number: u64 = digits.parse()
+        .map_err(|_| format!("invalid size {:?}", value))?;
+    number.checked_shl(shift)
+        .filter(|&bytes| bytes >> shift == number)
+        .ok_or_else(|| format!("size {:?} is too large", value))
+}
+
+/*
+    Block and inode ratios are per-block quantities and stay 32 bit.
+*/
+fn parse_ratio(value: &str) -> Result<u32, String> {
+    u32::try_from(parse_size(value)?).map_err(|_| format!("size {:?} is too large", value))
+}
+
+struct Options {
+    image: String,
+    size: u64,
+    format: FormatOptions,
+    force: bool,
+}
+
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut block_size = None;
+    let mut bytes_per_inode = None;
+    let mut label = None;
+    let mut extents = false;
+    let mut force = false;
+    let mut positional = Vec::new();
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let mut value = || iter.next().ok_or(format!("{} needs an argument", arg));
+        match arg.as_str() {
+            "-b" => block_size = Some(parse_ratio(value()?)?),
+            "-i" => bytes_per_inode = Some(parse_ratio(value()?)?),
+            "-L" => label = Some(value()?.clone()),
+            "-O" => match value()?.as_str() {
+                "extents" => extents = true,
+                feature => return Err(format!("unknown feature {:?}", feature)),
+            },
+            "-f" => force = true,
+            _ if arg.starts_with('-') => return Err(format!("unknown option {}\n{}", arg, USAGE)),
+            _ => positional.push(arg.clone()),
+        }
+    }
+
+    let [image, size] = &positional[..] else {
+        return Err(USAGE.to_string());
+    };
+    let size = parse_size(size)?;
+    let mut format = FormatOptions::new(size);
+    if let Some(block_size) = block_size {
+        format = format.block_size(block_size);
+    }
+    if let Some(bytes_per_inode) = bytes_per_inode {
+        format = format.bytes_per_inode(bytes_per_inode);
+    }
+    if let Some(label) = label {
+        format = format.label(label);
+    }
+    format = format.extents(extents);
+    // like mke2fs, the root directory belongs to whoever made the filesystem
+    // SAFETY: getuid and getgid cannot fail and touch no memory
+    let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+    format = format.root_owner(uid, gid);
+    Ok(Options {
+        image: image.clone(),
+        size,
+        format,
+        force,
+    })
+}
+
+fn run(args: &[String]) -> Result<(), String> {
+    let options = parse_args(args)?;
+
+    let image = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&options.image)
+        .map_err(|e| format!("cannot open {}: {}", options.image, e))?;
+    let existing_len = image.metadata().map_err(|e| e.to_string())?.len();
+    if existing_len > 0 && !options.force {
+        return Err(format!("{} is not empty, use -f to overwrite it", options.image));
+    }
+    image.set_len(options.size)
+        .map_err(|e| format!("cannot resize {}: {}", options.image, e))?;
+    drop(image);
+
+    let fs = FileMedium::load(options.image.as_str())
+        .and_then(|medium| options.format.format(medium))
+        .map_err(|e| format!("cannot format {}: {}", options.image, e))?;
+
+    let layout = fs.layout();
+    let block_size = layout.block_size as u64;
+    println!("{}: filefs, label {:?}", options.image, layout.label);
+    println!("  {} blocks of {} bytes, {} inodes", layout.total_blocks, block_size, layout.total_inodes);
+    println!("  block mapping   {}", if layout.extents { "extents" } else { "block pointers" });
+    println!("  superblock      block 0");
+    println!("  inode bitmap    blocks {}..{}",
+             layout.inode_bitmap_start, layout.inode_bitmap_start + layout.inode_bitmap_blocks);
+    println!("  block bitmap    blocks {}..{}",
+             layout.block_bitmap_start, layout.block_bitmap_start + layout.block_bitmap_blocks);
+    println!("  inode table     blocks {}..{}",
+             layout.inode_table_start, layout.inode_table_start + layout.inode_table_blocks);
+    println!("  data            blocks {}..{} ({} bytes)",
+             layout.first_data_block, layout.total_blocks,
+             (layout.total_blocks - layout.first_data_block) * block_size);
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let program = std::env::args().next()
+        .and_then(|arg0| arg0.rsplit('/').next().map(str::to_string))
+        .unwrap_or_else(|| "mkfs.filefs".to_string());
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(message) => {
+            eprintln!("{}: {}", program, message);
+            ExitCode::FAILURE
+        }
+    }
+}
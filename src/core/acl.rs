@@ -0,0 +1,261 @@
+use std::io::{Cursor, Error, ErrorKind};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+
+use crate::error::FsError;
+use crate::fs_metadata::fs_metadata;
+use crate::medium::types::byte_compatible;
+
+use super::inode::{FileType, Inode};
+
+/*
+    POSIX ACLs, kept in two extended attributes the way Linux exposes
+    them: the access ACL decides who may do what to the inode, the default
+    ACL of a directory is what new entries in it inherit.
+
+    Both hold the Linux xattr format (little endian):
+        version (4), always ACL_VERSION
+        followed by entries of 8 bytes:
+            tag (2) | permissions (2) | uid or gid (4)
+    Entries are sorted by tag and then id. The id of an entry that names
+    nobody in particular is ACL_UNDEFINED_ID.
+
+    The owner, group and other entries stand in for the mode bits and are
+    kept in step with them; once an ACL names users or groups, its mask
+    takes the place of the group bits.
+*/
+
+pub const ACL_ACCESS: &str = "system.posix_acl_access";
+pub const ACL_DEFAULT: &str = "system.posix_acl_default";
+
+const ACL_VERSION: u32 = 2;
+const ACL_UNDEFINED_ID: u32 = u32::MAX;
+const HEADER_SIZE: usize = 4;
+const ENTRY_SIZE: usize = 8;
+
+// in the order entries are sorted in
+#[repr(u16)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AclTag {
+    UserObj = 0x01,
+    User = 0x02,
+    GroupObj = 0x04,
+    Group = 0x08,
+    Mask = 0x10,
+    Other = 0x20,
+}
+
+impl AclTag {
+    fn from_u16(tag: u16) -> Option<Self> {
+        match tag {
+            0x01 => Some(Self::UserObj),
+            0x02 => Some(Self::User),
+            0x04 => Some(Self::GroupObj),
+            0x08 => Some(Self::Group),
+            0x10 => Some(Self::Mask),
+            0x20 => Some(Self::Other),
+            _ => None,
+        }
+    }
+
+    fn is_named(self) -> bool {
+        matches!(self, Self::User | Self::Group)
+    }
+}
+
+/*
+    One entry. `perm` combines MAY_READ, MAY_WRITE and MAY_EXEC; `id` is
+    the uid or gid of a User or Group entry and ignored for the others.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct AclEntry {
+    pub tag: AclTag,
+    pub id: u32,
+    pub perm: u16,
+}
+
+impl AclEntry {
+    pub fn new(tag: AclTag, id: u32, perm: u16) -> Self {
+        let id = if tag.is_named() { id } else { ACL_UNDEFINED_ID };
+        Self { tag, id, perm }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Acl {
+    entries: Vec<AclEntry>,
+}
+
+impl Acl {
+    /*
+        An ACL of `entries` in any order. Like acl_valid(3), it needs exactly
+        one owner, group and other entry, a mask if it names anybody, and no
+        user or group named twice.
+    */
+    pub fn new(mut entries: Vec<AclEntry>) -> Result<Self, Error> {
+        for entry in entries.iter_mut() {
+            *entry = AclEntry::new(entry.tag, entry.id, entry.perm);
+        }
+        entries.sort();
+        Self::validated(entries)
+    }
+
+    /*
+        The ACL equivalent to the permission bits of `mode`.
+    */
+    pub fn from_mode(mode: u16) -> Self {
+        Self {
+            entries: vec![AclEntry::new(AclTag::UserObj, 0, (mode >> 6) & 7),
+                          AclEntry::new(AclTag::GroupObj, 0, (mode >> 3) & 7),
+                          AclEntry::new(AclTag::Other, 0, mode & 7)],
+        }
+    }
+
+    fn validated(entries: Vec<AclEntry>) -> Result<Self, Error> {
+        let invalid = |reason: &str| Error::new(ErrorKind::InvalidInput, format!("Invalid ACL: {}", reason));
+        if entries.iter().any(|entry| entry.perm & !7 != 0) {
+            return Err(invalid("permissions out of range"));
+        }
+        if entries.windows(2).any(|pair| pair[0].tag == pair[1].tag && pair[0].id >= pair[1].id) {
+            return Err(invalid("duplicate entry"));
+        }
+        let count = |tag| entries.iter().filter(|entry| entry.tag == tag).count();
+        if count(AclTag::UserObj) != 1 || count(AclTag::GroupObj) != 1 || count(AclTag::Other) != 1 {
+            return Err(invalid("needs one owner, group and other entry"));
+        }
+        if count(AclTag::User) + count(AclTag::Group) > 0 && count(AclTag::Mask) == 0 {
+            return Err(invalid("named entries need a mask"));
+        }
+        Ok(Self { entries })
+    }
+
+    pub fn decode(value: &[u8]) -> Result<Self, Error> {
+        let invalid = |reason: &str| Error::new(ErrorKind::InvalidInput, format!("Invalid ACL: {}", reason));
+        if value.len() < HEADER_SIZE || !(value.len() - HEADER_SIZE).is_multiple_of(ENTRY_SIZE) {
+            return Err(invalid("bad length"));
+        }
+        let mut cursor = Cursor::new(value);
+        if cursor.read_u32::<LittleEndian>()? != ACL_VERSION {
+            return Err(invalid("unknown version"));
+        }
+        let mut entries = Vec::with_capacity((value.len() - HEADER_SIZE) / ENTRY_SIZE);
+        while (cursor.position() as usize) < value.len() {
+            let tag = AclTag::from_u16(cursor.read_u16::<LittleEndian>()?).ok_or_else(|| invalid("unknown tag"))?;
+            let perm = cursor.read_u16::<LittleEndian>()?;
+            let id = cursor.read_u32::<LittleEndian>()?;
+            entries.push(AclEntry::new(tag, id, perm));
+        }
+        if entries.windows(2).any(|pair| pair[0] > pair[1]) {
+            return Err(invalid("entries out of order"));
+        }
+        Self::validated(entries)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut value = ACL_VERSION.to_le_bytes().to_vec();
+        for entry in &self.entries {
+            value.extend_from_slice(&(entry.tag as u16).to_le_bytes());
+            value.extend_from_slice(&entry.perm.to_le_bytes());
+            value.extend_from_slice(&entry.id.to_le_bytes());
+        }
+        value
+    }
+
+    pub fn entries(&self) -> &[AclEntry] {
+        &self.entries
+    }
+
+    fn entry(&self, tag: AclTag) -> Option<&AclEntry> {
+        self.entries.iter().find(|entry| entry.tag == tag)
+    }
+
+    pub fn mask(&self) -> Option<u16> {
+        self.entry(AclTag::Mask).map(|entry| entry.perm)
+    }
+
+    /*
+        Whether the mode bits say all there is to say, so the ACL need not
+        be stored.
+    */
+    pub fn is_equivalent_to_mode(&self) -> bool {
+        self.entries.len() == 3
+    }
+
+    /*
+        The permission bits the ACL implies: owner, mask or else group, and
+        other.
+    */
+    pub fn mode(&self) -> u16 {
+        let perm = |tag| self.entry(tag).map_or(0, |entry| entry.perm);
+        let group = self.mask().unwrap_or_else(|| perm(AclTag::GroupObj));
+        perm(AclTag::UserObj) << 6 | group << 3 | perm(AclTag::Other)
+    }
+
+    /*
+        Applies `update` to the entries standing in for the owner, group
+        and other bits of `mode`.
+    */
+    fn update_classes(&mut self, mode: u16, update: impl Fn(u16, u16) -> u16) {
+        let group_tag = if self.mask().is_some() { AclTag::Mask } else { AclTag::GroupObj };
+        for entry in self.entries.iter_mut() {
+            let bits = match entry.tag {
+                AclTag::UserObj => (mode >> 6) & 7,
+                tag if tag == group_tag => (mode >> 3) & 7,
+                AclTag::Other => mode & 7,
+                _ => continue,
+            };
+            entry.perm = update(entry.perm, bits);
+        }
+    }
+
+    /*
+        Carries a chmod over to the ACL, like posix_acl_chmod.
+    */
+    pub fn chmod(&mut self, mode: u16) {
+        self.update_classes(mode, |_, bits| bits);
+    }
+
+    /*
+        Restricts an inherited ACL to the mode the new inode was created
+        with, like posix_acl_create_masq.
+    */
+    pub fn restrict(&mut self, mode: u16) {
+        self.update_classes(mode, |perm, bits| perm & bits);
+    }
+}
+
+/*
+    The ACL stored under `name` on `inode`, if any.
+*/
+pub fn load<M: byte_compatible>(inode: &Inode, name: &str, metadata: &fs_metadata<M>) -> Result<Option<Acl>, Error> {
+    match inode.read_xattrs(metadata)?.get(name) {
+        Some(value) => Acl::decode(value)
+            .map(Some)
+            .map_err(|e| FsError::corrupted("acl", e.to_string()).into()),
+        None => Ok(None),
+    }
+}
+
+/*
+    Gives a freshly created `inode` the default ACL of its `parent`, if it
+    has one: the ACL, restricted to the mode asked for, becomes the inode's
+    access ACL and decides its permission bits, and a directory passes the
+    default on. Persists the inode if it changes.
+*/
+pub fn inherit<M: byte_compatible>(inode: &mut Inode, parent: &Inode, metadata: &mut fs_metadata<M>) -> Result<(), Error> {
+    let Some(default) = load(parent, ACL_DEFAULT, metadata)? else {
+        return Ok(());
+    };
+    let mut xattrs = inode.read_xattrs(metadata)?;
+    if inode.file_type == FileType::Directory {
+        xattrs.insert(ACL_DEFAULT.to_string(), default.encode());
+    }
+    let mut access = default;
+    access.restrict(inode.mode);
+    inode.mode = (inode.mode & !0o777) | access.mode();
+    if !access.is_equivalent_to_mode() {
+        xattrs.insert(ACL_ACCESS.to_string(), access.encode());
+    }
+    inode.write_xattrs(xattrs, metadata)?;
+    metadata.persist_inode(inode)
+}
@@ -4,7 +4,8 @@ use super::block_data_types::BlockDataType;
 
 #[derive(Default)]
 pub struct Block {
-    pub block_number: u16,
+    pub block_number: u32,
     pub data: Vec<u8>,
+    #[allow(dead_code)]
     pub block_type: BlockDataType,
 }
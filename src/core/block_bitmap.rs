@@ -4,9 +4,9 @@ use std::cell::RefMut;
 use bitvec::prelude::*;
 
 use super::{block::Block, block_data_types::BlockDataType, super_block::SuperBlock};
-use crate::{medium::types::byte_compatible, util::INODE_BITMAP_STARTING_BLOCK_NUMBER};
+use crate::{error::FsError, medium::types::byte_compatible, util::{FS_VERSION_0_0_1, INODE_BITMAP_STARTING_BLOCK_NUMBER}};
 
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct BlockBitmap {
     bitmap: BitVec<u8>
 }
@@ -17,19 +17,29 @@ impl BlockBitmap {
         let mut bitmap = bitvec![u8, Lsb0; 0; num_blocks];
         bitmap.fill(false);
         Self {
-            bitmap: bitmap
+            bitmap
         }
     }
 
+    /*
+        Builds a bitmap of num_blocks bits from raw bytes, zero filling
+        whatever the byte slice does not cover.
+    */
+    pub fn from_bytes(bytes: &[u8], num_blocks: usize) -> Self {
+        let mut bitmap = bitvec![u8, Lsb0; 0; num_blocks];
+        let raw = bitmap.as_raw_mut_slice();
+        let len = raw.len().min(bytes.len());
+        raw[..len].copy_from_slice(&bytes[..len]);
+        bitmap.truncate(num_blocks);
+        Self { bitmap }
+    }
+
     pub fn persist<T: byte_compatible>(&self, medium: RefMut<'_, T>, super_block_ref: &SuperBlock) -> std::io::Result<()> {
         let blocks = self.serialize(super_block_ref);
 
         for block in blocks {
             let block_offset = block.block_number as u64 * super_block_ref.get_block_size() as u64;
-            let tmp_res = medium.write_all(block_offset, block.data.len(), block.data.as_slice());
-            if tmp_res.is_err() {
-                return Err(tmp_res.err().unwrap());
-            }
+            medium.write_all(block_offset, block.data.as_slice())?;
         }
 
         Ok(())
@@ -41,51 +51,46 @@ impl BlockBitmap {
         let mut start = ((1 + super_block_ref.get_inode_bitmap_block_count()) * super_block_ref.get_block_size()) as u64;
 
         for i in 0..total_block_bitmap_blocks {
-            let block = Block::default();
             let mut buffer = vec![0_u8; super_block_ref.get_block_size()];
-            let tmp_res = medium.read_all(start, buffer.len(), buffer.as_mut_slice());
-            if tmp_res.is_err() {
-                return Err(tmp_res.err().unwrap());
-            }
+            medium.read_all(start, buffer.as_mut_slice())?;
             blocks.push(Block {
-                block_number: (INODE_BITMAP_STARTING_BLOCK_NUMBER + super_block_ref.get_inode_bitmap_block_count() + i) as u16,
+                block_number: (INODE_BITMAP_STARTING_BLOCK_NUMBER + super_block_ref.get_inode_bitmap_block_count() + i) as u32,
                 data: buffer,
                 block_type: BlockDataType::BlockBitmap,
             });
             start += super_block_ref.get_block_size() as u64;
         }
         
-        Ok(Self::deserialize(blocks, super_block_ref))
+        Ok(Self::deserialize(blocks, super_block_ref)?)
     }
 
-    fn deserialize(blocks: Vec<Block>, super_block_ref: &SuperBlock) -> Self {
+    /*
+        The bitmap blocks must cover every block of the filesystem, and the
+        metadata blocks in front of the data area must all be marked used,
+        except in 0.0.1 images, which left some free until their upgrade.
+    */
+    fn deserialize(blocks: Vec<Block>, super_block_ref: &SuperBlock) -> Result<Self, FsError> {
         let total_blocks = super_block_ref.get_total_blocks();
-        let mut bitmap = bitvec![u8, Lsb0; 0; total_blocks];
-        bitmap.fill(false);
-
-        let mut current_index = 0;
-        for block in blocks[..blocks.len() - 1].iter() {
-            let data = &block.data;
-            let len = data.len();
-            bitmap.as_raw_mut_slice()[current_index..current_index + (len >> 3)]
-                .copy_from_slice(&data);
-            current_index += len;
+        let bytes: Vec<u8> = blocks.into_iter()
+            .flat_map(|block| block.data)
+            .collect();
+        if bytes.len() * 8 < total_blocks {
+            return Err(FsError::corrupted("block bitmap",
+                format!("{} bytes cannot cover {} blocks", bytes.len(), total_blocks)));
         }
-        {
-            // Handle the last block separately to avoid overrun
-            let last_block = &blocks[blocks.len() - 1];
-            let data = &last_block.data;
-            let remaining_bits = total_blocks - current_index * 8;
-            let bytes_to_copy = (remaining_bits + 7) / 8; // Round up to the nearest byte
-            bitmap.as_raw_mut_slice()[current_index..current_index + bytes_to_copy]
-                .copy_from_slice(&data[..bytes_to_copy]);
+
+        let bitmap = Self::from_bytes(&bytes, total_blocks);
+        let checked = if super_block_ref.get_version() == FS_VERSION_0_0_1 { 0 } else { super_block_ref.get_first_data_block() };
+        if let Some(block) = (0..checked).find(|&b| !bitmap.get(b)) {
+            return Err(FsError::corrupted("block bitmap",
+                format!("metadata block {} is marked free", block)));
         }
-        Self { bitmap }
+        Ok(bitmap)
     }
 
     fn serialize(&self, super_block_ref: &SuperBlock) -> Vec<Block> {
         let mut blocks: Vec<Block> = Vec::new();
-        let total_bitmap_blocks = super_block_ref.get_block_bitmap_block_count() as usize;
+        let total_bitmap_blocks = super_block_ref.get_block_bitmap_block_count();
         let bitmap_vec = self.bitmap.as_raw_slice();
 
         for i in 0..total_bitmap_blocks {
@@ -97,7 +102,7 @@ impl BlockBitmap {
                 &bitmap_vec[start..end]
             };
             blocks.push(Block {
-                block_number: (1 + super_block_ref.get_inode_bitmap_block_count() + i) as u16,
+                block_number: (1 + super_block_ref.get_inode_bitmap_block_count() + i) as u32,
                 data: data.to_vec(),
                 block_type: BlockDataType::BlockBitmap,
             });
@@ -106,11 +111,53 @@ impl BlockBitmap {
         blocks
     }
 
-    pub fn serialize_to_vec(&self) -> Vec<u8> {
-        self.bitmap.as_raw_slice().to_vec()
-    }
-
     pub fn set(&mut self, block_number: usize) {
         self.bitmap.set(block_number, true);
     }
+
+    pub fn clear(&mut self, block_number: usize) {
+        self.bitmap.set(block_number, false);
+    }
+
+    pub fn get(&self, block_number: usize) -> bool {
+        self.bitmap.get(block_number).map(|bit| *bit).unwrap_or(false)
+    }
+
+    pub fn count_free(&self) -> usize {
+        self.bitmap.count_zeros()
+    }
+
+    /*
+        The first free block at or after `from`, letting a caller collecting
+        several blocks carry on where its previous search stopped.
+    */
+    pub fn find_free_from(&self, from: usize) -> Option<usize> {
+        self.bitmap.get(from..)?.first_zero().map(|i| from + i)
+    }
+
+    /*
+        Returns the start of the first run of `count` consecutive free blocks,
+        so that multi-block allocations can be laid out contiguously.
+    */
+    pub fn find_free_run(&self, count: usize) -> Option<usize> {
+        if count == 0 {
+            return None;
+        }
+        let mut run_start = 0;
+        let mut run_len = 0;
+        for (i, bit) in self.bitmap.iter().enumerate() {
+            if *bit {
+                run_len = 0;
+                continue;
+            }
+            if run_len == 0 {
+                run_start = i;
+            }
+            run_len += 1;
+            if run_len == count {
+                return Some(run_start);
+            }
+        }
+        None
+    }
 }
@@ -10,6 +10,7 @@
         g. Children Inode Numbers (for directories)
 */
 
+#[allow(dead_code)]
 #[derive(Default)]
 pub enum BlockDataType {
     SuperBlock,
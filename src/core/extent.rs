@@ -0,0 +1,559 @@
+use std::collections::btree_map::{BTreeMap, Entry};
+use std::io::{Cursor, Error};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+
+use crate::error::FsError;
+use crate::fs_metadata::fs_metadata;
+use crate::medium::types::byte_compatible;
+
+/*
+    Extent trees map a file's blocks as runs on filesystems created with
+    FEATURE_EXTENTS. The root node lives in the inode, in the space that
+    otherwise holds the block pointers; when it overflows, its entries move
+    into tree nodes one block each and the root points at those.
+
+    Node layout (little endian):
+        magic (2) | entries (2) | max entries (2) | depth (2) | reserved (4)
+        followed by `entries` records of 12 bytes:
+            depth 0, an extent:  logical block (4) | physical block (4) | length (4)
+            depth > 0, a child:  first logical block (4) | node block (4) | reserved (4)
+    Entries are sorted by logical block and never overlap. A child's first
+    logical block is at most that of the first extent below it.
+*/
+
+pub const EXTENT_MAGIC: u16 = 0xE7E5;
+const HEADER_SIZE: usize = 12;
+const ENTRY_SIZE: usize = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extent {
+    pub logical: u32,
+    pub physical: u32,
+    pub length: u32,
+}
+
+impl Extent {
+    pub fn end(&self) -> u64 {
+        self.logical as u64 + self.length as u64
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExtentNode {
+    Leaf(Vec<Extent>),
+    // (first logical block, node block) for every child, which all sit at depth - 1
+    Index { depth: u16, children: Vec<(u32, u32)> },
+}
+
+impl ExtentNode {
+    /*
+        Entries that fit in a node of `size` bytes.
+    */
+    pub fn capacity(size: usize) -> usize {
+        (size - HEADER_SIZE) / ENTRY_SIZE
+    }
+
+    pub fn depth(&self) -> u16 {
+        match self {
+            ExtentNode::Leaf(_) => 0,
+            ExtentNode::Index { depth, .. } => *depth,
+        }
+    }
+
+    fn entry_count(&self) -> usize {
+        match self {
+            ExtentNode::Leaf(extents) => extents.len(),
+            ExtentNode::Index { children, .. } => children.len(),
+        }
+    }
+
+    // the first logical block the node holds an entry for
+    fn first(&self) -> Option<u32> {
+        match self {
+            ExtentNode::Leaf(extents) => extents.first().map(|extent| extent.logical),
+            ExtentNode::Index { children, .. } => children.first().map(|&(logical, _)| logical),
+        }
+    }
+
+    // the entries from `at` on, as a node of the same depth
+    fn split_off(&mut self, at: usize) -> Self {
+        match self {
+            ExtentNode::Leaf(extents) => ExtentNode::Leaf(extents.split_off(at)),
+            ExtentNode::Index { depth, children } => ExtentNode::Index { depth: *depth, children: children.split_off(at) },
+        }
+    }
+
+    // appends the entries of `other`, a node of the same depth that follows this one
+    fn append(&mut self, other: Self) {
+        match (self, other) {
+            (ExtentNode::Leaf(extents), ExtentNode::Leaf(mut more)) => extents.append(&mut more),
+            (ExtentNode::Index { children, .. }, ExtentNode::Index { children: mut more, .. }) => children.append(&mut more),
+            _ => unreachable!("only siblings are joined"),
+        }
+    }
+
+    /*
+        Encodes the node into exactly `size` bytes. The caller makes sure the
+        entries fit.
+    */
+    pub fn serialize(&self, size: usize) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(size);
+        let entries = match self {
+            ExtentNode::Leaf(extents) => extents.len(),
+            ExtentNode::Index { children, .. } => children.len(),
+        };
+        buffer.extend_from_slice(&EXTENT_MAGIC.to_le_bytes());
+        buffer.extend_from_slice(&(entries as u16).to_le_bytes());
+        buffer.extend_from_slice(&(Self::capacity(size) as u16).to_le_bytes());
+        buffer.extend_from_slice(&self.depth().to_le_bytes());
+        buffer.extend_from_slice(&[0; 4]);
+
+        match self {
+            ExtentNode::Leaf(extents) => for extent in extents {
+                buffer.extend_from_slice(&extent.logical.to_le_bytes());
+                buffer.extend_from_slice(&extent.physical.to_le_bytes());
+                buffer.extend_from_slice(&extent.length.to_le_bytes());
+            },
+            ExtentNode::Index { children, .. } => for (logical, block) in children {
+                buffer.extend_from_slice(&logical.to_le_bytes());
+                buffer.extend_from_slice(&block.to_le_bytes());
+                buffer.extend_from_slice(&[0; 4]);
+            },
+        }
+
+        buffer.resize(size, 0);
+        buffer
+    }
+
+    /*
+        Decodes a node, checking everything a lookup relies on: the magic,
+        the entry count, sorted non-overlapping entries and block numbers
+        inside a filesystem of `total_blocks` blocks.
+    */
+    pub fn deserialize(buffer: &[u8], total_blocks: usize) -> std::io::Result<Self> {
+        let corrupted = |reason: String| Error::from(FsError::corrupted("extent node", reason));
+
+        let mut cursor = Cursor::new(buffer);
+        let magic = cursor.read_u16::<LittleEndian>()?;
+        if magic != EXTENT_MAGIC {
+            return Err(corrupted(format!("bad magic {:#06x}", magic)));
+        }
+        let entries = cursor.read_u16::<LittleEndian>()? as usize;
+        let max_entries = cursor.read_u16::<LittleEndian>()? as usize;
+        let depth = cursor.read_u16::<LittleEndian>()?;
+        cursor.read_u32::<LittleEndian>()?;
+        if max_entries != Self::capacity(buffer.len()) || entries > max_entries {
+            return Err(corrupted(format!("{} of {} entries in a node of {} bytes", entries, max_entries, buffer.len())));
+        }
+        if depth > 0 && entries == 0 {
+            return Err(corrupted("empty index node".to_string()));
+        }
+
+        let mut records = Vec::with_capacity(entries);
+        for _ in 0..entries {
+            records.push((cursor.read_u32::<LittleEndian>()?,
+                          cursor.read_u32::<LittleEndian>()?,
+                          cursor.read_u32::<LittleEndian>()?));
+        }
+        if records.windows(2).any(|pair| pair[0].0 >= pair[1].0) {
+            return Err(corrupted("entries are out of order".to_string()));
+        }
+
+        if depth > 0 {
+            if let Some(&(_, block, _)) = records.iter().find(|&&(_, block, _)| block as usize >= total_blocks) {
+                return Err(corrupted(format!("child block {} is beyond the filesystem", block)));
+            }
+            let children = records.into_iter().map(|(logical, block, _)| (logical, block)).collect();
+            return Ok(ExtentNode::Index { depth, children });
+        }
+
+        let extents: Vec<Extent> = records.into_iter()
+            .map(|(logical, physical, length)| Extent { logical, physical, length })
+            .collect();
+        for extent in &extents {
+            if extent.length == 0
+                || extent.end() > u32::MAX as u64
+                || extent.physical as u64 + extent.length as u64 > total_blocks as u64 {
+                return Err(corrupted(format!("extent {:?} is out of range", extent)));
+            }
+        }
+        if extents.windows(2).any(|pair| pair[0].end() > pair[1].logical as u64) {
+            return Err(corrupted("extents overlap".to_string()));
+        }
+        Ok(ExtentNode::Leaf(extents))
+    }
+}
+
+/*
+    Reads the node in `block`, which its parent expects at `depth`.
+*/
+pub fn read_node<M: byte_compatible>(block: u32, depth: u16, metadata: &fs_metadata<M>) -> std::io::Result<ExtentNode> {
+    let mut raw = vec![0_u8; metadata.super_block_get_block_size()];
+    metadata.read_block_data(block, 0, &mut raw)?;
+    let node = ExtentNode::deserialize(&raw, metadata.super_block_get_total_blocks())?;
+    if node.depth() != depth {
+        return Err(Error::from(FsError::corrupted("extent node",
+            format!("Block {} is at depth {}, expected {}", block, node.depth(), depth))));
+    }
+    Ok(node)
+}
+
+/*
+    The physical block behind logical block `index` in a sorted extent
+    list, or None for a hole.
+*/
+pub fn find_block(extents: &[Extent], index: u64) -> Option<u32> {
+    let position = extents.partition_point(|e| e.logical as u64 <= index);
+    let extent = extents[..position].last()?;
+    (index < extent.end()).then(|| extent.physical + (index - extent.logical as u64) as u32)
+}
+
+/*
+    Sorts extents and joins those that continue each other both logically
+    and on disk.
+*/
+pub fn merge(mut extents: Vec<Extent>) -> Vec<Extent> {
+    extents.sort_by_key(|e| e.logical);
+    let mut merged: Vec<Extent> = Vec::with_capacity(extents.len());
+    for extent in extents {
+        match merged.last_mut() {
+            Some(last) if last.end() == extent.logical as u64
+                && last.physical as u64 + last.length as u64 == extent.physical as u64 => last.length += extent.length,
+            _ => merged.push(extent),
+        }
+    }
+    merged
+}
+
+// nodes created by a change are numbered down from here until commit gives them blocks
+const NEW_NODE: u32 = u32::MAX;
+
+/*
+    A change to an extent tree in progress. Nodes are read as the change
+    reaches them and kept until commit writes back the ones it modified, so
+    inserting an extent or truncating touches the nodes on its path and
+    leaves the rest of the tree alone. Nodes a split creates get
+    placeholder numbers and are allocated together at commit: if the
+    filesystem is full, nothing on disk has changed yet.
+*/
+pub struct ExtentTree {
+    root: ExtentNode,
+    root_capacity: usize,
+    block_size: usize,
+    // every node below the root read or created so far, and whether it changed
+    nodes: BTreeMap<u32, (ExtentNode, bool)>,
+    created: u32,
+    // node and data blocks to free at commit
+    released: Vec<u32>,
+}
+
+// a node of the tree: the root, or the one in a block
+type NodeId = Option<u32>;
+
+impl ExtentTree {
+    pub fn new(root: ExtentNode, root_size: usize, block_size: usize) -> Self {
+        Self {
+            root,
+            root_capacity: ExtentNode::capacity(root_size),
+            block_size,
+            nodes: BTreeMap::new(),
+            created: 0,
+            released: Vec::new(),
+        }
+    }
+
+    fn node(&self, id: NodeId) -> &ExtentNode {
+        match id {
+            None => &self.root,
+            Some(block) => &self.nodes[&block].0,
+        }
+    }
+
+    fn node_mut(&mut self, id: NodeId) -> &mut ExtentNode {
+        match id {
+            None => &mut self.root,
+            Some(block) => {
+                let (node, changed) = self.nodes.get_mut(&block).unwrap();
+                *changed = true;
+                node
+            }
+        }
+    }
+
+    fn capacity(&self, id: NodeId) -> usize {
+        id.map_or(self.root_capacity, |_| ExtentNode::capacity(self.block_size))
+    }
+
+    fn load<M: byte_compatible>(&mut self, block: u32, depth: u16, metadata: &fs_metadata<M>) -> std::io::Result<()> {
+        if let Entry::Vacant(entry) = self.nodes.entry(block) {
+            entry.insert((read_node(block, depth, metadata)?, false));
+        }
+        Ok(())
+    }
+
+    fn create(&mut self, node: ExtentNode) -> u32 {
+        let block = NEW_NODE - self.created;
+        self.created += 1;
+        self.nodes.insert(block, (node, true));
+        block
+    }
+
+    fn release_node(&mut self, block: u32) {
+        self.nodes.remove(&block);
+        self.released.push(block);
+    }
+
+    /*
+        The nodes from the root down to the leaf that covers `logical`, each
+        with the position of the child followed below it.
+    */
+    fn path<M: byte_compatible>(&mut self, logical: u64, metadata: &fs_metadata<M>) -> std::io::Result<Vec<(NodeId, usize)>> {
+        let mut path = Vec::new();
+        let mut id = None;
+        loop {
+            let ExtentNode::Index { depth, children } = self.node(id) else {
+                path.push((id, 0));
+                return Ok(path);
+            };
+            // the first child also takes anything below its first block
+            let position = children.partition_point(|&(first, _)| first as u64 <= logical).saturating_sub(1);
+            let (child, depth) = (children[position].1, depth - 1);
+            path.push((id, position));
+            self.load(child, depth, metadata)?;
+            id = Some(child);
+        }
+    }
+
+    /*
+        The extents that overlap logical blocks first..=last, in order.
+    */
+    pub fn extents_in<M: byte_compatible>(&mut self, first: u64, last: u64, metadata: &fs_metadata<M>) -> std::io::Result<Vec<Extent>> {
+        let mut extents = Vec::new();
+        self.collect(None, first, last, &mut extents, metadata)?;
+        Ok(extents)
+    }
+
+    fn collect<M: byte_compatible>(&mut self, id: NodeId, first: u64, last: u64, extents: &mut Vec<Extent>, metadata: &fs_metadata<M>)
+        -> std::io::Result<()>
+    {
+        let (depth, children) = match self.node(id) {
+            ExtentNode::Leaf(leaf) => {
+                extents.extend(leaf.iter().filter(|e| e.end() > first && e.logical as u64 <= last));
+                return Ok(());
+            }
+            ExtentNode::Index { depth, children } => (*depth, children.clone()),
+        };
+        for (i, &(start, child)) in children.iter().enumerate() {
+            let below_next = children.get(i + 1).is_none_or(|&(next, _)| next as u64 > first);
+            if (i == 0 || start as u64 <= last) && below_next {
+                self.load(child, depth - 1, metadata)?;
+                self.collect(Some(child), first, last, extents, metadata)?;
+            }
+        }
+        Ok(())
+    }
+
+    /*
+        Adds `extent`, which overlaps nothing in the tree, joining it to the
+        extents it continues where they share a leaf. A leaf that overflows
+        is split, and so on up; an overflowing root moves its entries into a
+        new node one level down.
+    */
+    pub fn insert<M: byte_compatible>(&mut self, extent: Extent, metadata: &fs_metadata<M>) -> std::io::Result<()> {
+        let path = self.path(extent.logical as u64, metadata)?;
+        let ExtentNode::Leaf(extents) = self.node_mut(path[path.len() - 1].0) else {
+            unreachable!("paths end in a leaf");
+        };
+        let continues = |a: &Extent, b: &Extent| a.end() == b.logical as u64
+            && a.physical as u64 + a.length as u64 == b.physical as u64;
+        let position = extents.partition_point(|e| e.logical < extent.logical);
+        if position > 0 && continues(&extents[position - 1], &extent) {
+            extents[position - 1].length += extent.length;
+            if position < extents.len() && continues(&extents[position - 1], &extents[position]) {
+                let next = extents.remove(position);
+                extents[position - 1].length += next.length;
+            }
+        } else if position < extents.len() && continues(&extent, &extents[position]) {
+            let next = &mut extents[position];
+            *next = Extent { length: extent.length + next.length, ..extent };
+            self.fix_first(&path);
+        } else {
+            extents.insert(position, extent);
+            self.fix_first(&path);
+            self.split(&path, position);
+        }
+        Ok(())
+    }
+
+    // lowers the first block recorded for each node on `path` to what it now holds
+    fn fix_first(&mut self, path: &[(NodeId, usize)]) {
+        for level in (1..path.len()).rev() {
+            let Some(first) = self.node(path[level].0).first() else {
+                return;
+            };
+            let (parent, position) = path[level - 1];
+            let ExtentNode::Index { children, .. } = self.node(parent) else {
+                unreachable!("parents are index nodes");
+            };
+            if children[position].0 <= first {
+                return;
+            }
+            let ExtentNode::Index { children, .. } = self.node_mut(parent) else {
+                unreachable!("parents are index nodes");
+            };
+            children[position].0 = first;
+        }
+    }
+
+    /*
+        Splits the nodes on `path` that overflowed after an entry went in at
+        `position` of the last one. An entry added at the end of a node
+        starts the new one alone, so appending fills nodes completely;
+        otherwise the entries are shared out evenly.
+    */
+    fn split(&mut self, path: &[(NodeId, usize)], mut position: usize) {
+        for level in (0..path.len()).rev() {
+            let id = path[level].0;
+            let count = self.node(id).entry_count();
+            if count <= self.capacity(id) {
+                return;
+            }
+            if id.is_none() {
+                let depth = self.root.depth() + 1;
+                let first = self.root.first().unwrap();
+                let node = std::mem::replace(&mut self.root, ExtentNode::Leaf(Vec::new()));
+                // nodes hold more than the root, so this one has room
+                let child = self.create(node);
+                self.root = ExtentNode::Index { depth, children: vec![(first, child)] };
+                return;
+            }
+            let at = if position + 1 == count { position } else { count / 2 };
+            let upper = self.node_mut(id).split_off(at);
+            let first = upper.first().unwrap();
+            let child = self.create(upper);
+            let (parent, parent_position) = path[level - 1];
+            let ExtentNode::Index { children, .. } = self.node_mut(parent) else {
+                unreachable!("parents are index nodes");
+            };
+            children.insert(parent_position + 1, (first, child));
+            position = parent_position + 1;
+        }
+    }
+
+    /*
+        Drops every block from `keep` on. Subtrees wholly past it are freed
+        and only the nodes along the cut are rewritten; a node left empty
+        goes, one that fits into its left sibling joins it, and a root left
+        with a single child small enough takes that child's place.
+    */
+    pub fn truncate<M: byte_compatible>(&mut self, keep: u64, metadata: &fs_metadata<M>) -> std::io::Result<()> {
+        self.truncate_node(None, keep, metadata)?;
+        while let ExtentNode::Index { depth, children } = &self.root {
+            if children.is_empty() {
+                self.root = ExtentNode::Leaf(Vec::new());
+                break;
+            }
+            let (child, depth) = (children[0].1, depth - 1);
+            if children.len() > 1 {
+                break;
+            }
+            self.load(child, depth, metadata)?;
+            if self.node(Some(child)).entry_count() > self.root_capacity {
+                break;
+            }
+            self.root = self.nodes[&child].0.clone();
+            self.release_node(child);
+        }
+        Ok(())
+    }
+
+    fn truncate_node<M: byte_compatible>(&mut self, id: NodeId, keep: u64, metadata: &fs_metadata<M>) -> std::io::Result<()> {
+        let (depth, mut children) = match self.node(id) {
+            ExtentNode::Leaf(extents) => {
+                let mut kept = Vec::new();
+                let mut released = Vec::new();
+                for &extent in extents {
+                    let length = keep.saturating_sub(extent.logical as u64).min(extent.length as u64) as u32;
+                    released.extend(extent.physical + length..extent.physical + extent.length);
+                    if length > 0 {
+                        kept.push(Extent { length, ..extent });
+                    }
+                }
+                if !released.is_empty() {
+                    self.released.append(&mut released);
+                    *self.node_mut(id) = ExtentNode::Leaf(kept);
+                }
+                return Ok(());
+            }
+            ExtentNode::Index { depth, children } => (*depth, children.clone()),
+        };
+
+        // children starting at or past `keep` go whole, the one before may be cut
+        let cut = children.partition_point(|&(first, _)| (first as u64) < keep);
+        for (_, child) in children.split_off(cut) {
+            self.release_subtree(child, depth - 1, metadata)?;
+        }
+        if let Some(&(_, last)) = children.last() {
+            self.load(last, depth - 1, metadata)?;
+            self.truncate_node(Some(last), keep, metadata)?;
+            let count = self.node(Some(last)).entry_count();
+            if count == 0 {
+                children.pop();
+                self.release_node(last);
+            } else if children.len() > 1 {
+                let previous = children[children.len() - 2].1;
+                self.load(previous, depth - 1, metadata)?;
+                if self.node(Some(previous)).entry_count() + count <= ExtentNode::capacity(self.block_size) {
+                    let node = self.nodes[&last].0.clone();
+                    self.node_mut(Some(previous)).append(node);
+                    children.pop();
+                    self.release_node(last);
+                }
+            }
+        }
+        if self.node(id).entry_count() != children.len() {
+            *self.node_mut(id) = ExtentNode::Index { depth, children };
+        }
+        Ok(())
+    }
+
+    // frees the node in `block`, everything below it and the data it maps
+    fn release_subtree<M: byte_compatible>(&mut self, block: u32, depth: u16, metadata: &fs_metadata<M>) -> std::io::Result<()> {
+        self.load(block, depth, metadata)?;
+        match self.nodes[&block].0.clone() {
+            ExtentNode::Leaf(extents) => for extent in extents {
+                self.released.extend(extent.physical..extent.physical + extent.length);
+            },
+            ExtentNode::Index { depth, children } => for (_, child) in children {
+                self.release_subtree(child, depth - 1, metadata)?;
+            },
+        }
+        self.release_node(block);
+        Ok(())
+    }
+
+    /*
+        Allocates the nodes the change created, writes every node it
+        modified and frees what it released. Returns the new root, for the
+        inode to store.
+    */
+    pub fn commit<M: byte_compatible>(mut self, metadata: &mut fs_metadata<M>) -> std::io::Result<ExtentNode> {
+        let fresh = metadata.allocate_blocks(self.created as usize)?;
+        let created = self.created;
+        let real = |block: u32| if block > NEW_NODE - created { fresh[(NEW_NODE - block) as usize] } else { block };
+        let relabel = |node: &mut ExtentNode| if let ExtentNode::Index { children, .. } = node {
+            children.iter_mut().for_each(|(_, child)| *child = real(*child));
+        };
+
+        relabel(&mut self.root);
+        for (block, (mut node, changed)) in std::mem::take(&mut self.nodes) {
+            if changed {
+                relabel(&mut node);
+                metadata.write_block_data(real(block), 0, &node.serialize(self.block_size))?;
+            }
+        }
+        metadata.free_blocks(&self.released)?;
+        Ok(self.root)
+    }
+}
@@ -1,63 +1,159 @@
 
 use std::cell::RefMut;
-use std::io::Cursor;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{Cursor, Error, ErrorKind};
 use byteorder::{LittleEndian, ReadBytesExt};
 
+use crate::access::MODE_MASK;
+use crate::error::FsError;
 use crate::fs_metadata::fs_metadata;
 use crate::medium::types::byte_compatible;
-use crate::util::{Path, INODE_SIZE};
+use crate::time::{AtimeMode, Timestamp};
+use crate::util::{FEATURE_EXTENTS, INODE_BLOCK_POINTERS, INODE_DIRECT_BLOCKS, INODE_SIZE};
 
-use super::block_bitmap::BlockBitmap;
-use super::inode_bitmap::InodeBitmap;
+use super::extent::{self, Extent, ExtentNode, ExtentTree};
 use super::super_block::SuperBlock;
+use super::xattr::{self, Xattrs, XATTR_MAGIC};
+
+// the extent tree root takes the place of the block pointers
+const EXTENT_ROOT_SIZE: usize = INODE_BLOCK_POINTERS * 4;
+// where the spare bytes holding a small set of xattrs start in the inode slot
+const INLINE_XATTR_START: usize = 4 + 4 + 4 + INODE_BLOCK_POINTERS * 4 + 1 + 8 + 4 * 12 + 2 + 4 + 4 + 4 + 4;
+pub const INLINE_XATTR_SIZE: usize = INODE_SIZE - INLINE_XATTR_START;
+// symlink targets up to this long are kept in the inode, in the same space
+pub const FAST_SYMLINK_SIZE: usize = INODE_BLOCK_POINTERS * 4;
 
 #[repr(u8)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
 pub enum FileType {
     #[default]
     File = 0_u8,
-    Directory = 1
+    Directory = 1,
+    Symlink = 2,
+    Fifo = 3,
+    CharDevice = 4,
+    BlockDevice = 5,
+    Socket = 6,
+}
+
+impl FileType {
+    pub fn is_device(self) -> bool {
+        matches!(self, FileType::CharDevice | FileType::BlockDevice)
+    }
+}
+
+impl TryFrom<u8> for FileType {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(FileType::File),
+            1 => Ok(FileType::Directory),
+            2 => Ok(FileType::Symlink),
+            3 => Ok(FileType::Fifo),
+            4 => Ok(FileType::CharDevice),
+            5 => Ok(FileType::BlockDevice),
+            6 => Ok(FileType::Socket),
+            _ => Err(Error::from(FsError::corrupted("inode", format!("Unknown file type {}", value)))),
+        }
+    }
+}
+
+/*
+    Device numbers are kept the way Linux encodes a dev_t in 32 bits: the
+    low byte of the minor, then 12 bits of major, then the rest of the
+    minor. Numbers up to 255 come out as the familiar major * 256 + minor.
+*/
+pub fn makedev(major: u32, minor: u32) -> u32 {
+    (minor & 0xff) | ((major & 0xfff) << 8) | ((minor & !0xff) << 12)
+}
+
+pub fn major(rdev: u32) -> u32 {
+    (rdev >> 8) & 0xfff
+}
+
+pub fn minor(rdev: u32) -> u32 {
+    (rdev & 0xff) | ((rdev >> 12) & 0xfff00)
 }
 
-#[derive(Debug, Clone, Default)]
+/*
+    Permission bits (within MODE_MASK) and owner of a new inode.
+*/
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Ownership {
+    pub mode: u16,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/*
+    A file, directory or other node. Its names live in the directory
+    entries that point at it, nlink of them for a file. A directory has exactly one name, and
+    its nlink also counts its own "." and the ".." of every subdirectory.
+*/
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct Inode {
-    pub inode_number: u16,
-    pub parent: u16,
-    pub name: String,
-    pub data_blocks: [u16; 32],
-    pub block_bitmap: BlockBitmap,
+    pub inode_number: u32,
+    // for a directory, the directory ".." leads to; unused for other files
+    pub parent: u32,
+    pub nlink: u32,
+    pub data_blocks: [u32; INODE_BLOCK_POINTERS],
     pub file_type: FileType,
-    pub file_size: u32,
+    pub file_size: u64,
+    pub atime: Timestamp,
+    pub mtime: Timestamp,
+    pub ctime: Timestamp,
+    pub crtime: Timestamp,
+    pub mode: u16,
+    pub uid: u32,
+    pub gid: u32,
+    // the device a character or block device node stands for, see makedev
+    pub rdev: u32,
+    // the block holding the extended attributes, or 0 if they are inline
+    pub xattr_block: u32,
+    pub inline_xattrs: Xattrs,
 }
 
 impl Inode {
-    pub fn create_new<T: Path, M: byte_compatible>
-        (parent: u16,
-         name: T,
+    /*
+        Allocates an inode with the link count of a freshly created entry:
+        one, or two for a directory and its ".". Linking it into `parent` is
+        up to the caller.
+    */
+    pub fn create_new<M: byte_compatible>
+        (parent: u32,
          file_type: FileType,
+         owner: Ownership,
          metadata: &mut fs_metadata<M>) -> Result<Self, std::io::Error>
     {
-        let name = name.to_String();
-        if name.len() > crate::util::MAX_FILE_NAME_SIZE {
-            return Err(std::io::Error::new(std::io::ErrorKind::Other, "File name too long"));
-        }
-
-        if metadata.is_inode_bitmap_full() {
-            return Err(std::io::Error::new(std::io::ErrorKind::Other, "No free inodes available"));
-        }
-
-        let inode_number = metadata.inode_find_first_free().expect("No free inodes available") as u16;
+        let inode_number = metadata.inode_find_first_free().ok_or(FsError::NoInodes)? as u32;
+        let now = metadata.now();
+        let data_blocks = if metadata.super_block_has_feature(FEATURE_EXTENTS) {
+            Self::empty_extent_root()
+        } else {
+            [0_u32; INODE_BLOCK_POINTERS]
+        };
         let new_inode = Self {
             inode_number,
-            parent: parent,
-            name: name,
-            data_blocks: [0_u16; 32],
-            block_bitmap: BlockBitmap::new(metadata.super_block_get_total_blocks() as usize),
+            parent,
+            nlink: if file_type == FileType::Directory { 2 } else { 1 },
+            data_blocks,
             file_type,
             file_size: 0,
+            atime: now,
+            mtime: now,
+            ctime: now,
+            crtime: now,
+            mode: owner.mode & MODE_MASK,
+            uid: owner.uid,
+            gid: owner.gid,
+            rdev: 0,
+            xattr_block: 0,
+            inline_xattrs: Xattrs::new(),
         };
         metadata.set_inode_in_bitmap(inode_number);
         metadata.persist_inode_bitmap()?;
+        metadata.persist_super_block()?;
         metadata.persist_inode(&new_inode)?;
 
         Ok(new_inode)
@@ -67,54 +163,846 @@ impl Inode {
         let inode_offset = 
             super_block_ref.get_inode_start_block() as u64 * super_block_ref.get_block_size() as u64
             + (INODE_SIZE as u64 * self.inode_number as u64);
-        medium.write_all(inode_offset, buffer.len(), buffer.as_slice())
+        medium.write_all(inode_offset, buffer.as_slice())
     }
 
+    /*
+        On-disk layout of an inode slot (INODE_SIZE bytes, little endian):
+            inode_number (4) | parent (4) | nlink (4)
+            | data_blocks (INODE_BLOCK_POINTERS * 4) | file_type (1) | file_size (8)
+            | atime (12) | mtime (12) | ctime (12) | crtime (12)
+            | mode (2) | uid (4) | gid (4) | rdev (4) | xattr_block (4)
+            | inline xattrs (rest, see core::xattr)
+        with every timestamp stored as seconds (8, signed) | nanoseconds (4).
+        Which blocks an inode owns is recorded by data_blocks alone. Slots
+        written by older versions are rewritten into this layout by the
+        migration module.
+    */
     fn serialize(&self) -> Vec<u8> {
         let mut buffer: Vec<u8> = Vec::with_capacity(INODE_SIZE);
 
         buffer.extend_from_slice(&self.inode_number.to_le_bytes());
         buffer.extend_from_slice(&self.parent.to_le_bytes());
 
-        let name_bytes = self.name.as_bytes();
-        let mut name_buffer = vec![0_u8; crate::util::MAX_FILE_NAME_SIZE];
-        name_buffer[..name_bytes.len().min(crate::util::MAX_FILE_NAME_SIZE)].copy_from_slice(&name_bytes[..name_bytes.len().min(crate::util::MAX_FILE_NAME_SIZE)]);
-        buffer.extend_from_slice(&name_buffer);
+        buffer.extend_from_slice(&self.nlink.to_le_bytes());
 
         for &block in &self.data_blocks {
             buffer.extend_from_slice(&block.to_le_bytes());
         }
 
-        buffer.extend_from_slice(self.block_bitmap.serialize_to_vec().as_slice());
-
         buffer.push(self.file_type as u8);
         buffer.extend_from_slice(&self.file_size.to_le_bytes());
-
+        for time in [self.atime, self.mtime, self.ctime, self.crtime] {
+            buffer.extend_from_slice(&time.seconds.to_le_bytes());
+            buffer.extend_from_slice(&time.nanoseconds.to_le_bytes());
+        }
+        buffer.extend_from_slice(&self.mode.to_le_bytes());
+        buffer.extend_from_slice(&self.uid.to_le_bytes());
+        buffer.extend_from_slice(&self.gid.to_le_bytes());
+        buffer.extend_from_slice(&self.rdev.to_le_bytes());
+        buffer.extend_from_slice(&self.xattr_block.to_le_bytes());
+        buffer.extend_from_slice(&xattr::encode(&self.inline_xattrs));
 
         buffer.resize(INODE_SIZE, 0); // Ensure the buffer is exactly INODE_SIZE
         buffer
     }
 
-    // fn deserialize(buffer: Vec<u8>) -> 
+    fn deserialize(buffer: &[u8], inode_number: u32, total_blocks: usize, block_size: usize, extents: bool) -> std::io::Result<Self> {
+        if buffer.len() != INODE_SIZE {
+            return Err(Error::from(FsError::corrupted("inode",
+                format!("Inode slot is {} bytes, expected {}", buffer.len(), INODE_SIZE))));
+        }
+
+        let mut cursor = Cursor::new(buffer);
+        let stored_number = cursor.read_u32::<LittleEndian>()?;
+        if stored_number != inode_number {
+            return Err(Error::from(FsError::corrupted("inode",
+                format!("Inode slot {} holds inode number {}", inode_number, stored_number))));
+        }
+        let parent = cursor.read_u32::<LittleEndian>()?;
+
+        let nlink = cursor.read_u32::<LittleEndian>()?;
+        if nlink == 0 {
+            return Err(Error::from(FsError::corrupted("inode",
+                format!("Inode {} is allocated but has no links", inode_number))));
+        }
+
+        let mut data_blocks = [0_u32; INODE_BLOCK_POINTERS];
+        for block in data_blocks.iter_mut() {
+            *block = cursor.read_u32::<LittleEndian>()?;
+        }
+
+        let file_type = FileType::try_from(cursor.read_u8()?)?;
+        let file_size = cursor.read_u64::<LittleEndian>()?;
+        if file_size > Self::max_file_size(block_size, extents) {
+            return Err(Error::from(FsError::corrupted("inode",
+                format!("Inode {} has size {} which does not fit in its data blocks", inode_number, file_size))));
+        }
+        if file_type == FileType::Symlink && (file_size == 0 || file_size > block_size as u64) {
+            return Err(Error::from(FsError::corrupted("inode",
+                format!("Symbolic link {} has a target of {} bytes", inode_number, file_size))));
+        }
+        let fast_symlink = file_type == FileType::Symlink && file_size <= FAST_SYMLINK_SIZE as u64;
+        if !extents && !fast_symlink {
+            if let Some(block) = data_blocks.iter().find(|&&block| block as usize >= total_blocks) {
+                return Err(Error::from(FsError::corrupted("inode",
+                    format!("Inode {} points to block {} beyond the {} blocks of the filesystem",
+                            inode_number, block, total_blocks))));
+            }
+        }
+        let mut times = [Timestamp::default(); 4];
+        for time in times.iter_mut() {
+            *time = Timestamp::new(cursor.read_i64::<LittleEndian>()?, cursor.read_u32::<LittleEndian>()?);
+            if time.nanoseconds >= 1_000_000_000 {
+                return Err(Error::from(FsError::corrupted("inode",
+                    format!("Inode {} has a timestamp with {} nanoseconds", inode_number, time.nanoseconds))));
+            }
+        }
+        let [atime, mtime, ctime, crtime] = times;
+        let mode = cursor.read_u16::<LittleEndian>()?;
+        if mode & !MODE_MASK != 0 {
+            return Err(Error::from(FsError::corrupted("inode",
+                format!("Inode {} has mode {:#o}", inode_number, mode))));
+        }
+        let uid = cursor.read_u32::<LittleEndian>()?;
+        let gid = cursor.read_u32::<LittleEndian>()?;
+        let rdev = cursor.read_u32::<LittleEndian>()?;
+        if rdev != 0 && !file_type.is_device() {
+            return Err(Error::from(FsError::corrupted("inode",
+                format!("Inode {} is not a device but has device number {:#x}", inode_number, rdev))));
+        }
+        let xattr_block = cursor.read_u32::<LittleEndian>()?;
+        if xattr_block as usize >= total_blocks {
+            return Err(Error::from(FsError::corrupted("inode",
+                format!("Inode {} has its xattrs in block {} beyond the filesystem", inode_number, xattr_block))));
+        }
+        let inline_xattrs = xattr::decode(&buffer[INLINE_XATTR_START..])?;
+
+        let inode = Self {
+            inode_number,
+            parent,
+            nlink,
+            data_blocks,
+            file_type,
+            file_size,
+            atime,
+            mtime,
+            ctime,
+            crtime,
+            mode,
+            uid,
+            gid,
+            rdev,
+            xattr_block,
+            inline_xattrs,
+        };
+        if extents && !fast_symlink {
+            inode.extent_root(total_blocks)?;
+        }
+        Ok(inode)
+    }
+
+    pub fn load<T: byte_compatible>(medium: RefMut<'_, T>, inode_number: u32, metadata: &fs_metadata<T>) -> std::io::Result<Self> {
+        if inode_number as usize >= metadata.super_block_get_total_inodes() {
+            return Err(Error::new(ErrorKind::InvalidInput,
+                format!("Inode number {} is out of range", inode_number)));
+        }
+        // a free slot keeps whatever the inode last in it left behind
+        if !metadata.is_inode_allocated(inode_number) {
+            return Err(FsError::NotFound.into());
+        }
 
-    pub fn load<T: byte_compatible>(medium: RefMut<'_, T>, inode_number: u16, metadata: &fs_metadata<T>) -> std::io::Result<Self> {
         let inode_offset = 
             metadata.super_block_get_inode_start_block() as u64 * metadata.super_block_get_block_size() as u64
             + (INODE_SIZE as u64 * inode_number as u64);
         
         let mut buffer = vec![0_u8; INODE_SIZE];
-        let tmp_res = medium.read_all(inode_offset, buffer.len(), &mut buffer);
+        medium.read_all(inode_offset, &mut buffer)?;
+
+        Self::deserialize(&buffer,
+                          inode_number,
+                          metadata.super_block_get_total_blocks(),
+                          metadata.super_block_get_block_size(),
+                          metadata.super_block_has_feature(FEATURE_EXTENTS))
+    }
 
-        if tmp_res.is_err() {
-            return Err(tmp_res.err().unwrap());
+    /*
+        Counts the blocks the file holds on disk: its data blocks, the
+        indirect blocks that point to them and its xattr block.
+    */
+    pub fn allocated_blocks<M: byte_compatible>(&self, metadata: &fs_metadata<M>) -> std::io::Result<u64> {
+        let xattr_blocks = (self.xattr_block != 0) as u64;
+        if self.is_fast_symlink() {
+            return Ok(xattr_blocks);
+        }
+        if metadata.super_block_has_feature(FEATURE_EXTENTS) {
+            let (extents, nodes) = self.read_extents(metadata)?;
+            return Ok(extents.iter().map(|e| e.length as u64).sum::<u64>() + nodes.len() as u64 + xattr_blocks);
         }
 
-        let mut cursor = Cursor::new(buffer);
-        let inode_number =  cursor.read_u16::<LittleEndian>()?; // Read inode number
-        // let inode_number = cursor.read_u16::<LittleEndian>();
+        let mut blocks = xattr_blocks + self.data_blocks[..INODE_DIRECT_BLOCKS].iter().filter(|&&b| b != 0).count() as u64;
+        for depth in 1..=3 {
+            let root = self.data_blocks[INODE_DIRECT_BLOCKS + depth - 1];
+            if root != 0 {
+                let mut tree = Vec::new();
+                Self::collect_tree(root, depth, metadata, &mut tree)?;
+                blocks += tree.len() as u64;
+            }
+        }
+        Ok(blocks)
+    }
 
-        Ok(Inode::default())
+    /*
+        The content changed: the modification and change times move to `now`.
+        write_data and truncate_data do this themselves.
+    */
+    pub fn touch_modified(&mut self, now: Timestamp) {
+        self.mtime = now;
+        self.ctime = now;
     }
-}
 
+    /*
+        The inode itself changed, say its name or its times.
+    */
+    pub fn touch_changed(&mut self, now: Timestamp) {
+        self.ctime = now;
+    }
+
+    /*
+        The content was read. Returns whether that moved the access time,
+        in which case the inode needs persisting.
+    */
+    pub fn touch_accessed(&mut self, now: Timestamp, mode: AtimeMode) -> bool {
+        const DAY: i64 = 24 * 60 * 60;
+        let update = match mode {
+            AtimeMode::Strict => true,
+            AtimeMode::Relatime => self.atime <= self.mtime
+                || self.atime <= self.ctime
+                || now.seconds - self.atime.seconds >= DAY,
+            AtimeMode::NoAtime => false,
+        };
+        if update {
+            self.atime = now;
+        }
+        update
+    }
+
+    /*
+        Releases the inode's data blocks, xattr block and inode slot.
+    */
+    pub fn release<M: byte_compatible>(mut self, metadata: &mut fs_metadata<M>) -> std::io::Result<()> {
+        if !self.is_fast_symlink() {
+            self.truncate_data(0, metadata)?;
+        }
+        if self.xattr_block != 0 {
+            metadata.free_block(self.xattr_block)?;
+        }
+        metadata.free_inode(self.inode_number)
+    }
+
+    pub fn read_xattrs<M: byte_compatible>(&self, metadata: &fs_metadata<M>) -> std::io::Result<Xattrs> {
+        if self.xattr_block == 0 {
+            return Ok(self.inline_xattrs.clone());
+        }
+        let mut block = vec![0_u8; metadata.super_block_get_block_size()];
+        metadata.read_block_data(self.xattr_block, 0, &mut block)?;
+        xattr::decode_block(&block)
+    }
+
+    /*
+        Replaces the extended attributes: in the inode slot if they fit,
+        else in an xattr block, which is allocated or freed as needed. A set
+        too large for a block fails with NoSpace. The caller persists the
+        inode.
+    */
+    pub fn write_xattrs<M: byte_compatible>(&mut self, xattrs: Xattrs, metadata: &mut fs_metadata<M>) -> std::io::Result<()> {
+        let encoded_len = xattr::encode(&xattrs).len();
+        if encoded_len <= INLINE_XATTR_SIZE {
+            if self.xattr_block != 0 {
+                metadata.free_block(self.xattr_block)?;
+                self.xattr_block = 0;
+            }
+            self.inline_xattrs = xattrs;
+            return Ok(());
+        }
+
+        let block_size = metadata.super_block_get_block_size();
+        if XATTR_MAGIC.len() + encoded_len > block_size {
+            return Err(FsError::NoSpace.into());
+        }
+        let allocated = self.xattr_block == 0;
+        if allocated {
+            self.xattr_block = metadata.allocate_block()?;
+        }
+        if let Err(e) = metadata.write_block_data(self.xattr_block, 0, &xattr::encode_block(&xattrs, block_size)) {
+            // a block allocated for the write goes back, leaving the inode as it was
+            if allocated {
+                metadata.free_block(std::mem::take(&mut self.xattr_block))?;
+            }
+            return Err(e);
+        }
+        self.inline_xattrs.clear();
+        Ok(())
+    }
+
+    /*
+        A symbolic link keeps its target the way ext2 does: one of up to
+        FAST_SYMLINK_SIZE bytes sits in the inode in place of the block
+        pointers (a fast symlink), a longer one is the content of a single
+        data block. file_size is the length of the target either way.
+    */
+    pub fn is_fast_symlink(&self) -> bool {
+        self.file_type == FileType::Symlink && self.file_size <= FAST_SYMLINK_SIZE as u64
+    }
+
+    pub fn write_link<M: byte_compatible>(&mut self, target: &[u8], metadata: &mut fs_metadata<M>) -> std::io::Result<()> {
+        if target.is_empty() {
+            return Err(Error::from(FsError::NotFound));
+        }
+        if target.len() > metadata.super_block_get_block_size() {
+            return Err(Error::from(FsError::NameTooLong));
+        }
+        if target.len() <= FAST_SYMLINK_SIZE {
+            let mut inline = [0_u8; FAST_SYMLINK_SIZE];
+            inline[..target.len()].copy_from_slice(target);
+            for (block, bytes) in self.data_blocks.iter_mut().zip(inline.chunks_exact(4)) {
+                *block = u32::from_le_bytes(bytes.try_into().unwrap());
+            }
+            self.file_size = target.len() as u64;
+            return metadata.persist_inode(self);
+        }
+        // sized first, so that releasing the inode after a failed write
+        // frees whatever blocks the write got to allocate
+        self.file_size = target.len() as u64;
+        self.write_data(0, target, metadata)?;
+        metadata.persist_inode(self)
+    }
+
+    pub fn read_link<M: byte_compatible>(&self, metadata: &fs_metadata<M>) -> std::io::Result<Vec<u8>> {
+        if self.is_fast_symlink() {
+            let mut inline: Vec<u8> = self.data_blocks.iter().flat_map(|block| block.to_le_bytes()).collect();
+            inline.truncate(self.file_size as usize);
+            return Ok(inline);
+        }
+        let mut target = vec![0_u8; self.file_size as usize];
+        self.read_data(0, &mut target, metadata)?;
+        Ok(target)
+    }
+
+    /*
+        File data is mapped the classic way: the first INODE_DIRECT_BLOCKS
+        entries of data_blocks point straight at data blocks, and the last
+        three at a single, a double and a triple indirect block, each a block
+        full of 4 byte pointers one level closer to the data. Block i of the
+        file holds bytes [i * block_size, (i + 1) * block_size).
+        A zero pointer is a hole (block 0 is the superblock, so it never
+        holds file data) and reads back as zeros; indirect blocks are only
+        allocated once something below them is. Bytes past file_size inside
+        an allocated block are kept zeroed so that growing the file never
+        exposes stale data. Filesystems with FEATURE_EXTENTS map blocks
+        through an extent tree instead, see core::extent, and are limited by
+        its 32 bit logical block numbers.
+    */
+    pub fn max_file_size(block_size: usize, extents: bool) -> u64 {
+        if extents {
+            return u32::MAX as u64 * block_size as u64;
+        }
+        let per_block = (block_size / 4) as u64;
+        (INODE_DIRECT_BLOCKS as u64 + per_block + per_block.pow(2) + per_block.pow(3)) * block_size as u64
+    }
+
+    /*
+        The data_blocks entry under which block `index` of the file lives,
+        and the entry to follow in each indirect block on the way down.
+    */
+    fn block_path(index: u64, per_block: u64) -> Option<(usize, Vec<usize>)> {
+        if index < INODE_DIRECT_BLOCKS as u64 {
+            return Some((index as usize, Vec::new()));
+        }
+        let mut index = index - INODE_DIRECT_BLOCKS as u64;
+        let mut span = per_block;
+        for depth in 1..=3 {
+            if index < span {
+                let mut path = Vec::with_capacity(depth);
+                let mut below = span;
+                for _ in 0..depth {
+                    below /= per_block;
+                    path.push((index / below) as usize);
+                    index %= below;
+                }
+                return Some((INODE_DIRECT_BLOCKS + depth - 1, path));
+            }
+            index -= span;
+            span *= per_block;
+        }
+        None
+    }
+
+    fn read_pointers<M: byte_compatible>(block: u32, metadata: &fs_metadata<M>) -> std::io::Result<Vec<u32>> {
+        let mut raw = vec![0_u8; metadata.super_block_get_block_size()];
+        metadata.read_block_data(block, 0, &mut raw)?;
+        let total_blocks = metadata.super_block_get_total_blocks();
+        raw.chunks_exact(4)
+            .map(|bytes| {
+                let pointer = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+                if pointer as usize >= total_blocks {
+                    return Err(Error::from(FsError::corrupted("indirect block",
+                        format!("Block {} points to block {} beyond the {} blocks of the filesystem",
+                                block, pointer, total_blocks))));
+                }
+                Ok(pointer)
+            })
+            .collect()
+    }
+
+    fn read_pointer<M: byte_compatible>(block: u32, entry: usize, metadata: &fs_metadata<M>) -> std::io::Result<u32> {
+        let mut raw = [0_u8; 4];
+        metadata.read_block_data(block, entry * 4, &mut raw)?;
+        let pointer = u32::from_le_bytes(raw);
+        if pointer as usize >= metadata.super_block_get_total_blocks() {
+            return Err(Error::from(FsError::corrupted("indirect block",
+                format!("Block {} points to block {} beyond the {} blocks of the filesystem",
+                        block, pointer, metadata.super_block_get_total_blocks()))));
+        }
+        Ok(pointer)
+    }
+
+    /*
+        The block holding block `index` of the file, 0 for a hole.
+    */
+    pub fn lookup_block<M: byte_compatible>(&self, index: u64, metadata: &fs_metadata<M>) -> std::io::Result<u32> {
+        if metadata.super_block_has_feature(FEATURE_EXTENTS) {
+            return self.lookup_extent(index, metadata);
+        }
+        let per_block = (metadata.super_block_get_block_size() / 4) as u64;
+        let Some((slot, path)) = Self::block_path(index, per_block) else {
+            return Ok(0);
+        };
+        let mut block = self.data_blocks[slot];
+        for entry in path {
+            if block == 0 {
+                break;
+            }
+            block = Self::read_pointer(block, entry, metadata)?;
+        }
+        Ok(block)
+    }
+
+    /*
+        The block a pointer path leads to, 0 if something on the way is
+        missing. Paths are looked up once and remembered in `known`.
+    */
+    fn resolve<M: byte_compatible>(&self,
+                                   slot: usize,
+                                   path: &[usize],
+                                   known: &mut BTreeMap<(usize, Vec<usize>), u32>,
+                                   metadata: &fs_metadata<M>) -> std::io::Result<u32> {
+        if let Some(&block) = known.get(&(slot, path.to_vec())) {
+            return Ok(block);
+        }
+        let block = match path.split_last() {
+            None => self.data_blocks[slot],
+            Some((&entry, parent_path)) => match self.resolve(slot, parent_path, known, metadata)? {
+                0 => 0,
+                parent => Self::read_pointer(parent, entry, metadata)?,
+            },
+        };
+        known.insert((slot, path.to_vec()), block);
+        Ok(block)
+    }
+
+    /*
+        Points the last step of a path at `block`. The parent must already
+        be in `known`.
+    */
+    fn link<M: byte_compatible>(&mut self,
+                                slot: usize,
+                                path: &[usize],
+                                block: u32,
+                                known: &BTreeMap<(usize, Vec<usize>), u32>,
+                                metadata: &mut fs_metadata<M>) -> std::io::Result<()> {
+        match path.split_last() {
+            None => self.data_blocks[slot] = block,
+            Some((&entry, parent_path)) => {
+                let parent = known[&(slot, parent_path.to_vec())];
+                metadata.write_block_data(parent, entry * 4, &block.to_le_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn read_data<M: byte_compatible>(&self, offset: u64, buffer: &mut [u8], metadata: &fs_metadata<M>) -> std::io::Result<usize> {
+        if offset >= self.file_size {
+            return Ok(0);
+        }
+        let block_size = metadata.super_block_get_block_size();
+        let len = buffer.len().min((self.file_size - offset) as usize);
+
+        let mut done = 0;
+        while done < len {
+            let position = offset + done as u64;
+            let block_index = position / block_size as u64;
+            let block_offset = (position % block_size as u64) as usize;
+            let chunk = (block_size - block_offset).min(len - done);
+
+            let block = self.lookup_block(block_index, metadata)?;
+            if block == 0 {
+                buffer[done..done + chunk].fill(0);
+            } else {
+                metadata.read_block_data(block, block_offset, &mut buffer[done..done + chunk])?;
+            }
+            done += chunk;
+        }
+
+        Ok(len)
+    }
+
+    /*
+        Writes `buffer` at `offset`, allocating any missing data and mapping
+        blocks in one go so that a write either fits completely or fails
+        without side effects.
+        The caller is responsible for persisting the inode afterwards.
+    */
+    pub fn write_data<M: byte_compatible>(&mut self, offset: u64, buffer: &[u8], metadata: &mut fs_metadata<M>) -> std::io::Result<usize> {
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+        let block_size = metadata.super_block_get_block_size();
+        let extents = metadata.super_block_has_feature(FEATURE_EXTENTS);
+        let end = offset + buffer.len() as u64;
+        if end > Self::max_file_size(block_size, extents) {
+            return Err(Error::new(ErrorKind::FileTooLarge, "File too large"));
+        }
+
+        let first_block = offset / block_size as u64;
+        let last_block = (end - 1) / block_size as u64;
+        let (targets, fresh) = if extents {
+            self.map_extents(first_block, last_block, metadata)?
+        } else {
+            self.map_pointers(first_block, last_block, metadata)?
+        };
+
+        let mut done = 0;
+        while done < buffer.len() {
+            let position = offset + done as u64;
+            let target = (position / block_size as u64 - first_block) as usize;
+            let block_offset = (position % block_size as u64) as usize;
+            let chunk = (block_size - block_offset).min(buffer.len() - done);
+            let block = targets[target];
+
+            if fresh.contains(&target) {
+                // fresh blocks may hold stale data, write them out in full
+                let mut block_buffer = vec![0_u8; block_size];
+                block_buffer[block_offset..block_offset + chunk].copy_from_slice(&buffer[done..done + chunk]);
+                metadata.write_block_data(block, 0, &block_buffer)?;
+            } else {
+                metadata.write_block_data(block, block_offset, &buffer[done..done + chunk])?;
+            }
+            done += chunk;
+        }
+
+        self.file_size = self.file_size.max(end);
+        self.touch_modified(metadata.now());
+        Ok(buffer.len())
+    }
+
+    /*
+        Makes sure file blocks first..=last are backed by pointers, and
+        returns the block behind each along with the positions of the ones
+        that were freshly allocated.
+    */
+    fn map_pointers<M: byte_compatible>(&mut self, first: u64, last: u64, metadata: &mut fs_metadata<M>)
+        -> std::io::Result<(Vec<u32>, BTreeSet<usize>)>
+    {
+        let block_size = metadata.super_block_get_block_size();
+        let per_block = (block_size / 4) as u64;
+
+        // find out what is missing before allocating anything
+        let mut known = BTreeMap::new();
+        let mut missing_indirect = BTreeSet::new();
+        let mut targets = Vec::new();
+        for index in first..=last {
+            // max_file_size was checked by the caller, so every block has a path
+            let (slot, path) = Self::block_path(index, per_block).unwrap();
+            for depth in 0..path.len() {
+                if self.resolve(slot, &path[..depth], &mut known, metadata)? == 0 {
+                    missing_indirect.insert((slot, path[..depth].to_vec()));
+                }
+            }
+            let block = self.resolve(slot, &path, &mut known, metadata)?;
+            targets.push((slot, path, block));
+        }
+
+        let missing_data = targets.iter().filter(|(_, _, block)| *block == 0).count();
+        let mut new_blocks = metadata.allocate_blocks(missing_data + missing_indirect.len())?;
+        let indirect_blocks = new_blocks.split_off(missing_data);
+
+        // a parent's path sorts before its children's, so parents get linked first
+        let zeros = vec![0_u8; block_size];
+        for ((slot, path), block) in missing_indirect.into_iter().zip(indirect_blocks) {
+            metadata.write_block_data(block, 0, &zeros)?;
+            self.link(slot, &path, block, &known, metadata)?;
+            known.insert((slot, path), block);
+        }
+        let mut fresh = BTreeSet::new();
+        let mut new_blocks = new_blocks.into_iter();
+        for (i, (slot, path, block)) in targets.iter_mut().enumerate() {
+            if *block == 0 {
+                *block = new_blocks.next().unwrap();
+                self.link(*slot, path, *block, &known, metadata)?;
+                fresh.insert(i);
+            }
+        }
+
+        Ok((targets.into_iter().map(|(_, _, block)| block).collect(), fresh))
+    }
+
+    /*
+        Appends `block` and, below an indirect block of the given depth,
+        every block it points to.
+    */
+    fn collect_tree<M: byte_compatible>(block: u32, depth: usize, metadata: &fs_metadata<M>, blocks: &mut Vec<u32>) -> std::io::Result<()> {
+        blocks.push(block);
+        if depth > 0 {
+            for pointer in Self::read_pointers(block, metadata)? {
+                if pointer != 0 {
+                    Self::collect_tree(pointer, depth - 1, metadata, blocks)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /*
+        Cuts the tree under indirect block `block` down to its first `keep`
+        file blocks, with 0 < keep < what the tree can map, and appends
+        whatever it no longer needs to `released`.
+    */
+    fn truncate_tree<M: byte_compatible>(block: u32,
+                                         depth: usize,
+                                         keep: u64,
+                                         metadata: &mut fs_metadata<M>,
+                                         released: &mut Vec<u32>) -> std::io::Result<()> {
+        let per_block = (metadata.super_block_get_block_size() / 4) as u64;
+        let child_span = per_block.pow(depth as u32 - 1);
+        let mut pointers = Self::read_pointers(block, metadata)?;
+
+        let kept_children = keep.div_ceil(child_span) as usize;
+        let mut changed = false;
+        for pointer in pointers[kept_children..].iter_mut().filter(|p| **p != 0) {
+            Self::collect_tree(*pointer, depth - 1, metadata, released)?;
+            *pointer = 0;
+            changed = true;
+        }
 
+        let partial = keep % child_span;
+        let last_child = pointers[kept_children - 1];
+        if partial != 0 && last_child != 0 {
+            Self::truncate_tree(last_child, depth - 1, partial, metadata, released)?;
+        }
+
+        if changed {
+            let raw: Vec<u8> = pointers.iter().flat_map(|p| p.to_le_bytes()).collect();
+            metadata.write_block_data(block, 0, &raw)?;
+        }
+        Ok(())
+    }
+
+    /*
+        Shrinks or grows the file to `len` bytes. Shrinking releases the data
+        blocks past the new end, along with any mapping block left with
+        nothing to map, and zeroes the tail of the last one; growing only
+        moves file_size, leaving a hole. Only an actual change of size counts
+        as a modification.
+        The caller is responsible for persisting the inode afterwards.
+    */
+    pub fn truncate_data<M: byte_compatible>(&mut self, len: u64, metadata: &mut fs_metadata<M>) -> std::io::Result<()> {
+        let block_size = metadata.super_block_get_block_size();
+        let extents = metadata.super_block_has_feature(FEATURE_EXTENTS);
+        if len > Self::max_file_size(block_size, extents) {
+            return Err(Error::new(ErrorKind::FileTooLarge, "File too large"));
+        }
+
+        if len < self.file_size {
+            let keep_blocks = len.div_ceil(block_size as u64);
+            if extents {
+                self.truncate_extents(keep_blocks, metadata)?;
+            } else {
+                self.truncate_pointers(keep_blocks, metadata)?;
+            }
+
+            let tail = len as usize % block_size;
+            if tail != 0 {
+                let last = self.lookup_block(keep_blocks - 1, metadata)?;
+                if last != 0 {
+                    let zeros = vec![0_u8; block_size - tail];
+                    metadata.write_block_data(last, tail, &zeros)?;
+                }
+            }
+        }
+
+        if len != self.file_size {
+            self.file_size = len;
+            self.touch_modified(metadata.now());
+        }
+        Ok(())
+    }
+
+    fn truncate_pointers<M: byte_compatible>(&mut self, keep_blocks: u64, metadata: &mut fs_metadata<M>) -> std::io::Result<()> {
+        let per_block = (metadata.super_block_get_block_size() / 4) as u64;
+
+        let mut released = Vec::new();
+        for slot in (keep_blocks.min(INODE_DIRECT_BLOCKS as u64) as usize)..INODE_DIRECT_BLOCKS {
+            if self.data_blocks[slot] != 0 {
+                released.push(self.data_blocks[slot]);
+                self.data_blocks[slot] = 0;
+            }
+        }
+
+        let mut start = INODE_DIRECT_BLOCKS as u64;
+        for depth in 1..=3 {
+            let slot = INODE_DIRECT_BLOCKS + depth - 1;
+            let span = per_block.pow(depth as u32);
+            let keep_here = keep_blocks.saturating_sub(start).min(span);
+            let root = self.data_blocks[slot];
+            if root != 0 && keep_here == 0 {
+                Self::collect_tree(root, depth, metadata, &mut released)?;
+                self.data_blocks[slot] = 0;
+            } else if root != 0 && keep_here < span {
+                Self::truncate_tree(root, depth, keep_here, metadata, &mut released)?;
+            }
+            start += span;
+        }
+        metadata.free_blocks(&released)
+    }
+
+    /*
+        With FEATURE_EXTENTS data_blocks holds the root node of the file's
+        extent tree instead of block pointers.
+    */
+    fn empty_extent_root() -> [u32; INODE_BLOCK_POINTERS] {
+        Self::encode_extent_root(&ExtentNode::Leaf(Vec::new()))
+    }
+
+    fn encode_extent_root(node: &ExtentNode) -> [u32; INODE_BLOCK_POINTERS] {
+        let raw = node.serialize(EXTENT_ROOT_SIZE);
+        let mut root = [0_u32; INODE_BLOCK_POINTERS];
+        for (word, bytes) in root.iter_mut().zip(raw.chunks_exact(4)) {
+            *word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        root
+    }
+
+    fn extent_root(&self, total_blocks: usize) -> std::io::Result<ExtentNode> {
+        let raw: Vec<u8> = self.data_blocks.iter().flat_map(|word| word.to_le_bytes()).collect();
+        ExtentNode::deserialize(&raw, total_blocks)
+    }
+
+    fn lookup_extent<M: byte_compatible>(&self, index: u64, metadata: &fs_metadata<M>) -> std::io::Result<u32> {
+        let mut node = self.extent_root(metadata.super_block_get_total_blocks())?;
+        loop {
+            match node {
+                ExtentNode::Leaf(extents) => return Ok(extent::find_block(&extents, index).unwrap_or(0)),
+                ExtentNode::Index { depth, children } => {
+                    let position = children.partition_point(|&(logical, _)| logical as u64 <= index);
+                    let Some(&(_, child)) = children[..position].last() else {
+                        return Ok(0);
+                    };
+                    node = extent::read_node(child, depth - 1, metadata)?;
+                }
+            }
+        }
+    }
+
+    /*
+        Every extent of the file in logical order, and the node blocks the
+        tree below the root takes.
+    */
+    fn read_extents<M: byte_compatible>(&self, metadata: &fs_metadata<M>) -> std::io::Result<(Vec<Extent>, Vec<u32>)> {
+        fn walk<M: byte_compatible>(node: ExtentNode,
+                                    metadata: &fs_metadata<M>,
+                                    extents: &mut Vec<Extent>,
+                                    nodes: &mut Vec<u32>) -> std::io::Result<()> {
+            match node {
+                ExtentNode::Leaf(leaf) => extents.extend(leaf),
+                ExtentNode::Index { depth, children } => for (_, child) in children {
+                    nodes.push(child);
+                    walk(extent::read_node(child, depth - 1, metadata)?, metadata, extents, nodes)?;
+                },
+            }
+            Ok(())
+        }
+
+        let mut extents = Vec::new();
+        let mut nodes = Vec::new();
+        walk(self.extent_root(metadata.super_block_get_total_blocks())?, metadata, &mut extents, &mut nodes)?;
+        if extents.windows(2).any(|pair| pair[0].end() > pair[1].logical as u64) {
+            return Err(Error::from(FsError::corrupted("extent node",
+                format!("Inode {} has overlapping extents", self.inode_number))));
+        }
+        Ok((extents, nodes))
+    }
+
+    /*
+        map_pointers for extent mapped files. The holes in first..=last get
+        blocks from a single allocation, which prefers one contiguous run,
+        so a sequential write usually extends or adds a single extent. Only
+        the nodes covering the range and those the new extents go into are
+        read.
+    */
+    fn map_extents<M: byte_compatible>(&mut self, first: u64, last: u64, metadata: &mut fs_metadata<M>)
+        -> std::io::Result<(Vec<u32>, BTreeSet<usize>)>
+    {
+        let block_size = metadata.super_block_get_block_size();
+        let mut tree = ExtentTree::new(self.extent_root(metadata.super_block_get_total_blocks())?, EXTENT_ROOT_SIZE, block_size);
+        let extents = tree.extents_in(first, last, metadata)?;
+
+        let mut holes = Vec::new();
+        let mut next = first;
+        for extent in &extents {
+            if extent.logical as u64 > next {
+                holes.push(next..extent.logical as u64);
+            }
+            next = next.max(extent.end());
+        }
+        if next <= last {
+            holes.push(next..last + 1);
+        }
+
+        let missing: u64 = holes.iter().map(|hole| hole.end - hole.start).sum();
+        let new_blocks = metadata.allocate_blocks(missing as usize)?;
+        let mut fresh = BTreeSet::new();
+        let mut added = Vec::new();
+        for (index, &block) in holes.into_iter().flatten().zip(&new_blocks) {
+            fresh.insert((index - first) as usize);
+            added.push(Extent { logical: index as u32, physical: block, length: 1 });
+        }
+        let added = extent::merge(added);
+
+        // the tree may need more nodes; give the data blocks back if there is no room for them
+        let root = added.iter()
+            .try_for_each(|&extent| tree.insert(extent, metadata))
+            .and_then(|_| tree.commit(metadata));
+        match root {
+            Ok(root) => self.data_blocks = Self::encode_extent_root(&root),
+            Err(e) => {
+                metadata.free_blocks(&new_blocks)?;
+                return Err(e);
+            }
+        }
+
+        let mapped = extent::merge(extents.into_iter().chain(added).collect());
+        let targets = (first..=last)
+            .map(|index| extent::find_block(&mapped, index).unwrap_or(0))
+            .collect();
+        Ok((targets, fresh))
+    }
+
+    fn truncate_extents<M: byte_compatible>(&mut self, keep_blocks: u64, metadata: &mut fs_metadata<M>) -> std::io::Result<()> {
+        let block_size = metadata.super_block_get_block_size();
+        let mut tree = ExtentTree::new(self.extent_root(metadata.super_block_get_total_blocks())?, EXTENT_ROOT_SIZE, block_size);
+        tree.truncate(keep_blocks, metadata)?;
+        self.data_blocks = Self::encode_extent_root(&tree.commit(metadata)?);
+        Ok(())
+    }
+}
@@ -4,7 +4,7 @@ use std::cell::RefMut;
 use bitvec::prelude::*;
 
 use super::{block::Block, block_data_types::BlockDataType, super_block::SuperBlock};
-use crate::{medium::types::byte_compatible, util::INODE_BITMAP_STARTING_BLOCK_NUMBER};
+use crate::{error::FsError, medium::types::byte_compatible, util::INODE_BITMAP_STARTING_BLOCK_NUMBER};
 
 #[derive(Clone, Default)]
 pub struct InodeBitmap {
@@ -25,10 +25,7 @@ impl InodeBitmap {
 
         for block in blocks {
             let block_offset = block.block_number as u64 * super_block_ref.get_block_size() as u64;
-            let tmp_res = medium.write_all(block_offset, block.data.len(), block.data.as_slice());
-            if tmp_res.is_err() {
-                return Err(tmp_res.err().unwrap());
-            }
+            medium.write_all(block_offset, block.data.as_slice())?;
         }
 
         Ok(())
@@ -45,45 +42,37 @@ impl InodeBitmap {
         let mut start = INODE_BITMAP_STARTING_BLOCK_NUMBER as u64 * super_block_ref.get_block_size() as u64;
 
         for i in 0..total_inode_bitmap_blocks {
-            let block = Block::default();
             let mut buffer = vec![0_u8; super_block_ref.get_block_size()];
-            let tmp_res = medium.read_all(start, buffer.len(), buffer.as_mut_slice());
-            if tmp_res.is_err() {
-                return Err(tmp_res.err().unwrap());
-            }
+            medium.read_all(start, buffer.as_mut_slice())?;
             blocks.push(Block {
-                block_number: (i + INODE_BITMAP_STARTING_BLOCK_NUMBER) as u16,
+                block_number: (i + INODE_BITMAP_STARTING_BLOCK_NUMBER) as u32,
                 data: buffer,
                 block_type: BlockDataType::InodeBitmap,
             });
             start += super_block_ref.get_block_size() as u64;
         }
         
-        Ok(Self::deserialize(blocks, super_block_ref))
+        Ok(Self::deserialize(blocks, super_block_ref)?)
     }
 
-    fn deserialize(blocks: Vec<Block>, super_block_ref: &SuperBlock) -> Self {
+    fn deserialize(blocks: Vec<Block>, super_block_ref: &SuperBlock) -> Result<Self, FsError> {
         let total_inodes = super_block_ref.get_total_inodes();
-        let mut bitmap = bitvec![u8, Lsb0; 0; total_inodes];
-        bitmap.fill(false);
-
-        let mut current_index = 0;
-        for block in blocks[..blocks.len() - 1].iter() {
-            let data = &block.data;
-            bitmap.as_raw_mut_slice()[current_index..current_index + (data.len() >> 3)]
-                .copy_from_slice(&data);
-            current_index += data.len();
+        let bytes: Vec<u8> = blocks.into_iter()
+            .flat_map(|block| block.data)
+            .collect();
+        if bytes.len() * 8 < total_inodes {
+            return Err(FsError::corrupted("inode bitmap",
+                format!("{} bytes cannot cover {} inodes", bytes.len(), total_inodes)));
         }
-        {
-            // Handle the last block separately to avoid overrun
-            let last_block = &blocks[blocks.len() - 1];
-            let data = &last_block.data;
-            let remaining_bits = total_inodes - current_index * 8;
-            let bytes_to_copy = (remaining_bits + 7) / 8; // Round up to the nearest byte
-            bitmap.as_raw_mut_slice()[current_index..current_index + bytes_to_copy]
-                .copy_from_slice(&data[..bytes_to_copy]);
+
+        let mut bitmap = bitvec![u8, Lsb0; 0; total_inodes];
+        let raw = bitmap.as_raw_mut_slice();
+        let len = raw.len().min(bytes.len());
+        raw[..len].copy_from_slice(&bytes[..len]);
+        if total_inodes > 0 && !bitmap[0] {
+            return Err(FsError::corrupted("inode bitmap", "the root inode is marked free"));
         }
-        Self { bitmap }
+        Ok(Self { bitmap })
     }
 
     fn serialize(&self, super_block_ref: &SuperBlock) -> Vec<Block> {
@@ -100,7 +89,7 @@ impl InodeBitmap {
                 &bitmap_vec[start..end]
             };
             blocks.push(Block {
-                block_number: (i + INODE_BITMAP_STARTING_BLOCK_NUMBER) as u16,
+                block_number: (i + INODE_BITMAP_STARTING_BLOCK_NUMBER) as u32,
                 data: data.to_vec(),
                 block_type: BlockDataType::InodeBitmap,
             });
@@ -116,13 +105,13 @@ impl InodeBitmap {
         self.bitmap.set(inode_num, true);
     }
 
-    pub fn get(&self, inode_num: usize) -> bool {
+    pub fn clear(&mut self, inode_num: usize) {
         assert!(inode_num < self.bitmap.len());
-        *self.bitmap.get(inode_num).unwrap()
+        self.bitmap.set(inode_num, false);
     }
 
-    pub fn is_full(&self) -> bool {
-        self.bitmap.all()
+    pub fn get(&self, inode_num: usize) -> bool {
+        self.bitmap.get(inode_num).map(|bit| *bit).unwrap_or(false)
     }
 
     pub fn find_first_free(&self) -> Option<usize> {
@@ -1,6 +1,9 @@
+pub mod extent;
 pub mod inode;
 pub mod inode_bitmap;
 pub mod super_block;
+pub mod xattr;
+pub mod acl;
 pub mod block;
 pub mod block_bitmap;
 pub mod block_data_types;
\ No newline at end of file
@@ -1,53 +1,186 @@
-use std::{cell::RefMut, cmp::max};
+use std::cell::RefMut;
+use std::io::{Cursor, Error, ErrorKind, Read};
+
+use byteorder::{LittleEndian, ReadBytesExt};
 
 use super::{block::Block, block_data_types::BlockDataType};
 
-use crate::{medium::types::byte_compatible, util::{
-    INODE_SIZE, SUPER_BLOCK_FILE_OFFSET, SUPER_BLOCK_SIZE
+use crate::{error::FsError, medium::types::byte_compatible, util::{
+    FS_MAGIC, INODE_SIZE, MAX_BLOCK_SIZE, MAX_LABEL_SIZE, MIN_BLOCK_SIZE, SUPER_BLOCK_FILE_OFFSET,
+    SUPER_BLOCK_SIZE, FS_VERSION_0_0_1, FS_VERSION_0_0_3, FS_VERSION_0_0_4, FS_VERSION_0_1_0, FS_VERSION_0_3_0, FS_VERSION_0_12_0, SUPPORTED_FEATURES, VALID_FS_VERSIONS
 }};
 
 #[derive(Default)]
 pub struct SuperBlock {
     version: [u8; 3],
-    total_inodes: u16,
-    total_blocks: u16,
-    free_inodes: u16,
-    free_blocks: u16,
+    total_inodes: u32,
+    total_blocks: u32,
+    free_inodes: u32,
+    free_blocks: u32,
     inode_size_log: u8,
     block_size_log: u8,
-    inode_bitmap_block_count: u8,
-    block_bitmap_block_count: u8,
-    inode_start_block: u16,
-    total_inode_blocks: u16,
+    inode_bitmap_block_count: u32,
+    block_bitmap_block_count: u32,
+    inode_start_block: u32,
+    total_inode_blocks: u32,
+    label: [u8; MAX_LABEL_SIZE],
+    features: u32,
+    // keys the hash of directory entry names, see dir_index::name_hash
+    hash_seed: [u32; 4],
 }
 
 
 impl SuperBlock {
-    pub fn create_new(fs_size: u32, block_size: u32, bytes_per_inode: u32) -> Self {
-        let ti = (fs_size / bytes_per_inode) as u16 ;
-        let tb = (fs_size / block_size) as u16;
-        let inode_block_count = (ti as usize * INODE_SIZE) / block_size as usize;
-        let inode_bitmap_block_count = max(1, ti / 8 / block_size as u16);
-        let block_bitmap_block_count = max(1, tb / 8 / block_size as u16);
-
-        Self {
+    /*
+        Works out the on-disk layout for a filesystem of fs_size bytes:
+            block 0                     superblock
+            1 ..                        inode bitmap
+            .. inode_start_block        block bitmap
+            .. first data block         inode table
+        Every count has to fit the width of its superblock field, so
+        geometries that would overflow one are rejected instead of wrapping.
+    */
+    pub fn create_new(fs_size: u64, block_size: u32, bytes_per_inode: u32, label: &str, features: u32) -> std::io::Result<Self> {
+        let invalid = |message: String| Err(Error::new(ErrorKind::InvalidInput, message));
+
+        if !block_size.is_power_of_two() || !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&block_size) {
+            return invalid(format!("Block size {} must be a power of two between {} and {}",
+                                   block_size, MIN_BLOCK_SIZE, MAX_BLOCK_SIZE));
+        }
+        if bytes_per_inode == 0 {
+            return invalid("Bytes per inode must be greater than zero".to_string());
+        }
+        if label.len() > MAX_LABEL_SIZE {
+            return invalid(format!("Label {:?} is longer than {} bytes", label, MAX_LABEL_SIZE));
+        }
+        if features & !SUPPORTED_FEATURES != 0 {
+            return invalid(format!("Unknown features {:#x}", features & !SUPPORTED_FEATURES));
+        }
+
+        let tb = fs_size / block_size as u64;
+        let ti = fs_size / bytes_per_inode as u64;
+        if tb > u32::MAX as u64 {
+            return invalid(format!("{} blocks exceed the limit of {} blocks, use a larger block size",
+                                   tb, u32::MAX));
+        }
+        if ti > u32::MAX as u64 {
+            return invalid(format!("{} inodes exceed the limit of {} inodes, use more bytes per inode",
+                                   ti, u32::MAX));
+        }
+        let (tb, ti) = (tb as usize, ti as usize);
+        if ti == 0 {
+            return invalid("The filesystem would not have any inodes".to_string());
+        }
+
+        let block_size = block_size as usize;
+        let (inode_bitmap_block_count, block_bitmap_block_count, inode_block_count) =
+            Self::metadata_block_counts(ti, tb, block_size);
+
+        let inode_start_block = inode_bitmap_block_count + block_bitmap_block_count + 1; // 1 for superblock
+        if inode_start_block + inode_block_count >= tb {
+            return invalid(format!("{} bytes leave no room for data blocks after the metadata", fs_size));
+        }
+
+        let mut label_bytes = [0_u8; MAX_LABEL_SIZE];
+        label_bytes[..label.len()].copy_from_slice(label.as_bytes());
+
+        Ok(Self {
             version: crate::util::get_latest_version(),
-            total_inodes: ti,
-            total_blocks: tb,
-            free_inodes: ti,
-            free_blocks: tb,
+            total_inodes: ti as u32,
+            total_blocks: tb as u32,
+            free_inodes: ti as u32,
+            free_blocks: tb as u32,
             block_size_log: block_size.ilog2() as u8,
             inode_size_log: INODE_SIZE.ilog2() as u8,
-            inode_bitmap_block_count: inode_bitmap_block_count as u8,
-            block_bitmap_block_count: block_bitmap_block_count as u8,
-            inode_start_block: inode_bitmap_block_count + block_bitmap_block_count + 1, // 1 for superblock
-            total_inode_blocks: inode_block_count as u16,
+            inode_bitmap_block_count: inode_bitmap_block_count as u32,
+            block_bitmap_block_count: block_bitmap_block_count as u32,
+            inode_start_block: inode_start_block as u32,
+            total_inode_blocks: inode_block_count as u32,
+            label: label_bytes,
+            features,
+            hash_seed: [0; 4],
+        })
+    }
+
+    /*
+        Blocks taken by the inode bitmap, the block bitmap and the inode
+        table for a given geometry.
+    */
+    fn metadata_block_counts(total_inodes: usize, total_blocks: usize, block_size: usize) -> (usize, usize, usize) {
+        (total_inodes.div_ceil(8).div_ceil(block_size),
+         total_blocks.div_ceil(8).div_ceil(block_size),
+         (total_inodes * INODE_SIZE).div_ceil(block_size))
+    }
+
+    /*
+        Checks that a superblock read from disk describes a layout create_new
+        could have produced and that fits on a medium of medium_size bytes.
+        Everything else trusts these numbers, so a bad one must stop the
+        load here rather than turn into an out of range read later.
+    */
+    fn validate(&self, medium_size: u64) -> Result<(), FsError> {
+        let corrupted = |reason: String| Err(FsError::corrupted("superblock", reason));
+
+        let block_size_logs = MIN_BLOCK_SIZE.ilog2()..=MAX_BLOCK_SIZE.ilog2();
+        if !block_size_logs.contains(&(self.block_size_log as u32)) {
+            return corrupted(format!("block size 2^{} is outside {} to {} bytes",
+                                     self.block_size_log, MIN_BLOCK_SIZE, MAX_BLOCK_SIZE));
+        }
+        if self.inode_size_log as u32 != INODE_SIZE.ilog2() {
+            return corrupted(format!("inode size 2^{} is not {} bytes", self.inode_size_log, INODE_SIZE));
+        }
+        if self.total_inodes == 0 {
+            return corrupted("there are no inodes".to_string());
+        }
+        if self.features & !SUPPORTED_FEATURES != 0 {
+            return Err(FsError::UnsupportedFeatures(self.features & !SUPPORTED_FEATURES));
+        }
+
+        let (inode_bitmap_blocks, block_bitmap_blocks, inode_table_blocks) =
+            Self::metadata_block_counts(self.get_total_inodes(), self.get_total_blocks(), self.get_block_size());
+        if self.get_inode_bitmap_block_count() != inode_bitmap_blocks
+            || self.get_block_bitmap_block_count() != block_bitmap_blocks
+            || self.get_total_inode_blocks() != inode_table_blocks
+            || self.get_inode_start_block() != 1 + inode_bitmap_blocks + block_bitmap_blocks {
+            return corrupted(format!("metadata layout does not match {} inodes and {} blocks",
+                                     self.total_inodes, self.total_blocks));
+        }
+        if self.get_first_data_block() >= self.get_total_blocks() {
+            return corrupted(format!("{} blocks leave no room for data", self.total_blocks));
         }
+
+        // 0.0.1 never grew the image to its full size, the upgrade does
+        let fs_size = self.get_total_blocks() as u64 * self.get_block_size() as u64;
+        if fs_size > medium_size && self.version != FS_VERSION_0_0_1 {
+            return corrupted(format!("{} blocks of {} bytes need {} bytes but the medium holds {}",
+                                     self.total_blocks, self.get_block_size(), fs_size, medium_size));
+        }
+        if self.free_inodes > self.total_inodes || self.free_blocks > self.total_blocks {
+            return corrupted(format!("free counts {} inodes / {} blocks exceed the totals",
+                                     self.free_inodes, self.free_blocks));
+        }
+        Ok(())
     }
 
     pub fn persist<T: byte_compatible>(&self, medium: RefMut<'_, T>) -> std::io::Result<()> {
         let buffer = self.serialize();
-        medium.write_all(SUPER_BLOCK_FILE_OFFSET, buffer.data.len(), buffer.data.as_slice())
+        medium.write_all(SUPER_BLOCK_FILE_OFFSET, buffer.data.as_slice())
+    }
+
+    pub fn get_version(&self) -> [u8; 3] {
+        self.version
+    }
+
+    pub fn set_version(&mut self, version: [u8; 3]) {
+        self.version = version;
+    }
+
+    pub fn get_hash_seed(&self) -> [u32; 4] {
+        self.hash_seed
+    }
+
+    pub fn set_hash_seed(&mut self, hash_seed: [u32; 4]) {
+        self.hash_seed = hash_seed;
     }
 
     #[inline(always)]
@@ -79,20 +212,94 @@ impl SuperBlock {
         self.block_bitmap_block_count as usize
     }
 
+    #[inline(always)]
+    pub fn get_total_inode_blocks(&self) -> usize {
+        self.total_inode_blocks as usize
+    }
+
+    #[inline(always)]
+    pub fn get_free_inodes(&self) -> usize {
+        self.free_inodes as usize
+    }
+
+    pub fn set_free_inodes(&mut self, free_inodes: usize) {
+        self.free_inodes = free_inodes as u32;
+    }
+
+    #[inline(always)]
+    pub fn get_free_blocks(&self) -> usize {
+        self.free_blocks as usize
+    }
+
+    pub fn set_free_blocks(&mut self, free_blocks: usize) {
+        self.free_blocks = free_blocks as u32;
+    }
+
+    pub fn get_label(&self) -> String {
+        let len = self.label.iter().position(|&b| b == 0).unwrap_or(MAX_LABEL_SIZE);
+        String::from_utf8_lossy(&self.label[..len]).into_owned()
+    }
+
+    pub fn has_feature(&self, feature: u32) -> bool {
+        self.features & feature != 0
+    }
+
+    /*
+        Blocks [0, get_first_data_block()) hold the superblock, both bitmaps
+        and the inode table; they are never handed out for file data.
+    */
+    pub fn get_first_data_block(&self) -> usize {
+        self.get_inode_start_block() + self.get_total_inode_blocks()
+    }
+
+    /*
+        magic (4) | version (3) | total_inodes (4) | total_blocks (4) | free_inodes (4)
+        | free_blocks (4) | inode_size_log (1) | block_size_log (1) | inode_bitmap_block_count (4)
+        | block_bitmap_block_count (4) | inode_start_block (4) | total_inode_blocks (4) | label (16)
+        | features (4) | hash_seed (16)
+        Versions before 0.0.4 have no magic and start with the version.
+        Versions before 0.1.0 have the same fields with the counts and block
+        numbers two bytes wide and the bitmap block counts one byte.
+        Versions before 0.0.3 have no label, versions before 0.3.0 no
+        features and versions before 0.12.0 no hash seed. A superblock is written in the layout of its version,
+        so that an upgrade can persist each step it takes.
+    */
     fn serialize(&self) -> Block {
         let mut buffer: Vec<u8> = Vec::new();
+        let narrow = self.version < FS_VERSION_0_1_0;
+        let push_wide = |buffer: &mut Vec<u8>, value: u32| if narrow {
+            buffer.extend_from_slice(&(value as u16).to_le_bytes());
+        } else {
+            buffer.extend_from_slice(&value.to_le_bytes());
+        };
         // serialize all the fields of the superblock into buffer
+        if self.version >= FS_VERSION_0_0_4 {
+            buffer.extend_from_slice(&FS_MAGIC);
+        }
         buffer.extend_from_slice(&self.version);
-        buffer.extend_from_slice(&self.total_inodes.to_le_bytes());
-        buffer.extend_from_slice(&self.total_blocks.to_le_bytes());
-        buffer.extend_from_slice(&self.free_inodes.to_le_bytes());
-        buffer.extend_from_slice(&self.free_blocks.to_le_bytes());
+        push_wide(&mut buffer, self.total_inodes);
+        push_wide(&mut buffer, self.total_blocks);
+        push_wide(&mut buffer, self.free_inodes);
+        push_wide(&mut buffer, self.free_blocks);
         buffer.push(self.inode_size_log);
         buffer.push(self.block_size_log);
-        buffer.push(self.inode_bitmap_block_count);
-        buffer.push(self.block_bitmap_block_count);
-        buffer.extend_from_slice(&self.inode_start_block.to_le_bytes());
-        buffer.extend_from_slice(&self.total_inode_blocks.to_le_bytes());
+        if narrow {
+            buffer.extend_from_slice(&[self.inode_bitmap_block_count as u8, self.block_bitmap_block_count as u8]);
+        } else {
+            buffer.extend_from_slice(&self.inode_bitmap_block_count.to_le_bytes());
+            buffer.extend_from_slice(&self.block_bitmap_block_count.to_le_bytes());
+        }
+        push_wide(&mut buffer, self.inode_start_block);
+        push_wide(&mut buffer, self.total_inode_blocks);
+        if self.version >= FS_VERSION_0_0_3 {
+            buffer.extend_from_slice(&self.label);
+        }
+        if self.version >= FS_VERSION_0_3_0 {
+            buffer.extend_from_slice(&self.features.to_le_bytes());
+        }
+        if self.version >= FS_VERSION_0_12_0 {
+            self.hash_seed.iter().for_each(|word| buffer.extend_from_slice(&word.to_le_bytes()));
+        }
         // buffer.resize(self.get_block_size(), 0);
 
         Block {
@@ -103,28 +310,88 @@ impl SuperBlock {
     }
 
     pub fn deserialize<T: byte_compatible>(file: RefMut<'_, T>) -> Result<SuperBlock, std::io::Error> {
+        let medium_size = file.size()?;
+        if medium_size < SUPER_BLOCK_FILE_OFFSET + SUPER_BLOCK_SIZE as u64 {
+            return Err(FsError::NotAFilesystem.into());
+        }
+
         let mut block = Block::default();
         block.data.resize(SUPER_BLOCK_SIZE, 0);
 
-        file.read_all(SUPER_BLOCK_FILE_OFFSET, block.data.len(), block.data.as_mut_slice())?;
-        SuperBlock::deserialize_block(block)
-    }
-
-    fn deserialize_block(block: Block) -> Result<SuperBlock, std::io::Error> {
-        let bytes = block.data.as_slice();
-        
-        Ok(SuperBlock {
-            version: [bytes[0], bytes[1], bytes[2]],
-            total_inodes: u16::from_le_bytes([bytes[3], bytes[4]]),
-            total_blocks: u16::from_le_bytes([bytes[5], bytes[6]]),
-            free_inodes: u16::from_le_bytes([bytes[7], bytes[8]]),
-            free_blocks: u16::from_le_bytes([bytes[9], bytes[10]]),
-            inode_size_log: bytes[11],
-            block_size_log: bytes[12],
-            inode_bitmap_block_count: bytes[13],
-            block_bitmap_block_count: bytes[14],
-            inode_start_block: u16::from_le_bytes([bytes[15], bytes[16]]),
-            total_inode_blocks: u16::from_le_bytes([bytes[17], bytes[18]]),
-        })
+        file.read_all(SUPER_BLOCK_FILE_OFFSET, block.data.as_mut_slice())?;
+        let super_block = SuperBlock::deserialize_block(block)?;
+        super_block.validate(medium_size)?;
+        Ok(super_block)
+    }
+
+    fn deserialize_block(block: Block) -> Result<SuperBlock, FsError> {
+        let mut cursor = Cursor::new(block.data.as_slice());
+        let mut read = || -> std::io::Result<SuperBlock> {
+            let mut magic = [0_u8; 4];
+            cursor.read_exact(&mut magic)?;
+            let mut version = [0_u8; 3];
+            if magic == FS_MAGIC {
+                cursor.read_exact(&mut version)?;
+                if !VALID_FS_VERSIONS.contains(&version) {
+                    return Err(FsError::UnsupportedVersion(version).into());
+                }
+            } else {
+                // versions without a magic start with their own number
+                version.copy_from_slice(&magic[..3]);
+                if version >= FS_VERSION_0_0_4 || !VALID_FS_VERSIONS.contains(&version) {
+                    return Err(FsError::NotAFilesystem.into());
+                }
+                cursor.set_position(3);
+            }
+
+            // before 0.1.0 everything was stored in the narrowest type that fit the old limits
+            let narrow = version < FS_VERSION_0_1_0;
+            let read_wide = |cursor: &mut Cursor<&[u8]>| if narrow {
+                cursor.read_u16::<LittleEndian>().map(u32::from)
+            } else {
+                cursor.read_u32::<LittleEndian>()
+            };
+
+            let total_inodes = read_wide(&mut cursor)?;
+            let total_blocks = read_wide(&mut cursor)?;
+            let free_inodes = read_wide(&mut cursor)?;
+            let free_blocks = read_wide(&mut cursor)?;
+            let inode_size_log = cursor.read_u8()?;
+            let block_size_log = cursor.read_u8()?;
+            let (inode_bitmap_block_count, block_bitmap_block_count) = if narrow {
+                (cursor.read_u8()? as u32, cursor.read_u8()? as u32)
+            } else {
+                (cursor.read_u32::<LittleEndian>()?, cursor.read_u32::<LittleEndian>()?)
+            };
+            let mut super_block = SuperBlock {
+                version,
+                total_inodes,
+                total_blocks,
+                free_inodes,
+                free_blocks,
+                inode_size_log,
+                block_size_log,
+                inode_bitmap_block_count,
+                block_bitmap_block_count,
+                inode_start_block: read_wide(&mut cursor)?,
+                total_inode_blocks: read_wide(&mut cursor)?,
+                label: [0_u8; MAX_LABEL_SIZE],
+                features: 0,
+                hash_seed: [0; 4],
+            };
+            if version >= FS_VERSION_0_0_3 {
+                cursor.read_exact(&mut super_block.label)?;
+            }
+            if version >= FS_VERSION_0_3_0 {
+                super_block.features = cursor.read_u32::<LittleEndian>()?;
+            }
+            if version >= FS_VERSION_0_12_0 {
+                for word in super_block.hash_seed.iter_mut() {
+                    *word = cursor.read_u32::<LittleEndian>()?;
+                }
+            }
+            Ok(super_block)
+        };
+        read().map_err(FsError::from)
     }
 }
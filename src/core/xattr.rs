@@ -0,0 +1,84 @@
+use std::collections::BTreeMap;
+use std::io::Error;
+
+use crate::error::FsError;
+
+/*
+    Extended attributes are name/value pairs attached to an inode. A set
+    small enough lives in the spare bytes at the end of the inode slot;
+    a larger one moves as a whole into an xattr block of its own, which
+    the inode points at.
+
+    Both places hold the same list of entries, sorted by name (little
+    endian):
+        name length (1) | value length (2) | name | value
+    ending at a zero name length or at the end of the space. An xattr
+    block starts with XATTR_MAGIC.
+*/
+
+pub type Xattrs = BTreeMap<String, Vec<u8>>;
+
+pub const XATTR_MAGIC: [u8; 4] = *b"FiXA";
+// the limits Linux puts on names and values
+pub const XATTR_NAME_MAX: usize = 255;
+pub const XATTR_SIZE_MAX: usize = u16::MAX as usize;
+
+// setxattr(2) flags: fail if the attribute exists, or if it does not
+pub const XATTR_CREATE: u32 = 1;
+pub const XATTR_REPLACE: u32 = 2;
+
+const ENTRY_HEADER_SIZE: usize = 3;
+
+pub fn encode(xattrs: &Xattrs) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    for (name, value) in xattrs {
+        buffer.push(name.len() as u8);
+        buffer.extend_from_slice(&(value.len() as u16).to_le_bytes());
+        buffer.extend_from_slice(name.as_bytes());
+        buffer.extend_from_slice(value);
+    }
+    buffer
+}
+
+pub fn decode(buffer: &[u8]) -> Result<Xattrs, Error> {
+    let corrupted = |reason: &str| Error::from(FsError::corrupted("xattrs", reason));
+
+    let mut xattrs = Xattrs::new();
+    let mut rest = buffer;
+    while let Some(&name_len) = rest.first() {
+        if name_len == 0 {
+            break;
+        }
+        if rest.len() < ENTRY_HEADER_SIZE {
+            return Err(corrupted("entry header runs past the end"));
+        }
+        let name_len = name_len as usize;
+        let value_len = u16::from_le_bytes([rest[1], rest[2]]) as usize;
+        let end = ENTRY_HEADER_SIZE + name_len + value_len;
+        if end > rest.len() {
+            return Err(corrupted("entry runs past the end"));
+        }
+        let name = std::str::from_utf8(&rest[ENTRY_HEADER_SIZE..ENTRY_HEADER_SIZE + name_len])
+            .map_err(|_| corrupted("name is not UTF-8"))?;
+        let value = rest[ENTRY_HEADER_SIZE + name_len..end].to_vec();
+        if xattrs.insert(name.to_string(), value).is_some() {
+            return Err(corrupted("duplicate name"));
+        }
+        rest = &rest[end..];
+    }
+    Ok(xattrs)
+}
+
+pub fn encode_block(xattrs: &Xattrs, block_size: usize) -> Vec<u8> {
+    let mut block = XATTR_MAGIC.to_vec();
+    block.extend_from_slice(&encode(xattrs));
+    block.resize(block_size, 0);
+    block
+}
+
+pub fn decode_block(block: &[u8]) -> Result<Xattrs, Error> {
+    if block[..XATTR_MAGIC.len()] != XATTR_MAGIC {
+        return Err(FsError::corrupted("xattrs", "bad xattr block magic").into());
+    }
+    decode(&block[XATTR_MAGIC.len()..])
+}
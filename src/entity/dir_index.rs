@@ -0,0 +1,182 @@
+use std::hash::{BuildHasher, Hasher, RandomState};
+use std::io::Error;
+
+use crate::error::FsError;
+
+/*
+    Hashed directory indexes, after ext3's htree. Once a directory outgrows
+    its first block, that block becomes the root of a tree keyed by the hash
+    of entry names, whose leaves are ordinary blocks of directory records.
+    Lookup, insertion and removal read one index node per level and then a
+    single leaf.
+
+    Index nodes hide from a reader walking the records: every one starts
+    with a free record spanning its block, so a linear scan of an indexed
+    directory still finds each entry exactly once. The root's free record
+    carries INDEX_MAGIC where an entry would have its inode number, which a
+    free record otherwise never has.
+
+    Node layout (little endian):
+        free record header (8) | depth (1) | reserved (3) | count (4)
+        followed by `count` entries of 8 bytes:
+            lowest hash (4) | block (4)
+    Entries are sorted by hash, and a node's first entry covers every hash
+    below the second one; the root's first hash is 0. Nodes of depth 0 point
+    at leaves, deeper ones at nodes one level down.
+
+    Name hashes are even. An entry whose hash has the CONTINUATION bit set
+    points at a leaf carrying on the names of that hash from the leaf
+    before, after ext3: when more names share a hash than a leaf holds,
+    they spill into as many leaves as it takes, and a lookup goes on into
+    the next leaf for as long as it continues. Only continuations may
+    repeat a hash.
+*/
+
+pub const INDEX_MAGIC: u32 = u32::from_le_bytes(*b"FiDX");
+// the root may sit this far above the nodes pointing at leaves
+pub const MAX_INDEX_DEPTH: u8 = 3;
+const HEADER_SIZE: usize = 16;
+const ENTRY_SIZE: usize = 8;
+
+// marks an entry whose leaf continues the names of the hash from the one before
+pub const CONTINUATION: u32 = 1;
+
+/*
+    The hash names are indexed by: SipHash-2-4 keyed with the filesystem's
+    hash seed, cut to 32 bits with the CONTINUATION bit clear. Without the
+    seed, nobody can pick names that all land in one leaf.
+*/
+pub fn name_hash(seed: [u32; 4], name: &[u8]) -> u32 {
+    name_hashes(seed, name).0
+}
+
+/*
+    name_hash along with a minor hash from the other half of the SipHash,
+    which tells apart nearly all names sharing the first. Listings go in
+    the order of both.
+*/
+pub fn name_hashes(seed: [u32; 4], name: &[u8]) -> (u32, u32) {
+    let key = [seed[0] as u64 | (seed[1] as u64) << 32, seed[2] as u64 | (seed[3] as u64) << 32];
+    let hash = siphash(key, name);
+    (hash as u32 & !CONTINUATION, (hash >> 32) as u32)
+}
+
+fn siphash(key: [u64; 2], data: &[u8]) -> u64 {
+    fn round(v: &mut [u64; 4]) {
+        v[0] = v[0].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(13) ^ v[0];
+        v[0] = v[0].rotate_left(32);
+        v[2] = v[2].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(16) ^ v[2];
+        v[0] = v[0].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(21) ^ v[0];
+        v[2] = v[2].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(17) ^ v[2];
+        v[2] = v[2].rotate_left(32);
+    }
+    fn compress(v: &mut [u64; 4], word: u64) {
+        v[3] ^= word;
+        round(v);
+        round(v);
+        v[0] ^= word;
+    }
+
+    let mut v = [key[0] ^ 0x736f_6d65_7073_6575, key[1] ^ 0x646f_7261_6e64_6f6d,
+                 key[0] ^ 0x6c79_6765_6e65_7261, key[1] ^ 0x7465_6462_7974_6573];
+    let mut words = data.chunks_exact(8);
+    for word in &mut words {
+        compress(&mut v, u64::from_le_bytes(word.try_into().unwrap()));
+    }
+    // the last word holds what is left over and the length
+    let mut last = [0_u8; 8];
+    last[..words.remainder().len()].copy_from_slice(words.remainder());
+    last[7] = data.len() as u8;
+    compress(&mut v, u64::from_le_bytes(last));
+    v[2] ^= 0xff;
+    (0..4).for_each(|_| round(&mut v));
+    v[0] ^ v[1] ^ v[2] ^ v[3]
+}
+
+/*
+    A fresh hash seed for a filesystem. RandomState is keyed from the
+    operating system's random source.
+*/
+pub fn random_seed() -> [u32; 4] {
+    let mut seed = [0; 4];
+    for pair in seed.chunks_exact_mut(2) {
+        let random = RandomState::new().build_hasher().finish();
+        pair.copy_from_slice(&[random as u32, (random >> 32) as u32]);
+    }
+    seed
+}
+
+// whether `block` is the root of an index
+pub fn is_root(block: &[u8]) -> bool {
+    block.len() >= HEADER_SIZE && block[..4] == INDEX_MAGIC.to_le_bytes() && block[6] == 0
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexNode {
+    pub depth: u8,
+    // (lowest hash, block) for every child
+    pub children: Vec<(u32, u32)>,
+}
+
+impl IndexNode {
+    /*
+        Entries that fit in a node of `size` bytes.
+    */
+    pub fn capacity(size: usize) -> usize {
+        (size - HEADER_SIZE) / ENTRY_SIZE
+    }
+
+    /*
+        The child covering `hash`. The caller has made sure the node covers
+        it at all.
+    */
+    pub fn child_for(&self, hash: u32) -> usize {
+        self.children.partition_point(|&(low, _)| low <= hash).saturating_sub(1)
+    }
+
+    /*
+        Encodes the node into a block of `size` bytes, the root if `root`.
+        The caller makes sure the entries fit.
+    */
+    pub fn serialize(&self, size: usize, root: bool) -> Vec<u8> {
+        let mut block = Vec::with_capacity(size);
+        block.extend_from_slice(&(if root { INDEX_MAGIC } else { 0 }).to_le_bytes());
+        // a whole block of the largest size is stored as 0, like any record
+        block.extend_from_slice(&(size as u16).to_le_bytes());
+        block.extend_from_slice(&[0, 0, self.depth, 0, 0, 0]);
+        block.extend_from_slice(&(self.children.len() as u32).to_le_bytes());
+        for &(low, child) in &self.children {
+            block.extend_from_slice(&low.to_le_bytes());
+            block.extend_from_slice(&child.to_le_bytes());
+        }
+        block.resize(size, 0);
+        block
+    }
+
+    pub fn deserialize(block: &[u8], root: bool) -> Result<Self, Error> {
+        let corrupted = |reason: &str| Error::from(FsError::corrupted("directory index", reason));
+        let length = u16::from_le_bytes([block[4], block[5]]) as usize;
+        if block[6] != 0 || length != block.len() % (1 << 16) {
+            return Err(corrupted("node is not hidden in a free record"));
+        }
+        let depth = block[8];
+        let count = u32::from_le_bytes([block[12], block[13], block[14], block[15]]) as usize;
+        if depth > MAX_INDEX_DEPTH || count == 0 || count > Self::capacity(block.len()) {
+            return Err(corrupted("bad node header"));
+        }
+        let children: Vec<(u32, u32)> = block[HEADER_SIZE..HEADER_SIZE + count * ENTRY_SIZE]
+            .chunks(ENTRY_SIZE)
+            .map(|entry| (u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]]),
+                          u32::from_le_bytes([entry[4], entry[5], entry[6], entry[7]])))
+            .collect();
+        let out_of_order = |pair: &[(u32, u32)]| pair[0].0 > pair[1].0 || (pair[0].0 == pair[1].0 && pair[1].0 & CONTINUATION == 0);
+        if children.windows(2).any(out_of_order) || (root && children[0].0 != 0) {
+            return Err(corrupted("entries out of order"));
+        }
+        Ok(Self { depth, children })
+    }
+}
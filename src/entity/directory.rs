@@ -1,51 +1,947 @@
 /*
     this file represents the structure of a directory entry in the filesystem
     it contains, the corresponding Inode, and helper functions
+
+    A directory's data is a whole number of blocks, read and written through
+    the same block mapping as regular file data. Each block holds a chain of
+    variable length records (see put_record), the way ext2 lays them out: a
+    record runs up to the next one and the last one up to the end of the
+    block, so none crosses a block boundary. A record without a name is
+    free space.
+
+    A small directory is a single block, searched from start to end. A new
+    entry goes into the slack after the first record with room for it; when
+    there is none, the directory is rebuilt with a hashed index (see
+    dir_index) whose leaves are blocks like this one, and from then on each
+    name has exactly one leaf it can be in. A removed entry's record is
+    merged into the one before it. A directory without an index cuts off
+    blocks left empty at its end; an indexed one keeps them for the index
+    to point at.
 */
 
+use std::borrow::Cow;
 use std::cell::RefMut;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::io::{Error, ErrorKind};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
 
-use crate::core::inode::{FileType, Inode};
+use crate::core::acl;
+use crate::core::inode::{FileType, Inode, Ownership};
+use crate::error::FsError;
 use crate::fs_metadata::fs_metadata;
 use crate::medium::types::byte_compatible;
-use crate::util::Path;
+use crate::util::{Path, MAX_BLOCK_SIZE, MAX_FILE_NAME_SIZE};
+
+use super::dir_index::{self, IndexNode, CONTINUATION, MAX_INDEX_DEPTH};
+
+// inode_number (4) | record length (2) | name length (1) | file type (1)
+const RECORD_HEADER_SIZE: usize = 8;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirectoryEntry {
+    pub name: OsString,
+    pub inode_number: u32,
+    // the type of the inode, kept in the entry so listings need not load it
+    pub file_type: FileType,
+}
+
+/*
+    Writes a record of `length` bytes at `offset`: `entry`, or free space
+    for None.
+        inode_number (4) | record length (2) | name length (1) | file type (1) | name
+    A whole block of MAX_BLOCK_SIZE is one more than the length field
+    holds and is stored as 0.
+*/
+fn put_record(block: &mut [u8], offset: usize, length: usize, entry: Option<&DirectoryEntry>) {
+    let (inode_number, file_type, name) = entry.map_or((0, 0, &[][..]),
+        |entry| (entry.inode_number, entry.file_type as u8, entry.name.as_bytes()));
+    let record = &mut block[offset..offset + RECORD_HEADER_SIZE + name.len()];
+    record[..4].copy_from_slice(&inode_number.to_le_bytes());
+    record[4..6].copy_from_slice(&(length as u16).to_le_bytes());
+    record[6] = name.len() as u8;
+    record[7] = file_type;
+    record[RECORD_HEADER_SIZE..].copy_from_slice(name);
+}
+
+fn set_record_length(block: &mut [u8], offset: usize, length: usize) {
+    block[offset + 4..offset + 6].copy_from_slice(&(length as u16).to_le_bytes());
+}
+
+/*
+    The records of one block, in order: where each starts, how long it is
+    and the entry it holds, if any.
+*/
+struct Records<'a> {
+    block: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Records<'a> {
+    fn new(block: &'a [u8]) -> Self {
+        Self { block, offset: 0 }
+    }
+}
+
+impl Iterator for Records<'_> {
+    type Item = Result<(usize, usize, Option<DirectoryEntry>), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let offset = self.offset;
+        if offset >= self.block.len() {
+            return None;
+        }
+        let corrupted = |reason: String| {
+            // nothing after a broken record can be trusted
+            Some(Err(FsError::corrupted("directory entry", reason).into()))
+        };
+        if offset + RECORD_HEADER_SIZE > self.block.len() {
+            self.offset = self.block.len();
+            return corrupted(format!("record at {} runs past the end of the block", offset));
+        }
+        let record = &self.block[offset..];
+        let inode_number = u32::from_le_bytes([record[0], record[1], record[2], record[3]]);
+        let length = match u16::from_le_bytes([record[4], record[5]]) as usize {
+            0 if self.block.len() == MAX_BLOCK_SIZE as usize => MAX_BLOCK_SIZE as usize,
+            length => length,
+        };
+        let name_len = record[6] as usize;
+        if length < RECORD_HEADER_SIZE + name_len || offset + length > self.block.len() {
+            self.offset = self.block.len();
+            return corrupted(format!("invalid record length {} at {}", length, offset));
+        }
+        self.offset += length;
+        if name_len == 0 {
+            return Some(Ok((offset, length, None)));
+        }
+
+        let Ok(file_type) = FileType::try_from(record[7]) else {
+            return corrupted(format!("unknown file type {} at {}", record[7], offset));
+        };
+        let entry = DirectoryEntry {
+            name: OsString::from_vec(record[RECORD_HEADER_SIZE..RECORD_HEADER_SIZE + name_len].to_vec()),
+            inode_number,
+            file_type,
+        };
+        Some(Ok((offset, length, Some(entry))))
+    }
+}
+
+// the bytes a record holding `entry` needs
+fn record_size(entry: Option<&DirectoryEntry>) -> usize {
+    entry.map_or(0, |entry| RECORD_HEADER_SIZE + entry.name.len())
+}
+
+// entries paired with their name hashes, in hash order
+fn sorted_by_hash(entries: Vec<DirectoryEntry>, seed: [u32; 4]) -> Vec<(u32, DirectoryEntry)> {
+    let mut hashed: Vec<_> = entries.into_iter()
+        .map(|entry| (dir_index::name_hash(seed, entry.name.as_bytes()), entry))
+        .collect();
+    hashed.sort_by_key(|&(hash, _)| hash);
+    hashed
+}
+
+/*
+    A block of `block_size` bytes holding `entries`, the last record running
+    to the end of it. The caller makes sure they fit.
+*/
+fn pack_leaf(entries: &[(u32, DirectoryEntry)], block_size: usize) -> Vec<u8> {
+    let mut block = vec![0_u8; block_size];
+    let mut offset = 0;
+    for (i, (_, entry)) in entries.iter().enumerate() {
+        let size = record_size(Some(entry));
+        let length = if i + 1 == entries.len() { block_size - offset } else { size };
+        put_record(&mut block, offset, length, Some(entry));
+        offset += size;
+    }
+    if entries.is_empty() {
+        put_record(&mut block, 0, block_size, None);
+    }
+    block
+}
+
+/*
+    Shares the `entries` of an overflowing leaf, sorted by hash, out into
+    blocks: two as even as can be, split between names with different
+    hashes if that is possible at all. Failing that, a few long names may
+    take three.
+*/
+fn split_leaf(mut entries: Vec<(u32, DirectoryEntry)>, block_size: usize) -> Vec<Vec<(u32, DirectoryEntry)>> {
+    let total: usize = entries.iter().map(|(_, entry)| record_size(Some(entry))).sum();
+    let mut lower = 0;
+    // (splits a hash, imbalance, where)
+    let mut best: Option<(bool, usize, usize)> = None;
+    for i in 1..entries.len() {
+        lower += record_size(Some(&entries[i - 1].1));
+        if lower > block_size || total - lower > block_size {
+            continue;
+        }
+        let candidate = (entries[i].0 == entries[i - 1].0, lower.abs_diff(total - lower), i);
+        if best.is_none_or(|best| candidate < best) {
+            best = Some(candidate);
+        }
+    }
+    if let Some((_, _, i)) = best {
+        let upper = entries.split_off(i);
+        return vec![entries, upper];
+    }
+
+    let mut parts = vec![Vec::new()];
+    let mut used = 0;
+    for entry in entries {
+        let size = record_size(Some(&entry.1));
+        if used + size > block_size {
+            parts.push(Vec::new());
+            used = 0;
+        }
+        used += size;
+        parts.last_mut().unwrap().push(entry);
+    }
+    parts
+}
 
-#[derive(Default)]
+// the index entry for a leaf starting with `first`, after one ending with `previous`
+fn leaf_key(previous: u32, first: u32) -> u32 {
+    if first == previous { first | CONTINUATION } else { first }
+}
+
+/*
+    Lays out `entries` as an indexed directory, block by block: the root,
+    then the leaves, filled to three quarters so that the next insertions
+    need not split them, then the index nodes between the two, if the root
+    cannot point at every leaf itself. Names sharing a hash stay in one
+    leaf, unless there are more of them than a block holds.
+*/
+fn build_index(entries: Vec<DirectoryEntry>, block_size: usize, seed: [u32; 4]) -> Result<Vec<Vec<u8>>, Error> {
+    let entries = sorted_by_hash(entries, seed);
+    let fill = block_size * 3 / 4;
+    // (index entry, first entry, bytes used) of every leaf
+    let mut leaves: Vec<(u32, usize, usize)> = Vec::new();
+    let mut start = 0;
+    while start < entries.len() {
+        let hash = entries[start].0;
+        let run = entries[start..].iter().take_while(|(other, _)| *other == hash).count();
+        let size: usize = entries[start..start + run].iter().map(|(_, entry)| record_size(Some(entry))).sum();
+        if size <= block_size {
+            match leaves.last_mut() {
+                Some((_, _, used)) if *used + size <= fill => *used += size,
+                _ => leaves.push((hash, start, size)),
+            }
+        } else {
+            let mut used = block_size;
+            for (i, (_, entry)) in entries.iter().enumerate().skip(start).take(run) {
+                let size = record_size(Some(entry));
+                if used + size > block_size {
+                    leaves.push((if i == start { hash } else { hash | CONTINUATION }, i, 0));
+                    used = 0;
+                }
+                used += size;
+                leaves.last_mut().unwrap().2 = used;
+            }
+        }
+        start += run;
+    }
+
+    let mut blocks = vec![Vec::new()];
+    let mut level = Vec::with_capacity(leaves.len());
+    for (i, &(low, first, _)) in leaves.iter().enumerate() {
+        let end = leaves.get(i + 1).map_or(entries.len(), |&(_, next, _)| next);
+        // the first leaf covers every hash below the second
+        level.push((if i == 0 { 0 } else { low }, blocks.len() as u32));
+        blocks.push(pack_leaf(&entries[first..end], block_size));
+    }
+
+    let capacity = IndexNode::capacity(block_size);
+    let mut depth = 0;
+    while level.len() > capacity {
+        if depth == MAX_INDEX_DEPTH {
+            return Err(Error::new(ErrorKind::StorageFull, "Directory index is full"));
+        }
+        let mut parents = Vec::new();
+        for children in level.chunks(capacity * 3 / 4) {
+            parents.push((children[0].0, blocks.len() as u32));
+            blocks.push(IndexNode { depth, children: children.to_vec() }.serialize(block_size, false));
+        }
+        level = parents;
+        depth += 1;
+    }
+    blocks[0] = IndexNode { depth, children: level }.serialize(block_size, true);
+    Ok(blocks)
+}
+
+// a record with slack for another entry: offset, length and the bytes in use
+type Room = (usize, usize, usize);
+// an index node on the way down: its block, the node and the position of the child taken
+type IndexStep = (usize, IndexNode, usize);
+
+#[derive(Default, Clone)]
 pub struct Directory {
     inode: Inode,
 }
 
 impl Directory {
-    pub fn get_inode_number(&self) -> u16 {
+    pub fn get_inode_number(&self) -> u32 {
         self.inode.inode_number
     }
 
+    pub fn inode(&self) -> &Inode {
+        &self.inode
+    }
+
     pub fn create_new<T: Path, M: byte_compatible>(
         ftype: FileType,
         name: T,
-        parent: Option<&Directory>,
+        parent: Option<&mut Directory>,
+        owner: Ownership,
         metadata: &mut fs_metadata<M>
     ) -> Result<Self, std::io::Error> {
-        Ok(Self { 
-            inode: Inode::create_new(
-                if parent.is_none() { 0 } else { parent.unwrap().get_inode_number() },
-                name,
-                ftype,
-                metadata)?
-        })
+        let name = name.to_os_str();
+        let mut inode = Inode::create_new(
+            parent.as_ref().map_or(0, |p| p.get_inode_number()),
+            ftype,
+            owner,
+            metadata)?;
+
+        if let Some(parent) = parent {
+            if let Err(e) = acl::inherit(&mut inode, parent.inode(), metadata) {
+                inode.release(metadata)?;
+                return Err(e);
+            }
+            // the new directory's ".." links back to its parent
+            if ftype == FileType::Directory {
+                parent.adjust_nlink(1);
+            }
+            if let Err(e) = parent.add_entry(name, &inode, metadata) {
+                inode.release(metadata)?;
+                return Err(e);
+            }
+        }
+
+        Ok(Self { inode })
     }
 
     pub fn load<M: byte_compatible>(
-        inode_num: u16,
+        inode_num: u32,
         metadata: &fs_metadata<M>,
         medium: RefMut<'_, M>) -> Result<Self, std::io::Error>
     {
-        Ok(Self {
-            inode: Inode::load(
-                medium,
-                0,
-                metadata)?
+        Self::from_inode(Inode::load(
+            medium,
+            inode_num,
+            metadata)?)
+    }
+
+    pub fn from_inode(inode: Inode) -> Result<Self, std::io::Error> {
+        if inode.file_type != FileType::Directory {
+            return Err(FsError::NotADirectory.into());
+        }
+        Ok(Self { inode })
+    }
+
+    /*
+        Opens the directory for reading, counting its entries once.
+    */
+    pub fn open<M: byte_compatible>(self, metadata: &fs_metadata<M>) -> Result<DirectoryHandle<'_, M>, Error> {
+        let len = self.entry_count(metadata)?;
+        Ok(DirectoryHandle {
+            directory: self,
+            metadata,
+            len,
         })
     }
 
-}
\ No newline at end of file
+    pub fn entry_count<M: byte_compatible>(&self, metadata: &fs_metadata<M>) -> Result<usize, Error> {
+        self.iter(metadata).try_fold(0, |count, entry| entry.map(|_| count + 1))
+    }
+
+    pub fn is_empty<M: byte_compatible>(&self, metadata: &fs_metadata<M>) -> Result<bool, Error> {
+        Ok(self.iter(metadata).next().transpose()?.is_none())
+    }
+
+    pub fn iter<'a, M: byte_compatible>(&'a self, metadata: &'a fs_metadata<M>) -> DirectoryIter<'a, M> {
+        DirectoryIter::new(Cow::Borrowed(self), metadata, 0)
+    }
+
+    /*
+        Iterates from `position`, which an earlier iterator's position()
+        handed out, to the end. Listings go in the order of the names'
+        hashes (see listing_position), not of where the records are, so a
+        position still holds once entries have moved, as they do when a leaf
+        splits or the directory gets an index. Entries added since come up
+        if they hash after it.
+    */
+    pub fn into_iter_from<M: byte_compatible>(self, metadata: &fs_metadata<M>, position: u64) -> DirectoryIter<'_, M> {
+        DirectoryIter::new(Cow::Owned(self), metadata, position)
+    }
+
+    fn block_count<M: byte_compatible>(&self, metadata: &fs_metadata<M>) -> Result<usize, Error> {
+        let block_size = metadata.super_block_get_block_size() as u64;
+        if !self.inode.file_size.is_multiple_of(block_size) {
+            return Err(FsError::corrupted("directory",
+                format!("size {} is not a whole number of blocks", self.inode.file_size)).into());
+        }
+        Ok((self.inode.file_size / block_size) as usize)
+    }
+
+    fn read_block<M: byte_compatible>(&self, index: usize, metadata: &fs_metadata<M>) -> Result<Vec<u8>, Error> {
+        let mut block = vec![0_u8; metadata.super_block_get_block_size()];
+        self.inode.read_data((index * block.len()) as u64, &mut block, metadata)?;
+        Ok(block)
+    }
+
+    fn write_block<M: byte_compatible>(&mut self, index: usize, block: &[u8], metadata: &mut fs_metadata<M>) -> Result<(), Error> {
+        self.inode.write_data((index * block.len()) as u64, block, metadata)?;
+        Ok(())
+    }
+
+    /*
+        The root of the directory's index, if it has one.
+    */
+    fn index_root<M: byte_compatible>(&self, metadata: &fs_metadata<M>) -> Result<Option<IndexNode>, Error> {
+        if self.block_count(metadata)? == 0 {
+            return Ok(None);
+        }
+        let block = self.read_block(0, metadata)?;
+        if !dir_index::is_root(&block) {
+            return Ok(None);
+        }
+        IndexNode::deserialize(&block, true).map(Some)
+    }
+
+    /*
+        Follows the index from `root` down to the leaf covering `hash`:
+        the nodes passed on the way, each with its block and the position
+        of the child taken, and the leaf.
+    */
+    fn walk_index<M: byte_compatible>(
+        &self,
+        root: IndexNode,
+        hash: u32,
+        metadata: &fs_metadata<M>) -> Result<(Vec<IndexStep>, usize), Error>
+    {
+        let position = root.child_for(hash);
+        let mut path = vec![(0, root, position)];
+        let leaf = self.descend(&mut path, |node| node.child_for(hash), metadata)?;
+        Ok((path, leaf))
+    }
+
+    /*
+        Goes down from the last node on `path`, taking the child `choose`
+        picks in each node below it, to a leaf.
+    */
+    fn descend<M: byte_compatible>(
+        &self,
+        path: &mut Vec<IndexStep>,
+        choose: impl Fn(&IndexNode) -> usize,
+        metadata: &fs_metadata<M>) -> Result<usize, Error>
+    {
+        let corrupted = |reason: &str| Error::from(FsError::corrupted("directory index", reason));
+        let blocks = self.block_count(metadata)?;
+        loop {
+            let (_, node, position) = &path[path.len() - 1];
+            let child = node.children[*position].1 as usize;
+            if child == 0 || child >= blocks {
+                return Err(corrupted("entry points outside the directory"));
+            }
+            if node.depth == 0 {
+                return Ok(child);
+            }
+            let depth = node.depth - 1;
+            let next = IndexNode::deserialize(&self.read_block(child, metadata)?, false)?;
+            // depths only go down, so the walk cannot loop
+            if next.depth != depth {
+                return Err(corrupted("node at the wrong depth"));
+            }
+            let position = choose(&next);
+            path.push((child, next, position));
+        }
+    }
+
+    /*
+        Moves `path` on to the next leaf, if there is one and `follows`
+        accepts the lowest hash it is entered under, and returns that leaf.
+    */
+    fn next_leaf<M: byte_compatible>(
+        &self,
+        path: &mut Vec<IndexStep>,
+        follows: impl Fn(u32) -> bool,
+        metadata: &fs_metadata<M>) -> Result<Option<usize>, Error>
+    {
+        let Some(level) = path.iter().rposition(|(_, node, position)| position + 1 < node.children.len()) else {
+            return Ok(None);
+        };
+        let (_, node, position) = &path[level];
+        if !follows(node.children[position + 1].0) {
+            return Ok(None);
+        }
+        path.truncate(level + 1);
+        path[level].2 += 1;
+        self.descend(path, |_| 0, metadata).map(Some)
+    }
+
+    /*
+        Writes `blocks`, given by index. Those past the end go first, so if
+        the filesystem runs out of space they are dropped again and the
+        directory is left as it was.
+    */
+    fn write_blocks<M: byte_compatible>(&mut self, mut blocks: Vec<(usize, Vec<u8>)>, metadata: &mut fs_metadata<M>) -> Result<(), Error> {
+        let old_blocks = self.block_count(metadata)?;
+        blocks.sort_by_key(|&(index, _)| (index < old_blocks, index));
+        for (index, block) in &blocks {
+            if let Err(e) = self.write_block(*index, block, metadata) {
+                if *index >= old_blocks {
+                    self.inode.truncate_data((old_blocks * block.len()) as u64, metadata)?;
+                }
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /*
+        The entry called `name`, with the block and offset of its record.
+    */
+    fn find<M: byte_compatible>(&self, name: &[u8], metadata: &fs_metadata<M>) -> Result<Option<(usize, usize, DirectoryEntry)>, Error> {
+        let search = |index: usize| -> Result<Option<(usize, usize, DirectoryEntry)>, Error> {
+            let block = self.read_block(index, metadata)?;
+            for record in Records::new(&block) {
+                if let (offset, _, Some(entry)) = record? {
+                    if entry.name.as_bytes() == name {
+                        return Ok(Some((index, offset, entry)));
+                    }
+                }
+            }
+            Ok(None)
+        };
+
+        let Some(root) = self.index_root(metadata)? else {
+            for index in 0..self.block_count(metadata)? {
+                if let Some(found) = search(index)? {
+                    return Ok(Some(found));
+                }
+            }
+            return Ok(None);
+        };
+        let hash = dir_index::name_hash(metadata.super_block_get_hash_seed(), name);
+        let (mut path, mut leaf) = self.walk_index(root, hash, metadata)?;
+        loop {
+            if let Some(found) = search(leaf)? {
+                return Ok(Some(found));
+            }
+            match self.next_leaf(&mut path, |low| low == hash | CONTINUATION, metadata)? {
+                Some(next) => leaf = next,
+                None => return Ok(None),
+            }
+        }
+    }
+
+    pub fn lookup<T: Path, M: byte_compatible>(&self, name: T, metadata: &fs_metadata<M>) -> Result<Option<u32>, Error> {
+        Ok(self.find(name.to_le_bytes(), metadata)?
+            .map(|(_, _, entry)| entry.inode_number))
+    }
+
+    /*
+        The name of an entry pointing at `inode_number`, if there is one.
+    */
+    pub fn name_of<M: byte_compatible>(&self, inode_number: u32, metadata: &fs_metadata<M>) -> Result<Option<OsString>, Error> {
+        for entry in self.iter(metadata) {
+            let entry = entry?;
+            if entry.inode_number == inode_number {
+                return Ok(Some(entry.name));
+            }
+        }
+        Ok(None)
+    }
+
+    /*
+        Changes the link count of the directory itself, as a subdirectory's
+        ".." comes or goes. Persisted with the next change to the directory.
+    */
+    pub fn adjust_nlink(&mut self, delta: i32) {
+        self.inode.nlink = self.inode.nlink.saturating_add_signed(delta);
+    }
+
+    /*
+        Links `name` to `inode`, which must be persisted already.
+    */
+    pub fn add_entry<T: Path, M: byte_compatible>(
+        &mut self,
+        name: T,
+        inode: &Inode,
+        metadata: &mut fs_metadata<M>) -> Result<(), Error>
+    {
+        let name = name.to_os_str();
+        if name.is_empty() || name.as_bytes().contains(&b'/') || name.as_bytes().contains(&0) {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("Invalid file name {:?}", name)));
+        }
+        if name.len() > MAX_FILE_NAME_SIZE {
+            return Err(FsError::NameTooLong.into());
+        }
+
+        let entry = DirectoryEntry { name: name.to_os_string(), inode_number: inode.inode_number, file_type: inode.file_type };
+        match self.index_root(metadata)? {
+            Some(root) => self.add_indexed(root, entry, metadata)?,
+            None => self.add_linear(entry, metadata)?,
+        }
+        metadata.persist_inode(&self.inode)
+    }
+
+    /*
+        Reads the records of `block` for adding `entry`: the entries already
+        there, and the first record with room for it.
+    */
+    fn scan_for_room(block: &[u8], entry: &DirectoryEntry) -> Result<(Vec<DirectoryEntry>, Option<Room>), Error> {
+        let needed = record_size(Some(entry));
+        let mut entries = Vec::new();
+        let mut room = None;
+        for record in Records::new(block) {
+            let (offset, length, existing) = record?;
+            let used = record_size(existing.as_ref());
+            if room.is_none() && length - used >= needed {
+                room = Some((offset, length, used));
+            }
+            if let Some(existing) = existing {
+                if existing.name == entry.name {
+                    return Err(Error::new(ErrorKind::AlreadyExists, format!("{:?} already exists", entry.name)));
+                }
+                entries.push(existing);
+            }
+        }
+        Ok((entries, room))
+    }
+
+    // puts `entry` into the room scan_for_room found
+    fn put_in_room(block: &mut [u8], (offset, length, used): Room, entry: &DirectoryEntry) {
+        if used > 0 {
+            set_record_length(block, offset, used);
+        }
+        put_record(block, offset + used, length - used, Some(entry));
+    }
+
+    /*
+        Adds `entry` to a directory without an index, into the first block
+        with room for it. A directory that needs another block gets an index
+        instead.
+    */
+    fn add_linear<M: byte_compatible>(&mut self, entry: DirectoryEntry, metadata: &mut fs_metadata<M>) -> Result<(), Error> {
+        let block_size = metadata.super_block_get_block_size();
+        let blocks = self.block_count(metadata)?;
+        let mut entries = Vec::new();
+        let mut room = None;
+        for index in 0..blocks {
+            let block = self.read_block(index, metadata)?;
+            let (existing, found) = Self::scan_for_room(&block, &entry)?;
+            entries.extend(existing);
+            if room.is_none() {
+                room = found.map(|found| (index, block, found));
+            }
+        }
+
+        match room {
+            Some((index, mut block, found)) => {
+                Self::put_in_room(&mut block, found, &entry);
+                self.write_block(index, &block, metadata)
+            }
+            None if blocks == 0 => self.write_block(0, &pack_leaf(&[(0, entry)], block_size), metadata),
+            None => {
+                entries.push(entry);
+                let layout = build_index(entries, block_size, metadata.super_block_get_hash_seed())?;
+                let new_blocks = layout.len();
+                self.write_blocks(layout.into_iter().enumerate().collect(), metadata)?;
+                if new_blocks < blocks {
+                    self.inode.truncate_data((new_blocks * block_size) as u64, metadata)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /*
+        Adds `entry` to the leaf of an indexed directory covering its hash,
+        or to one continuing it. When all of them are full the last is split
+        and the new leaves added to the node above it, which may split in
+        turn; a full root moves its entries down into two new nodes and
+        becomes one level deeper.
+    */
+    fn add_indexed<M: byte_compatible>(&mut self, root: IndexNode, entry: DirectoryEntry, metadata: &mut fs_metadata<M>) -> Result<(), Error> {
+        let block_size = metadata.super_block_get_block_size();
+        let seed = metadata.super_block_get_hash_seed();
+        let hash = dir_index::name_hash(seed, entry.name.as_bytes());
+        let (mut path, mut leaf) = self.walk_index(root, hash, metadata)?;
+        // every leaf of the run is read, for a name already there
+        let mut room = None;
+        let mut entries = loop {
+            let block = self.read_block(leaf, metadata)?;
+            let (entries, found) = Self::scan_for_room(&block, &entry)?;
+            if room.is_none() {
+                room = found.map(|found| (leaf, block, found));
+            }
+            match self.next_leaf(&mut path, |low| low == hash | CONTINUATION, metadata)? {
+                Some(next) => leaf = next,
+                None => break entries,
+            }
+        };
+        if let Some((index, mut block, found)) = room {
+            Self::put_in_room(&mut block, found, &entry);
+            return self.write_block(index, &block, metadata);
+        }
+
+        entries.push(entry);
+        let parts = split_leaf(sorted_by_hash(entries, seed), block_size);
+        let mut next = self.block_count(metadata)?;
+        let mut writes = BTreeMap::from([(leaf, pack_leaf(&parts[0], block_size))]);
+        let mut added = Vec::new();
+        for pair in parts.windows(2) {
+            added.push((leaf_key(pair[0][pair[0].len() - 1].0, pair[1][0].0), next as u32));
+            writes.insert(next, pack_leaf(&pair[1], block_size));
+            next += 1;
+        }
+
+        let capacity = IndexNode::capacity(block_size);
+        while let Some((index, mut node, position)) = path.pop() {
+            node.children.splice(position + 1..position + 1, added);
+            if node.children.len() <= capacity {
+                writes.insert(index, node.serialize(block_size, index == 0));
+                break;
+            }
+            let upper = IndexNode { depth: node.depth, children: node.children.split_off(node.children.len() / 2) };
+            if index == 0 {
+                if node.depth == MAX_INDEX_DEPTH {
+                    return Err(Error::new(ErrorKind::StorageFull, "Directory index is full"));
+                }
+                let root = IndexNode { depth: node.depth + 1, children: vec![(0, next as u32), (upper.children[0].0, next as u32 + 1)] };
+                writes.insert(next, node.serialize(block_size, false));
+                writes.insert(next + 1, upper.serialize(block_size, false));
+                writes.insert(0, root.serialize(block_size, true));
+                break;
+            }
+            added = vec![(upper.children[0].0, next as u32)];
+            writes.insert(index, node.serialize(block_size, false));
+            writes.insert(next, upper.serialize(block_size, false));
+            next += 1;
+        }
+        self.write_blocks(writes.into_iter().collect(), metadata)
+    }
+
+    /*
+        Unlinks `name` and returns the inode number it pointed to. The inode
+        itself is left alone; releasing it is up to the caller.
+    */
+    pub fn remove_entry<T: Path, M: byte_compatible>(
+        &mut self,
+        name: T,
+        metadata: &mut fs_metadata<M>) -> Result<u32, Error>
+    {
+        let name = name.to_os_str();
+        let indexed = self.index_root(metadata)?.is_some();
+        let (index, offset, entry) = self.find(name.as_bytes(), metadata)?
+            .ok_or(FsError::NotFound)?;
+
+        let mut block = self.read_block(index, metadata)?;
+        let mut previous = None;
+        let mut length = 0;
+        for record in Records::new(&block) {
+            let (record_offset, record_length, _) = record?;
+            if record_offset == offset {
+                length = record_length;
+                break;
+            }
+            previous = Some((record_offset, record_length));
+        }
+        match previous {
+            Some((previous_offset, previous_length)) => set_record_length(&mut block, previous_offset, previous_length + length),
+            None => put_record(&mut block, offset, length, None),
+        }
+        self.write_block(index, &block, metadata)?;
+
+        let mut blocks = self.block_count(metadata)?;
+        while !indexed && blocks > 0 && self.is_block_empty(blocks - 1, metadata)? {
+            blocks -= 1;
+            self.inode.truncate_data((blocks * block.len()) as u64, metadata)?;
+        }
+        metadata.persist_inode(&self.inode)?;
+
+        Ok(entry.inode_number)
+    }
+
+    /*
+        Points the entry `name` at `inode` instead, with a single write, and
+        returns the inode number it pointed to.
+    */
+    pub fn replace_entry<T: Path, M: byte_compatible>(
+        &mut self,
+        name: T,
+        inode: &Inode,
+        metadata: &mut fs_metadata<M>) -> Result<u32, Error>
+    {
+        let name = name.to_os_str();
+        let (index, offset, entry) = self.find(name.as_bytes(), metadata)?
+            .ok_or(FsError::NotFound)?;
+        let mut block = self.read_block(index, metadata)?;
+        block[offset..offset + 4].copy_from_slice(&inode.inode_number.to_le_bytes());
+        block[offset + 7] = inode.file_type as u8;
+        self.write_block(index, &block, metadata)?;
+        metadata.persist_inode(&self.inode)?;
+        Ok(entry.inode_number)
+    }
+
+    // a block without entries is a single free record
+    fn is_block_empty<M: byte_compatible>(&self, index: usize, metadata: &fs_metadata<M>) -> Result<bool, Error> {
+        let block = self.read_block(index, metadata)?;
+        let first = Records::new(&block).next().transpose()?;
+        Ok(matches!(first, Some((_, length, None)) if length == block.len()))
+    }
+}
+
+/*
+    An open directory. Like FileHandle it borrows the filesystem, so the
+    entries cannot change underneath it while it is alive.
+*/
+pub struct DirectoryHandle<'a, M: byte_compatible> {
+    directory: Directory,
+    metadata: &'a fs_metadata<M>,
+    len: usize,
+}
+
+impl<M: byte_compatible> DirectoryHandle<'_, M> {
+    pub fn get_inode_number(&self) -> u32 {
+        self.directory.get_inode_number()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn lookup<T: Path>(&self, name: T) -> Result<Option<u32>, Error> {
+        self.directory.lookup(name, self.metadata)
+    }
+
+    pub fn iter(&self) -> DirectoryIter<'_, M> {
+        self.directory.iter(self.metadata)
+    }
+}
+
+/*
+    Where an entry called `name` comes in a listing: its hash above its
+    minor hash, each without its lowest bit, which the hash never has set
+    anyway. That keeps positions below 2^62, clear of the sign of the
+    offsets FUSE hands around.
+*/
+fn listing_position(seed: [u32; 4], name: &[u8]) -> u64 {
+    let (hash, minor) = dir_index::name_hashes(seed, name);
+    (hash as u64) << 30 | (minor >> 1) as u64
+}
+
+// the lowest hash a listing from `position` may still return
+fn listing_hash(position: u64) -> u32 {
+    (position >> 30).min(u32::MAX as u64) as u32 & !CONTINUATION
+}
+
+// where a DirectoryIter gets more entries from
+enum Leaves {
+    Start,
+    // the index nodes down to the last leaf read
+    After(Vec<IndexStep>),
+    Done,
+}
+
+pub struct DirectoryIter<'a, M: byte_compatible> {
+    directory: Cow<'a, Directory>,
+    metadata: &'a fs_metadata<M>,
+    // every entry returned so far comes before this
+    position: u64,
+    // the entries read but not returned yet, with their positions, the next one last
+    pending: Vec<(u64, DirectoryEntry)>,
+    leaves: Leaves,
+}
+
+impl<M: byte_compatible> Iterator for DirectoryIter<'_, M> {
+    type Item = Result<DirectoryEntry, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((position, entry)) = self.pending.pop() {
+                self.position = position + 1;
+                return Some(Ok(entry));
+            }
+            match self.read_more() {
+                Ok(true) => continue,
+                Ok(false) => return None,
+                // an error ends the iteration
+                Err(e) => {
+                    self.leaves = Leaves::Done;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+impl<'a, M: byte_compatible> DirectoryIter<'a, M> {
+    fn new(directory: Cow<'a, Directory>, metadata: &'a fs_metadata<M>, position: u64) -> Self {
+        Self {
+            directory,
+            metadata,
+            position,
+            pending: Vec::new(),
+            leaves: Leaves::Start,
+        }
+    }
+
+    /*
+        Where to pick the listing up again with Directory::into_iter_from:
+        after every entry returned so far.
+    */
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /*
+        Reads the entries from position on of the next blocks that are
+        ordered among themselves: a whole directory without an index, or a
+        leaf together with the leaves continuing it, which may share its
+        last hash. Returns false at the end of the directory.
+    */
+    fn read_more(&mut self) -> Result<bool, Error> {
+        let (directory, metadata) = (&self.directory, self.metadata);
+        let (mut blocks, path) = match std::mem::replace(&mut self.leaves, Leaves::Done) {
+            Leaves::Done => return Ok(false),
+            Leaves::Start => match directory.index_root(metadata)? {
+                None => ((0..directory.block_count(metadata)?).collect(), None),
+                Some(root) => {
+                    let (path, leaf) = directory.walk_index(root, listing_hash(self.position), metadata)?;
+                    (vec![leaf], Some(path))
+                }
+            },
+            Leaves::After(mut path) => match directory.next_leaf(&mut path, |_| true, metadata)? {
+                Some(leaf) => (vec![leaf], Some(path)),
+                None => return Ok(false),
+            },
+        };
+        if let Some(mut path) = path {
+            while let Some(leaf) = directory.next_leaf(&mut path, |low| low & CONTINUATION != 0, metadata)? {
+                blocks.push(leaf);
+            }
+            self.leaves = Leaves::After(path);
+        }
+
+        let seed = metadata.super_block_get_hash_seed();
+        for index in blocks {
+            let block = directory.read_block(index, metadata)?;
+            for record in Records::new(&block) {
+                if let (_, _, Some(entry)) = record? {
+                    let position = listing_position(seed, entry.name.as_bytes());
+                    if position >= self.position {
+                        self.pending.push((position, entry));
+                    }
+                }
+            }
+        }
+        self.pending.sort_unstable_by_key(|&(position, _)| std::cmp::Reverse(position));
+        Ok(true)
+    }
+}
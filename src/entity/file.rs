@@ -1,6 +1,6 @@
-use std::io::Error;
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom, Write};
 
-use crate::{core::inode::{FileType, Inode}, entity::directory::Directory, fs_metadata::fs_metadata, medium::types::byte_compatible, util::Path};
+use crate::{access::{MAY_READ, MAY_WRITE}, core::{acl, inode::{FileType, Inode, Ownership}}, entity::directory::Directory, fs_metadata::fs_metadata, medium::types::byte_compatible, util::{Path, FEATURE_EXTENTS}};
 
 pub struct file {
     inode: Inode
@@ -9,15 +9,156 @@ pub struct file {
 impl file {
     pub fn new<T: Path, M: byte_compatible>(
         name: T,
-        parent: &Directory,
+        parent: &mut Directory,
+        owner: Ownership,
         metadata: &mut fs_metadata<M>) -> Result<Self, Error>
     {
-        Ok(Self {
-            inode: Inode::create_new(
-                parent.get_inode_number(),
-                name,
-                FileType::File,
-                metadata)?
-        })
-    }
-}
\ No newline at end of file
+        let name = name.to_os_str();
+        let mut inode = Inode::create_new(
+            0,
+            FileType::File,
+            owner,
+            metadata)?;
+
+        let linked = acl::inherit(&mut inode, parent.inode(), metadata)
+            .and_then(|_| parent.add_entry(name, &inode, metadata));
+        if let Err(e) = linked {
+            inode.release(metadata)?;
+            return Err(e);
+        }
+
+        Ok(Self { inode })
+    }
+
+    pub fn load<M: byte_compatible>(
+        inode_num: u32,
+        metadata: &fs_metadata<M>) -> Result<Self, Error>
+    {
+        let inode = metadata.load_inode(inode_num)?;
+        match inode.file_type {
+            FileType::File => {}
+            FileType::Directory => return Err(Error::new(ErrorKind::IsADirectory, "Is a directory")),
+            FileType::Symlink => return Err(Error::new(ErrorKind::InvalidInput, "Is a symbolic link")),
+            // FIFOs, sockets and devices have no data of their own to read or write
+            _ => return Err(Error::new(ErrorKind::InvalidInput, "Not a regular file")),
+        }
+        Ok(Self { inode })
+    }
+
+    pub fn get_inode_number(&self) -> u32 {
+        self.inode.inode_number
+    }
+
+    /*
+        A handle for the MAY_READ and MAY_WRITE bits in `access`; checking
+        them against the file's permissions is up to the caller.
+    */
+    pub fn open<M: byte_compatible>(self, access: u32, metadata: &mut fs_metadata<M>) -> FileHandle<'_, M> {
+        FileHandle {
+            inode: self.inode,
+            metadata,
+            position: 0,
+            access,
+        }
+    }
+}
+
+/*
+    An open file. It behaves like std::fs::File: reads and writes happen at
+    the current position, which Seek moves around. Every change to the size,
+    the block list or the timestamps is persisted to the inode table right
+    away.
+*/
+pub struct FileHandle<'a, M: byte_compatible> {
+    inode: Inode,
+    metadata: &'a mut fs_metadata<M>,
+    position: u64,
+    // what the handle was opened for, as a MAY_* mask
+    access: u32,
+}
+
+impl<M: byte_compatible> FileHandle<'_, M> {
+    pub fn get_inode_number(&self) -> u32 {
+        self.inode.inode_number
+    }
+
+    pub fn len(&self) -> u64 {
+        self.inode.file_size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn set_len(&mut self, size: u64) -> Result<(), Error> {
+        self.require(MAY_WRITE)?;
+        self.inode.truncate_data(size, self.metadata)?;
+        self.metadata.persist_inode(&self.inode)
+    }
+}
+
+impl<M: byte_compatible> FileHandle<'_, M> {
+    // like a file descriptor, a handle only does what it was opened for
+    fn require(&self, mask: u32) -> Result<(), Error> {
+        if self.access & mask != mask {
+            let what = if mask == MAY_READ { "reading" } else { "writing" };
+            return Err(Error::new(ErrorKind::PermissionDenied, format!("File is not open for {}", what)));
+        }
+        Ok(())
+    }
+}
+
+impl<M: byte_compatible> Read for FileHandle<'_, M> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.require(MAY_READ)?;
+        let read = self.inode.read_data(self.position, buf, self.metadata)?;
+        if self.inode.touch_accessed(self.metadata.now(), self.metadata.atime_mode()) {
+            self.metadata.persist_inode(&self.inode)?;
+        }
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl<M: byte_compatible> Write for FileHandle<'_, M> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.require(MAY_WRITE)?;
+        // like a regular file, write as much as fits below the size limit
+        let limit = Inode::max_file_size(self.metadata.super_block_get_block_size(),
+                                         self.metadata.super_block_has_feature(FEATURE_EXTENTS));
+        let room = limit.saturating_sub(self.position).min(buf.len() as u64) as usize;
+        if room == 0 && !buf.is_empty() {
+            return Err(Error::new(ErrorKind::FileTooLarge, "File too large"));
+        }
+
+        let written = self.inode.write_data(self.position, &buf[..room], self.metadata)?;
+        self.metadata.persist_inode(&self.inode)?;
+        self.position += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<M: byte_compatible> Seek for FileHandle<'_, M> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let (base, delta) = match pos {
+            SeekFrom::Start(offset) => {
+                self.position = offset;
+                return Ok(offset);
+            }
+            SeekFrom::End(delta) => (self.len(), delta),
+            SeekFrom::Current(delta) => (self.position, delta),
+        };
+
+        match base.checked_add_signed(delta) {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            }
+            None => Err(Error::new(ErrorKind::InvalidInput, "Invalid seek to a negative or overflowing position")),
+        }
+    }
+}
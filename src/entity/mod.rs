@@ -1,2 +1,3 @@
+pub mod dir_index;
 pub mod directory;
 pub mod file;
\ No newline at end of file
@@ -0,0 +1,173 @@
+/*
+    The errors filefs itself can produce. The public API keeps returning
+    std::io::Error so handles compose with Read and Write; an FsError turns
+    into one without losing its variant, and FsError::from gets it back.
+*/
+
+use std::fmt;
+use std::io::{Error, ErrorKind};
+
+use libc::c_int;
+
+#[derive(Debug)]
+pub enum FsError {
+    NameTooLong,
+    NoSpace,
+    NoInodes,
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    DirectoryNotEmpty,
+    // ELOOP: a path lookup followed too many symbolic links
+    SymlinkLoop,
+    // ENODATA: the inode has no extended attribute of that name
+    NoAttribute,
+    // EACCES: the mode bits do not grant the access
+    PermissionDenied,
+    // EPERM: only the owner or root may do this
+    NotPermitted,
+    NotAFilesystem,
+    UnsupportedVersion([u8; 3]),
+    // an older version this release can upgrade, but only when asked to
+    NeedsUpgrade([u8; 3]),
+    UnsupportedFeatures(u32),
+    Corrupted { structure: &'static str, reason: String },
+    Io(Error),
+}
+
+impl FsError {
+    pub fn corrupted<R: Into<String>>(structure: &'static str, reason: R) -> Self {
+        FsError::Corrupted { structure, reason: reason.into() }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            FsError::NameTooLong => ErrorKind::InvalidFilename,
+            FsError::NoSpace | FsError::NoInodes => ErrorKind::StorageFull,
+            FsError::NotFound => ErrorKind::NotFound,
+            FsError::NotADirectory => ErrorKind::NotADirectory,
+            FsError::IsADirectory => ErrorKind::IsADirectory,
+            FsError::DirectoryNotEmpty => ErrorKind::DirectoryNotEmpty,
+            // ErrorKind::FilesystemLoop is not stable yet
+            FsError::SymlinkLoop => ErrorKind::Other,
+            FsError::NoAttribute => ErrorKind::NotFound,
+            FsError::PermissionDenied | FsError::NotPermitted => ErrorKind::PermissionDenied,
+            FsError::NotAFilesystem | FsError::UnsupportedVersion(_) | FsError::NeedsUpgrade(_)
+                | FsError::UnsupportedFeatures(_) => ErrorKind::InvalidData,
+            FsError::Corrupted { .. } => ErrorKind::InvalidData,
+            FsError::Io(e) => e.kind(),
+        }
+    }
+
+    /*
+        The errno a kernel filesystem would report, as FUSE expects it.
+    */
+    pub fn errno(&self) -> c_int {
+        match self {
+            FsError::NameTooLong => libc::ENAMETOOLONG,
+            FsError::NoSpace | FsError::NoInodes => libc::ENOSPC,
+            FsError::NotFound => libc::ENOENT,
+            FsError::NotADirectory => libc::ENOTDIR,
+            FsError::IsADirectory => libc::EISDIR,
+            FsError::DirectoryNotEmpty => libc::ENOTEMPTY,
+            FsError::SymlinkLoop => libc::ELOOP,
+            FsError::NoAttribute => libc::ENODATA,
+            FsError::PermissionDenied => libc::EACCES,
+            FsError::NotPermitted => libc::EPERM,
+            // what mount(2) reports for a device without a recognisable superblock
+            FsError::NotAFilesystem | FsError::UnsupportedVersion(_) | FsError::NeedsUpgrade(_)
+                | FsError::UnsupportedFeatures(_) => libc::EINVAL,
+            FsError::Corrupted { .. } => libc::EIO,
+            FsError::Io(e) => errno(e),
+        }
+    }
+}
+
+/*
+    errno for any io::Error: the FsError inside it if there is one, the OS
+    error code if it came from the medium, and otherwise its ErrorKind.
+*/
+pub fn errno(e: &Error) -> c_int {
+    if let Some(inner) = e.get_ref().and_then(|inner| inner.downcast_ref::<FsError>()) {
+        return inner.errno();
+    }
+    if let Some(code) = e.raw_os_error() {
+        return code;
+    }
+    match e.kind() {
+        ErrorKind::NotFound => libc::ENOENT,
+        ErrorKind::AlreadyExists => libc::EEXIST,
+        ErrorKind::NotADirectory => libc::ENOTDIR,
+        ErrorKind::IsADirectory => libc::EISDIR,
+        ErrorKind::DirectoryNotEmpty => libc::ENOTEMPTY,
+        ErrorKind::PermissionDenied => libc::EACCES,
+        ErrorKind::StorageFull => libc::ENOSPC,
+        ErrorKind::FileTooLarge => libc::EFBIG,
+        ErrorKind::ResourceBusy => libc::EBUSY,
+        ErrorKind::TooManyLinks => libc::EMLINK,
+        ErrorKind::InvalidFilename => libc::ENAMETOOLONG,
+        ErrorKind::ArgumentListTooLong => libc::E2BIG,
+        ErrorKind::Unsupported => libc::EOPNOTSUPP,
+        ErrorKind::InvalidInput => libc::EINVAL,
+        _ => libc::EIO,
+    }
+}
+
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FsError::NameTooLong => write!(f, "File name too long"),
+            FsError::NoSpace => write!(f, "No space left on device"),
+            FsError::NoInodes => write!(f, "No free inodes available"),
+            FsError::NotFound => write!(f, "No such file or directory"),
+            FsError::NotADirectory => write!(f, "Not a directory"),
+            FsError::IsADirectory => write!(f, "Is a directory"),
+            FsError::DirectoryNotEmpty => write!(f, "Directory not empty"),
+            FsError::SymlinkLoop => write!(f, "Too many levels of symbolic links"),
+            FsError::NoAttribute => write!(f, "No such attribute"),
+            FsError::PermissionDenied => write!(f, "Permission denied"),
+            FsError::NotPermitted => write!(f, "Operation not permitted"),
+            FsError::NotAFilesystem => write!(f, "Not a filefs filesystem (bad magic number)"),
+            FsError::UnsupportedVersion([major, minor, patch]) =>
+                write!(f, "Unsupported filefs version {}.{}.{}", major, minor, patch),
+            FsError::NeedsUpgrade([major, minor, patch]) =>
+                write!(f, "filefs version {}.{}.{} is out of date, upgrade the image first", major, minor, patch),
+            FsError::UnsupportedFeatures(features) => write!(f, "Unsupported filefs features {:#x}", features),
+            FsError::Corrupted { structure, reason } => write!(f, "Corrupted {}: {}", structure, reason),
+            FsError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for FsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FsError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<FsError> for Error {
+    fn from(e: FsError) -> Self {
+        match e {
+            FsError::Io(e) => e,
+            e => Error::new(e.kind(), e),
+        }
+    }
+}
+
+impl From<Error> for FsError {
+    fn from(e: Error) -> Self {
+        // OS errors carry no payload, and into_inner would drop their code
+        if e.get_ref().is_none() {
+            return FsError::Io(e);
+        }
+        let kind = e.kind();
+        match e.into_inner().map(|inner| inner.downcast::<FsError>()) {
+            Some(Ok(fs_error)) => *fs_error,
+            Some(Err(inner)) => FsError::Io(Error::new(kind, inner)),
+            None => FsError::Io(Error::from(kind)),
+        }
+    }
+}
@@ -1,52 +1,1259 @@
 
 use std::cell::RefCell;
+use std::ffi::{OsStr, OsString};
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
 use std::rc::Rc;
 
-use crate::core::inode::FileType;
-use crate::entity::directory::Directory;
+use crate::access::{Credentials, MAY_EXEC, MAY_READ, MAY_WRITE, MODE_MASK, S_ISGID, S_ISUID};
+use crate::core::acl::{self, Acl, ACL_ACCESS, ACL_DEFAULT};
+use crate::core::inode::{FileType, Inode, Ownership};
+use crate::core::xattr::{Xattrs, XATTR_CREATE, XATTR_NAME_MAX, XATTR_REPLACE, XATTR_SIZE_MAX};
+use crate::entity::directory::{Directory, DirectoryEntry, DirectoryHandle, DirectoryIter};
+use crate::entity::file::{file, FileHandle};
+use crate::error::FsError;
 use crate::fs_metadata::fs_metadata;
 use crate::medium::types::byte_compatible;
+use crate::time::{AtimeMode, Clock, SetTime, Timestamp};
+use crate::util::{components, Path, DEFAULT_BLOCK_SIZE, DEFAULT_BYTES_PER_INODE, FEATURE_EXTENTS, INODE_BITMAP_STARTING_BLOCK_NUMBER,
+                  MAX_FILE_NAME_SIZE};
+
+const ROOT_INODE: u32 = 0;
+const DEFAULT_UMASK: u16 = 0o022;
+// how many symbolic links a single path lookup may follow, as on Linux
+const MAX_SYMLINK_FOLLOWS: usize = 40;
+
+// renameat2(2) flags: fail if the target exists, or swap the two entries
+pub const RENAME_NOREPLACE: u32 = 1;
+pub const RENAME_EXCHANGE: u32 = 2;
 
 pub struct ffs<T: byte_compatible> {
     metadata: fs_metadata<T>,
+    #[allow(dead_code)] // only the tests poke at the raw medium
     medium: Rc<RefCell<T>>,
     cwd: Directory,
+    // like a process: who the operations run as, and the mode bits they mask off new files
+    credentials: Credentials,
+    umask: u16,
 }
 
 impl <T: byte_compatible> ffs<T> {
+    /*
+        Loads an image in the current format. One written by an older
+        release fails with FsError::NeedsUpgrade and is left as it is.
+    */
     pub fn load(medium: T) -> Result<Self, std::io::Error> {
         let medium = Rc::new(RefCell::new(medium));
         let metadata = fs_metadata::fetch(medium.clone())?;
-        let cwd = Directory::load(0, &metadata, medium.borrow_mut())?;
+        Self::with_metadata(metadata, medium)
+    }
+
+    /*
+        Loads an image written by any release and converts it to the
+        current format in place, one version at a time. The conversion is
+        not crash safe: an interrupted upgrade can leave an image no
+        release reads, so upgrade a copy or keep a backup.
+    */
+    pub fn upgrade(medium: T) -> Result<Self, std::io::Error> {
+        let medium = Rc::new(RefCell::new(medium));
+        let metadata = fs_metadata::upgrade(medium.clone())?;
+        Self::with_metadata(metadata, medium)
+    }
+
+    fn with_metadata(metadata: fs_metadata<T>, medium: Rc<RefCell<T>>) -> Result<Self, std::io::Error> {
+        let cwd = Directory::load(ROOT_INODE, &metadata, medium.borrow_mut())?;
+        Ok(Self { metadata, medium, cwd, credentials: Credentials::root(), umask: DEFAULT_UMASK })
+    }
 
-        Ok(Self { metadata, medium, cwd })
+    pub fn new(medium: T, size: u64, block_size: u32, bytes_per_inode: u32) -> Result<Self, std::io::Error> {
+        Self::new_with_label(medium, size, block_size, bytes_per_inode, "")
     }
 
-    pub fn new(medium: T, size: u32, block_size: u32, bytes_per_inode: u32) -> Result<Self, std::io::Error> {
+    pub fn new_with_label(medium: T, size: u64, block_size: u32, bytes_per_inode: u32, label: &str) -> Result<Self, std::io::Error> {
+        Self::format(medium, size, block_size, bytes_per_inode, label, 0, (0, 0))
+    }
+
+    fn format(medium: T, size: u64, block_size: u32, bytes_per_inode: u32, label: &str, features: u32, (uid, gid): (u32, u32))
+        -> Result<Self, std::io::Error>
+    {
         let medium = Rc::new(RefCell::new(medium));
         let mut metadata = fs_metadata::create_new(medium.clone(),
                                                                size,
                                                                block_size,
-                                                               bytes_per_inode)?;
+                                                               bytes_per_inode,
+                                                               label,
+                                                               features)?;
         let cwd = Directory::create_new(FileType::Directory,
                                                     "/",
                                                     None,
+                                                    Ownership { mode: 0o755, uid, gid },
                                                     &mut metadata)?;
 
-        Ok(Self { metadata, medium, cwd })
+        Ok(Self { metadata, medium, cwd, credentials: Credentials::root(), umask: DEFAULT_UMASK })
+    }
+
+    /*
+        Replaces the system clock every timestamp is taken from, say with a
+        fixed one for reproducible images.
+    */
+    pub fn set_clock<C: Clock + 'static>(&mut self, clock: C) {
+        self.metadata.set_clock(Box::new(clock));
+    }
+
+    pub fn set_atime_mode(&mut self, mode: AtimeMode) {
+        self.metadata.set_atime_mode(mode);
+    }
+
+    pub fn atime_mode(&self) -> AtimeMode {
+        self.metadata.atime_mode()
+    }
+
+    pub fn now(&self) -> Timestamp {
+        self.metadata.now()
+    }
+
+    /*
+        Every operation runs as these credentials and is checked against the
+        mode bits of the inodes it touches, the way open(2) and friends are.
+        They start out as root, which may do almost anything.
+    */
+    pub fn set_credentials(&mut self, credentials: Credentials) {
+        self.credentials = credentials;
+    }
+
+    pub fn credentials(&self) -> &Credentials {
+        &self.credentials
+    }
+
+    /*
+        Mode bits create and mkdir clear from 0666 and 0777, 022 by default.
+        In a directory with a default ACL, the ACL takes its place.
+    */
+    pub fn set_umask(&mut self, umask: u16) {
+        self.umask = umask & 0o777;
+    }
+
+    fn check(&self, inode: &Inode, mask: u32) -> Result<(), Error> {
+        let acl = acl::load(inode, ACL_ACCESS, &self.metadata)?;
+        if self.credentials.may(inode, acl.as_ref(), mask) {
+            Ok(())
+        } else {
+            Err(FsError::PermissionDenied.into())
+        }
+    }
+
+    /*
+        Like access(2): fails with PermissionDenied unless the credentials
+        grant every access in `mask`, a combination of MAY_READ, MAY_WRITE
+        and MAY_EXEC. A mask of 0 only checks that the inode exists.
+    */
+    pub fn access(&self, inode_number: u32, mask: u32) -> Result<(), Error> {
+        self.check(&self.metadata.load_inode(inode_number)?, mask)
+    }
+
+    /*
+        Where each on-disk structure lives, in blocks.
+    */
+    pub fn layout(&self) -> FsLayout {
+        let inode_bitmap_blocks = self.metadata.super_block_get_inode_bitmap_block_count() as u64;
+        FsLayout {
+            label: self.metadata.super_block_get_label(),
+            block_size: self.metadata.super_block_get_block_size() as u32,
+            total_blocks: self.metadata.super_block_get_total_blocks() as u64,
+            total_inodes: self.metadata.super_block_get_total_inodes() as u64,
+            inode_bitmap_start: INODE_BITMAP_STARTING_BLOCK_NUMBER as u64,
+            inode_bitmap_blocks,
+            block_bitmap_start: INODE_BITMAP_STARTING_BLOCK_NUMBER as u64 + inode_bitmap_blocks,
+            block_bitmap_blocks: self.metadata.super_block_get_block_bitmap_block_count() as u64,
+            inode_table_start: self.metadata.super_block_get_inode_start_block() as u64,
+            inode_table_blocks: self.metadata.super_block_get_total_inode_blocks() as u64,
+            first_data_block: self.metadata.super_block_get_first_data_block() as u64,
+            extents: self.metadata.super_block_has_feature(FEATURE_EXTENTS),
+        }
+    }
+
+    /*
+        Walks `path` one component at a time. Absolute paths start at the
+        root inode, relative ones at the current directory. "." stays put
+        and ".." follows Inode::parent (the root is its own parent). Every
+        directory passed through needs search (exec) permission.
+        Symbolic links met along the way are followed, from the directory
+        holding them or from the root for an absolute target; one in the
+        final component only when `follow` is set or the path ends in a
+        slash. More than MAX_SYMLINK_FOLLOWS of them fail with SymlinkLoop.
+    */
+    fn resolve<P: Path>(&self, path: P, follow: bool) -> Result<Inode, Error> {
+        let path = path.to_os_str();
+        let bytes = path.as_bytes();
+        let start = if bytes.starts_with(b"/") { ROOT_INODE } else { self.cwd.get_inode_number() };
+        let mut current = self.metadata.load_inode(start)?;
+        let follow = follow || bytes.ends_with(b"/");
+
+        // the components still to walk, the next one last
+        let mut pending: Vec<Vec<u8>> = components(bytes).rev().map(<[u8]>::to_vec).collect();
+        let mut followed = 0;
+        while let Some(component) = pending.pop() {
+            if current.file_type != FileType::Directory {
+                return Err(FsError::NotADirectory.into());
+            }
+            self.check(&current, MAY_EXEC)?;
+            let next = match component.as_slice() {
+                b"." => current.clone(),
+                b".." => self.metadata.load_inode(current.parent)?,
+                name => {
+                    let directory = Directory::from_inode(current.clone())?;
+                    let inode_number = directory.lookup(name, &self.metadata)?
+                        .ok_or(FsError::NotFound)?;
+                    self.metadata.load_inode(inode_number)?
+                }
+            };
+
+            if next.file_type == FileType::Symlink && (follow || !pending.is_empty()) {
+                followed += 1;
+                if followed > MAX_SYMLINK_FOLLOWS {
+                    return Err(FsError::SymlinkLoop.into());
+                }
+                let target = next.read_link(&self.metadata)?;
+                if target.starts_with(b"/") {
+                    current = self.metadata.load_inode(ROOT_INODE)?;
+                }
+                pending.extend(components(&target).rev().map(<[u8]>::to_vec));
+                continue;
+            }
+            current = next;
+        }
+
+        Ok(current)
+    }
+
+    /*
+        Splits `path` into the inode number of its parent directory and the
+        final component, which must be a real name.
+    */
+    fn resolve_parent<P: Path>(&self, path: P) -> Result<(u32, OsString), Error> {
+        let path = path.to_os_str();
+        let bytes = path.as_bytes();
+        let trimmed = &bytes[..bytes.iter().rposition(|&b| b != b'/').map_or(0, |last| last + 1)];
+        let (parent, name): (&[u8], &[u8]) = match trimmed.iter().rposition(|&b| b == b'/') {
+            Some(0) => (b"/", &trimmed[1..]),
+            Some(slash) => (&trimmed[..slash], &trimmed[slash + 1..]),
+            None => (b".", trimmed),
+        };
+        if name.is_empty() || name == b"." || name == b".." {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("{}: Invalid path", path.to_string_lossy())));
+        }
+
+        let parent = self.resolve(parent, true)?;
+        if parent.file_type != FileType::Directory {
+            return Err(FsError::NotADirectory.into());
+        }
+        Ok((parent.inode_number, OsString::from_vec(name.to_vec())))
+    }
+
+    pub fn lookup_path<P: Path>(&self, path: P) -> Result<u32, Error> {
+        Ok(self.resolve(path, true)?.inode_number)
+    }
+
+    /*
+        Like lookup_path, but a symbolic link in the final component is
+        returned itself rather than followed, as lstat(2) and O_NOFOLLOW
+        see it.
+    */
+    pub fn lookup_path_nofollow<P: Path>(&self, path: P) -> Result<u32, Error> {
+        Ok(self.resolve(path, false)?.inode_number)
+    }
+
+    pub fn chdir<P: Path>(&mut self, path: P) -> Result<(), Error> {
+        let directory = Directory::from_inode(self.resolve(path, true)?)?;
+        self.check(directory.inode(), MAY_EXEC)?;
+        self.cwd = directory;
+        Ok(())
+    }
+
+    /*
+        Rebuilds the absolute path of the current directory by following the
+        parent pointers up to the root and looking up each directory's name
+        in its parent.
+    */
+    pub fn current_dir(&self) -> Result<OsString, Error> {
+        let mut names = Vec::new();
+        let mut current = self.metadata.load_inode(self.cwd.get_inode_number())?;
+        while current.inode_number != ROOT_INODE {
+            let parent = self.load_directory(current.parent)?;
+            let name = parent.name_of(current.inode_number, &self.metadata)?
+                .ok_or_else(|| Error::from(FsError::corrupted("directory",
+                    format!("directory {} is not listed in its parent {}", current.inode_number, current.parent))))?;
+            names.push(name.into_vec());
+            current = self.metadata.load_inode(current.parent)?;
+        }
+        names.reverse();
+        Ok(OsString::from_vec([b"/".to_vec(), names.join(&b'/')].concat()))
+    }
+
+    /*
+        Creates an empty file and opens it.
+    */
+    pub fn create_file<P: Path>(&mut self, path: P) -> Result<FileHandle<'_, T>, Error> {
+        let (parent, name) = self.resolve_parent(path)?;
+        let inode_number = self.create(parent, name)?;
+        // whatever its mode, the creator may write what it just created
+        self.handle(inode_number, MAY_READ | MAY_WRITE)
+    }
+
+    pub fn open<P: Path>(&mut self, path: P, mask: u32) -> Result<FileHandle<'_, T>, Error> {
+        let inode_number = self.lookup_path(path)?;
+        self.open_file(inode_number, mask)
+    }
+
+    /*
+        Opens a file for reading, writing or both, as MAY_READ and MAY_WRITE
+        in `mask` say, the way open(2) takes O_RDONLY, O_WRONLY or O_RDWR.
+        Only the permissions asked for are checked, and the handle refuses
+        whatever it was not opened for.
+    */
+    pub fn open_file(&mut self, inode_number: u32, mask: u32) -> Result<FileHandle<'_, T>, Error> {
+        let mask = mask & (MAY_READ | MAY_WRITE);
+        self.access(inode_number, mask)?;
+        self.handle(inode_number, mask)
+    }
+
+    fn handle(&mut self, inode_number: u32, mask: u32) -> Result<FileHandle<'_, T>, Error> {
+        let existing = file::load(inode_number, &self.metadata)?;
+        Ok(existing.open(mask, &mut self.metadata))
+    }
+
+    pub fn create_dir<P: Path>(&mut self, path: P) -> Result<(), Error> {
+        let (parent, name) = self.resolve_parent(path)?;
+        self.mkdir(parent, name)?;
+        Ok(())
+    }
+
+    pub fn open_dir<P: Path>(&self, path: P) -> Result<DirectoryHandle<'_, T>, Error> {
+        let inode_number = self.lookup_path(path)?;
+        self.open_directory(inode_number)
+    }
+
+    pub fn open_directory(&self, inode_number: u32) -> Result<DirectoryHandle<'_, T>, Error> {
+        self.load_directory_for(inode_number, MAY_READ)?.open(&self.metadata)
+    }
+
+    pub fn read_dir<P: Path>(&self, path: P) -> Result<Vec<DirectoryEntry>, Error> {
+        let inode_number = self.lookup_path(path)?;
+        self.readdir(inode_number)
+    }
+
+    pub fn remove_file<P: Path>(&mut self, path: P) -> Result<(), Error> {
+        let (parent, name) = self.resolve_parent(path)?;
+        self.unlink(parent, name)
+    }
+
+    pub fn remove_dir<P: Path>(&mut self, path: P) -> Result<(), Error> {
+        let (parent, name) = self.resolve_parent(path)?;
+        self.rmdir(parent, name)
+    }
+
+    /*
+        Makes `to` another name for the file at `from`. Like link(2), a
+        symbolic link at `from` is linked itself rather than its target.
+    */
+    pub fn link_path<P: Path, Q: Path>(&mut self, from: P, to: Q) -> Result<(), Error> {
+        let inode_number = self.lookup_path_nofollow(from)?;
+        let (new_parent, new_name) = self.resolve_parent(to)?;
+        self.link(inode_number, new_parent, new_name)
+    }
+
+    pub fn rename_path<P: Path, Q: Path>(&mut self, from: P, to: Q) -> Result<(), Error> {
+        let (parent, name) = self.resolve_parent(from)?;
+        let (new_parent, new_name) = self.resolve_parent(to)?;
+        self.rename(parent, name, new_parent, new_name)
+    }
+
+    /*
+        Creates a symbolic link at `path` that points to `target`.
+    */
+    pub fn symlink_path<P: Path, Q: Path>(&mut self, target: P, path: Q) -> Result<(), Error> {
+        let (parent, name) = self.resolve_parent(path)?;
+        self.symlink(parent, name, target)?;
+        Ok(())
+    }
+
+    /*
+        Creates a FIFO, socket or device node at `path` with `mode` less the
+        umask, see mknod.
+    */
+    pub fn mknod_path<P: Path>(&mut self, path: P, file_type: FileType, mode: u16, rdev: u32) -> Result<(), Error> {
+        let (parent, name) = self.resolve_parent(path)?;
+        let mode = self.umasked(parent, mode)?;
+        self.mknod(parent, name, file_type, mode, rdev)?;
+        Ok(())
+    }
+
+    pub fn readlink_path<P: Path>(&self, path: P) -> Result<OsString, Error> {
+        let inode_number = self.lookup_path_nofollow(path)?;
+        self.readlink(inode_number)
+    }
+
+    pub fn stat_path<P: Path>(&self, path: P) -> Result<FileStat, Error> {
+        let inode_number = self.lookup_path(path)?;
+        self.stat(inode_number)
+    }
+
+    /*
+        stat_path without following a symbolic link in the final component.
+    */
+    pub fn lstat_path<P: Path>(&self, path: P) -> Result<FileStat, Error> {
+        let inode_number = self.lookup_path_nofollow(path)?;
+        self.stat(inode_number)
+    }
+
+    /*
+        The operations below work on inode numbers rather than paths, the way
+        the kernel talks to a filesystem. The path based API above is built
+        on top of them.
+    */
+    fn load_directory(&self, inode_number: u32) -> Result<Directory, Error> {
+        Directory::from_inode(self.metadata.load_inode(inode_number)?)
+    }
+
+    /*
+        Loads a directory the credentials have `mask` access to.
+    */
+    fn load_directory_for(&self, inode_number: u32, mask: u32) -> Result<Directory, Error> {
+        let directory = self.load_directory(inode_number)?;
+        self.check(directory.inode(), mask)?;
+        Ok(directory)
+    }
+
+    pub fn lookup<P: Path>(&self, parent: u32, name: P) -> Result<u32, Error> {
+        let name = name.to_os_str();
+        let directory = self.load_directory_for(parent, MAY_EXEC)?;
+        match name.as_bytes() {
+            b"." => Ok(directory.get_inode_number()),
+            b".." => Ok(directory.inode().parent),
+            _ => directory.lookup(name, &self.metadata)?
+                .ok_or_else(|| FsError::NotFound.into()),
+        }
+    }
+
+    pub fn stat(&self, inode_number: u32) -> Result<FileStat, Error> {
+        let inode = self.metadata.load_inode(inode_number)?;
+        Ok(FileStat {
+            inode_number,
+            file_type: inode.file_type,
+            nlink: inode.nlink,
+            size: inode.file_size,
+            blocks: inode.allocated_blocks(&self.metadata)?,
+            block_size: self.metadata.super_block_get_block_size() as u32,
+            atime: inode.atime,
+            mtime: inode.mtime,
+            ctime: inode.ctime,
+            crtime: inode.crtime,
+            mode: inode.mode,
+            uid: inode.uid,
+            gid: inode.gid,
+            rdev: inode.rdev,
+        })
+    }
+
+    pub fn statfs(&self) -> FsStat {
+        FsStat {
+            block_size: self.metadata.super_block_get_block_size() as u32,
+            total_blocks: self.metadata.super_block_get_total_blocks() as u64,
+            free_blocks: self.metadata.super_block_get_free_blocks() as u64,
+            total_inodes: self.metadata.super_block_get_total_inodes() as u64,
+            free_inodes: self.metadata.super_block_get_free_inodes() as u64,
+            max_name_len: MAX_FILE_NAME_SIZE as u32,
+        }
+    }
+
+    pub fn readdir(&self, inode_number: u32) -> Result<Vec<DirectoryEntry>, Error> {
+        let directory = self.load_directory_for(inode_number, MAY_READ)?;
+        directory.iter(&self.metadata).collect()
+    }
+
+    /*
+        The entries of a directory from `position` on, where 0 is the start
+        and any other position is one the iterator's position() handed out.
+        A listing of an indexed directory taken in parts reads only the
+        leaves each part returns entries from.
+    */
+    pub fn readdir_from(&self, inode_number: u32, position: u64) -> Result<DirectoryIter<'_, T>, Error> {
+        let directory = self.load_directory_for(inode_number, MAY_READ)?;
+        Ok(directory.into_iter_from(&self.metadata, position))
+    }
+
+    /*
+        The owner and mode of an inode created in `parent` with `mode`. In a
+        setgid directory the group comes from the directory rather than the
+        creator, and new subdirectories inherit the setgid bit.
+    */
+    fn new_owner(&self, parent: &Inode, file_type: FileType, mode: u16) -> Ownership {
+        let mut mode = mode & MODE_MASK;
+        let gid = if parent.mode & S_ISGID != 0 {
+            if file_type == FileType::Directory {
+                mode |= S_ISGID;
+            }
+            parent.gid
+        } else {
+            self.credentials.gid
+        };
+        // a setgid bit the creator could not have set with chmod is dropped
+        if file_type != FileType::Directory && !self.credentials.is_root() && !self.credentials.in_group(gid) {
+            mode &= !S_ISGID;
+        }
+        Ownership { mode, uid: self.credentials.uid, gid }
+    }
+
+    /*
+        `mode` less the umask, unless `parent` has a default ACL to restrict
+        it instead.
+    */
+    fn umasked(&self, parent: u32, mode: u16) -> Result<u16, Error> {
+        let parent = self.metadata.load_inode(parent)?;
+        if acl::load(&parent, ACL_DEFAULT, &self.metadata)?.is_some() {
+            Ok(mode)
+        } else {
+            Ok(mode & !self.umask)
+        }
+    }
+
+    /*
+        Creates a file with mode 0666 less the umask.
+    */
+    pub fn create<P: Path>(&mut self, parent: u32, name: P) -> Result<u32, Error> {
+        let mode = self.umasked(parent, 0o666)?;
+        self.create_with_mode(parent, name, mode)
+    }
+
+    /*
+        Creates a file with exactly `mode`, as the kernel passes it on
+        after applying the caller's umask, or what a default ACL of the
+        parent leaves of it.
+    */
+    pub fn create_with_mode<P: Path>(&mut self, parent: u32, name: P, mode: u16) -> Result<u32, Error> {
+        let mut parent = self.load_directory_for(parent, MAY_WRITE | MAY_EXEC)?;
+        let owner = self.new_owner(parent.inode(), FileType::File, mode);
+        Ok(file::new(name, &mut parent, owner, &mut self.metadata)?.get_inode_number())
+    }
+
+    /*
+        Creates a directory with mode 0777 less the umask.
+    */
+    pub fn mkdir<P: Path>(&mut self, parent: u32, name: P) -> Result<u32, Error> {
+        let mode = self.umasked(parent, 0o777)?;
+        self.mkdir_with_mode(parent, name, mode)
+    }
+
+    pub fn mkdir_with_mode<P: Path>(&mut self, parent: u32, name: P, mode: u16) -> Result<u32, Error> {
+        let mut parent = self.load_directory_for(parent, MAY_WRITE | MAY_EXEC)?;
+        let owner = self.new_owner(parent.inode(), FileType::Directory, mode);
+        let directory = Directory::create_new(FileType::Directory,
+                                              name,
+                                              Some(&mut parent),
+                                              owner,
+                                              &mut self.metadata)?;
+        Ok(directory.get_inode_number())
+    }
+
+    /*
+        Creates a symbolic link to `target`. Its mode is always 0777; what
+        the link grants is decided by the file it leads to.
+    */
+    pub fn symlink<P: Path, Q: Path>(&mut self, parent: u32, name: P, target: Q) -> Result<u32, Error> {
+        let mut parent = self.load_directory_for(parent, MAY_WRITE | MAY_EXEC)?;
+        let owner = self.new_owner(parent.inode(), FileType::Symlink, 0o777);
+        let mut inode = Inode::create_new(0, FileType::Symlink, owner, &mut self.metadata)?;
+        let linked = inode.write_link(target.to_le_bytes(), &mut self.metadata)
+            .and_then(|_| parent.add_entry(name.to_os_str(), &inode, &mut self.metadata));
+        if let Err(e) = linked {
+            inode.release(&mut self.metadata)?;
+            return Err(e);
+        }
+        Ok(inode.inode_number)
+    }
+
+    /*
+        Creates a node of any type but a directory or symbolic link with
+        exactly `mode`. `rdev` is the device a character or block device
+        node stands for (see makedev) and is ignored for other types. Like
+        mknod(2) without CAP_MKNOD, only root may create device nodes.
+    */
+    pub fn mknod<P: Path>(&mut self, parent: u32, name: P, file_type: FileType, mode: u16, rdev: u32) -> Result<u32, Error> {
+        if matches!(file_type, FileType::Directory | FileType::Symlink) {
+            return Err(Error::new(ErrorKind::InvalidInput, "mknod cannot create directories or symbolic links"));
+        }
+        let mut parent = self.load_directory_for(parent, MAY_WRITE | MAY_EXEC)?;
+        if file_type.is_device() && !self.credentials.is_root() {
+            return Err(FsError::NotPermitted.into());
+        }
+        let owner = self.new_owner(parent.inode(), file_type, mode);
+        let mut inode = Inode::create_new(0, file_type, owner, &mut self.metadata)?;
+        if file_type.is_device() {
+            inode.rdev = rdev;
+        }
+        let linked = self.metadata.persist_inode(&inode)
+            .and_then(|_| acl::inherit(&mut inode, parent.inode(), &mut self.metadata))
+            .and_then(|_| parent.add_entry(name.to_os_str(), &inode, &mut self.metadata));
+        if let Err(e) = linked {
+            inode.release(&mut self.metadata)?;
+            return Err(e);
+        }
+        Ok(inode.inode_number)
+    }
+
+    pub fn readlink(&self, inode_number: u32) -> Result<OsString, Error> {
+        let inode = self.metadata.load_inode(inode_number)?;
+        if inode.file_type != FileType::Symlink {
+            return Err(Error::new(ErrorKind::InvalidInput, "Not a symbolic link"));
+        }
+        Ok(OsString::from_vec(inode.read_link(&self.metadata)?))
+    }
+
+    /*
+        read and write act on an inode the caller already opened, like I/O
+        on a file descriptor, so they do not check permissions again.
+    */
+    pub fn read(&mut self, inode_number: u32, offset: u64, buffer: &mut [u8]) -> Result<usize, Error> {
+        let mut handle = self.handle(inode_number, MAY_READ)?;
+        handle.seek(SeekFrom::Start(offset))?;
+        handle.read(buffer)
+    }
+
+    pub fn write(&mut self, inode_number: u32, offset: u64, data: &[u8]) -> Result<usize, Error> {
+        let mut handle = self.handle(inode_number, MAY_WRITE)?;
+        handle.seek(SeekFrom::Start(offset))?;
+        handle.write(data)
+    }
+
+    pub fn truncate(&mut self, inode_number: u32, size: u64) -> Result<(), Error> {
+        self.access(inode_number, MAY_WRITE)?;
+        self.handle(inode_number, MAY_WRITE)?.set_len(size)
+    }
+
+    /*
+        Sets the permission bits, and the matching entries of the access
+        ACL. Only the owner and root may; anybody else outside the file's
+        group loses the setgid bit they ask for.
+    */
+    pub fn chmod(&mut self, inode_number: u32, mode: u16) -> Result<(), Error> {
+        let mut inode = self.metadata.load_inode(inode_number)?;
+        if !self.credentials.owns(&inode) {
+            return Err(FsError::NotPermitted.into());
+        }
+        let mut mode = mode & MODE_MASK;
+        if !self.credentials.is_root() && !self.credentials.in_group(inode.gid) {
+            mode &= !S_ISGID;
+        }
+        inode.mode = mode;
+        if let Some(mut acl) = acl::load(&inode, ACL_ACCESS, &self.metadata)? {
+            acl.chmod(mode);
+            let mut xattrs = inode.read_xattrs(&self.metadata)?;
+            xattrs.insert(ACL_ACCESS.to_string(), acl.encode());
+            inode.write_xattrs(xattrs, &mut self.metadata)?;
+        }
+        inode.touch_changed(self.metadata.now());
+        self.metadata.persist_inode(&inode)
+    }
+
+    /*
+        Changes the owner and group, leaving those passed as None alone.
+        Root may set anything; the owner may only move the file into one of
+        their own groups. Like on Linux, a changed owner or group clears the
+        setuid bit of a file, and its setgid bit if it is group executable.
+    */
+    pub fn chown(&mut self, inode_number: u32, uid: Option<u32>, gid: Option<u32>) -> Result<(), Error> {
+        let mut inode = self.metadata.load_inode(inode_number)?;
+        let credentials = &self.credentials;
+        if !credentials.is_root() {
+            let owner = credentials.uid == inode.uid;
+            let uid_ok = uid.is_none_or(|uid| owner && uid == inode.uid);
+            let gid_ok = gid.is_none_or(|gid| owner && (gid == inode.gid || credentials.in_group(gid)));
+            if !(uid_ok && gid_ok) {
+                return Err(FsError::NotPermitted.into());
+            }
+        }
+
+        if (uid.is_some() || gid.is_some()) && inode.file_type != FileType::Directory {
+            inode.mode &= !S_ISUID;
+            if inode.mode & 0o010 != 0 {
+                inode.mode &= !S_ISGID;
+            }
+        }
+        inode.uid = uid.unwrap_or(inode.uid);
+        inode.gid = gid.unwrap_or(inode.gid);
+        inode.touch_changed(self.metadata.now());
+        self.metadata.persist_inode(&inode)
+    }
+
+    /*
+        Sets the access and modification times like utimensat, leaving those
+        passed as None alone. The change time always becomes the current time.
+        Only the owner and root may set a given time; setting them to now
+        only takes permission to write the file.
+    */
+    pub fn set_times(&mut self, inode_number: u32, atime: Option<SetTime>, mtime: Option<SetTime>) -> Result<(), Error> {
+        let mut inode = self.metadata.load_inode(inode_number)?;
+        if !self.credentials.owns(&inode) {
+            if [atime, mtime].iter().any(|time| matches!(time, Some(SetTime::To(_)))) {
+                return Err(FsError::NotPermitted.into());
+            }
+            self.check(&inode, MAY_WRITE)?;
+        }
+        let now = self.metadata.now();
+        let time = |time| match time {
+            SetTime::Now => now,
+            SetTime::To(time) => time,
+        };
+        if let Some(atime) = atime {
+            inode.atime = time(atime);
+        }
+        if let Some(mtime) = mtime {
+            inode.mtime = time(mtime);
+        }
+        inode.touch_changed(now);
+        self.metadata.persist_inode(&inode)
+    }
+
+    /*
+        Extended attribute names start with a namespace, each with its own
+        rules, as on Linux:
+            user.      the file's read or write permission decides; only
+                       regular files and directories have them
+            trusted.   root only, and hidden from everybody else
+            security.  anybody may read them, only root may change them
+            system.    only the POSIX ACLs, which anybody may read and the
+                       owner may change; symbolic links have none
+        Other namespaces are not supported.
+    */
+    fn check_xattr(&self, inode: &Inode, name: &str, mask: u32) -> Result<(), Error> {
+        let root_only = || if self.credentials.is_root() { Ok(()) } else { Err(Error::from(FsError::NotPermitted)) };
+        if name.len() > XATTR_NAME_MAX {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("{}: Invalid attribute name", name)));
+        }
+        match name.split_once('.') {
+            Some((_, "")) | None => Err(Error::new(ErrorKind::InvalidInput, format!("{}: Invalid attribute name", name))),
+            Some(("user", _)) => {
+                if !matches!(inode.file_type, FileType::File | FileType::Directory) {
+                    return Err(if mask == MAY_WRITE { FsError::NotPermitted } else { FsError::NoAttribute }.into());
+                }
+                self.check(inode, mask)
+            }
+            Some(("trusted", _)) => root_only(),
+            Some(("security", _)) if mask == MAY_WRITE => root_only(),
+            Some(("security", _)) => Ok(()),
+            Some(("system", _)) if name == ACL_ACCESS || name == ACL_DEFAULT => {
+                if mask != MAY_WRITE {
+                    Ok(())
+                } else if inode.file_type == FileType::Symlink {
+                    Err(Error::new(ErrorKind::Unsupported, format!("{}: Operation not supported", name)))
+                } else if !self.credentials.owns(inode) {
+                    Err(FsError::NotPermitted.into())
+                } else {
+                    Ok(())
+                }
+            }
+            Some(_) => Err(Error::new(ErrorKind::Unsupported, format!("{}: Operation not supported", name))),
+        }
+    }
+
+    pub fn getxattr(&self, inode_number: u32, name: &str) -> Result<Vec<u8>, Error> {
+        let inode = self.metadata.load_inode(inode_number)?;
+        self.check_xattr(&inode, name, MAY_READ)?;
+        inode.read_xattrs(&self.metadata)?.remove(name).ok_or_else(|| FsError::NoAttribute.into())
+    }
+
+    /*
+        The names of the attributes the credentials may see.
+    */
+    pub fn listxattr(&self, inode_number: u32) -> Result<Vec<String>, Error> {
+        let inode = self.metadata.load_inode(inode_number)?;
+        Ok(inode.read_xattrs(&self.metadata)?.into_keys()
+            .filter(|name| self.credentials.is_root() || !name.starts_with("trusted."))
+            .collect())
+    }
+
+    /*
+        Sets an attribute. With XATTR_CREATE it must not exist yet, with
+        XATTR_REPLACE it must. An ACL is checked first and an empty one
+        removes it; see set_acl.
+    */
+    pub fn setxattr(&mut self, inode_number: u32, name: &str, value: &[u8], flags: u32) -> Result<(), Error> {
+        let mut inode = self.metadata.load_inode(inode_number)?;
+        self.check_xattr(&inode, name, MAY_WRITE)?;
+        if value.len() > XATTR_SIZE_MAX {
+            return Err(Error::new(ErrorKind::ArgumentListTooLong, "Attribute value too large"));
+        }
+        let mut xattrs = inode.read_xattrs(&self.metadata)?;
+        match (xattrs.contains_key(name), flags) {
+            (true, XATTR_CREATE) => return Err(Error::new(ErrorKind::AlreadyExists, format!("{}: Attribute exists", name))),
+            (false, XATTR_REPLACE) => return Err(FsError::NoAttribute.into()),
+            (_, 0 | XATTR_CREATE | XATTR_REPLACE) => {}
+            _ => return Err(Error::new(ErrorKind::InvalidInput, format!("Invalid setxattr flags {:#x}", flags))),
+        }
+        if name == ACL_ACCESS || name == ACL_DEFAULT {
+            self.set_acl(&mut inode, &mut xattrs, name, value)?;
+        } else {
+            xattrs.insert(name.to_string(), value.to_vec());
+        }
+        inode.write_xattrs(xattrs, &mut self.metadata)?;
+        inode.touch_changed(self.metadata.now());
+        self.metadata.persist_inode(&inode)
+    }
+
+    /*
+        Setting the access ACL sets the permission bits from it, and only
+        an ACL the bits cannot express is kept. Only directories take a
+        default ACL.
+    */
+    fn set_acl(&self, inode: &mut Inode, xattrs: &mut Xattrs, name: &str, value: &[u8]) -> Result<(), Error> {
+        let acl = if value.is_empty() { None } else { Some(Acl::decode(value)?) };
+        let Some(acl) = acl else {
+            xattrs.remove(name);
+            return Ok(());
+        };
+        if name == ACL_DEFAULT {
+            if inode.file_type != FileType::Directory {
+                return Err(FsError::PermissionDenied.into());
+            }
+            xattrs.insert(name.to_string(), acl.encode());
+            return Ok(());
+        }
+
+        inode.mode = (inode.mode & !0o777) | acl.mode();
+        if !self.credentials.is_root() && !self.credentials.in_group(inode.gid) {
+            inode.mode &= !S_ISGID;
+        }
+        if acl.is_equivalent_to_mode() {
+            xattrs.remove(name);
+        } else {
+            xattrs.insert(name.to_string(), acl.encode());
+        }
+        Ok(())
+    }
+
+    pub fn removexattr(&mut self, inode_number: u32, name: &str) -> Result<(), Error> {
+        let mut inode = self.metadata.load_inode(inode_number)?;
+        self.check_xattr(&inode, name, MAY_WRITE)?;
+        let mut xattrs = inode.read_xattrs(&self.metadata)?;
+        if xattrs.remove(name).is_none() {
+            return Err(FsError::NoAttribute.into());
+        }
+        inode.write_xattrs(xattrs, &mut self.metadata)?;
+        inode.touch_changed(self.metadata.now());
+        self.metadata.persist_inode(&inode)
+    }
+
+    /*
+        Removing an entry takes write and search permission on the directory
+        and, in a sticky directory, owning the entry or the directory.
+    */
+    fn check_unlink(&self, directory: &Directory, inode: &Inode) -> Result<(), Error> {
+        self.check(directory.inode(), MAY_WRITE | MAY_EXEC)?;
+        if !self.credentials.may_unlink(directory.inode(), inode) {
+            return Err(FsError::NotPermitted.into());
+        }
+        Ok(())
+    }
+
+    pub fn unlink<P: Path>(&mut self, parent: u32, name: P) -> Result<(), Error> {
+        let name = name.to_os_str();
+        let inode = self.metadata.load_inode(self.lookup(parent, name)?)?;
+        if inode.file_type == FileType::Directory {
+            return Err(FsError::IsADirectory.into());
+        }
+        let mut directory = self.load_directory(parent)?;
+        self.check_unlink(&directory, &inode)?;
+
+        directory.remove_entry(name, &mut self.metadata)?;
+        self.drop_link(inode)
+    }
+
+    /*
+        Drops one link to a file, freeing it and its blocks with the last.
+    */
+    fn drop_link(&mut self, mut inode: Inode) -> Result<(), Error> {
+        if inode.nlink > 1 {
+            inode.nlink -= 1;
+            inode.touch_changed(self.metadata.now());
+            self.metadata.persist_inode(&inode)
+        } else {
+            inode.release(&mut self.metadata)
+        }
+    }
+
+    /*
+        Adds `new_name` in `new_parent` as one more name for `inode_number`.
+        Like link(2), this refuses directories, which keep their single name
+        so that ".." stays well defined.
+    */
+    pub fn link<P: Path>(&mut self, inode_number: u32, new_parent: u32, new_name: P) -> Result<(), Error> {
+        let mut inode = self.metadata.load_inode(inode_number)?;
+        if inode.file_type == FileType::Directory {
+            return Err(FsError::NotPermitted.into());
+        }
+        let mut directory = self.load_directory_for(new_parent, MAY_WRITE | MAY_EXEC)?;
+
+        // count the link before adding it, so a failure never leaves an entry the count misses
+        inode.nlink = inode.nlink.checked_add(1)
+            .ok_or_else(|| Error::new(ErrorKind::TooManyLinks, "Too many links"))?;
+        inode.touch_changed(self.metadata.now());
+        self.metadata.persist_inode(&inode)?;
+        if let Err(e) = directory.add_entry(new_name, &inode, &mut self.metadata) {
+            inode.nlink -= 1;
+            self.metadata.persist_inode(&inode)?;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    pub fn rmdir<P: Path>(&mut self, parent: u32, name: P) -> Result<(), Error> {
+        let name = name.to_os_str();
+        if name == "." || name == ".." {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("{}: Invalid argument", name.to_string_lossy())));
+        }
+        let directory = self.load_directory(self.lookup(parent, name)?)?;
+        let mut parent = self.load_directory(parent)?;
+        self.check_unlink(&parent, directory.inode())?;
+        if !directory.is_empty(&self.metadata)? {
+            return Err(FsError::DirectoryNotEmpty.into());
+        }
+        if directory.get_inode_number() == self.cwd.get_inode_number() {
+            return Err(Error::new(ErrorKind::ResourceBusy, format!("{}: Is the current directory", name.to_string_lossy())));
+        }
+
+        parent.adjust_nlink(-1);
+        parent.remove_entry(name, &mut self.metadata)?;
+        self.metadata.load_inode(directory.get_inode_number())?.release(&mut self.metadata)
+    }
+
+    pub fn rename<P: Path, Q: Path>(&mut self, parent: u32, name: P, new_parent: u32, new_name: Q) -> Result<(), Error> {
+        self.rename_with_flags(parent, name, new_parent, new_name, 0)
+    }
+
+    /*
+        Moves `name` from directory `parent` to `new_name` in `new_parent`,
+        like renameat2(2). An existing target is replaced in one step, a
+        directory only by a directory and only if it is empty, anything else
+        only by a non-directory. With RENAME_NOREPLACE an existing target is
+        an error instead; with RENAME_EXCHANGE it must exist, and the two
+        entries swap places. A directory cannot end up below itself.
+
+        Both directories need write permission and, if sticky, ownership of
+        the entries leaving them. A directory changing parents also needs
+        write permission on itself, since its ".." changes.
+    */
+    pub fn rename_with_flags<P: Path, Q: Path>(&mut self, parent: u32, name: P, new_parent: u32, new_name: Q, flags: u32)
+        -> Result<(), Error>
+    {
+        let (name, new_name) = (name.to_os_str(), new_name.to_os_str());
+        if ![0, RENAME_NOREPLACE, RENAME_EXCHANGE].contains(&flags) {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("Invalid rename flags {:#x}", flags)));
+        }
+        if let Some(dots) = [name, new_name].into_iter().find(|n| *n == "." || *n == "..") {
+            return Err(Error::new(ErrorKind::ResourceBusy, format!("{}: Device or resource busy", dots.to_string_lossy())));
+        }
+        let mut inode = self.metadata.load_inode(self.lookup(parent, name)?)?;
+        let target = match self.lookup(new_parent, new_name) {
+            Ok(inode_number) => Some(self.metadata.load_inode(inode_number)?),
+            Err(e) if e.kind() == ErrorKind::NotFound && flags != RENAME_EXCHANGE => None,
+            Err(e) => return Err(e),
+        };
+        if target.is_some() && flags == RENAME_NOREPLACE {
+            return Err(Error::new(ErrorKind::AlreadyExists, format!("{}: File exists", new_name.to_string_lossy())));
+        }
+
+        let changes_parent = |inode: &Inode| inode.file_type == FileType::Directory && parent != new_parent;
+        self.check_unlink(&self.load_directory(parent)?, &inode)?;
+        let destination = self.load_directory(new_parent)?;
+        match &target {
+            Some(target) => self.check_unlink(&destination, target)?,
+            None => self.check(destination.inode(), MAY_WRITE | MAY_EXEC)?,
+        }
+        if changes_parent(&inode) {
+            self.check(&inode, MAY_WRITE)?;
+        }
+
+        // two names for the same inode: there is nothing to do
+        if target.as_ref().is_some_and(|target| target.inode_number == inode.inode_number) {
+            return Ok(());
+        }
+        if inode.file_type == FileType::Directory {
+            self.check_not_below(&inode, new_parent, name)?;
+        }
+
+        match target {
+            Some(target) if flags == RENAME_EXCHANGE => {
+                if changes_parent(&target) {
+                    self.check(&target, MAY_WRITE)?;
+                }
+                if target.file_type == FileType::Directory {
+                    self.check_not_below(&target, parent, new_name)?;
+                }
+                self.exchange(&mut inode, parent, name, target, new_parent, new_name)?;
+            }
+            Some(target) => self.replace(&inode, parent, name, target, new_parent, new_name)?,
+            None => {
+                // a directory's ".." moves from one parent's link count to the other's
+                let moves_directory = changes_parent(&inode);
+
+                // add the new name first, so a failure leaves the old one intact
+                let mut destination = destination;
+                if moves_directory {
+                    destination.adjust_nlink(1);
+                }
+                destination.add_entry(new_name, &inode, &mut self.metadata)?;
+                let mut source = self.load_directory(parent)?;
+                if moves_directory {
+                    source.adjust_nlink(-1);
+                }
+                source.remove_entry(name, &mut self.metadata)?;
+            }
+        }
+
+        if inode.file_type == FileType::Directory {
+            inode.parent = new_parent;
+        }
+        inode.touch_changed(self.metadata.now());
+        self.metadata.persist_inode(&inode)
+    }
+
+    // refuses to move `directory`, called `name`, into `new_parent` if that is inside it
+    fn check_not_below(&self, directory: &Inode, new_parent: u32, name: &OsStr) -> Result<(), Error> {
+        let mut ancestor = self.metadata.load_inode(new_parent)?;
+        loop {
+            if ancestor.inode_number == directory.inode_number {
+                return Err(Error::new(ErrorKind::InvalidInput,
+                    format!("{}: Cannot move a directory into itself", name.to_string_lossy())));
+            }
+            if ancestor.inode_number == ROOT_INODE {
+                return Ok(());
+            }
+            ancestor = self.metadata.load_inode(ancestor.parent)?;
+        }
+    }
+
+    /*
+        The rename of `inode` over an existing `target`. The target entry is
+        pointed at `inode` in place, so `new_name` never goes missing, and
+        only then is the old name removed and the target dropped.
+    */
+    fn replace(&mut self, inode: &Inode, parent: u32, name: &OsStr, target: Inode, new_parent: u32, new_name: &OsStr)
+        -> Result<(), Error>
+    {
+        let target_is_directory = target.file_type == FileType::Directory;
+        match (inode.file_type == FileType::Directory, target_is_directory) {
+            (true, false) => return Err(FsError::NotADirectory.into()),
+            (false, true) => return Err(FsError::IsADirectory.into()),
+            _ => {}
+        }
+        if target_is_directory {
+            if !self.load_directory(target.inode_number)?.is_empty(&self.metadata)? {
+                return Err(FsError::DirectoryNotEmpty.into());
+            }
+            if target.inode_number == self.cwd.get_inode_number() {
+                return Err(Error::new(ErrorKind::ResourceBusy,
+                    format!("{}: Is the current directory", new_name.to_string_lossy())));
+            }
+        }
+
+        // the replaced directory's ".." leaves the new parent, a moved one's arrives
+        let moves_directory = inode.file_type == FileType::Directory && parent != new_parent;
+        let mut destination = self.load_directory(new_parent)?;
+        destination.adjust_nlink(moves_directory as i32 - target_is_directory as i32);
+        destination.replace_entry(new_name, inode, &mut self.metadata)?;
+        let mut source = self.load_directory(parent)?;
+        if moves_directory {
+            source.adjust_nlink(-1);
+        }
+        source.remove_entry(name, &mut self.metadata)?;
+
+        if target_is_directory {
+            target.release(&mut self.metadata)
+        } else {
+            self.drop_link(target)
+        }
+    }
+
+    /*
+        RENAME_EXCHANGE: `name` in `parent` and `new_name` in `new_parent`
+        swap the inodes they point at. A directory among them moves its ".."
+        to its new parent; the caller persists `inode`, this persists
+        `target`.
+    */
+    fn exchange(&mut self, inode: &mut Inode, parent: u32, name: &OsStr, mut target: Inode, new_parent: u32, new_name: &OsStr)
+        -> Result<(), Error>
+    {
+        // each parent gains the other's subdirectory, if only one of the two is one
+        let delta = if parent == new_parent {
+            0
+        } else {
+            (inode.file_type == FileType::Directory) as i32 - (target.file_type == FileType::Directory) as i32
+        };
+        let mut destination = self.load_directory(new_parent)?;
+        destination.adjust_nlink(delta);
+        destination.replace_entry(new_name, inode, &mut self.metadata)?;
+        let mut source = self.load_directory(parent)?;
+        source.adjust_nlink(-delta);
+        source.replace_entry(name, &target, &mut self.metadata)?;
+
+        if target.file_type == FileType::Directory {
+            target.parent = parent;
+        }
+        target.touch_changed(self.metadata.now());
+        self.metadata.persist_inode(&target)
+    }
+}
+
+/*
+    Attributes of a single inode, as reported by stat.
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileStat {
+    pub inode_number: u32,
+    pub file_type: FileType,
+    pub nlink: u32,
+    pub size: u64,
+    pub blocks: u64,
+    pub block_size: u32,
+    pub atime: Timestamp,
+    pub mtime: Timestamp,
+    pub ctime: Timestamp,
+    // when the inode was created, the epoch if unknown
+    pub crtime: Timestamp,
+    // permission bits including setuid, setgid and sticky, without the file type
+    pub mode: u16,
+    pub uid: u32,
+    pub gid: u32,
+    // the device of a character or block device node, zero otherwise
+    pub rdev: u32,
+}
+
+/*
+    Capacity and usage of the whole filesystem, as reported by statfs.
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FsStat {
+    pub block_size: u32,
+    pub total_blocks: u64,
+    pub free_blocks: u64,
+    pub total_inodes: u64,
+    pub free_inodes: u64,
+    pub max_name_len: u32,
+}
+
+/*
+    On-disk layout of a filesystem, as printed by mkfs.
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FsLayout {
+    pub label: String,
+    pub block_size: u32,
+    pub total_blocks: u64,
+    pub total_inodes: u64,
+    pub inode_bitmap_start: u64,
+    pub inode_bitmap_blocks: u64,
+    pub block_bitmap_start: u64,
+    pub block_bitmap_blocks: u64,
+    pub inode_table_start: u64,
+    pub inode_table_blocks: u64,
+    pub first_data_block: u64,
+    // files are mapped through extent trees rather than block pointers
+    pub extents: bool,
+}
+
+/*
+    Geometry for a new filesystem. Only the size is required; the rest
+    defaults to 4K blocks, one inode per 16K of space, no label, block
+    pointers rather than extents and a root directory owned by root.
+
+        let fs = FormatOptions::new(64 << 20).block_size(1024).label("scratch").format(medium)?;
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FormatOptions {
+    size: u64,
+    block_size: u32,
+    bytes_per_inode: u32,
+    label: String,
+    extents: bool,
+    root_owner: (u32, u32),
+}
+
+impl FormatOptions {
+    pub fn new(size: u64) -> Self {
+        Self {
+            size,
+            block_size: DEFAULT_BLOCK_SIZE,
+            bytes_per_inode: DEFAULT_BYTES_PER_INODE,
+            label: String::new(),
+            extents: false,
+            root_owner: (0, 0),
+        }
+    }
+
+    pub fn block_size(mut self, block_size: u32) -> Self {
+        self.block_size = block_size;
+        self
+    }
+
+    pub fn bytes_per_inode(mut self, bytes_per_inode: u32) -> Self {
+        self.bytes_per_inode = bytes_per_inode;
+        self
+    }
+
+    pub fn label<L: Into<String>>(mut self, label: L) -> Self {
+        self.label = label.into();
+        self
+    }
+
+    /*
+        Maps file blocks through extent trees, which keeps the mapping of
+        large, mostly contiguous files down to a few records.
+    */
+    pub fn extents(mut self, extents: bool) -> Self {
+        self.extents = extents;
+        self
+    }
+
+    /*
+        Owner and group of the root directory, root's by default.
+    */
+    pub fn root_owner(mut self, uid: u32, gid: u32) -> Self {
+        self.root_owner = (uid, gid);
+        self
+    }
+
+    /*
+        Writes a fresh filesystem to `medium`, replacing whatever was there.
+    */
+    pub fn format<T: byte_compatible>(&self, medium: T) -> Result<ffs<T>, Error> {
+        let features = if self.extents { FEATURE_EXTENTS } else { 0 };
+        ffs::format(medium, self.size, self.block_size, self.bytes_per_inode, self.label.as_str(), features, self.root_owner)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::entity::dir_index;
+    use crate::medium::file::file_medium;
+    use crate::migration::LEGACY_NAME_SIZE;
+
+    const TEST_FS_SIZE: u64 = 10 * (1 << 20); // 10 MB
+    const BLOCK_SIZE: u32 = 4 * (1 << 10); // 4 KB
+    const BYTES_PER_INODE: u32 = 1 << 12; // 4096 bytes per inode
+
+    fn test_image_path(name: &str) -> String {
+        std::env::temp_dir().join(name).to_string_lossy().into_owned()
+    }
+
+    fn new_test_fs(name: &str) -> ffs<file_medium> {
+        let medium = file_medium::new(test_image_path(name)).unwrap();
+        ffs::new(medium, TEST_FS_SIZE, BLOCK_SIZE, BYTES_PER_INODE).unwrap()
+    }
 
     #[test]
     fn test_new_fs() {
-        const TEST_FS_SIZE: u32 = 10 * (1 << 20); // 10 MB
-        const BLOCK_SIZE: u32 = 4 * (1 << 10); // 4 KB
-        const BYTES_PER_INODE: u32 = 1 << 12; // 4096 bytes per inode
-        let medium = crate::medium::file::file_medium::new("test_fs.dat");
+        let medium = file_medium::new(test_image_path("test_new_fs.dat")).unwrap();
 
         let fs = ffs::new(
             medium,
@@ -59,8 +1266,1498 @@ mod tests {
 
     #[test]
     fn test_existing_fs() {
-        let medium = crate::medium::file::file_medium::new("test_fs.dat");
+        drop(new_test_fs("test_existing_fs.dat"));
+
+        let medium = file_medium::new(test_image_path("test_existing_fs.dat")).unwrap();
         let fs = ffs::load(medium);
         assert!(fs.is_ok());
+        assert_eq!(fs.unwrap().cwd.get_inode_number(), 0);
+    }
+
+    #[test]
+    fn test_inode_round_trip() {
+        let mut fs = new_test_fs("test_inode_round_trip.dat");
+
+        let mut inode = Inode::create_new(0, FileType::File, Ownership::default(), &mut fs.metadata).unwrap();
+        inode.data_blocks[0] = 42;
+        inode.data_blocks[14] = 7;
+        inode.file_size = 5000;
+        fs.metadata.persist_inode(&inode).unwrap();
+
+        let root = Inode::load(fs.medium.borrow_mut(), 0, &fs.metadata).unwrap();
+        assert_eq!((root.parent, root.nlink), (ROOT_INODE, 2));
+        assert_eq!(root.file_type, FileType::Directory);
+
+        let loaded = Inode::load(fs.medium.borrow_mut(), inode.inode_number, &fs.metadata).unwrap();
+        assert_eq!(loaded, inode);
+
+        // and again through a freshly loaded filesystem
+        drop(fs);
+        let fs = ffs::load(file_medium::new(test_image_path("test_inode_round_trip.dat")).unwrap()).unwrap();
+        let reloaded = Inode::load(fs.medium.borrow_mut(), inode.inode_number, &fs.metadata).unwrap();
+        assert_eq!(reloaded, inode);
+    }
+
+    #[test]
+    fn test_inode_load_rejects_malformed_slot() {
+        let mut fs = new_test_fs("test_inode_malformed.dat");
+        let inode = Inode::create_new(0, FileType::File, Ownership::default(), &mut fs.metadata).unwrap();
+
+        let slot_offset = (fs.metadata.super_block_get_inode_start_block() * fs.metadata.super_block_get_block_size()
+            + crate::util::INODE_SIZE * inode.inode_number as usize) as u64;
+        let type_offset = slot_offset + 4 + 4 + 4 + crate::util::INODE_BLOCK_POINTERS as u64 * 4;
+        fs.medium.borrow_mut().write_all(type_offset, &[0xff]).unwrap();
+        assert!(Inode::load(fs.medium.borrow_mut(), inode.inode_number, &fs.metadata).is_err());
+
+        // a slot that claims to be a different inode is rejected as well
+        fs.medium.borrow_mut().write_all(slot_offset, &9_u32.to_le_bytes()).unwrap();
+        assert!(Inode::load(fs.medium.borrow_mut(), inode.inode_number, &fs.metadata).is_err());
+    }
+
+    #[test]
+    fn test_block_allocation() {
+        let mut fs = new_test_fs("test_block_allocation.dat");
+        let free_before = fs.metadata.super_block_get_free_blocks();
+        let first_data_block = fs.metadata.super_block_get_inode_start_block()
+            + (TEST_FS_SIZE / BYTES_PER_INODE as u64) as usize * crate::util::INODE_SIZE / BLOCK_SIZE as usize;
+
+        let single = fs.metadata.allocate_block().unwrap();
+        assert_eq!(single as usize, first_data_block);
+        let run = fs.metadata.allocate_blocks(4).unwrap();
+        assert_eq!(run, (single + 1..single + 5).collect::<Vec<u32>>());
+        assert_eq!(fs.metadata.super_block_get_free_blocks(), free_before - 5);
+
+        assert!(fs.metadata.free_blocks(&[run[0], run[1], run[0]]).is_err());
+        assert!(fs.metadata.is_block_allocated(run[0]) && fs.metadata.is_block_allocated(run[1]));
+        fs.metadata.free_blocks(&run).unwrap();
+        assert!(fs.metadata.free_block(run[0]).is_err());
+        assert!(fs.metadata.free_block(0).is_err());
+        assert_eq!(fs.metadata.super_block_get_free_blocks(), free_before - 1);
+
+        drop(fs);
+        let mut fs = ffs::load(file_medium::new(test_image_path("test_block_allocation.dat")).unwrap()).unwrap();
+        assert_eq!(fs.metadata.super_block_get_free_blocks(), free_before - 1);
+        assert!(fs.metadata.is_block_allocated(single));
+
+        let err = fs.metadata.allocate_blocks(free_before).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::StorageFull);
+        assert_eq!(fs.metadata.super_block_get_free_blocks(), free_before - 1);
+        let rest = fs.metadata.allocate_blocks(free_before - 1).unwrap();
+        assert_eq!(rest.len(), free_before - 1);
+        assert_eq!(fs.metadata.allocate_block().unwrap_err().kind(), std::io::ErrorKind::StorageFull);
+    }
+
+    #[test]
+    fn test_file_read_write_seek() {
+        use std::io::{Read, Seek, SeekFrom, Write};
+
+        let mut fs = new_test_fs("test_file_read_write_seek.dat");
+        let data: Vec<u8> = (0..3 * BLOCK_SIZE as usize + 100).map(|i| (i % 251) as u8).collect();
+
+        let inode_number = {
+            let mut handle = fs.create_file("data.bin").unwrap();
+            handle.write_all(&data).unwrap();
+            assert_eq!(handle.len(), data.len() as u64);
+
+            handle.seek(SeekFrom::Start(10)).unwrap();
+            handle.write_all(b"hello").unwrap();
+            assert_eq!(handle.stream_position().unwrap(), 15);
+            handle.get_inode_number()
+        };
+
+        let mut handle = fs.open_file(inode_number, MAY_READ | MAY_WRITE).unwrap();
+        let mut read_back = Vec::new();
+        handle.read_to_end(&mut read_back).unwrap();
+        assert_eq!(&read_back[10..15], b"hello");
+        assert_eq!(&read_back[15..], &data[15..]);
+
+        let mut tail = [0_u8; 4];
+        handle.seek(SeekFrom::End(-4)).unwrap();
+        handle.read_exact(&mut tail).unwrap();
+        assert_eq!(&tail, &data[data.len() - 4..]);
+        assert!(handle.seek(SeekFrom::Current(-100_000)).is_err());
+    }
+
+    #[test]
+    fn test_file_set_len_and_holes() {
+        use std::io::{Read, Seek, SeekFrom, Write};
+
+        let mut fs = new_test_fs("test_file_set_len_and_holes.dat");
+        let inode_number = fs.create_file("sparse").unwrap().get_inode_number();
+        let free_before = fs.metadata.super_block_get_free_blocks();
+
+        let mut handle = fs.open_file(inode_number, MAY_READ | MAY_WRITE).unwrap();
+        handle.seek(SeekFrom::Start(2 * BLOCK_SIZE as u64)).unwrap();
+        handle.write_all(b"end").unwrap();
+        handle.set_len(2 * BLOCK_SIZE as u64 + 1).unwrap();
+        handle.set_len(2 * BLOCK_SIZE as u64 + 3).unwrap();
+
+        let mut contents = Vec::new();
+        handle.seek(SeekFrom::Start(0)).unwrap();
+        handle.read_to_end(&mut contents).unwrap();
+        assert_eq!(contents.len(), 2 * BLOCK_SIZE as usize + 3);
+        assert!(contents[..2 * BLOCK_SIZE as usize].iter().all(|&b| b == 0));
+        assert_eq!(&contents[2 * BLOCK_SIZE as usize..], b"e\0\0");
+
+        handle.set_len(0).unwrap();
+        assert!(handle.is_empty());
+        assert_eq!(fs.metadata.super_block_get_free_blocks(), free_before);
+
+        let mut handle = fs.create_file("big").unwrap();
+        let limit = Inode::max_file_size(BLOCK_SIZE as usize, false);
+        handle.seek(SeekFrom::Start(limit - 2)).unwrap();
+        assert_eq!(handle.write(b"abcd").unwrap(), 2);
+        assert_eq!(handle.write(b"cd").unwrap_err().kind(), std::io::ErrorKind::FileTooLarge);
+    }
+
+    #[test]
+    fn test_directory_entries() {
+        let mut fs = new_test_fs("test_directory_entries.dat");
+        let free_inodes = fs.metadata.super_block_get_free_inodes();
+
+        fs.create_file("a.txt").unwrap();
+        fs.create_dir("docs").unwrap();
+        fs.create_file("b.txt").unwrap();
+        assert_eq!(fs.create_file("a.txt").err().unwrap().kind(), std::io::ErrorKind::AlreadyExists);
+        assert_eq!(fs.metadata.super_block_get_free_inodes(), free_inodes - 3);
+
+        let mut names: Vec<OsString> = fs.read_dir(".").unwrap().into_iter().map(|e| e.name).collect();
+        names.sort();
+        assert_eq!(names, vec!["a.txt", "b.txt", "docs"]);
+        assert!(fs.lookup_path("docs").is_ok());
+        assert_eq!(fs.lookup_path("missing").unwrap_err().kind(), std::io::ErrorKind::NotFound);
+
+        assert_eq!(fs.remove_file("docs").unwrap_err().kind(), std::io::ErrorKind::IsADirectory);
+        fs.remove_file("a.txt").unwrap();
+        fs.remove_dir("docs").unwrap();
+        assert_eq!(fs.metadata.super_block_get_free_inodes(), free_inodes - 1);
+
+        drop(fs);
+        let fs = ffs::load(file_medium::new(test_image_path("test_directory_entries.dat")).unwrap()).unwrap();
+        let names: Vec<OsString> = fs.read_dir(".").unwrap().into_iter().map(|e| e.name).collect();
+        assert_eq!(names, vec!["b.txt"]);
+    }
+
+    #[test]
+    fn test_directory_index() {
+        // small blocks, so that the root soon needs index nodes below it
+        let image = test_image_path("test_directory_index.dat");
+        let mut fs = ffs::new(file_medium::new(image.as_str()).unwrap(), 4 << 20, 512, 4096).unwrap();
+        let target = fs.create(ROOT_INODE, "target").unwrap();
+        let big = fs.mkdir(ROOT_INODE, "big").unwrap();
+        let count = 5000;
+        for i in 0..count {
+            fs.link(target, big, format!("link-{}", i)).unwrap();
+        }
+        assert_eq!(fs.link(target, big, "link-42").unwrap_err().kind(), ErrorKind::AlreadyExists);
+        let mut names: Vec<OsString> = fs.readdir(big).unwrap().into_iter().map(|e| e.name).collect();
+        names.sort();
+        let mut expected: Vec<OsString> = (0..count).map(|i| format!("link-{}", i).into()).collect();
+        expected.sort();
+        assert_eq!(names, expected);
+
+        for i in (0..count).step_by(2) {
+            fs.unlink(big, format!("link-{}", i)).unwrap();
+        }
+        drop(fs);
+        let mut fs = ffs::load(file_medium::load(image.as_str()).unwrap()).unwrap();
+        for i in 0..count {
+            assert_eq!(fs.lookup(big, format!("link-{}", i)).is_ok(), i % 2 == 1);
+        }
+        assert_eq!(fs.stat(target).unwrap().nlink as usize, count / 2 + 1);
+        assert_eq!(fs.rmdir(ROOT_INODE, "big").unwrap_err().kind(), ErrorKind::DirectoryNotEmpty);
+        for i in (1..count).step_by(2) {
+            fs.unlink(big, format!("link-{}", i)).unwrap();
+        }
+        assert!(fs.readdir(big).unwrap().is_empty());
+        fs.rmdir(ROOT_INODE, "big").unwrap();
+        assert_eq!(fs.stat(target).unwrap().nlink, 1);
+    }
+
+    #[test]
+    fn test_directory_index_collisions() {
+        // a 512 byte block holds one name this long, so two sharing a hash need a continuation leaf
+        let image = test_image_path("test_directory_index_collisions.dat");
+        let mut fs = ffs::new(file_medium::new(image.as_str()).unwrap(), 4 << 20, 512, 4096).unwrap();
+        let seed = fs.metadata.super_block_get_hash_seed();
+        assert_ne!(seed, [0; 4]);
+        let long = |i: u32| format!("{}-{}", "x".repeat(248), i);
+        let mut seen = std::collections::HashMap::new();
+        let mut pairs = Vec::new();
+        for i in 0.. {
+            if let Some(other) = seen.insert(dir_index::name_hash(seed, long(i).as_bytes()), i) {
+                pairs.push((long(other), long(i)));
+                if pairs.len() == 2 {
+                    break;
+                }
+            }
+        }
+
+        // one pair goes in while the directory is made indexed, the other after
+        let target = fs.create(ROOT_INODE, "target").unwrap();
+        let dir = fs.mkdir(ROOT_INODE, "dir").unwrap();
+        fs.link(target, dir, pairs[0].0.as_str()).unwrap();
+        fs.link(target, dir, pairs[0].1.as_str()).unwrap();
+        for i in 0..20 {
+            fs.link(target, dir, format!("filler-{}", i)).unwrap();
+        }
+        fs.link(target, dir, pairs[1].0.as_str()).unwrap();
+        fs.link(target, dir, pairs[1].1.as_str()).unwrap();
+        assert_eq!(fs.link(target, dir, pairs[1].1.as_str()).unwrap_err().kind(), ErrorKind::AlreadyExists);
+
+        drop(fs);
+        let mut fs = ffs::load(file_medium::load(image.as_str()).unwrap()).unwrap();
+        assert_eq!(fs.metadata.super_block_get_hash_seed(), seed);
+        assert_eq!(fs.readdir(dir).unwrap().len(), 24);
+        for (first, second) in &pairs {
+            assert!(fs.lookup(dir, first.as_str()).is_ok());
+            fs.unlink(dir, second.as_str()).unwrap();
+            assert!(fs.lookup(dir, first.as_str()).is_ok());
+            assert!(fs.lookup(dir, second.as_str()).is_err());
+        }
+    }
+
+    #[test]
+    fn test_readdir_from() {
+        let mut fs = new_test_fs("test_readdir_from.dat");
+        let dir = fs.mkdir(ROOT_INODE, "dir").unwrap();
+        for i in 0..200 {
+            fs.create(dir, format!("file-{}", i)).unwrap();
+        }
+        fs.mkdir(dir, "sub").unwrap();
+        fs.symlink(dir, "link", "sub").unwrap();
+        fs.mknod(dir, "fifo", FileType::Fifo, 0o644, 0).unwrap();
+
+        // every entry with the position after it, the types as the inodes have them
+        let mut listed = Vec::new();
+        let mut entries = fs.readdir_from(dir, 0).unwrap();
+        while let Some(entry) = entries.next() {
+            listed.push((entry.unwrap(), entries.position()));
+        }
+        assert_eq!(listed.len(), 203);
+        for (entry, _) in &listed {
+            assert_eq!(entry.file_type, fs.stat(entry.inode_number).unwrap().file_type);
+        }
+
+        // picking up after any entry gives exactly the rest
+        let rest = |fs: &ffs<file_medium>, position| -> Vec<OsString> {
+            fs.readdir_from(dir, position).unwrap().map(|entry| entry.unwrap().name).collect()
+        };
+        for at in [0, 1, 100, 202] {
+            let expected: Vec<OsString> = listed[at + 1..].iter().map(|(entry, _)| entry.name.clone()).collect();
+            assert_eq!(rest(&fs, listed[at].1), expected);
+        }
+
+        // the entry a position pointed at is gone, and its record merged into the one before
+        fs.unlink(dir, listed[51].0.name.as_os_str()).unwrap();
+        fs.unlink(dir, listed[50].0.name.as_os_str()).unwrap();
+        let expected: Vec<OsString> = listed[52..].iter().map(|(entry, _)| entry.name.clone()).collect();
+        assert_eq!(rest(&fs, listed[50].1), expected);
+
+        fs.rename_with_flags(dir, "sub", dir, "fifo", RENAME_EXCHANGE).unwrap();
+        let fifo = fs.readdir(dir).unwrap().into_iter().find(|entry| entry.name == "fifo").unwrap();
+        assert_eq!(fifo.file_type, FileType::Directory);
+    }
+
+    #[test]
+    fn test_readdir_while_adding() {
+        use std::collections::HashMap;
+        use std::ffi::OsStr;
+
+        let mut fs = new_test_fs("test_readdir_while_adding.dat");
+        let dir = fs.mkdir(ROOT_INODE, "dir").unwrap();
+        let add = |fs: &mut ffs<file_medium>, names: std::ops::Range<u32>| {
+            for i in names {
+                fs.create(dir, format!("entry-{}", i)).unwrap();
+            }
+        };
+        let mut listed = HashMap::new();
+        let mut batch = |fs: &ffs<file_medium>, position, size| {
+            let mut entries = fs.readdir_from(dir, position).unwrap();
+            for entry in entries.by_ref().take(size) {
+                *listed.entry(entry.unwrap().name).or_insert(0) += 1;
+            }
+            entries.position()
+        };
+
+        // the first block fills up and the directory gets an index between
+        // the first two batches, and its leaves split between the next two
+        add(&mut fs, 0..100);
+        let mut position = batch(&fs, 0, 40);
+        add(&mut fs, 100..400);
+        position = batch(&fs, position, 100);
+        add(&mut fs, 400..1500);
+        batch(&fs, position, usize::MAX);
+
+        // those there all along come up exactly once, the others at most once
+        for i in 0..100 {
+            assert_eq!(listed.get(OsStr::new(&format!("entry-{}", i))), Some(&1));
+        }
+        assert!(listed.values().all(|&count| count == 1));
+        assert!(listed.len() < 1500);
+    }
+
+    #[test]
+    fn test_long_names() {
+        use std::ffi::OsStr;
+        use crate::util::FS_VERSION_0_10_0;
+
+        let image = test_image_path("test_long_names.dat");
+        let mut fs = new_test_fs("test_long_names.dat");
+
+        // names are bytes: any length up to the limit, UTF-8 or not
+        let names: Vec<OsString> = (0..20_u8)
+            .map(|i| OsString::from_vec([vec![b'a' + i; MAX_FILE_NAME_SIZE - 1], vec![0xe9]].concat()))
+            .collect();
+        fs.create(ROOT_INODE, OsStr::from_bytes(b"caf\xe9")).unwrap();
+        for name in &names {
+            fs.create(ROOT_INODE, name.as_os_str()).unwrap();
+        }
+        let mut listed: Vec<OsString> = fs.readdir(ROOT_INODE).unwrap().into_iter().map(|e| e.name).collect();
+        let mut expected = [names.clone(), vec![OsString::from_vec(b"caf\xe9".to_vec())]].concat();
+        listed.sort();
+        expected.sort();
+        assert_eq!(listed, expected);
+        assert!(fs.stat(ROOT_INODE).unwrap().size > BLOCK_SIZE as u64);
+        assert!(fs.lookup(ROOT_INODE, OsStr::from_bytes(b"caf\xe9")).is_ok());
+        assert_eq!(fs.create(ROOT_INODE, "a/b").unwrap_err().kind(), ErrorKind::InvalidInput);
+
+        // freed records are reused
+        fs.unlink(ROOT_INODE, names[1].as_os_str()).unwrap();
+        fs.create(ROOT_INODE, names[1].as_os_str()).unwrap();
+        for name in &names {
+            fs.unlink(ROOT_INODE, name.as_os_str()).unwrap();
+        }
+        fs.unlink(ROOT_INODE, OsStr::from_bytes(b"caf\xe9")).unwrap();
+        assert!(fs.readdir(ROOT_INODE).unwrap().is_empty());
+
+        // a 0.10.0 image has fixed width entries, which the upgrade packs into records
+        fs.mkdir(ROOT_INODE, "docs").unwrap();
+        fs.create_file("/docs/readme").unwrap();
+        fs.create(ROOT_INODE, OsStr::from_bytes(b"caf\xe9")).unwrap();
+        relay_directories_as_0_10_0(&mut fs);
+        fs.medium.borrow_mut().write_all(4, &FS_VERSION_0_10_0).unwrap();
+        drop(fs);
+        let mut fs = ffs::upgrade(file_medium::load(image.as_str()).unwrap()).unwrap();
+        let mut listed: Vec<OsString> = fs.readdir(ROOT_INODE).unwrap().into_iter().map(|e| e.name).collect();
+        listed.sort();
+        assert_eq!(listed, vec![OsStr::from_bytes(b"caf\xe9"), OsStr::new("docs")]);
+        assert!(fs.lookup_path("/docs/readme").is_ok());
+        fs.create(ROOT_INODE, names[0].as_os_str()).unwrap();
+    }
+
+    #[test]
+    fn test_path_resolution() {
+        use std::io::{Read, Write};
+
+        let mut fs = new_test_fs("test_path_resolution.dat");
+        fs.create_dir("/a").unwrap();
+        fs.create_dir("a/b").unwrap();
+        fs.create_dir("/a/c").unwrap();
+        fs.create_file("/a/c/readme").unwrap().write_all(b"hi").unwrap();
+
+        assert_eq!(fs.lookup_path("/a/b/../c/readme").unwrap(), fs.lookup_path("a/c/readme").unwrap());
+        assert_eq!(fs.lookup_path("/..").unwrap(), ROOT_INODE);
+        assert_eq!(fs.lookup_path("a/./b/").unwrap(), fs.lookup_path("/a/b").unwrap());
+        assert_eq!(fs.lookup_path("/a/nope").unwrap_err().kind(), std::io::ErrorKind::NotFound);
+        assert_eq!(fs.lookup_path("/a/c/readme/x").unwrap_err().kind(), std::io::ErrorKind::NotADirectory);
+        assert_eq!(fs.create_dir("/a/b/..").unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+
+        fs.chdir("/a/b").unwrap();
+        assert_eq!(fs.current_dir().unwrap(), "/a/b");
+        let mut contents = String::new();
+        fs.open("../c/readme", MAY_READ).unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "hi");
+        assert_eq!(fs.chdir("../c/readme").unwrap_err().kind(), std::io::ErrorKind::NotADirectory);
+        assert_eq!(fs.remove_dir("/a/b").unwrap_err().kind(), std::io::ErrorKind::ResourceBusy);
+
+        fs.chdir("..").unwrap();
+        fs.create_file("b/new").unwrap();
+        let names: Vec<OsString> = fs.read_dir("b").unwrap().into_iter().map(|e| e.name).collect();
+        assert_eq!(names, vec!["new"]);
+        assert_eq!(fs.current_dir().unwrap(), "/a");
+
+        fs.chdir("/").unwrap();
+        assert_eq!(fs.current_dir().unwrap(), "/");
+        assert_eq!(fs.remove_dir("a").unwrap_err().kind(), std::io::ErrorKind::DirectoryNotEmpty);
+    }
+
+    #[test]
+    fn test_inode_operations() {
+        let mut fs = new_test_fs("test_inode_operations.dat");
+        let docs = fs.mkdir(ROOT_INODE, "docs").unwrap();
+        let notes = fs.create(docs, "notes").unwrap();
+        assert_eq!(fs.lookup(ROOT_INODE, "docs").unwrap(), docs);
+        assert_eq!(fs.lookup(docs, "..").unwrap(), ROOT_INODE);
+
+        assert_eq!(fs.write(notes, 5, b"world").unwrap(), 5);
+        let mut buffer = [0xff_u8; 16];
+        assert_eq!(fs.read(notes, 0, &mut buffer).unwrap(), 10);
+        assert_eq!(&buffer[..10], b"\0\0\0\0\0world");
+        let stat = fs.stat(notes).unwrap();
+        assert_eq!((stat.file_type, stat.size, stat.blocks), (FileType::File, 10, 1));
+
+        fs.rename(docs, "notes", ROOT_INODE, "moved").unwrap();
+        assert_eq!(fs.stat_path("/moved").unwrap().inode_number, notes);
+        assert_eq!(fs.lookup(docs, "notes").unwrap_err().kind(), std::io::ErrorKind::NotFound);
+        assert_eq!(fs.rename(ROOT_INODE, "docs", docs, "inner").unwrap_err().kind(),
+                   std::io::ErrorKind::InvalidInput);
+        assert_eq!(fs.rename(ROOT_INODE, "docs", ROOT_INODE, "moved").unwrap_err().kind(),
+                   std::io::ErrorKind::NotADirectory);
+
+        let free_blocks = fs.statfs().free_blocks;
+        fs.unlink(ROOT_INODE, "moved").unwrap();
+        assert_eq!(fs.statfs().free_blocks, free_blocks + 1);
+        fs.rmdir(ROOT_INODE, "docs").unwrap();
+        assert!(fs.readdir(ROOT_INODE).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_rename() {
+        let mut fs = new_test_fs("test_rename.dat");
+        let a = fs.mkdir(ROOT_INODE, "a").unwrap();
+        let b = fs.mkdir(ROOT_INODE, "b").unwrap();
+        let old = fs.create(a, "old").unwrap();
+        fs.create(b, "new").unwrap();
+        let free_inodes = fs.statfs().free_inodes;
+
+        // a file replaces a file, which goes with its last link
+        fs.rename(a, "old", b, "new").unwrap();
+        assert_eq!(fs.lookup(b, "new").unwrap(), old);
+        assert_eq!(fs.lookup(a, "old").unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(fs.statfs().free_inodes, free_inodes + 1);
+
+        // two names for one inode: nothing happens
+        fs.link(old, a, "alias").unwrap();
+        fs.rename(a, "alias", b, "new").unwrap();
+        assert_eq!((fs.lookup(a, "alias").unwrap(), fs.stat(old).unwrap().nlink), (old, 2));
+        fs.unlink(a, "alias").unwrap();
+
+        // a directory replaces an empty directory in another parent
+        let moving = fs.mkdir(a, "moving").unwrap();
+        fs.mkdir(moving, "child").unwrap();
+        fs.mkdir(b, "empty").unwrap();
+        assert_eq!(fs.rename(a, "moving", b, "new").unwrap_err().kind(), ErrorKind::NotADirectory);
+        assert_eq!(fs.rename(b, "new", a, "moving").unwrap_err().kind(), ErrorKind::IsADirectory);
+        assert_eq!(fs.rename(b, "empty", a, "moving").unwrap_err().kind(), ErrorKind::DirectoryNotEmpty);
+        assert_eq!(fs.rename(a, "moving", moving, "child").unwrap_err().kind(), ErrorKind::InvalidInput);
+        fs.rename(a, "moving", b, "empty").unwrap();
+        assert_eq!(fs.lookup(b, "empty").unwrap(), moving);
+        assert_eq!(fs.lookup(moving, "..").unwrap(), b);
+        assert_eq!((fs.stat(a).unwrap().nlink, fs.stat(b).unwrap().nlink), (2, 3));
+
+        // RENAME_NOREPLACE only moves to a free name
+        assert_eq!(fs.rename_with_flags(b, "new", b, "empty", RENAME_NOREPLACE).unwrap_err().kind(),
+                   ErrorKind::AlreadyExists);
+        fs.rename_with_flags(b, "new", a, "file", RENAME_NOREPLACE).unwrap();
+        assert_eq!(fs.rename_with_flags(a, "file", b, "x", 4).unwrap_err().kind(), ErrorKind::InvalidInput);
+
+        // RENAME_EXCHANGE swaps a file and a directory between parents
+        assert_eq!(fs.rename_with_flags(a, "file", b, "missing", RENAME_EXCHANGE).unwrap_err().kind(),
+                   ErrorKind::NotFound);
+        assert_eq!(fs.rename_with_flags(b, "empty", moving, "child", RENAME_EXCHANGE).unwrap_err().kind(),
+                   ErrorKind::InvalidInput);
+        fs.rename_with_flags(a, "file", b, "empty", RENAME_EXCHANGE).unwrap();
+        assert_eq!((fs.lookup(a, "file").unwrap(), fs.lookup(b, "empty").unwrap()), (moving, old));
+        assert_eq!(fs.lookup(moving, "..").unwrap(), a);
+        assert_eq!((fs.stat(a).unwrap().nlink, fs.stat(b).unwrap().nlink), (3, 2));
+        assert_eq!(fs.read_dir("/a/file").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_format_geometry() {
+        let image = test_image_path("test_format_geometry.dat");
+        let fs = ffs::new_with_label(file_medium::new(image.as_str()).unwrap(), 63 << 20, 1024, 4096, "scratch").unwrap();
+        let layout = fs.layout();
+        assert_eq!(layout.label, "scratch");
+        assert_eq!((layout.total_blocks, layout.total_inodes), (64512, 16128));
+        assert_eq!(layout.inode_bitmap_blocks, 2);
+        assert_eq!(layout.block_bitmap_blocks, 8);
+        assert_eq!(layout.inode_table_start, 1 + 2 + 8);
+        assert_eq!(layout.first_data_block, layout.inode_table_start + 16128 * 256 / 1024);
+
+        drop(fs);
+        assert_eq!(ffs::load(file_medium::new(image.as_str()).unwrap()).unwrap().layout().label, "scratch");
+
+        // the geometry is checked before anything is written, so these never touch 8 TiB of disk
+        let too_many_blocks = ffs::new(file_medium::new(image.as_str()).unwrap(), 8 << 40, 1024, 1 << 16);
+        assert_eq!(too_many_blocks.err().unwrap().kind(), std::io::ErrorKind::InvalidInput);
+        let too_many_inodes = ffs::new(file_medium::new(image.as_str()).unwrap(), 8 << 40, 1 << 16, 1024);
+        assert_eq!(too_many_inodes.err().unwrap().kind(), std::io::ErrorKind::InvalidInput);
+        let odd_block_size = ffs::new(file_medium::new(image.as_str()).unwrap(), 1 << 20, 3000, 4096);
+        assert_eq!(odd_block_size.err().unwrap().kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_typed_errors() {
+        use crate::error::FsError;
+
+        let image = test_image_path("test_typed_errors.dat");
+        // 64 inodes, the root takes one of them
+        let mut fs = ffs::new(file_medium::new(image.as_str()).unwrap(), 256 << 10, 4096, 4096).unwrap();
+
+        let long_name = "x".repeat(MAX_FILE_NAME_SIZE + 1);
+        let err = FsError::from(fs.create(ROOT_INODE, long_name.as_str()).err().unwrap());
+        assert!(matches!(err, FsError::NameTooLong));
+        assert_eq!(err.errno(), libc::ENAMETOOLONG);
+
+        for i in 0..63 {
+            fs.mkdir(ROOT_INODE, format!("d{}", i)).unwrap();
+        }
+        let err = fs.mkdir(ROOT_INODE, "one-too-many").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::StorageFull);
+        assert!(matches!(FsError::from(err), FsError::NoInodes));
+
+        // errors that did not start out as an FsError still get an errno
+        assert_eq!(crate::error::errno(&fs.lookup(ROOT_INODE, "missing").unwrap_err()), libc::ENOENT);
+        assert!(file_medium::load(test_image_path("test_typed_errors_missing.dat")).is_err());
+
+        // a data area marked free in the block bitmap is reported as corruption
+        let block_bitmap_offset = fs.layout().block_bitmap_start * 4096;
+        fs.medium.borrow_mut().write_all(block_bitmap_offset, &[0]).unwrap();
+        drop(fs);
+        let err = ffs::load(file_medium::load(image.as_str()).unwrap()).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(matches!(FsError::from(err), FsError::Corrupted { structure: "block bitmap", .. }));
+    }
+
+    #[test]
+    fn test_load_validation() {
+        use crate::error::FsError;
+
+        let load_error = |image: &str| FsError::from(ffs::load(file_medium::load(image).unwrap()).err().unwrap());
+
+        // random bytes and files too small to hold a superblock are not filesystems
+        let image = test_image_path("test_load_validation.dat");
+        let mut state = 0x2545_f491_u32;
+        let noise: Vec<u8> = (0..64 << 10).map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (state >> 16) as u8
+        }).collect();
+        std::fs::write(&image, &noise).unwrap();
+        assert!(matches!(load_error(image.as_str()), FsError::NotAFilesystem));
+        std::fs::write(&image, b"FiFS").unwrap();
+        assert!(matches!(load_error(image.as_str()), FsError::NotAFilesystem));
+
+        let format = |image: &str| {
+            std::fs::remove_file(image).unwrap();
+            ffs::new(file_medium::new(image).unwrap(), 1 << 20, 1024, 4096).unwrap();
+        };
+        let patch = |image: &str, offset: u64, bytes: &[u8]| {
+            file_medium::load(image).unwrap().write_all(offset, bytes).unwrap();
+        };
+
+        format(image.as_str());
+        patch(image.as_str(), 4, &[9, 9, 9]);
+        assert!(matches!(load_error(image.as_str()), FsError::UnsupportedVersion([9, 9, 9])));
+
+        // block_size_log
+        format(image.as_str());
+        patch(image.as_str(), 16, &[40]);
+        assert!(matches!(load_error(image.as_str()), FsError::Corrupted { structure: "superblock", .. }));
+
+        // an image cut short of the blocks its superblock claims
+        format(image.as_str());
+        std::fs::OpenOptions::new().write(true).open(&image).unwrap().set_len(512 << 10).unwrap();
+        assert!(matches!(load_error(image.as_str()), FsError::Corrupted { structure: "superblock", .. }));
+    }
+
+    #[test]
+    fn test_wide_geometry() {
+        // 2^20 blocks, beyond what 16 bit block numbers could address; the image is sparse
+        let image = test_image_path("test_wide_geometry.dat");
+        let mut fs = ffs::new(file_medium::new(image.as_str()).unwrap(), 1 << 30, 1024, 1 << 20).unwrap();
+        assert_eq!(fs.layout().total_blocks, 1 << 20);
+        fs.create_file("/big").unwrap().write_all(&[7_u8; 3000]).unwrap();
+        let free_blocks = fs.statfs().free_blocks;
+
+        drop(fs);
+        let mut fs = ffs::load(file_medium::load(image.as_str()).unwrap()).unwrap();
+        assert_eq!(fs.statfs().free_blocks, free_blocks);
+        assert_eq!(fs.stat_path("/big").unwrap().size, 3000);
+        fs.remove_file("/big").unwrap();
+        // three data blocks, plus the root directory's now empty entry block
+        assert_eq!(fs.statfs().free_blocks, free_blocks + 4);
+    }
+
+    #[test]
+    fn test_indirect_blocks() {
+        use std::io::Read;
+
+        // 1K blocks hold 256 pointers, so block 268 is the first one behind the double indirect block
+        let image = test_image_path("test_indirect_blocks.dat");
+        let mut fs = ffs::new(file_medium::new(image.as_str()).unwrap(), 1 << 20, 1024, 16384).unwrap();
+        let big = fs.create_file("/big").unwrap().get_inode_number();
+        let free_blocks = fs.statfs().free_blocks;
+
+        let data: Vec<u8> = (0..270 * 1024).map(|i| (i / 1024) as u8).collect();
+        fs.open("/big", MAY_WRITE).unwrap().write_all(&data).unwrap();
+        // 270 data blocks, the single and double indirect blocks and one block below the latter
+        assert_eq!(fs.stat(big).unwrap().blocks, 273);
+        assert_eq!(fs.statfs().free_blocks, free_blocks - 273);
+
+        // a write far into the triple indirect range only maps what it touches
+        let far = (12 + 256 + 256 * 256) * 1024 + 100;
+        fs.write(big, far, b"far").unwrap();
+        assert_eq!(fs.stat(big).unwrap().blocks, 277);
+
+        drop(fs);
+        let mut fs = ffs::load(file_medium::load(image.as_str()).unwrap()).unwrap();
+        let mut contents = vec![0_u8; data.len()];
+        fs.open("/big", MAY_READ).unwrap().read_exact(&mut contents).unwrap();
+        assert!(contents == data);
+        let mut tail = [0_u8; 5];
+        assert_eq!(fs.read(big, far - 2, &mut tail).unwrap(), 5);
+        assert_eq!(&tail, b"\0\0far");
+
+        // cutting back into the single indirect range drops the double and triple trees
+        fs.truncate(big, 13 * 1024 + 10).unwrap();
+        assert_eq!(fs.stat(big).unwrap().blocks, 15);
+        assert_eq!(fs.statfs().free_blocks, free_blocks - 15);
+        fs.truncate(big, 12 * 1024).unwrap();
+        assert_eq!(fs.stat(big).unwrap().blocks, 12);
+        let mut contents = Vec::new();
+        fs.open("/big", MAY_READ).unwrap().read_to_end(&mut contents).unwrap();
+        assert!(contents == data[..12 * 1024]);
+
+        fs.truncate(big, 0).unwrap();
+        assert_eq!(fs.statfs().free_blocks, free_blocks);
+    }
+
+    #[test]
+    fn test_extents() {
+        use std::io::Read;
+        use crate::error::FsError;
+
+        let image = test_image_path("test_extents.dat");
+        let medium = file_medium::new(image.as_str()).unwrap();
+        let mut fs = FormatOptions::new(4 << 20).block_size(1024).extents(true).format(medium).unwrap();
+        assert!(fs.layout().extents);
+        let file = fs.create_file("/sequential").unwrap().get_inode_number();
+        let free_blocks = fs.statfs().free_blocks;
+
+        // one contiguous write is a single extent, with no tree nodes
+        let data: Vec<u8> = (0..300 * 1024).map(|i| (i / 1024) as u8).collect();
+        fs.write(file, 0, &data).unwrap();
+        assert_eq!(fs.stat(file).unwrap().blocks, 300);
+        fs.truncate(file, 0).unwrap();
+        assert_eq!(fs.statfs().free_blocks, free_blocks);
+
+        // every other block is its own extent: 400 extents need 5 leaves and an index node
+        for index in 0..400_u64 {
+            fs.write(file, index * 2048, &[(index % 250) as u8 + 1; 10]).unwrap();
+        }
+        assert_eq!(fs.stat(file).unwrap().blocks, 406);
+
+        drop(fs);
+        let mut fs = ffs::load(file_medium::load(image.as_str()).unwrap()).unwrap();
+        let mut contents = Vec::new();
+        fs.open("/sequential", MAY_READ).unwrap().read_to_end(&mut contents).unwrap();
+        assert_eq!(contents.len(), 399 * 2048 + 10);
+        for index in [0_usize, 1, 250, 399] {
+            assert_eq!(contents[index * 2048..index * 2048 + 10], [(index % 250) as u8 + 1; 10]);
+        }
+        assert_eq!(contents[2048 + 10], 0);
+        assert!(contents[1024..2048].iter().all(|&b| b == 0));
+
+        // cutting it in half leaves 200 extents, which fit in 3 leaves below the root
+        fs.truncate(file, 200 * 2048).unwrap();
+        assert_eq!(fs.stat(file).unwrap().blocks, 203);
+        fs.truncate(file, 0).unwrap();
+        assert_eq!(fs.statfs().free_blocks, free_blocks);
+
+        // written back to front, each extent goes in ahead of the rest and splits leaves in the middle
+        for index in (0..400_u64).rev() {
+            fs.write(file, index * 2048, &[(index % 250) as u8 + 1; 10]).unwrap();
+        }
+        for keep in [400_u64, 330, 170, 90, 3] {
+            fs.truncate(file, (keep - 1) * 2048 + 10).unwrap();
+            // no block is lost or counted twice along the way
+            assert_eq!(fs.statfs().free_blocks + fs.stat(file).unwrap().blocks, free_blocks);
+            let mut contents = Vec::new();
+            fs.open("/sequential", MAY_READ).unwrap().read_to_end(&mut contents).unwrap();
+            for index in [0, keep as usize / 2, keep as usize - 1] {
+                assert_eq!(contents[index * 2048..index * 2048 + 10], [(index % 250) as u8 + 1; 10]);
+            }
+        }
+        assert_eq!(fs.stat(file).unwrap().blocks, 3);
+        fs.truncate(file, 0).unwrap();
+        assert_eq!(fs.statfs().free_blocks, free_blocks);
+
+        // an image using a feature this build does not know is refused
+        drop(fs);
+        let fs = ffs::load(file_medium::load(image.as_str()).unwrap()).unwrap();
+        fs.medium.borrow_mut().write_all(57, &(1_u32 << 31 | 1).to_le_bytes()).unwrap();
+        drop(fs);
+        let err = ffs::load(file_medium::load(image.as_str()).unwrap()).err().unwrap();
+        assert!(matches!(FsError::from(err), FsError::UnsupportedFeatures(0x8000_0000)));
+    }
+
+    #[test]
+    fn test_upgrade_from_0_0_1() {
+        use std::io::Read;
+        use crate::util::{get_latest_version, FS_VERSION_0_0_1, INODE_SIZE};
+
+        // what the first release wrote for ffs::new(medium, 1 << 20, 1024, 4096), byte for byte: a
+        // superblock without magic or label, both bitmaps, and the root's slot, where the inode's
+        // copy of the block bitmap came ahead of the file type and size and pushed them out
+        let image = test_image_path("test_upgrade_from_0_0_1.dat");
+        let mut bytes = FS_VERSION_0_0_1.to_vec();
+        for value in [256_u16, 1024, 256, 1024] {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        bytes.extend_from_slice(&[8, 10, 1, 1]);
+        bytes.extend_from_slice(&3_u16.to_le_bytes());
+        bytes.extend_from_slice(&64_u16.to_le_bytes());
+        bytes.resize(3 * 1024 + INODE_SIZE, 0);
+        // inode 0 is taken; blocks 1 and 2 are, but the superblock and the inode table are not
+        bytes[1024] = 0b1;
+        bytes[2 * 1024] = 0b110;
+        bytes[3 * 1024 + 4] = b'/';
+        std::fs::write(&image, &bytes).unwrap();
+
+        // loading does not touch an old image, only an explicit upgrade does
+        let err = ffs::load(file_medium::load(image.as_str()).unwrap()).err().unwrap();
+        assert!(matches!(FsError::from(err), FsError::NeedsUpgrade(FS_VERSION_0_0_1)));
+        assert_eq!(std::fs::read(&image).unwrap(), bytes);
+        let mut fs = ffs::upgrade(file_medium::load(image.as_str()).unwrap()).unwrap();
+        assert_eq!(fs.metadata.super_block_get_version(), get_latest_version());
+        assert_eq!(fs.stat(ROOT_INODE).unwrap().file_type, FileType::Directory);
+        assert!(fs.readdir(ROOT_INODE).unwrap().is_empty());
+        let stat = fs.statfs();
+        assert_eq!((stat.free_inodes, stat.free_blocks), (255, 1024 - 67));
+
+        fs.create_dir("/docs").unwrap();
+        fs.create_file("/docs/readme").unwrap().write_all(b"hello").unwrap();
+        drop(fs);
+        let mut fs = ffs::load(file_medium::load(image.as_str()).unwrap()).unwrap();
+        let mut contents = String::new();
+        fs.open("/docs/readme", MAY_READ).unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "hello");
+    }
+
+    #[test]
+    fn test_upgrade_from_0_0_4() {
+        use std::io::Read;
+        use crate::util::{get_latest_version, FS_MAGIC, FS_VERSION_0_0_4, INODE_SIZE};
+
+        let image = test_image_path("test_upgrade_from_0_0_4.dat");
+        let mut fs = ffs::new(file_medium::new(image.as_str()).unwrap(), 1 << 20, 1024, 4096).unwrap();
+        fs.create_dir("/docs").unwrap();
+        fs.create_file("/docs/readme").unwrap().write_all(b"hello").unwrap();
+        let docs = fs.lookup_path("/docs").unwrap();
+        let readme = fs.lookup_path("/docs/readme").unwrap();
+
+        // rewrite the superblock, inode slots and directory entries the way 0.0.4 stored them
+        let layout = fs.layout();
+        let stat = fs.statfs();
+        let mut super_block = FS_MAGIC.to_vec();
+        super_block.extend_from_slice(&FS_VERSION_0_0_4);
+        for value in [stat.total_inodes, layout.total_blocks, stat.free_inodes, stat.free_blocks] {
+            super_block.extend_from_slice(&(value as u16).to_le_bytes());
+        }
+        super_block.extend_from_slice(&[8, 10, layout.inode_bitmap_blocks as u8, layout.block_bitmap_blocks as u8]);
+        super_block.extend_from_slice(&(layout.inode_table_start as u16).to_le_bytes());
+        super_block.extend_from_slice(&(layout.inode_table_blocks as u16).to_le_bytes());
+        super_block.extend_from_slice(b"old disk\0\0\0\0\0\0\0\0");
+        fs.medium.borrow_mut().write_all(0, &super_block).unwrap();
+
+        for (inode_number, name) in [(ROOT_INODE, "/"), (docs, "docs"), (readme, "readme")] {
+            let inode = fs.metadata.load_inode(inode_number).unwrap();
+            let entries = fs.readdir(inode_number).unwrap_or_default();
+            let size = if inode.file_type == FileType::Directory { entries.len() * 67 } else { inode.file_size as usize };
+
+            let mut slot = Vec::new();
+            slot.extend_from_slice(&(inode.inode_number as u16).to_le_bytes());
+            slot.extend_from_slice(&(inode.parent as u16).to_le_bytes());
+            let mut name = name.as_bytes().to_vec();
+            name.resize(LEGACY_NAME_SIZE, 0);
+            slot.extend_from_slice(&name);
+            for index in 0..32 {
+                let block = inode.lookup_block(index, &fs.metadata).unwrap();
+                slot.extend_from_slice(&(block as u16).to_le_bytes());
+            }
+            slot.push(inode.file_type as u8);
+            slot.extend_from_slice(&(size as u32).to_le_bytes());
+            slot.resize(INODE_SIZE, 0);
+            let slot_offset = layout.inode_table_start * 1024 + (INODE_SIZE as u64) * inode_number as u64;
+            fs.medium.borrow_mut().write_all(slot_offset, &slot).unwrap();
+
+            let mut records = Vec::new();
+            for entry in entries {
+                records.extend_from_slice(&(entry.inode_number as u16).to_le_bytes());
+                records.push(entry.name.len() as u8);
+                let mut name = entry.name.into_vec();
+                name.resize(LEGACY_NAME_SIZE, 0);
+                records.extend_from_slice(&name);
+            }
+            if !records.is_empty() {
+                fs.medium.borrow_mut().write_all(inode.data_blocks[0] as u64 * 1024, &records).unwrap();
+            }
+        }
+        drop(fs);
+
+        let mut fs = ffs::upgrade(file_medium::load(image.as_str()).unwrap()).unwrap();
+        assert_eq!(fs.metadata.super_block_get_version(), get_latest_version());
+        assert_eq!(fs.metadata.super_block_get_label(), "old disk");
+        let mut contents = String::new();
+        fs.open("/docs/readme", MAY_READ).unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "hello");
+        fs.create_file("/docs/second").unwrap();
+        assert_eq!(fs.read_dir("/docs").unwrap().len(), 2);
+
+        // the upgraded image loads as a current one
+        drop(fs);
+        let fs = ffs::load(file_medium::load(image.as_str()).unwrap()).unwrap();
+        assert_eq!(fs.stat_path("/docs/readme").unwrap().size, 5);
+    }
+
+    #[test]
+    fn test_upgrade_from_0_1_0() {
+        use std::io::Read;
+        use crate::util::{get_latest_version, FS_VERSION_0_1_0, INODE_DIRECT_BLOCKS, INODE_SIZE};
+
+        let image = test_image_path("test_upgrade_from_0_1_0.dat");
+        let mut fs = ffs::new(file_medium::new(image.as_str()).unwrap(), 1 << 20, 1024, 4096).unwrap();
+        let data: Vec<u8> = (0..20 * 1024).map(|i| (i / 1024) as u8).collect();
+        fs.create_file("/twenty").unwrap().write_all(&data).unwrap();
+        let twenty = fs.lookup_path("/twenty").unwrap();
+        relay_directories_as_0_10_0(&mut fs);
+
+        // 0.1.0 kept all 32 pointers in the slot, with no indirect block
+        let layout = fs.layout();
+        for (inode_number, name) in [(ROOT_INODE, "/"), (twenty, "twenty")] {
+            let inode = fs.metadata.load_inode(inode_number).unwrap();
+            let mut slot = Vec::new();
+            slot.extend_from_slice(&inode.inode_number.to_le_bytes());
+            slot.extend_from_slice(&inode.parent.to_le_bytes());
+            let mut name = name.as_bytes().to_vec();
+            name.resize(LEGACY_NAME_SIZE, 0);
+            slot.extend_from_slice(&name);
+            for index in 0..32 {
+                slot.extend_from_slice(&inode.lookup_block(index, &fs.metadata).unwrap().to_le_bytes());
+            }
+            slot.push(inode.file_type as u8);
+            slot.extend_from_slice(&inode.file_size.to_le_bytes());
+            slot.resize(INODE_SIZE, 0);
+            let slot_offset = layout.inode_table_start * 1024 + (INODE_SIZE as u64) * inode_number as u64;
+            fs.medium.borrow_mut().write_all(slot_offset, &slot).unwrap();
+
+            let indirect = inode.data_blocks[INODE_DIRECT_BLOCKS];
+            if indirect != 0 {
+                fs.metadata.free_blocks(&[indirect]).unwrap();
+            }
+        }
+        let free_blocks = fs.statfs().free_blocks;
+        fs.medium.borrow_mut().write_all(4, &FS_VERSION_0_1_0).unwrap();
+        drop(fs);
+
+        let mut fs = ffs::upgrade(file_medium::load(image.as_str()).unwrap()).unwrap();
+        assert_eq!(fs.metadata.super_block_get_version(), get_latest_version());
+        assert_eq!(fs.statfs().free_blocks, free_blocks - 1);
+        assert_eq!(fs.stat(twenty).unwrap().blocks, 21);
+        let mut contents = Vec::new();
+        fs.open("/twenty", MAY_READ).unwrap().read_to_end(&mut contents).unwrap();
+        assert!(contents == data);
+
+        fs.truncate(twenty, 0).unwrap();
+        assert_eq!(fs.statfs().free_blocks, free_blocks + 20);
+    }
+
+    #[test]
+    fn test_upgrade_from_0_3_0() {
+        use crate::util::{get_latest_version, FS_VERSION_0_3_0, INODE_SIZE};
+
+        let image = test_image_path("test_upgrade_from_0_3_0.dat");
+        let mut fs = ffs::new(file_medium::new(image.as_str()).unwrap(), 1 << 20, 1024, 4096).unwrap();
+        fs.create_file("/kept").unwrap().write_all(b"still here").unwrap();
+        let kept = fs.lookup_path("/kept").unwrap();
+
+        // 0.3.0 filled the rest of every slot with a copy of the block bitmap
+        relay_slots_as_0_6_0(&mut fs);
+        let reserved_start = 4 + 4 + LEGACY_NAME_SIZE + crate::util::INODE_BLOCK_POINTERS * 4 + 1 + 8;
+        let slot_offset = |inode_number: u32| fs.layout().inode_table_start * 1024 + (INODE_SIZE as u64) * inode_number as u64;
+        for inode_number in [ROOT_INODE, kept] {
+            let garbage = vec![0xa5_u8; INODE_SIZE - reserved_start];
+            fs.medium.borrow_mut().write_all(slot_offset(inode_number) + reserved_start as u64, &garbage).unwrap();
+        }
+        fs.medium.borrow_mut().write_all(4, &FS_VERSION_0_3_0).unwrap();
+        let kept_offset = slot_offset(kept);
+        drop(fs);
+
+        let mut fs = ffs::upgrade(file_medium::load(image.as_str()).unwrap()).unwrap();
+        assert_eq!(fs.metadata.super_block_get_version(), get_latest_version());
+        let mut slot = vec![0_u8; INODE_SIZE];
+        fs.medium.borrow_mut().read_all(kept_offset, &mut slot).unwrap();
+        // later versions then put the timestamps, mode, uid and gid at the start of the reserved bytes
+        // and replaced the name with the link count
+        let fields_end = 4 + 4 + 4 + crate::util::INODE_BLOCK_POINTERS * 4 + 1 + 8 + 4 * 12 + 10;
+        assert!(slot[fields_end..].iter().all(|&b| b == 0));
+        let mut contents = [0_u8; 10];
+        assert_eq!(fs.read(kept, 0, &mut contents).unwrap(), 10);
+        assert_eq!(&contents, b"still here");
+    }
+
+    /*
+        Rewrites every inode slot in the layout of 0.2.0 to 0.6.0, which had
+        a name where the link count is now, so that tests can turn an image
+        into one of those versions by also changing the version bytes.
+    */
+    fn relay_slots_as_0_6_0(fs: &mut ffs<file_medium>) {
+        use crate::util::INODE_SIZE;
+
+        relay_directories_as_0_10_0(fs);
+        let table = fs.layout().inode_table_start * fs.layout().block_size as u64;
+        for inode_number in 0..fs.metadata.super_block_get_total_inodes() as u32 {
+            if !fs.metadata.is_inode_allocated(inode_number) {
+                continue;
+            }
+            let offset = table + (INODE_SIZE * inode_number as usize) as u64;
+            let mut slot = vec![0_u8; INODE_SIZE];
+            fs.medium.borrow_mut().read_all(offset, &mut slot).unwrap();
+            let mut relaid = slot[..8].to_vec();
+            relaid.resize(8 + LEGACY_NAME_SIZE, 0);
+            relaid.extend_from_slice(&slot[12..INODE_SIZE - LEGACY_NAME_SIZE + 4]);
+            fs.medium.borrow_mut().write_all(offset, &relaid).unwrap();
+        }
+    }
+
+    /*
+        Rewrites every directory in the layout of 0.10.0 and earlier, an
+        array of fixed width entries. Only the version bytes are left for
+        the caller to change.
+    */
+    fn relay_directories_as_0_10_0(fs: &mut ffs<file_medium>) {
+        for inode_number in 0..fs.metadata.super_block_get_total_inodes() as u32 {
+            if !fs.metadata.is_inode_allocated(inode_number) {
+                continue;
+            }
+            let Ok(directory) = fs.load_directory(inode_number) else {
+                continue;
+            };
+            let mut records = Vec::new();
+            for entry in directory.iter(&fs.metadata) {
+                let entry = entry.unwrap();
+                records.extend_from_slice(&entry.inode_number.to_le_bytes());
+                records.push(entry.name.len() as u8);
+                let mut name = entry.name.into_vec();
+                name.resize(LEGACY_NAME_SIZE, 0);
+                records.extend_from_slice(&name);
+            }
+            let mut inode = directory.inode().clone();
+            inode.truncate_data(0, &mut fs.metadata).unwrap();
+            inode.write_data(0, &records, &mut fs.metadata).unwrap();
+            fs.metadata.persist_inode(&inode).unwrap();
+        }
+    }
+
+    struct TestClock(Rc<std::cell::Cell<Timestamp>>);
+
+    impl Clock for TestClock {
+        fn now(&self) -> Timestamp {
+            self.0.get()
+        }
+    }
+
+    #[test]
+    fn test_timestamps() {
+        use std::cell::Cell;
+        use crate::util::{FS_VERSION_0_4_0, INODE_SIZE};
+
+        let image = test_image_path("test_timestamps.dat");
+        let mut fs = new_test_fs("test_timestamps.dat");
+        let time = Rc::new(Cell::new(Timestamp::new(1_000, 5)));
+        fs.set_clock(TestClock(time.clone()));
+
+        let file = fs.create(ROOT_INODE, "file").unwrap();
+        let stat = fs.stat(file).unwrap();
+        assert_eq!([stat.atime, stat.mtime, stat.ctime, stat.crtime], [Timestamp::new(1_000, 5); 4]);
+        assert_eq!(fs.stat(ROOT_INODE).unwrap().mtime, Timestamp::new(1_000, 5));
+
+        time.set(Timestamp::new(2_000, 0));
+        fs.write(file, 0, b"hello").unwrap();
+        let stat = fs.stat(file).unwrap();
+        assert_eq!((stat.atime, stat.mtime, stat.ctime), (Timestamp::new(1_000, 5), time.get(), time.get()));
+
+        // relatime: the first read after a write moves atime, the next one within a day does not
+        let mut buffer = [0_u8; 5];
+        time.set(Timestamp::new(3_000, 0));
+        fs.read(file, 0, &mut buffer).unwrap();
+        assert_eq!(fs.stat(file).unwrap().atime, Timestamp::new(3_000, 0));
+        time.set(Timestamp::new(4_000, 0));
+        fs.read(file, 0, &mut buffer).unwrap();
+        assert_eq!(fs.stat(file).unwrap().atime, Timestamp::new(3_000, 0));
+        time.set(Timestamp::new(3_000 + 24 * 60 * 60, 0));
+        fs.read(file, 0, &mut buffer).unwrap();
+        assert_eq!(fs.stat(file).unwrap().atime, time.get());
+
+        fs.set_atime_mode(AtimeMode::Strict);
+        time.set(Timestamp::new(100_000, 1));
+        fs.read(file, 0, &mut buffer).unwrap();
+        assert_eq!(fs.stat(file).unwrap().atime, time.get());
+        fs.set_atime_mode(AtimeMode::NoAtime);
+        time.set(Timestamp::new(200_000, 0));
+        fs.write(file, 0, b"j").unwrap();
+        fs.read(file, 0, &mut buffer).unwrap();
+        assert_eq!(fs.stat(file).unwrap().atime, Timestamp::new(100_000, 1));
+
+        // truncating to the same size changes nothing, a real truncate is a modification
+        time.set(Timestamp::new(300_000, 0));
+        fs.truncate(file, 5).unwrap();
+        assert_eq!(fs.stat(file).unwrap().mtime, Timestamp::new(200_000, 0));
+        fs.truncate(file, 2).unwrap();
+        assert_eq!(fs.stat(file).unwrap().mtime, time.get());
+
+        // rename changes the inode and both directories
+        fs.mkdir(ROOT_INODE, "dir").unwrap();
+        time.set(Timestamp::new(400_000, 0));
+        let dir = fs.lookup(ROOT_INODE, "dir").unwrap();
+        fs.rename(ROOT_INODE, "file", dir, "moved").unwrap();
+        let stat = fs.stat(file).unwrap();
+        assert_eq!((stat.mtime, stat.ctime), (Timestamp::new(300_000, 0), time.get()));
+        assert_eq!(fs.stat(ROOT_INODE).unwrap().mtime, time.get());
+        assert_eq!(fs.stat(dir).unwrap().mtime, time.get());
+
+        time.set(Timestamp::new(500_000, 0));
+        fs.set_times(file, Some(SetTime::To(Timestamp::new(-5, 999_999_999))), None).unwrap();
+        let stat = fs.stat(file).unwrap();
+        assert_eq!((stat.atime, stat.mtime, stat.ctime, stat.crtime),
+                   (Timestamp::new(-5, 999_999_999), Timestamp::new(300_000, 0), time.get(), Timestamp::new(1_000, 5)));
+        drop(fs);
+
+        let mut fs = ffs::load(file_medium::load(image.as_str()).unwrap()).unwrap();
+        assert_eq!(fs.stat(file).unwrap(), stat);
+
+        // a 0.4.0 image has zeros where the times go: the upgrade stamps them, birth stays unknown
+        let slot = fs.layout().inode_table_start * BLOCK_SIZE as u64 + (INODE_SIZE as u64) * file as u64;
+        let times_start = 4 + 4 + LEGACY_NAME_SIZE + crate::util::INODE_BLOCK_POINTERS * 4 + 1 + 8;
+        relay_slots_as_0_6_0(&mut fs);
+        fs.medium.borrow_mut().write_all(slot + times_start as u64, &[0; 4 * 12]).unwrap();
+        fs.medium.borrow_mut().write_all(4, &FS_VERSION_0_4_0).unwrap();
+        let before = fs.now();
+        drop(fs);
+        let fs = ffs::upgrade(file_medium::load(image.as_str()).unwrap()).unwrap();
+        let stat = fs.stat(file).unwrap();
+        assert!(stat.mtime >= before && stat.atime == stat.mtime && stat.ctime == stat.mtime);
+        assert_eq!(stat.crtime, Timestamp::default());
+
+        // nanoseconds past a second are corruption
+        let atime_nanoseconds = 4 + 4 + 4 + crate::util::INODE_BLOCK_POINTERS * 4 + 1 + 8 + 8;
+        fs.medium.borrow_mut().write_all(slot + atime_nanoseconds as u64, &1_000_000_000_u32.to_le_bytes()).unwrap();
+        assert_eq!(fs.stat(file).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+
+    #[test]
+    fn test_permissions() {
+        use crate::util::FS_VERSION_0_5_0;
+
+        let image = test_image_path("test_permissions.dat");
+        let mut fs = new_test_fs("test_permissions.dat");
+        let alice = Credentials::new(1000, 100);
+        let bob = Credentials::new(1001, 100);
+        let mallory = Credentials::new(1002, 200);
+        fn denied<R>(result: Result<R, Error>) -> i32 {
+            crate::error::errno(&result.err().unwrap())
+        }
+
+        // root owns the root directory; new inodes belong to their creator, less the umask
+        assert_eq!((fs.stat(ROOT_INODE).unwrap().mode, fs.stat(ROOT_INODE).unwrap().uid), (0o755, 0));
+        fs.set_credentials(alice.clone());
+        assert_eq!(denied(fs.mkdir(ROOT_INODE, "home")), libc::EACCES);
+        fs.set_credentials(Credentials::root());
+        let home = fs.mkdir(ROOT_INODE, "home").unwrap();
+        fs.chown(home, Some(1000), Some(100)).unwrap();
+        fs.set_credentials(alice.clone());
+        let notes = fs.create(home, "notes").unwrap();
+        let stat = fs.stat(notes).unwrap();
+        assert_eq!((stat.mode, stat.uid, stat.gid), (0o644, 1000, 100));
+        fs.set_umask(0o077);
+        let secret = fs.create_with_mode(home, "secret", 0o600).unwrap();
+        let private = fs.mkdir(home, "private").unwrap();
+        assert_eq!(fs.stat(private).unwrap().mode, 0o700);
+
+        // the group and everybody else only get their own bits
+        fs.set_credentials(bob.clone());
+        fs.access(notes, MAY_READ).unwrap();
+        assert_eq!(denied(fs.open("/home/notes", MAY_READ | MAY_WRITE)), libc::EACCES);
+        assert_eq!(denied(fs.open("/home/secret", MAY_READ)), libc::EACCES);
+        // opening for reading checks only the read bit, and the handle stays read only
+        let mut handle = fs.open("/home/notes", MAY_READ).unwrap();
+        let mut contents = Vec::new();
+        handle.read_to_end(&mut contents).unwrap();
+        assert_eq!(handle.write(b"x").unwrap_err().kind(), ErrorKind::PermissionDenied);
+        assert_eq!(handle.set_len(0).unwrap_err().kind(), ErrorKind::PermissionDenied);
+        drop(handle);
+        assert_eq!(denied(fs.lookup_path("/home/private/x")), libc::EACCES);
+        assert_eq!(denied(fs.truncate(notes, 0)), libc::EACCES);
+        assert_eq!(denied(fs.chmod(notes, 0o666)), libc::EPERM);
+        assert_eq!(denied(fs.chown(notes, Some(1001), None)), libc::EPERM);
+        assert_eq!(denied(fs.unlink(home, "notes")), libc::EACCES);
+        fs.set_credentials(alice.clone());
+        fs.chmod(home, 0o711).unwrap();
+        fs.set_credentials(bob.clone());
+        assert_eq!(denied(fs.readdir(home)), libc::EACCES);
+        assert_eq!(fs.lookup(home, "notes").unwrap(), notes);
+
+        // whoever may write a file may set its times to now, only the owner to anything else
+        fs.set_credentials(alice.clone());
+        fs.chmod(notes, 0o664).unwrap();
+        fs.set_credentials(bob.clone());
+        fs.set_times(notes, Some(SetTime::Now), Some(SetTime::Now)).unwrap();
+        assert_eq!(denied(fs.set_times(notes, Some(SetTime::Now), Some(SetTime::To(Timestamp::new(0, 0))))), libc::EPERM);
+        fs.set_credentials(mallory.clone());
+        assert_eq!(denied(fs.set_times(notes, Some(SetTime::Now), Some(SetTime::Now))), libc::EACCES);
+
+        // the owner can give the file to one of their groups, but not to somebody else
+        fs.set_credentials(alice.clone().with_groups(vec![300]));
+        fs.chmod(secret, 0o4755).unwrap();
+        assert_eq!(denied(fs.chown(secret, Some(1001), None)), libc::EPERM);
+        assert_eq!(denied(fs.chown(secret, None, Some(200))), libc::EPERM);
+        fs.chown(secret, None, Some(300)).unwrap();
+        let stat = fs.stat(secret).unwrap();
+        assert_eq!((stat.mode, stat.gid), (0o755, 300));
+        // and cannot set the setgid bit for a group they are not in
+        fs.set_credentials(alice.clone());
+        fs.chmod(secret, 0o2755).unwrap();
+        assert_eq!(fs.stat(secret).unwrap().mode, 0o755);
+
+        // in a sticky directory only the owners of an entry or the directory may remove it
+        fs.set_credentials(Credentials::root());
+        fs.set_umask(0o022);
+        let tmp = fs.mkdir_with_mode(ROOT_INODE, "tmp", 0o1777).unwrap();
+        fs.set_credentials(bob.clone());
+        fs.create(tmp, "bobs").unwrap();
+        fs.set_credentials(mallory.clone());
+        assert_eq!(denied(fs.unlink(tmp, "bobs")), libc::EPERM);
+        assert_eq!(denied(fs.rename(tmp, "bobs", tmp, "mine")), libc::EPERM);
+        fs.create(tmp, "mallorys").unwrap();
+        fs.rename(tmp, "mallorys", tmp, "renamed").unwrap();
+        fs.set_credentials(bob.clone());
+        fs.unlink(tmp, "bobs").unwrap();
+
+        // files in a setgid directory take its group, directories also its setgid bit
+        fs.set_credentials(Credentials::root());
+        let shared = fs.mkdir_with_mode(ROOT_INODE, "shared", 0o2777).unwrap();
+        fs.chown(shared, None, Some(500)).unwrap();
+        fs.set_credentials(mallory.clone());
+        let file = fs.create(shared, "file").unwrap();
+        let dir = fs.mkdir(shared, "dir").unwrap();
+        assert_eq!((fs.stat(file).unwrap().gid, fs.stat(file).unwrap().mode), (500, 0o644));
+        assert_eq!((fs.stat(dir).unwrap().gid, fs.stat(dir).unwrap().mode), (500, 0o2755));
+        drop(fs);
+
+        // a 0.5.0 image had no owners: everything becomes root's and stays open
+        let mut fs = ffs::load(file_medium::load(image.as_str()).unwrap()).unwrap();
+        assert_eq!(fs.stat(file).unwrap().uid, 1002);
+        relay_slots_as_0_6_0(&mut fs);
+        fs.medium.borrow_mut().write_all(4, &FS_VERSION_0_5_0).unwrap();
+        drop(fs);
+        let fs = ffs::upgrade(file_medium::load(image.as_str()).unwrap()).unwrap();
+        let (file, dir) = (fs.stat(file).unwrap(), fs.stat(dir).unwrap());
+        assert_eq!((file.mode, file.uid, file.gid), (0o666, 0, 0));
+        assert_eq!((dir.mode, dir.uid, dir.gid), (0o777, 0, 0));
+    }
+
+
+    #[test]
+    fn test_hard_links() {
+        let image = test_image_path("test_hard_links.dat");
+        let mut fs = new_test_fs("test_hard_links.dat");
+        fs.create_dir("/a").unwrap();
+        fs.create_dir("/b").unwrap();
+        fs.create_file("/a/file").unwrap().write_all(b"shared").unwrap();
+        let file = fs.lookup_path("/a/file").unwrap();
+        let free_blocks = fs.statfs().free_blocks;
+
+        fs.link_path("/a/file", "/b/other").unwrap();
+        fs.link_path("/a/file", "/a/again").unwrap();
+        assert_eq!(fs.lookup_path("/b/other").unwrap(), file);
+        assert_eq!(fs.stat(file).unwrap().nlink, 3);
+        fs.open("/b/other", MAY_WRITE).unwrap().write_all(b"SHARED").unwrap();
+        let mut contents = String::new();
+        fs.open("/a/again", MAY_READ).unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "SHARED");
+
+        assert_eq!(fs.link_path("/a/file", "/b/other").unwrap_err().kind(), ErrorKind::AlreadyExists);
+        assert_eq!(fs.link_path("/a", "/b/dir").unwrap_err().kind(), ErrorKind::PermissionDenied);
+        assert_eq!(fs.stat(file).unwrap().nlink, 3);
+
+        // the inode and its blocks only go with the last name
+        fs.remove_file("/a/file").unwrap();
+        fs.rename_path("/a/again", "/b/moved").unwrap();
+        fs.remove_file("/b/other").unwrap();
+        assert_eq!(fs.stat(file).unwrap().nlink, 1);
+        assert_eq!(fs.statfs().free_blocks, free_blocks);
+        fs.remove_file("/b/moved").unwrap();
+        assert!(fs.statfs().free_blocks > free_blocks);
+        assert!(!fs.metadata.is_inode_allocated(file));
+        assert_eq!(fs.stat(file).unwrap_err().kind(), ErrorKind::NotFound);
+
+        // directories count their "." and the ".." of each subdirectory
+        let root = fs.stat(ROOT_INODE).unwrap().nlink;
+        assert_eq!(root, 4);
+        fs.create_dir("/a/sub").unwrap();
+        let a = fs.lookup_path("/a").unwrap();
+        let b = fs.lookup_path("/b").unwrap();
+        assert_eq!(fs.stat(a).unwrap().nlink, 3);
+        fs.rename_path("/a/sub", "/b/sub").unwrap();
+        assert_eq!((fs.stat(a).unwrap().nlink, fs.stat(b).unwrap().nlink), (2, 3));
+        fs.chdir("/b/sub").unwrap();
+        assert_eq!(fs.current_dir().unwrap(), "/b/sub");
+        fs.chdir("/").unwrap();
+        fs.remove_dir("/b/sub").unwrap();
+        assert_eq!(fs.stat(b).unwrap().nlink, 2);
+        fs.create_dir("/b/sub").unwrap();
+        drop(fs);
+
+        // 0.6.0 kept names in the inodes; the upgrade counts the links
+        let mut fs = ffs::load(file_medium::load(image.as_str()).unwrap()).unwrap();
+        relay_slots_as_0_6_0(&mut fs);
+        fs.medium.borrow_mut().write_all(4, &crate::util::FS_VERSION_0_6_0).unwrap();
+        drop(fs);
+        let fs = ffs::upgrade(file_medium::load(image.as_str()).unwrap()).unwrap();
+        assert_eq!(fs.stat(ROOT_INODE).unwrap().nlink, 4);
+        assert_eq!(fs.stat(b).unwrap().nlink, 3);
+        assert_eq!(fs.stat(fs.lookup_path("/b/sub").unwrap()).unwrap().nlink, 2);
+    }
+
+
+    #[test]
+    fn test_symlinks() {
+        use crate::error::FsError;
+
+        for extents in [false, true] {
+            let image = test_image_path(&format!("test_symlinks_{}.dat", extents));
+            let medium = file_medium::new(image.as_str()).unwrap();
+            let mut fs = FormatOptions::new(4 << 20).block_size(1024).extents(extents).format(medium).unwrap();
+            fs.create_dir("/data").unwrap();
+            fs.create_file("/data/file").unwrap().write_all(b"contents").unwrap();
+            let file = fs.lookup_path("/data/file").unwrap();
+            let free_blocks = fs.statfs().free_blocks;
+
+            // a short target lives in the inode, a long one in a block of its own
+            fs.symlink_path("data/file", "/short").unwrap();
+            let long_target = format!("/{}data/file", "./".repeat(40));
+            fs.symlink_path(long_target.as_str(), "/long").unwrap();
+            assert_eq!(fs.statfs().free_blocks, free_blocks - 1);
+            let short = fs.lstat_path("/short").unwrap();
+            assert_eq!((short.file_type, short.size, short.blocks, short.mode), (FileType::Symlink, 9, 0, 0o777));
+            assert_eq!(fs.lstat_path("/long").unwrap().blocks, 1);
+            drop(fs);
+
+            let mut fs = ffs::load(file_medium::load(image.as_str()).unwrap()).unwrap();
+            assert_eq!(fs.readlink_path("/short").unwrap(), "data/file");
+            assert_eq!(fs.readlink_path("/long").unwrap(), long_target.as_str());
+            assert_eq!(fs.lookup_path("/short").unwrap(), file);
+            assert_eq!(fs.stat_path("/short").unwrap().size, 8);
+            assert_eq!(fs.lookup_path("/long").unwrap(), file);
+
+            // links in the middle of a path are always followed, relative ones
+            // from the directory that holds them
+            fs.symlink_path("data", "/dir").unwrap();
+            fs.symlink_path("../data/file", "/data/up").unwrap();
+            let mut contents = String::new();
+            fs.open("/dir/up", MAY_READ).unwrap().read_to_string(&mut contents).unwrap();
+            assert_eq!(contents, "contents");
+            fs.chdir("/dir").unwrap();
+            assert_eq!(fs.current_dir().unwrap(), "/data");
+            fs.chdir("/").unwrap();
+            assert_eq!(fs.lookup_path_nofollow("/dir").unwrap(), fs.lookup(ROOT_INODE, "dir").unwrap());
+            assert_eq!(fs.lookup_path("/dir/").unwrap(), fs.lookup_path("/data").unwrap());
+
+            fs.symlink_path("/loop2", "/loop1").unwrap();
+            fs.symlink_path("/loop1", "/loop2").unwrap();
+            let err = fs.lookup_path("/loop1").unwrap_err();
+            assert_eq!(crate::error::errno(&err), libc::ELOOP);
+            assert!(matches!(FsError::from(err), FsError::SymlinkLoop));
+            assert!(fs.lookup_path_nofollow("/loop1").is_ok());
+
+            assert_eq!(fs.symlink_path("", "/empty").unwrap_err().kind(), ErrorKind::NotFound);
+            assert!(matches!(FsError::from(fs.symlink_path("y".repeat(1025).as_str(), "/huge").unwrap_err()),
+                             FsError::NameTooLong));
+            assert_eq!(fs.symlink_path("data", "/short").unwrap_err().kind(), ErrorKind::AlreadyExists);
+            assert_eq!(fs.readlink_path("/data").unwrap_err().kind(), ErrorKind::InvalidInput);
+
+            // removing a link leaves its target alone and frees what the link held
+            for link in ["/short", "/long", "/dir", "/data/up", "/loop1", "/loop2"] {
+                fs.remove_file(link).unwrap();
+            }
+            assert_eq!(fs.statfs().free_blocks, free_blocks);
+            assert_eq!(fs.stat(file).unwrap().nlink, 1);
+        }
+    }
+
+    #[test]
+    fn test_special_files() {
+        use crate::core::inode::{major, makedev, minor};
+
+        let image = test_image_path("test_special_files.dat");
+        let mut fs = ffs::new(file_medium::new(image.as_str()).unwrap(), 1 << 20, 1024, 4096).unwrap();
+        assert_eq!((major(makedev(8, 1)), minor(makedev(8, 1))), (8, 1));
+        assert_eq!(makedev(1, 3), 0x103);
+        assert_eq!((major(makedev(259, 70000)), minor(makedev(259, 70000))), (259, 70000));
+
+        fs.mknod_path("/fifo", FileType::Fifo, 0o666, 0).unwrap();
+        fs.mknod_path("/socket", FileType::Socket, 0o600, 0).unwrap();
+        fs.mknod_path("/null", FileType::CharDevice, 0o666, makedev(1, 3)).unwrap();
+        let disk = fs.mknod(ROOT_INODE, "disk", FileType::BlockDevice, 0o660, makedev(8, 0)).unwrap();
+        assert_eq!(fs.mknod_path("/dir", FileType::Directory, 0o755, 0).unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert_eq!(fs.mknod_path("/fifo", FileType::Fifo, 0o666, 0).unwrap_err().kind(), ErrorKind::AlreadyExists);
+
+        // only devices may carry a device number, and only root may make them
+        fs.chmod(ROOT_INODE, 0o777).unwrap();
+        fs.set_credentials(Credentials::new(1000, 1000));
+        let err = fs.mknod_path("/tty", FileType::CharDevice, 0o620, makedev(4, 1)).unwrap_err();
+        assert!(matches!(FsError::from(err), FsError::NotPermitted));
+        fs.mknod_path("/mine", FileType::Fifo, 0o644, makedev(4, 1)).unwrap();
+        fs.set_credentials(Credentials::root());
+        drop(fs);
+
+        let mut fs = ffs::load(file_medium::load(image.as_str()).unwrap()).unwrap();
+        let null = fs.lstat_path("/null").unwrap();
+        assert_eq!((null.file_type, null.mode, null.rdev, null.size, null.blocks), (FileType::CharDevice, 0o644, 0x103, 0, 0));
+        assert_eq!((fs.stat(disk).unwrap().file_type, fs.stat(disk).unwrap().rdev), (FileType::BlockDevice, makedev(8, 0)));
+        assert_eq!(fs.stat_path("/socket").unwrap().file_type, FileType::Socket);
+        let mine = fs.stat_path("/mine").unwrap();
+        assert_eq!((mine.file_type, mine.uid, mine.rdev), (FileType::Fifo, 1000, 0));
+
+        // there is no data behind them to read or write
+        assert_eq!(fs.open("/fifo", MAY_READ).err().unwrap().kind(), ErrorKind::InvalidInput);
+        assert_eq!(fs.write(disk, 0, b"boot").unwrap_err().kind(), ErrorKind::InvalidInput);
+        for path in ["/fifo", "/socket", "/null", "/disk", "/mine"] {
+            fs.remove_file(path).unwrap();
+        }
+        assert_eq!(fs.statfs().free_inodes, fs.statfs().total_inodes - 1);
+    }
+
+    #[test]
+    fn test_xattrs() {
+        use crate::core::inode::INLINE_XATTR_SIZE;
+        use crate::core::xattr::{XATTR_CREATE, XATTR_REPLACE};
+
+        let image = test_image_path("test_xattrs.dat");
+        let mut fs = ffs::new(file_medium::new(image.as_str()).unwrap(), 1 << 20, 1024, 4096).unwrap();
+        fs.create_file("/file").unwrap();
+        let file = fs.lookup_path("/file").unwrap();
+        let free_blocks = fs.statfs().free_blocks;
+
+        // a small set stays in the inode slot
+        fs.setxattr(file, "user.origin", b"https://example.com", 0).unwrap();
+        fs.setxattr(file, "security.selinux", b"system_u:object_r:etc_t:s0", 0).unwrap();
+        assert_eq!(fs.statfs().free_blocks, free_blocks);
+        assert_eq!(fs.getxattr(file, "user.origin").unwrap(), b"https://example.com");
+        let err = fs.getxattr(file, "user.missing").unwrap_err();
+        assert_eq!(crate::error::errno(&err), libc::ENODATA);
+
+        // one that does not fit moves the whole set into a block
+        let big = vec![0xa5_u8; INLINE_XATTR_SIZE];
+        fs.setxattr(file, "user.big", &big, XATTR_CREATE).unwrap();
+        assert_eq!(fs.statfs().free_blocks, free_blocks - 1);
+        assert_eq!(fs.stat(file).unwrap().blocks, 1);
+        assert_eq!(fs.setxattr(file, "user.big", b"", XATTR_CREATE).unwrap_err().kind(), ErrorKind::AlreadyExists);
+        assert!(matches!(FsError::from(fs.setxattr(file, "user.new", b"", XATTR_REPLACE).unwrap_err()), FsError::NoAttribute));
+        assert_eq!(fs.setxattr(file, "user.huge", &vec![0; 1024], 0).unwrap_err().kind(), ErrorKind::StorageFull);
+        fs.setxattr(file, "trusted.overlay", b"y", 0).unwrap();
+        drop(fs);
+
+        let mut fs = ffs::load(file_medium::load(image.as_str()).unwrap()).unwrap();
+        assert_eq!(fs.listxattr(file).unwrap(), ["security.selinux", "trusted.overlay", "user.big", "user.origin"]);
+        assert_eq!(fs.getxattr(file, "user.big").unwrap(), big);
+
+        // user.* follows the file's permissions, trusted.* and writing security.* need root
+        fs.set_credentials(Credentials::new(1000, 1000));
+        assert_eq!(fs.listxattr(file).unwrap(), ["security.selinux", "user.big", "user.origin"]);
+        assert_eq!(fs.getxattr(file, "user.origin").unwrap(), b"https://example.com");
+        assert!(matches!(FsError::from(fs.setxattr(file, "user.origin", b"", 0).unwrap_err()), FsError::PermissionDenied));
+        assert!(matches!(FsError::from(fs.getxattr(file, "trusted.overlay").unwrap_err()), FsError::NotPermitted));
+        assert!(matches!(FsError::from(fs.removexattr(file, "security.selinux").unwrap_err()), FsError::NotPermitted));
+        assert_eq!(fs.getxattr(file, "security.selinux").unwrap(), b"system_u:object_r:etc_t:s0");
+        fs.set_credentials(Credentials::root());
+        assert_eq!(fs.setxattr(file, "os2.name", b"", 0).unwrap_err().kind(), ErrorKind::Unsupported);
+        assert_eq!(fs.setxattr(file, "user.", b"", 0).unwrap_err().kind(), ErrorKind::InvalidInput);
+        fs.symlink_path("file", "/link").unwrap();
+        let link = fs.lookup_path_nofollow("/link").unwrap();
+        assert!(matches!(FsError::from(fs.setxattr(link, "user.origin", b"", 0).unwrap_err()), FsError::NotPermitted));
+
+        // shrinking the set brings it back inline and frees the block
+        fs.removexattr(file, "user.big").unwrap();
+        assert_eq!(fs.statfs().free_blocks, free_blocks);
+        fs.setxattr(file, "user.big", &big, 0).unwrap();
+        fs.remove_file("/file").unwrap();
+        assert_eq!(fs.statfs().free_blocks, free_blocks);
+    }
+
+    #[test]
+    fn test_acls() {
+        use crate::core::acl::{AclEntry, AclTag};
+
+        let mut fs = new_test_fs("test_acls.dat");
+        let shared = fs.mkdir_with_mode(ROOT_INODE, "shared", 0o750).unwrap();
+        let report = fs.create_with_mode(shared, "report", 0o640).unwrap();
+        let alice = Credentials::new(1000, 1000);
+        let bob = Credentials::new(1001, 1001);
+
+        // a named user entry lets bob in, limited by the mask
+        let acl = Acl::new(vec![AclEntry::new(AclTag::UserObj, 0, 7),
+                                AclEntry::new(AclTag::User, 1001, 7),
+                                AclEntry::new(AclTag::GroupObj, 0, 5),
+                                AclEntry::new(AclTag::Mask, 0, 5),
+                                AclEntry::new(AclTag::Other, 0, 0)]).unwrap();
+        fs.setxattr(shared, ACL_ACCESS, &acl.encode(), 0).unwrap();
+        assert_eq!(fs.stat(shared).unwrap().mode, 0o750);
+        assert_eq!(Acl::decode(&fs.getxattr(shared, ACL_ACCESS).unwrap()).unwrap(), acl);
+        fs.set_credentials(bob.clone());
+        fs.access(shared, MAY_READ | MAY_EXEC).unwrap();
+        assert!(matches!(FsError::from(fs.access(shared, MAY_WRITE).unwrap_err()), FsError::PermissionDenied));
+        fs.set_credentials(alice.clone());
+        assert!(matches!(FsError::from(fs.access(shared, MAY_EXEC).unwrap_err()), FsError::PermissionDenied));
+        assert!(matches!(FsError::from(fs.setxattr(shared, ACL_ACCESS, &acl.encode(), 0).unwrap_err()),
+                         FsError::NotPermitted));
+
+        // chmod moves the mask, not the group entry
+        fs.set_credentials(Credentials::root());
+        fs.chmod(shared, 0o770).unwrap();
+        assert_eq!(Acl::decode(&fs.getxattr(shared, ACL_ACCESS).unwrap()).unwrap().mask(), Some(7));
+        fs.set_credentials(bob.clone());
+        fs.access(shared, MAY_WRITE).unwrap();
+        fs.set_credentials(Credentials::root());
+
+        // an ACL the mode bits can express is not kept
+        fs.setxattr(report, ACL_ACCESS, &Acl::from_mode(0o604).encode(), 0).unwrap();
+        assert_eq!(fs.stat(report).unwrap().mode, 0o604);
+        assert!(matches!(FsError::from(fs.getxattr(report, ACL_ACCESS).unwrap_err()), FsError::NoAttribute));
+        assert_eq!(fs.setxattr(report, ACL_ACCESS, b"\x02\0\0\0\x01\0", 0).unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert!(matches!(FsError::from(fs.setxattr(report, ACL_DEFAULT, &acl.encode(), 0).unwrap_err()),
+                         FsError::PermissionDenied));
+
+        // new entries inherit the default ACL, restricted by their mode and regardless of the umask
+        let default = Acl::new(vec![AclEntry::new(AclTag::UserObj, 0, 7),
+                                    AclEntry::new(AclTag::Group, 1000, 6),
+                                    AclEntry::new(AclTag::GroupObj, 0, 5),
+                                    AclEntry::new(AclTag::Mask, 0, 7),
+                                    AclEntry::new(AclTag::Other, 0, 0)]).unwrap();
+        fs.setxattr(shared, ACL_DEFAULT, &default.encode(), 0).unwrap();
+        let child = fs.create(shared, "child").unwrap();
+        assert_eq!(fs.stat(child).unwrap().mode, 0o660);
+        let inherited = Acl::decode(&fs.getxattr(child, ACL_ACCESS).unwrap()).unwrap();
+        assert_eq!(inherited.mask(), Some(6));
+        assert!(matches!(FsError::from(fs.getxattr(child, ACL_DEFAULT).unwrap_err()), FsError::NoAttribute));
+        fs.set_credentials(alice);
+        fs.access(child, MAY_READ | MAY_WRITE).unwrap();
+        fs.set_credentials(Credentials::root());
+
+        let subdir = fs.mkdir(shared, "subdir").unwrap();
+        assert_eq!(fs.stat(subdir).unwrap().mode, 0o770);
+        assert_eq!(fs.getxattr(subdir, ACL_DEFAULT).unwrap(), default.encode());
+        let fifo = fs.mknod(subdir, "fifo", FileType::Fifo, 0o600, 0).unwrap();
+        assert_eq!(fs.stat(fifo).unwrap().mode, 0o600);
+        assert_eq!(Acl::decode(&fs.getxattr(fifo, ACL_ACCESS).unwrap()).unwrap().mask(), Some(0));
+
+        // removing the default ACL brings the umask back
+        fs.removexattr(shared, ACL_DEFAULT).unwrap();
+        let plain = fs.create(shared, "plain").unwrap();
+        assert_eq!(fs.stat(plain).unwrap().mode, 0o644);
+        assert_eq!(fs.listxattr(plain).unwrap(), Vec::<String>::new());
     }
 }
@@ -1,67 +1,186 @@
-use std::{cell::RefCell, io::Error, rc::Rc};
+use std::{cell::RefCell, io::{Error, ErrorKind}, rc::Rc};
 
-use crate::{core::{block_bitmap::BlockBitmap, inode::Inode, inode_bitmap::InodeBitmap, super_block::SuperBlock}, medium::types::byte_compatible};
+use crate::{error::FsError, migration, util::get_latest_version, entity::dir_index, core::{block_bitmap::BlockBitmap, inode::Inode, inode_bitmap::InodeBitmap, super_block::SuperBlock}, medium::types::byte_compatible, time::{AtimeMode, Clock, SystemClock, Timestamp}};
 
 pub struct fs_metadata<T: byte_compatible> {
     super_block: SuperBlock,
     inode_bitmap: InodeBitmap,
     block_bitmap: BlockBitmap,
-    medium: Rc<RefCell<T>>
+    medium: Rc<RefCell<T>>,
+    // not on disk: where timestamps come from and how reads treat atime
+    clock: Box<dyn Clock>,
+    atime_mode: AtimeMode
 }
 
 impl <T: byte_compatible> fs_metadata<T> {
     
-    pub fn create_new(medium: Rc<RefCell<T>>, fs_size: u32, block_size: u32, bytes_per_inode: u32) -> Result<Self, Error>
+    pub fn create_new(medium: Rc<RefCell<T>>, fs_size: u64, block_size: u32, bytes_per_inode: u32, label: &str, features: u32) -> Result<Self, Error>
     {   
-        let super_block = SuperBlock::create_new(fs_size, block_size, bytes_per_inode);
-        super_block.persist(medium.borrow_mut())?;
+        let mut super_block = SuperBlock::create_new(fs_size, block_size, bytes_per_inode, label, features)?;
+        super_block.set_hash_seed(dir_index::random_seed());
+
+        Self::grow(&medium, &super_block)?;
         
         let inode_bitmap = InodeBitmap::new(super_block.get_total_inodes());
         inode_bitmap.persist(medium.borrow_mut(), &super_block)?;
         
         let mut block_bitmap = BlockBitmap::new(super_block.get_total_blocks());
 
-        // set the bitmap in the bitmap blocks for the above structures
-        block_bitmap.set(1);
-        (0..super_block.get_inode_bitmap_block_count())
-            .for_each(|b|
-                block_bitmap.set(b + 1));
-        (0..super_block.get_block_bitmap_block_count())
+        // mark the superblock, both bitmaps and the inode table as used
+        (0..super_block.get_first_data_block())
             .for_each(|b|
-                block_bitmap.set(1 + super_block.get_inode_bitmap_block_count() + b));
+                block_bitmap.set(b));
+        super_block.set_free_blocks(block_bitmap.count_free());
 
         block_bitmap.persist(medium.borrow_mut(), &super_block)?;
+        super_block.persist(medium.borrow_mut())?;
         
         Ok(Self {
             super_block,
             inode_bitmap,
             block_bitmap,
-            medium
+            medium,
+            clock: Box::new(SystemClock),
+            atime_mode: AtimeMode::default()
         })
     }
 
+    /*
+        Loads the metadata of a current image. Older ones are refused rather
+        than rewritten behind the caller's back; upgrade converts them.
+    */
     pub fn fetch(medium: Rc<RefCell<T>>) -> Result<Self, Error>
+    {
+        let metadata = Self::read(medium)?;
+        let version = metadata.super_block_get_version();
+        if version != get_latest_version() {
+            return Err(FsError::NeedsUpgrade(version).into());
+        }
+        Ok(metadata)
+    }
+
+    /*
+        Loads the metadata of an image of any supported version and brings
+        it up to the current one, see migration.
+    */
+    pub fn upgrade(medium: Rc<RefCell<T>>) -> Result<Self, Error>
+    {
+        let mut metadata = Self::read(medium)?;
+        migration::upgrade(&mut metadata)?;
+        Ok(metadata)
+    }
+
+    fn read(medium: Rc<RefCell<T>>) -> Result<Self, Error>
     {
         let super_block = SuperBlock::deserialize(medium.borrow_mut())?;
         let inode_bitmap = InodeBitmap::fetch(medium.borrow_mut(), &super_block)?;
         let block_bitmap = BlockBitmap::fetch(medium.borrow_mut(), &super_block)?;
-        
+
         Ok(Self {
             super_block,
             inode_bitmap,
             block_bitmap,
-            medium
+            medium,
+            clock: Box::new(SystemClock),
+            atime_mode: AtimeMode::default()
         })
     }
 
-    fn persist_super_block(&mut self) -> Result<(), std::io::Error> {
+    // grows the medium to the full size, loading refuses a filesystem larger than its medium
+    fn grow(medium: &Rc<RefCell<T>>, super_block: &SuperBlock) -> Result<(), Error> {
+        let total_bytes = super_block.get_total_blocks() as u64 * super_block.get_block_size() as u64;
+        if medium.borrow().size()? < total_bytes {
+            medium.borrow_mut().write_all(total_bytes - 1, &[0])?;
+        }
+        Ok(())
+    }
+
+    pub fn grow_medium(&mut self) -> Result<(), Error> {
+        Self::grow(&self.medium, &self.super_block)
+    }
+
+    /*
+        Marks the superblock, both bitmaps and the inode table used and
+        counts the free blocks and inodes again from the bitmaps, for images
+        whose bitmaps and counts cannot be trusted. Persists the superblock.
+    */
+    pub fn recount_free(&mut self) -> Result<(), Error> {
+        (0..self.super_block.get_first_data_block()).for_each(|b| self.block_bitmap.set(b));
+        self.super_block.set_free_blocks(self.block_bitmap.count_free());
+        let total_inodes = self.super_block.get_total_inodes();
+        self.super_block.set_free_inodes((0..total_inodes).filter(|&i| !self.inode_bitmap.get(i)).count());
+        self.persist_block_bitmap()?;
+        self.persist_super_block()
+    }
+
+    pub fn persist_super_block(&mut self) -> Result<(), std::io::Error> {
         self.super_block.persist(self.medium.borrow_mut())
     }
 
+    pub fn now(&self) -> Timestamp {
+        self.clock.now()
+    }
+
+    pub fn set_clock(&mut self, clock: Box<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    pub fn atime_mode(&self) -> AtimeMode {
+        self.atime_mode
+    }
+
+    pub fn set_atime_mode(&mut self, mode: AtimeMode) {
+        self.atime_mode = mode;
+    }
+
+    pub fn super_block_get_version(&self) -> [u8; 3] {
+        self.super_block.get_version()
+    }
+
+    pub fn set_version(&mut self, version: [u8; 3]) {
+        self.super_block.set_version(version);
+    }
+
+    pub fn super_block_get_hash_seed(&self) -> [u32; 4] {
+        self.super_block.get_hash_seed()
+    }
+
+    pub fn set_hash_seed(&mut self, hash_seed: [u32; 4]) {
+        self.super_block.set_hash_seed(hash_seed);
+    }
+
+    pub fn super_block_has_feature(&self, feature: u32) -> bool {
+        self.super_block.has_feature(feature)
+    }
+
     pub fn super_block_get_total_blocks(&self) -> usize {
         self.super_block.get_total_blocks()
     }
 
+    pub fn super_block_get_total_inodes(&self) -> usize {
+        self.super_block.get_total_inodes()
+    }
+
+    pub fn super_block_get_inode_bitmap_block_count(&self) -> usize {
+        self.super_block.get_inode_bitmap_block_count()
+    }
+
+    pub fn super_block_get_block_bitmap_block_count(&self) -> usize {
+        self.super_block.get_block_bitmap_block_count()
+    }
+
+    pub fn super_block_get_total_inode_blocks(&self) -> usize {
+        self.super_block.get_total_inode_blocks()
+    }
+
+    pub fn super_block_get_first_data_block(&self) -> usize {
+        self.super_block.get_first_data_block()
+    }
+
+    pub fn super_block_get_label(&self) -> String {
+        self.super_block.get_label()
+    }
+
     pub fn super_block_get_inode_start_block(&self) -> usize {
         self.super_block.get_inode_start_block()
     }
@@ -78,16 +197,146 @@ impl <T: byte_compatible> fs_metadata<T> {
         inode.persist(self.medium.borrow_mut(), &self.super_block)
     }
 
-    pub fn set_inode_in_bitmap(&mut self, inode: u16) {
-        self.inode_bitmap.set(inode as usize);
+    pub fn load_inode(&self, inode_number: u32) -> Result<Inode, std::io::Error> {
+        Inode::load(self.medium.borrow_mut(), inode_number, self)
+    }
+
+    /*
+        Reads `buffer.len()` bytes starting `offset` bytes into data block `block`.
+    */
+    pub fn read_block_data(&self, block: u32, offset: usize, buffer: &mut [u8]) -> Result<(), Error> {
+        assert!(offset + buffer.len() <= self.super_block.get_block_size());
+        let block_offset = block as u64 * self.super_block.get_block_size() as u64 + offset as u64;
+        self.medium.borrow_mut().read_all(block_offset, buffer)
+    }
+
+    /*
+        Writes `buffer` starting `offset` bytes into data block `block`.
+    */
+    pub fn write_block_data(&mut self, block: u32, offset: usize, buffer: &[u8]) -> Result<(), Error> {
+        assert!(offset + buffer.len() <= self.super_block.get_block_size());
+        let block_offset = block as u64 * self.super_block.get_block_size() as u64 + offset as u64;
+        self.medium.borrow_mut().write_all(block_offset, buffer)
+    }
+
+    pub fn set_inode_in_bitmap(&mut self, inode: u32) {
+        if !self.inode_bitmap.get(inode as usize) {
+            self.inode_bitmap.set(inode as usize);
+            self.super_block.set_free_inodes(self.super_block.get_free_inodes() - 1);
+        }
+    }
+
+    /*
+        Returns an inode slot to the free pool. The inode's data blocks must
+        already have been released.
+    */
+    pub fn free_inode(&mut self, inode: u32) -> Result<(), Error> {
+        if inode as usize >= self.super_block.get_total_inodes() || !self.inode_bitmap.get(inode as usize) {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("Inode {} is not allocated", inode)));
+        }
+        self.inode_bitmap.clear(inode as usize);
+        self.super_block.set_free_inodes(self.super_block.get_free_inodes() + 1);
+        self.persist_inode_bitmap()?;
+        self.persist_super_block()
     }
 
     fn persist_block_bitmap(&mut self) -> Result<(), std::io::Error> {
         self.block_bitmap.persist(self.medium.borrow_mut(), &self.super_block)
     }
 
-    pub fn is_inode_bitmap_full(&self) -> bool {
-        self.inode_bitmap.is_full()
+    /*
+        Allocates a single data block, marks it used in the block bitmap and
+        persists both the bitmap and the superblock's free block counter.
+    */
+    pub fn allocate_block(&mut self) -> Result<u32, Error> {
+        let blocks = self.allocate_blocks(1)?;
+        Ok(blocks[0])
+    }
+
+    /*
+        Allocates `count` data blocks, preferring a single contiguous run and
+        falling back to the first free blocks found. Either all blocks are
+        allocated or none are.
+    */
+    pub fn allocate_blocks(&mut self, count: usize) -> Result<Vec<u32>, Error> {
+        if count == 0 {
+            return Ok(Vec::new());
+        }
+        if count > self.super_block.get_free_blocks() || count > self.block_bitmap.count_free() {
+            return Err(FsError::NoSpace.into());
+        }
+
+        let mut blocks: Vec<usize> = Vec::with_capacity(count);
+        if let Some(start) = self.block_bitmap.find_free_run(count) {
+            blocks.extend(start..start + count);
+            blocks.iter().for_each(|&b| self.block_bitmap.set(b));
+        } else {
+            let mut cursor = 0;
+            for _ in 0..count {
+                // count_free() was checked above, so a free block always exists here
+                let block = self.block_bitmap.find_free_from(cursor).unwrap();
+                self.block_bitmap.set(block);
+                blocks.push(block);
+                cursor = block + 1;
+            }
+        }
+
+        self.super_block.set_free_blocks(self.super_block.get_free_blocks() - count);
+        self.persist_block_bitmap()?;
+        self.persist_super_block()?;
+
+        Ok(blocks.into_iter().map(|b| b as u32).collect())
+    }
+
+    #[allow(dead_code)]
+    pub fn free_block(&mut self, block: u32) -> Result<(), Error> {
+        self.free_blocks(&[block])
+    }
+
+    /*
+        Returns data blocks to the free pool. Freeing a metadata block or a
+        block that is not allocated is refused without changing anything.
+        Blocks are cleared as they are checked, so a block listed twice is
+        found already free the second time; on a refusal the blocks cleared
+        so far are marked used again.
+    */
+    pub fn free_blocks(&mut self, blocks: &[u32]) -> Result<(), Error> {
+        for (i, &block) in blocks.iter().enumerate() {
+            let block = block as usize;
+            let refusal = if block < self.super_block.get_first_data_block() || block >= self.super_block.get_total_blocks() {
+                Some(format!("Block {} is not a data block", block))
+            } else if !self.block_bitmap.get(block) {
+                Some(format!("Block {} is already free", block))
+            } else {
+                None
+            };
+            if let Some(refusal) = refusal {
+                blocks[..i].iter().for_each(|&b| self.block_bitmap.set(b as usize));
+                return Err(Error::new(ErrorKind::InvalidInput, refusal));
+            }
+            self.block_bitmap.clear(block);
+        }
+
+        self.super_block.set_free_blocks(self.super_block.get_free_blocks() + blocks.len());
+        self.persist_block_bitmap()?;
+        self.persist_super_block()
+    }
+
+    pub fn super_block_get_free_inodes(&self) -> usize {
+        self.super_block.get_free_inodes()
+    }
+
+    pub fn super_block_get_free_blocks(&self) -> usize {
+        self.super_block.get_free_blocks()
+    }
+
+    #[allow(dead_code)]
+    pub fn is_block_allocated(&self, block: u32) -> bool {
+        self.block_bitmap.get(block as usize)
+    }
+
+    pub fn is_inode_allocated(&self, inode: u32) -> bool {
+        self.inode_bitmap.get(inode as usize)
     }
 
     pub fn inode_find_first_free(&self) -> Option<usize> {
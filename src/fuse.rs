@@ -0,0 +1,563 @@
+/*
+    FUSE front end: exposes an ffs through the kernel's FUSE interface.
+
+    FUSE reserves inode number 1 for the root, while filefs numbers its
+    inodes from 0, so every inode number crossing the boundary is shifted
+    by one.
+
+    Requests run as the uid and gid of the calling process, so permissions
+    are enforced by filefs itself and a mount without default_permissions
+    still behaves like a local filesystem. FUSE does not pass on the
+    caller's supplementary groups; only its primary group counts.
+
+    POSIX ACLs reach filefs as the system.posix_acl_* xattrs, without the
+    kernel's FUSE_POSIX_ACL support, so the kernel applies the umask
+    before a default ACL gets to.
+*/
+
+use std::ffi::{OsStr, OsString};
+use std::io::Error;
+use std::os::raw::c_int;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use fuser::{FileAttr, MountOption, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty,
+            ReplyEntry, ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr, Request, TimeOrNow, FUSE_ROOT_ID};
+
+use crate::access::{Credentials, MAY_READ, MAY_WRITE};
+
+use crate::core::inode::FileType;
+use crate::error::errno;
+use crate::fs::{ffs, FileStat};
+use crate::medium::file::file_medium;
+use crate::medium::types::byte_compatible;
+use crate::time::{AtimeMode, SetTime, Timestamp};
+
+const TTL: Duration = Duration::from_secs(1);
+
+pub struct FuseFilesystem<T: byte_compatible> {
+    fs: ffs<T>,
+}
+
+/*
+    Loads the image at `image` and serves it at `mountpoint` until the
+    filesystem is unmounted. `atime` decides when reads update access times;
+    the kernel leaves that to the filesystem.
+*/
+pub fn mount(image: &str, mountpoint: &str, options: &[MountOption], atime: AtimeMode) -> Result<(), Error> {
+    let mut fs = ffs::load(file_medium::load(image)?)?;
+    fs.set_atime_mode(atime);
+
+    let mut mount_options = vec![MountOption::FSName(image.to_string()),
+                                 MountOption::Subtype("filefs".to_string())];
+    mount_options.extend_from_slice(options);
+
+    fuser::mount2(FuseFilesystem::new(fs), mountpoint, &mount_options)
+}
+
+fn to_fuse_ino(inode_number: u32) -> u64 {
+    inode_number as u64 + FUSE_ROOT_ID
+}
+
+/*
+    Maps a kernel inode number back onto a filefs one, rejecting numbers
+    that cannot belong to this filesystem.
+*/
+fn from_fuse_ino(ino: u64) -> Result<u32, c_int> {
+    ino.checked_sub(FUSE_ROOT_ID)
+        .and_then(|n| u32::try_from(n).ok())
+        .ok_or(libc::ENOENT)
+}
+
+fn caller(req: &Request<'_>) -> Credentials {
+    Credentials::new(req.uid(), req.gid())
+}
+
+/*
+    What open(2) flags ask for, as a MAY_* mask.
+*/
+fn open_mask(flags: i32) -> u32 {
+    match flags & libc::O_ACCMODE {
+        libc::O_RDONLY => MAY_READ,
+        libc::O_WRONLY => MAY_WRITE,
+        _ => MAY_READ | MAY_WRITE,
+    }
+}
+
+/*
+    getxattr and listxattr are called with a size of 0 to ask how large a
+    buffer they need, and fail with ERANGE when the buffer is too small.
+*/
+fn reply_xattr(reply: ReplyXattr, data: &[u8], size: u32) {
+    if size == 0 {
+        reply.size(data.len() as u32);
+    } else if data.len() > size as usize {
+        reply.error(libc::ERANGE);
+    } else {
+        reply.data(data);
+    }
+}
+
+fn name_str(name: &OsStr) -> Result<&str, c_int> {
+    name.to_str().ok_or(libc::EINVAL)
+}
+
+fn fuse_file_type(file_type: FileType) -> fuser::FileType {
+    match file_type {
+        FileType::File => fuser::FileType::RegularFile,
+        FileType::Directory => fuser::FileType::Directory,
+        FileType::Symlink => fuser::FileType::Symlink,
+        FileType::Fifo => fuser::FileType::NamedPipe,
+        FileType::CharDevice => fuser::FileType::CharDevice,
+        FileType::BlockDevice => fuser::FileType::BlockDevice,
+        FileType::Socket => fuser::FileType::Socket,
+    }
+}
+
+/*
+    The file type in the S_IFMT bits of a mode mknod(2) was called with.
+*/
+fn mknod_file_type(mode: u32) -> Result<FileType, c_int> {
+    match mode & libc::S_IFMT {
+        libc::S_IFREG | 0 => Ok(FileType::File),
+        libc::S_IFIFO => Ok(FileType::Fifo),
+        libc::S_IFCHR => Ok(FileType::CharDevice),
+        libc::S_IFBLK => Ok(FileType::BlockDevice),
+        libc::S_IFSOCK => Ok(FileType::Socket),
+        _ => Err(libc::EINVAL),
+    }
+}
+
+impl<T: byte_compatible> FuseFilesystem<T> {
+    pub fn new(fs: ffs<T>) -> Self {
+        Self { fs }
+    }
+
+    fn attr(&self, stat: &FileStat) -> FileAttr {
+        FileAttr {
+            ino: to_fuse_ino(stat.inode_number),
+            size: stat.size,
+            blocks: stat.blocks * (stat.block_size as u64 / 512),
+            atime: stat.atime.into(),
+            mtime: stat.mtime.into(),
+            ctime: stat.ctime.into(),
+            crtime: stat.crtime.into(),
+            kind: fuse_file_type(stat.file_type),
+            perm: stat.mode,
+            nlink: stat.nlink,
+            uid: stat.uid,
+            gid: stat.gid,
+            rdev: stat.rdev,
+            blksize: stat.block_size,
+            flags: 0,
+        }
+    }
+
+    fn attr_of(&self, inode_number: u32) -> Result<FileAttr, c_int> {
+        self.fs.stat(inode_number)
+            .map(|stat| self.attr(&stat))
+            .map_err(|e| errno(&e))
+    }
+
+    fn do_lookup(&mut self, parent: u64, name: &OsStr) -> Result<FileAttr, c_int> {
+        let inode_number = self.fs.lookup(from_fuse_ino(parent)?, name)
+            .map_err(|e| errno(&e))?;
+        self.attr_of(inode_number)
+    }
+
+    fn do_setattr(&mut self, ino: u64, mode: Option<u32>, (uid, gid): (Option<u32>, Option<u32>), size: Option<u64>,
+                  atime: Option<TimeOrNow>, mtime: Option<TimeOrNow>) -> Result<FileAttr, c_int>
+    {
+        let inode_number = from_fuse_ino(ino)?;
+        if uid.is_some() || gid.is_some() {
+            self.fs.chown(inode_number, uid, gid).map_err(|e| errno(&e))?;
+        }
+        if let Some(mode) = mode {
+            self.fs.chmod(inode_number, mode as u16).map_err(|e| errno(&e))?;
+        }
+        if let Some(size) = size {
+            self.fs.truncate(inode_number, size).map_err(|e| errno(&e))?;
+        }
+        if atime.is_some() || mtime.is_some() {
+            let time = |time: TimeOrNow| match time {
+                TimeOrNow::SpecificTime(time) => SetTime::To(Timestamp::from(time)),
+                TimeOrNow::Now => SetTime::Now,
+            };
+            let (atime, mtime) = (atime.map(time), mtime.map(time));
+            self.fs.set_times(inode_number, atime, mtime).map_err(|e| errno(&e))?;
+        }
+        self.attr_of(inode_number)
+    }
+
+    fn do_readdir(&mut self, ino: u64, offset: i64, reply: &mut ReplyDirectory) -> Result<(), c_int> {
+        let inode_number = from_fuse_ino(ino)?;
+        // "." and ".." take offsets 1 and 2; past them an offset is DOTS plus a position in the directory
+        const DOTS: i64 = 2;
+        if offset < DOTS {
+            let parent = self.fs.lookup(inode_number, "..").map_err(|e| errno(&e))?;
+            let dots = [(".", inode_number), ("..", parent)];
+            for (i, (name, dot)) in dots.into_iter().enumerate().skip(offset as usize) {
+                // the offset handed back is the position of the next entry
+                if reply.add(to_fuse_ino(dot), i as i64 + 1, fuse_file_type(FileType::Directory), name) {
+                    return Ok(());
+                }
+            }
+        }
+
+        let mut entries = self.fs.readdir_from(inode_number, (offset.max(DOTS) - DOTS) as u64).map_err(|e| errno(&e))?;
+        while let Some(entry) = entries.next() {
+            let entry = entry.map_err(|e| errno(&e))?;
+            let next = DOTS + entries.position() as i64;
+            if reply.add(to_fuse_ino(entry.inode_number), next, fuse_file_type(entry.file_type), entry.name) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn do_mkdir(&mut self, parent: u64, name: &OsStr, mode: u16) -> Result<FileAttr, c_int> {
+        let inode_number = self.fs.mkdir_with_mode(from_fuse_ino(parent)?, name, mode)
+            .map_err(|e| errno(&e))?;
+        self.attr_of(inode_number)
+    }
+
+    fn do_create(&mut self, parent: u64, name: &OsStr, mode: u16) -> Result<FileAttr, c_int> {
+        let inode_number = self.fs.create_with_mode(from_fuse_ino(parent)?, name, mode)
+            .map_err(|e| errno(&e))?;
+        self.attr_of(inode_number)
+    }
+
+    fn do_mknod(&mut self, parent: u64, name: &OsStr, mode: u32, rdev: u32) -> Result<FileAttr, c_int> {
+        let file_type = mknod_file_type(mode)?;
+        let inode_number = self.fs.mknod(from_fuse_ino(parent)?, name, file_type, mode as u16, rdev)
+            .map_err(|e| errno(&e))?;
+        self.attr_of(inode_number)
+    }
+
+    fn do_setxattr(&mut self, ino: u64, name: &OsStr, value: &[u8], flags: i32) -> Result<(), c_int> {
+        self.fs.setxattr(from_fuse_ino(ino)?, name_str(name)?, value, flags as u32).map_err(|e| errno(&e))
+    }
+
+    fn do_getxattr(&mut self, ino: u64, name: &OsStr) -> Result<Vec<u8>, c_int> {
+        self.fs.getxattr(from_fuse_ino(ino)?, name_str(name)?).map_err(|e| errno(&e))
+    }
+
+    /*
+        The names as listxattr(2) returns them, each followed by a NUL.
+    */
+    fn do_listxattr(&mut self, ino: u64) -> Result<Vec<u8>, c_int> {
+        let names = self.fs.listxattr(from_fuse_ino(ino)?).map_err(|e| errno(&e))?;
+        Ok(names.iter().flat_map(|name| name.bytes().chain([0])).collect())
+    }
+
+    fn do_removexattr(&mut self, ino: u64, name: &OsStr) -> Result<(), c_int> {
+        self.fs.removexattr(from_fuse_ino(ino)?, name_str(name)?).map_err(|e| errno(&e))
+    }
+
+    fn do_symlink(&mut self, parent: u64, name: &OsStr, target: &Path) -> Result<FileAttr, c_int> {
+        let inode_number = self.fs.symlink(from_fuse_ino(parent)?, name, target)
+            .map_err(|e| errno(&e))?;
+        self.attr_of(inode_number)
+    }
+
+    fn do_readlink(&mut self, ino: u64) -> Result<OsString, c_int> {
+        self.fs.readlink(from_fuse_ino(ino)?).map_err(|e| errno(&e))
+    }
+
+    fn do_read(&mut self, ino: u64, offset: i64, size: u32) -> Result<Vec<u8>, c_int> {
+        let offset = u64::try_from(offset).map_err(|_| libc::EINVAL)?;
+        let mut buffer = vec![0_u8; size as usize];
+        let read = self.fs.read(from_fuse_ino(ino)?, offset, &mut buffer)
+            .map_err(|e| errno(&e))?;
+        buffer.truncate(read);
+        Ok(buffer)
+    }
+
+    fn do_write(&mut self, ino: u64, offset: i64, data: &[u8]) -> Result<u32, c_int> {
+        let offset = u64::try_from(offset).map_err(|_| libc::EINVAL)?;
+        let written = self.fs.write(from_fuse_ino(ino)?, offset, data)
+            .map_err(|e| errno(&e))?;
+        Ok(written as u32)
+    }
+
+    fn do_link(&mut self, ino: u64, newparent: u64, newname: &OsStr) -> Result<FileAttr, c_int> {
+        let inode_number = from_fuse_ino(ino)?;
+        self.fs.link(inode_number, from_fuse_ino(newparent)?, newname).map_err(|e| errno(&e))?;
+        self.attr_of(inode_number)
+    }
+
+    fn do_unlink(&mut self, parent: u64, name: &OsStr) -> Result<(), c_int> {
+        self.fs.unlink(from_fuse_ino(parent)?, name).map_err(|e| errno(&e))
+    }
+
+    fn do_rmdir(&mut self, parent: u64, name: &OsStr) -> Result<(), c_int> {
+        self.fs.rmdir(from_fuse_ino(parent)?, name).map_err(|e| errno(&e))
+    }
+
+    fn do_rename(&mut self, parent: u64, name: &OsStr, newparent: u64, newname: &OsStr, flags: u32) -> Result<(), c_int> {
+        // the flags are renameat2's, which rename_with_flags takes as they are
+        self.fs.rename_with_flags(from_fuse_ino(parent)?, name, from_fuse_ino(newparent)?, newname, flags)
+            .map_err(|e| errno(&e))
+    }
+}
+
+impl<T: byte_compatible> fuser::Filesystem for FuseFilesystem<T> {
+    fn lookup(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        self.fs.set_credentials(caller(req));
+        match self.do_lookup(parent, name) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
+        match from_fuse_ino(ino).and_then(|n| self.attr_of(n)) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn setattr(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        _fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        self.fs.set_credentials(caller(req));
+        match self.do_setattr(ino, mode, (uid, gid), size, atime, mtime) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn readdir(&mut self, req: &Request<'_>, ino: u64, _fh: u64, offset: i64, mut reply: ReplyDirectory) {
+        self.fs.set_credentials(caller(req));
+        match self.do_readdir(ino, offset, &mut reply) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn mkdir(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, mode: u32, umask: u32, reply: ReplyEntry) {
+        self.fs.set_credentials(caller(req));
+        match self.do_mkdir(parent, name, (mode & !umask) as u16) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn mknod(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        rdev: u32,
+        reply: ReplyEntry,
+    ) {
+        self.fs.set_credentials(caller(req));
+        match self.do_mknod(parent, name, mode & !umask, rdev) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn create(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        _flags: i32,
+        reply: ReplyCreate,
+    ) {
+        self.fs.set_credentials(caller(req));
+        match self.do_create(parent, name, (mode & !umask) as u16) {
+            Ok(attr) => reply.created(&TTL, &attr, 0, 0, 0),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        match self.do_read(ino, offset, size) {
+            Ok(data) => reply.data(&data),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn write(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        match self.do_write(ino, offset, data) {
+            Ok(written) => reply.written(written),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn setxattr(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: i32,
+        _position: u32,
+        reply: ReplyEmpty,
+    ) {
+        self.fs.set_credentials(caller(req));
+        match self.do_setxattr(ino, name, value, flags) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn getxattr(&mut self, req: &Request<'_>, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        self.fs.set_credentials(caller(req));
+        match self.do_getxattr(ino, name) {
+            Ok(value) => reply_xattr(reply, &value, size),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn listxattr(&mut self, req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+        self.fs.set_credentials(caller(req));
+        match self.do_listxattr(ino) {
+            Ok(names) => reply_xattr(reply, &names, size),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn removexattr(&mut self, req: &Request<'_>, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        self.fs.set_credentials(caller(req));
+        match self.do_removexattr(ino, name) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn symlink(&mut self, req: &Request<'_>, parent: u64, link_name: &OsStr, target: &Path, reply: ReplyEntry) {
+        self.fs.set_credentials(caller(req));
+        match self.do_symlink(parent, link_name, target) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
+        match self.do_readlink(ino) {
+            Ok(target) => reply.data(target.as_bytes()),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn link(&mut self, req: &Request<'_>, ino: u64, newparent: u64, newname: &OsStr, reply: ReplyEntry) {
+        self.fs.set_credentials(caller(req));
+        match self.do_link(ino, newparent, newname) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn unlink(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        self.fs.set_credentials(caller(req));
+        match self.do_unlink(parent, name) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn rmdir(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        self.fs.set_credentials(caller(req));
+        match self.do_rmdir(parent, name) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn rename(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        self.fs.set_credentials(caller(req));
+        match self.do_rename(parent, name, newparent, newname, flags) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn open(&mut self, req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        self.fs.set_credentials(caller(req));
+        match from_fuse_ino(ino).and_then(|n| self.fs.access(n, open_mask(flags)).map_err(|e| errno(&e))) {
+            Ok(()) => reply.opened(0, 0),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn opendir(&mut self, req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
+        self.fs.set_credentials(caller(req));
+        match from_fuse_ino(ino).and_then(|n| self.fs.access(n, MAY_READ).map_err(|e| errno(&e))) {
+            Ok(()) => reply.opened(0, 0),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn access(&mut self, req: &Request<'_>, ino: u64, mask: i32, reply: ReplyEmpty) {
+        self.fs.set_credentials(caller(req));
+        match from_fuse_ino(ino).and_then(|n| self.fs.access(n, mask as u32).map_err(|e| errno(&e))) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn statfs(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyStatfs) {
+        let stat = self.fs.statfs();
+        reply.statfs(stat.total_blocks,
+                     stat.free_blocks,
+                     stat.free_blocks,
+                     stat.total_inodes,
+                     stat.free_inodes,
+                     stat.block_size,
+                     stat.max_name_len,
+                     stat.block_size);
+    }
+}
@@ -1,9 +1,56 @@
+//! filefs: a small educational filesystem that lives on any byte
+//! addressable medium.
+//!
+//! ```
+//! use std::io::{Read, Write};
+//! use filefs::{FileMedium, Filesystem, FormatOptions, MAY_READ};
+//!
+//! let image = std::env::temp_dir().join("filefs-doc-example.img");
+//! let fs = FormatOptions::new(1 << 20).block_size(1024).format(FileMedium::new(image.to_str().unwrap())?)?;
+//! drop(fs);
+//!
+//! let mut fs = Filesystem::load(FileMedium::load(image.to_str().unwrap())?)?;
+//! fs.create_dir("/notes")?;
+//! fs.create_file("/notes/todo")?.write_all(b"write docs")?;
+//!
+//! let mut contents = String::new();
+//! fs.open("/notes/todo", MAY_READ)?.read_to_string(&mut contents)?;
+//! assert_eq!(contents, "write docs");
+//! assert_eq!(fs.open_dir("/notes")?.len(), 1);
+//! # Ok::<(), std::io::Error>(())
+//! ```
 
+// the internal types predate the public names re-exported below
+#![allow(non_camel_case_types)]
+
+mod access;
+mod error;
 mod fs_metadata;
 mod fs;
 
 mod core;
 mod entity;
 mod medium;
+mod migration;
+mod time;
 
 mod util;
+
+pub mod fuse;
+pub mod tar;
+
+/*
+    The public API. Everything else, in particular the on-disk structures
+    under core, is an implementation detail and may change between releases.
+*/
+pub use access::{Credentials, MAY_EXEC, MAY_READ, MAY_WRITE};
+pub use core::acl::{Acl, AclEntry, AclTag, ACL_ACCESS, ACL_DEFAULT};
+pub use core::inode::{major, makedev, minor, FileType};
+pub use core::xattr::{XATTR_CREATE, XATTR_REPLACE};
+pub use entity::directory::{DirectoryEntry, DirectoryHandle, DirectoryIter};
+pub use entity::file::FileHandle;
+pub use error::FsError;
+pub use fs::{ffs as Filesystem, FileStat, FormatOptions, FsLayout, FsStat, RENAME_EXCHANGE, RENAME_NOREPLACE};
+pub use medium::file::file_medium as FileMedium;
+pub use medium::types::byte_compatible as Medium;
+pub use time::{AtimeMode, Clock, SetTime, SystemClock, Timestamp};
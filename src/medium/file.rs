@@ -1,5 +1,5 @@
 use std::fs::{OpenOptions, File};
-use std::io::Error;
+use std::io::{Error, Seek, SeekFrom};
 use std::os::unix::fs::FileExt;
 use crate::{medium::types::byte_compatible, util::Path};
 
@@ -9,40 +9,40 @@ pub struct file_medium {
 }
 
 impl file_medium {
-    pub fn new<T: Path>(path: T) -> Self {
-        let file = file_medium::create_file_obj(path, false);
-        Self { file }
+    pub fn new<T: Path>(path: T) -> Result<Self, Error> {
+        let file = file_medium::create_file_obj(path, false)?;
+        Ok(Self { file })
     }
 
-    pub fn load<T: Path>(path: T) -> Self {
-        let file = file_medium::create_file_obj(path, true);
-        Self { file }
+    pub fn load<T: Path>(path: T) -> Result<Self, Error> {
+        let file = file_medium::create_file_obj(path, true)?;
+        Ok(Self { file })
     }
 
-    fn create_file_obj<T: Path>(path: T, existing: bool) -> File {
-        let file = OpenOptions::new()
-                .create(!existing)
-                .read(true)
-                .write(true)
-                .open(path.to_String());
-
-        if file.is_err() {
-            panic!("{}", if existing { "Cannot load the file" }
-                   else { "Cannot create the file" });
-        }
-
-        file.unwrap()
+    fn create_file_obj<T: Path>(path: T, existing: bool) -> Result<File, Error> {
+        OpenOptions::new()
+            .create(!existing)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(path.to_os_str())
     }
 }
 
 impl byte_compatible for file_medium {
-    fn read_all(&self, offset: u64, len: usize, buffer: &mut [u8]) -> Result<(), Error>
+    fn read_all(&self, offset: u64, buffer: &mut [u8]) -> Result<(), Error>
     {
         self.file.read_exact_at(buffer, offset)
     }
 
-    fn write_all(&self, offset: u64, len: usize, buffer: &[u8]) -> Result<(), Error>
+    fn write_all(&self, offset: u64, buffer: &[u8]) -> Result<(), Error>
     {
         self.file.write_all_at(buffer, offset)
     }
+
+    fn size(&self) -> Result<u64, Error>
+    {
+        // seeking works for block devices too, whose metadata length is 0
+        (&self.file).seek(SeekFrom::End(0))
+    }
 }
\ No newline at end of file
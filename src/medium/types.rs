@@ -1,11 +1,13 @@
 use std::io::Error;
 
-
-pub enum medium {
-
-}
-
+/*
+    Anything the filesystem can live on: an image file, a block device, a
+    buffer in memory. Reads and writes are positioned and must transfer the
+    whole buffer or fail. size() reports how many bytes are addressable, so
+    a superblock claiming more than that can be refused on load.
+*/
 pub trait byte_compatible {
-    fn read_all(&self, offset: u64, len: usize, buffer: &mut [u8]) -> Result<(), Error>;
-    fn write_all(&self, offset: u64, len: usize, bufffer: &[u8]) -> Result<(), Error>;
-}
\ No newline at end of file
+    fn read_all(&self, offset: u64, buffer: &mut [u8]) -> Result<(), Error>;
+    fn write_all(&self, offset: u64, buffer: &[u8]) -> Result<(), Error>;
+    fn size(&self) -> Result<u64, Error>;
+}
@@ -0,0 +1,565 @@
+/*
+    Upgrades images written by older releases to the current on-disk
+    format, one version at a time. Loading refuses such images; this only
+    runs when the caller asks for it with ffs::upgrade. Each step reads and
+    writes the raw structures of the two versions it sits between, so that
+    later changes to Inode and Directory never have to know about old
+    layouts.
+
+    An upgrade rewrites structures in place and is not crash safe: an
+    interrupted upgrade leaves an image that neither version can read.
+*/
+
+use std::io::{Cursor, Error, Read};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+
+use crate::core::inode::FileType;
+use crate::entity::dir_index;
+use crate::error::FsError;
+use crate::fs_metadata::fs_metadata;
+use crate::medium::types::byte_compatible;
+use crate::util::{FS_VERSION_0_0_1, FS_VERSION_0_0_2, FS_VERSION_0_0_3, FS_VERSION_0_0_4, FS_VERSION_0_1_0, FS_VERSION_0_2_0, FS_VERSION_0_3_0, FS_VERSION_0_4_0, FS_VERSION_0_5_0,
+                  FS_VERSION_0_6_0, FS_VERSION_0_7_0, FS_VERSION_0_8_0, FS_VERSION_0_9_0,
+                  FS_VERSION_0_10_0, FS_VERSION_0_11_0, FS_VERSION_0_12_0,
+                  INODE_BLOCK_POINTERS, INODE_DIRECT_BLOCKS, INODE_SIZE};
+
+// names were fixed width fields of this size, first in inodes and then in directory entries, up to 0.10.0
+pub const LEGACY_NAME_SIZE: usize = 64;
+
+// where the fixed inode fields of 0.2.0 to 0.4.0 ended
+const RESERVED_START: usize = 4 + 4 + LEGACY_NAME_SIZE + INODE_BLOCK_POINTERS * 4 + 1 + 8;
+
+// inodes up to 0.1.0 held this many direct block pointers and nothing else
+const LEGACY_INODE_BLOCKS: usize = 32;
+
+pub fn upgrade<M: byte_compatible>(metadata: &mut fs_metadata<M>) -> Result<(), Error> {
+    if metadata.super_block_get_version() == FS_VERSION_0_0_1 {
+        upgrade_from_0_0_1(metadata)?;
+    }
+    if metadata.super_block_get_version() == FS_VERSION_0_0_2 {
+        // 0.0.3 only adds the label, which starts out empty
+        metadata.set_version(FS_VERSION_0_0_3);
+        metadata.persist_super_block()?;
+    }
+    if metadata.super_block_get_version() == FS_VERSION_0_0_3 {
+        // 0.0.4 only puts the magic in front of the superblock
+        metadata.set_version(FS_VERSION_0_0_4);
+        metadata.persist_super_block()?;
+    }
+    if metadata.super_block_get_version() == FS_VERSION_0_0_4 {
+        upgrade_from_0_0_4(metadata)?;
+    }
+    if metadata.super_block_get_version() == FS_VERSION_0_1_0 {
+        upgrade_from_0_1_0(metadata)?;
+    }
+    if metadata.super_block_get_version() == FS_VERSION_0_2_0 {
+        // 0.3.0 only adds the feature flags, which start out empty
+        metadata.set_version(FS_VERSION_0_3_0);
+        metadata.persist_super_block()?;
+    }
+    if metadata.super_block_get_version() == FS_VERSION_0_3_0 {
+        upgrade_from_0_3_0(metadata)?;
+    }
+    if metadata.super_block_get_version() == FS_VERSION_0_4_0 {
+        upgrade_from_0_4_0(metadata)?;
+    }
+    if metadata.super_block_get_version() == FS_VERSION_0_5_0 {
+        upgrade_from_0_5_0(metadata)?;
+    }
+    if metadata.super_block_get_version() == FS_VERSION_0_6_0 {
+        upgrade_from_0_6_0(metadata)?;
+    }
+    if metadata.super_block_get_version() == FS_VERSION_0_7_0 {
+        // 0.8.0 only adds a file type for symbolic links
+        metadata.set_version(FS_VERSION_0_8_0);
+        metadata.persist_super_block()?;
+    }
+    if metadata.super_block_get_version() == FS_VERSION_0_8_0 {
+        // the device number takes reserved bytes that were always zero
+        metadata.set_version(FS_VERSION_0_9_0);
+        metadata.persist_super_block()?;
+    }
+    if metadata.super_block_get_version() == FS_VERSION_0_9_0 {
+        // zeroed xattr fields read as no block and an empty inline set
+        metadata.set_version(FS_VERSION_0_10_0);
+        metadata.persist_super_block()?;
+    }
+    if metadata.super_block_get_version() == FS_VERSION_0_10_0 {
+        upgrade_from_0_10_0(metadata)?;
+    }
+    if metadata.super_block_get_version() == FS_VERSION_0_11_0 {
+        // directories without an index read the same; they get one as they grow, hashed with a new seed
+        metadata.set_hash_seed(dir_index::random_seed());
+        metadata.set_version(FS_VERSION_0_12_0);
+        metadata.persist_super_block()?;
+    }
+    Ok(())
+}
+
+/*
+    An inode slot as 0.0.2 up to 0.1.0 stored it:
+        inode_number | parent | name (LEGACY_NAME_SIZE, NUL padded)
+        | data_blocks (LEGACY_INODE_BLOCKS) | file_type (1) | file_size | block_bitmap (rest)
+    Versions before 0.1.0 store the numbers in 2 bytes and file_size in 4,
+    0.1.0 in 4 and 8.
+*/
+struct LegacyInode {
+    inode_number: u32,
+    parent: u32,
+    name: Vec<u8>,
+    data_blocks: [u32; LEGACY_INODE_BLOCKS],
+    file_type: FileType,
+    file_size: u64,
+}
+
+fn slot_location<M: byte_compatible>(inode_number: u32, metadata: &fs_metadata<M>) -> (u32, usize) {
+    let block_size = metadata.super_block_get_block_size();
+    let offset = inode_number as usize * INODE_SIZE;
+    ((metadata.super_block_get_inode_start_block() + offset / block_size) as u32, offset % block_size)
+}
+
+fn read_legacy_inode<M: byte_compatible>(inode_number: u32, narrow: bool, metadata: &fs_metadata<M>) -> Result<LegacyInode, Error> {
+    let (block, offset) = slot_location(inode_number, metadata);
+    let mut raw = vec![0_u8; INODE_SIZE];
+    metadata.read_block_data(block, offset, &mut raw)?;
+
+    let mut cursor = Cursor::new(raw.as_slice());
+    let read_number = |cursor: &mut Cursor<&[u8]>| if narrow {
+        cursor.read_u16::<LittleEndian>().map(u32::from)
+    } else {
+        cursor.read_u32::<LittleEndian>()
+    };
+
+    let stored_number = read_number(&mut cursor)?;
+    if stored_number != inode_number {
+        return Err(FsError::corrupted("inode",
+            format!("Inode slot {} holds inode number {}", inode_number, stored_number)).into());
+    }
+    let parent = read_number(&mut cursor)?;
+    let mut name = vec![0_u8; LEGACY_NAME_SIZE];
+    cursor.read_exact(&mut name)?;
+
+    let mut data_blocks = [0_u32; LEGACY_INODE_BLOCKS];
+    for block in data_blocks.iter_mut() {
+        *block = read_number(&mut cursor)?;
+        if *block as usize >= metadata.super_block_get_total_blocks() {
+            return Err(FsError::corrupted("inode",
+                format!("Inode {} points to block {} beyond the filesystem", inode_number, block)).into());
+        }
+    }
+    let file_type = FileType::try_from(cursor.read_u8()?)?;
+    let file_size = if narrow {
+        cursor.read_u32::<LittleEndian>()? as u64
+    } else {
+        cursor.read_u64::<LittleEndian>()?
+    };
+
+    Ok(LegacyInode { inode_number, parent, name, data_blocks, file_type, file_size })
+}
+
+fn read_legacy_inodes<M: byte_compatible>(narrow: bool, metadata: &fs_metadata<M>) -> Result<Vec<LegacyInode>, Error> {
+    let mut inodes = Vec::new();
+    for inode_number in 0..metadata.super_block_get_total_inodes() as u32 {
+        if metadata.is_inode_allocated(inode_number) {
+            inodes.push(read_legacy_inode(inode_number, narrow, metadata)?);
+        }
+    }
+    Ok(inodes)
+}
+
+/*
+    0.0.1 -> 0.0.2 gives directories entries, and none of the 0.0.1 ones
+    has any. It also moves the copy of the block bitmap every inode slot
+    carried behind the fixed fields: 0.0.1 wrote it ahead of the file type
+    and size, and with more than 952 blocks it pushed both out of the slot.
+    0.0.1 could not create anything but the root directory, so a root that
+    lost them is an empty directory and any other such inode is corrupt.
+    0.0.1 also left the superblock and the inode table free in the block
+    bitmap, never updated the free counts and did not grow the image to
+    its full size, which the upgrade puts right.
+*/
+fn upgrade_from_0_0_1<M: byte_compatible>(metadata: &mut fs_metadata<M>) -> Result<(), Error> {
+    const FIXED_SIZE: usize = 2 + 2 + LEGACY_NAME_SIZE + LEGACY_INODE_BLOCKS * 2;
+    let bitmap_size = metadata.super_block_get_total_blocks().div_ceil(8);
+
+    metadata.grow_medium()?;
+    let mut slots = Vec::new();
+    for inode_number in 0..metadata.super_block_get_total_inodes() as u32 {
+        if !metadata.is_inode_allocated(inode_number) {
+            continue;
+        }
+        let (block, offset) = slot_location(inode_number, metadata);
+        let mut raw = vec![0_u8; INODE_SIZE];
+        metadata.read_block_data(block, offset, &mut raw)?;
+        let mut slot = raw[..FIXED_SIZE].to_vec();
+        match raw.get(FIXED_SIZE + bitmap_size..FIXED_SIZE + bitmap_size + 5) {
+            Some(tail) => slot.extend_from_slice(tail),
+            None if inode_number == 0 => slot.extend_from_slice(&[FileType::Directory as u8, 0, 0, 0, 0]),
+            None => return Err(FsError::corrupted("inode",
+                format!("Inode {} lost its file type and size to the block bitmap", inode_number)).into()),
+        }
+        slot.resize(INODE_SIZE, 0);
+        slots.push((inode_number, slot));
+    }
+
+    metadata.set_version(FS_VERSION_0_0_2);
+    metadata.recount_free()?;
+
+    for (inode_number, slot) in slots {
+        let (block, offset) = slot_location(inode_number, metadata);
+        metadata.write_block_data(block, offset, &slot)?;
+    }
+    Ok(())
+}
+
+/*
+    0.0.4 -> 0.1.0 widens block and inode numbers to 32 bits and file sizes
+    to 64. The bitmaps and the geometry stay as they are; the superblock,
+    every inode slot and every directory entry get rewritten.
+*/
+fn upgrade_from_0_0_4<M: byte_compatible>(metadata: &mut fs_metadata<M>) -> Result<(), Error> {
+    const LEGACY_ENTRY_SIZE: usize = 2 + 1 + LEGACY_NAME_SIZE;
+
+    // read everything in the old layout before the version changes
+    let mut inodes = read_legacy_inodes(true, metadata)?;
+    let mut contents = Vec::with_capacity(inodes.len());
+    for inode in &inodes {
+        let mut entries = Vec::new();
+        if inode.file_type == FileType::Directory {
+            let raw = read_legacy_data(inode, metadata)?;
+            for record in raw.chunks(LEGACY_ENTRY_SIZE) {
+                let (name, inode_number) = parse_legacy_entry(record)?;
+                // 0.1.0 entries: inode_number (4) | name length (1) | name (LEGACY_NAME_SIZE, NUL padded)
+                entries.extend_from_slice(&inode_number.to_le_bytes());
+                entries.push(name.len() as u8);
+                entries.extend_from_slice(name.as_bytes());
+                entries.resize(entries.len() + LEGACY_NAME_SIZE - name.len(), 0);
+            }
+        }
+        contents.push(entries);
+    }
+
+    metadata.set_version(FS_VERSION_0_1_0);
+    metadata.persist_super_block()?;
+
+    for (inode, entries) in inodes.iter_mut().zip(contents) {
+        if inode.file_type == FileType::Directory {
+            write_legacy_data(inode, &entries, metadata)?;
+        }
+
+        let (block, offset) = slot_location(inode.inode_number, metadata);
+        let mut slot = Vec::with_capacity(INODE_SIZE);
+        slot.extend_from_slice(&inode.inode_number.to_le_bytes());
+        slot.extend_from_slice(&inode.parent.to_le_bytes());
+        slot.extend_from_slice(&inode.name);
+        inode.data_blocks.iter().for_each(|b| slot.extend_from_slice(&b.to_le_bytes()));
+        slot.push(inode.file_type as u8);
+        slot.extend_from_slice(&inode.file_size.to_le_bytes());
+        slot.resize(INODE_SIZE, 0);
+        metadata.write_block_data(block, offset, &slot)?;
+    }
+    Ok(())
+}
+
+/*
+    0.1.0 -> 0.2.0 cuts the 32 direct block pointers down to 12 and adds
+    single, double and triple indirect ones. Pointers 12 to 31 move into a
+    freshly allocated single indirect block.
+*/
+fn upgrade_from_0_1_0<M: byte_compatible>(metadata: &mut fs_metadata<M>) -> Result<(), Error> {
+    let inodes = read_legacy_inodes(false, metadata)?;
+
+    // allocate up front, so that running out of space leaves the image at 0.1.0
+    let needs_indirect = |legacy: &LegacyInode| legacy.data_blocks[INODE_DIRECT_BLOCKS..].iter().any(|&b| b != 0);
+    let mut indirect_blocks = metadata.allocate_blocks(inodes.iter().filter(|i| needs_indirect(i)).count())?.into_iter();
+
+    metadata.set_version(FS_VERSION_0_2_0);
+    metadata.persist_super_block()?;
+
+    for legacy in inodes {
+        let mut data_blocks = [0_u32; INODE_BLOCK_POINTERS];
+        data_blocks[..INODE_DIRECT_BLOCKS].copy_from_slice(&legacy.data_blocks[..INODE_DIRECT_BLOCKS]);
+
+        if needs_indirect(&legacy) {
+            let moved = &legacy.data_blocks[INODE_DIRECT_BLOCKS..];
+            let indirect = indirect_blocks.next().unwrap();
+            let mut pointers = vec![0_u8; metadata.super_block_get_block_size()];
+            for (raw, block) in pointers.chunks_exact_mut(4).zip(moved) {
+                raw.copy_from_slice(&block.to_le_bytes());
+            }
+            metadata.write_block_data(indirect, 0, &pointers)?;
+            data_blocks[INODE_DIRECT_BLOCKS] = indirect;
+        }
+
+        // 0.2.0 slot: the 0.1.0 one with 15 block pointers, rest zero
+        let (block, offset) = slot_location(legacy.inode_number, metadata);
+        let mut slot = Vec::with_capacity(INODE_SIZE);
+        slot.extend_from_slice(&legacy.inode_number.to_le_bytes());
+        slot.extend_from_slice(&legacy.parent.to_le_bytes());
+        slot.extend_from_slice(&legacy.name);
+        data_blocks.iter().for_each(|b| slot.extend_from_slice(&b.to_le_bytes()));
+        slot.push(legacy.file_type as u8);
+        slot.extend_from_slice(&legacy.file_size.to_le_bytes());
+        slot.resize(INODE_SIZE, 0);
+        metadata.write_block_data(block, offset, &slot)?;
+    }
+    Ok(())
+}
+
+/*
+    0.3.0 -> 0.4.0 drops the copy of the whole block bitmap every inode
+    carried after its fixed fields. It was never read back, and on
+    filesystems of more than a few thousand blocks it did not even fit the
+    slot. The bytes become reserved and have to read as zero.
+*/
+fn upgrade_from_0_3_0<M: byte_compatible>(metadata: &mut fs_metadata<M>) -> Result<(), Error> {
+    metadata.set_version(FS_VERSION_0_4_0);
+    metadata.persist_super_block()?;
+
+    let zeros = [0_u8; INODE_SIZE - RESERVED_START];
+    for inode_number in 0..metadata.super_block_get_total_inodes() as u32 {
+        if metadata.is_inode_allocated(inode_number) {
+            let (block, offset) = slot_location(inode_number, metadata);
+            metadata.write_block_data(block, offset + RESERVED_START, &zeros)?;
+        }
+    }
+    Ok(())
+}
+
+/*
+    0.4.0 -> 0.5.0 puts the inode timestamps into the first reserved bytes.
+    Nobody recorded when the existing files were written, so access,
+    modification and change time become the time of the upgrade, and the
+    creation time stays at zero, the epoch, for unknown.
+*/
+fn upgrade_from_0_4_0<M: byte_compatible>(metadata: &mut fs_metadata<M>) -> Result<(), Error> {
+    metadata.set_version(FS_VERSION_0_5_0);
+    metadata.persist_super_block()?;
+
+    let now = metadata.now();
+    let mut times = Vec::with_capacity(3 * 12);
+    for _ in 0..3 {
+        times.extend_from_slice(&now.seconds.to_le_bytes());
+        times.extend_from_slice(&now.nanoseconds.to_le_bytes());
+    }
+    for inode_number in 0..metadata.super_block_get_total_inodes() as u32 {
+        if metadata.is_inode_allocated(inode_number) {
+            let (block, offset) = slot_location(inode_number, metadata);
+            metadata.write_block_data(block, offset + RESERVED_START, &times)?;
+        }
+    }
+    Ok(())
+}
+
+/*
+    0.5.0 -> 0.6.0 adds permission bits, owner and group after the
+    timestamps. Nothing was enforced before, so existing files become
+    root's with modes that keep them open to everyone: 0777 for
+    directories, 0666 for everything else. Root can tighten them later.
+*/
+fn upgrade_from_0_5_0<M: byte_compatible>(metadata: &mut fs_metadata<M>) -> Result<(), Error> {
+    const FILE_TYPE_OFFSET: usize = 4 + 4 + LEGACY_NAME_SIZE + INODE_BLOCK_POINTERS * 4;
+    const OWNER_OFFSET: usize = RESERVED_START + 4 * 12;
+
+    metadata.set_version(FS_VERSION_0_6_0);
+    metadata.persist_super_block()?;
+
+    for inode_number in 0..metadata.super_block_get_total_inodes() as u32 {
+        if metadata.is_inode_allocated(inode_number) {
+            let (block, offset) = slot_location(inode_number, metadata);
+            let mut file_type = [0_u8];
+            metadata.read_block_data(block, offset + FILE_TYPE_OFFSET, &mut file_type)?;
+            let mode: u16 = if file_type[0] == FileType::Directory as u8 { 0o777 } else { 0o666 };
+
+            let mut owner = Vec::with_capacity(10);
+            owner.extend_from_slice(&mode.to_le_bytes());
+            owner.extend_from_slice(&0_u32.to_le_bytes());
+            owner.extend_from_slice(&0_u32.to_le_bytes());
+            metadata.write_block_data(block, offset + OWNER_OFFSET, &owner)?;
+        }
+    }
+    Ok(())
+}
+
+/*
+    0.6.0 -> 0.7.0 drops the name from the inode, where only the directory
+    entry pointing at it should have kept it, and puts a link count in its
+    place. Every file still has its one entry; a directory is also linked
+    from its own "." and the ".." of each subdirectory.
+*/
+fn upgrade_from_0_6_0<M: byte_compatible>(metadata: &mut fs_metadata<M>) -> Result<(), Error> {
+    const NAME_START: usize = 4 + 4;
+    const FILE_TYPE_OFFSET: usize = NAME_START + LEGACY_NAME_SIZE + INODE_BLOCK_POINTERS * 4;
+
+    let mut slots = Vec::new();
+    for inode_number in 0..metadata.super_block_get_total_inodes() as u32 {
+        if metadata.is_inode_allocated(inode_number) {
+            let (block, offset) = slot_location(inode_number, metadata);
+            let mut slot = vec![0_u8; INODE_SIZE];
+            metadata.read_block_data(block, offset, &mut slot)?;
+            slots.push((inode_number, slot));
+        }
+    }
+
+    let is_directory = |slot: &[u8]| slot[FILE_TYPE_OFFSET] == FileType::Directory as u8;
+    let mut subdirectories = vec![0_u32; metadata.super_block_get_total_inodes()];
+    for (inode_number, slot) in &slots {
+        let parent = u32::from_le_bytes(slot[4..8].try_into().unwrap());
+        // the root is its own parent
+        if is_directory(slot) && parent != *inode_number {
+            let count = subdirectories.get_mut(parent as usize).ok_or_else(|| Error::from(FsError::corrupted("inode",
+                format!("Inode {} has parent {} beyond the inode table", inode_number, parent))))?;
+            *count += 1;
+        }
+    }
+
+    metadata.set_version(FS_VERSION_0_7_0);
+    metadata.persist_super_block()?;
+
+    for (inode_number, slot) in slots {
+        let nlink = if is_directory(&slot) { 2 + subdirectories[inode_number as usize] } else { 1 };
+        let mut relaid = Vec::with_capacity(INODE_SIZE);
+        relaid.extend_from_slice(&slot[..NAME_START]);
+        relaid.extend_from_slice(&nlink.to_le_bytes());
+        relaid.extend_from_slice(&slot[NAME_START + LEGACY_NAME_SIZE..]);
+        relaid.resize(INODE_SIZE, 0);
+        let (block, offset) = slot_location(inode_number, metadata);
+        metadata.write_block_data(block, offset, &relaid)?;
+    }
+    Ok(())
+}
+
+/*
+    0.10.0 -> 0.11.0 replaces the fixed size directory entries
+        inode_number (4) | name length (1) | name (LEGACY_NAME_SIZE, NUL padded)
+    with variable length records packed into blocks
+        inode_number (4) | record length (2) | name length (1) | file type (1) | name
+    where the last record of a block reaches to its end, and the file type
+    is copied from the inode the entry points at. The inode slots are the
+    same in both versions, so directories are read and rewritten through
+    Inode.
+*/
+fn upgrade_from_0_10_0<M: byte_compatible>(metadata: &mut fs_metadata<M>) -> Result<(), Error> {
+    const LEGACY_ENTRY_SIZE: usize = 4 + 1 + LEGACY_NAME_SIZE;
+    const RECORD_HEADER_SIZE: usize = 8;
+    let block_size = metadata.super_block_get_block_size();
+
+    // read every directory in the old layout before the version changes
+    let mut directories = Vec::new();
+    for inode_number in 0..metadata.super_block_get_total_inodes() as u32 {
+        if !metadata.is_inode_allocated(inode_number) {
+            continue;
+        }
+        let inode = metadata.load_inode(inode_number)?;
+        if inode.file_type != FileType::Directory {
+            continue;
+        }
+        let mut raw = vec![0_u8; inode.file_size as usize];
+        inode.read_data(0, &mut raw, metadata)?;
+
+        // the records of each block, and where the last of them starts
+        let mut blocks: Vec<(Vec<u8>, usize)> = Vec::new();
+        for entry in raw.chunks(LEGACY_ENTRY_SIZE) {
+            let name_len = entry.get(4).copied().unwrap_or(0) as usize;
+            if entry.len() < LEGACY_ENTRY_SIZE || name_len == 0 || name_len > LEGACY_NAME_SIZE {
+                return Err(FsError::corrupted("directory entry",
+                    format!("malformed 0.10.0 entry in directory {}", inode_number)).into());
+            }
+            let child = u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]]);
+            if !metadata.is_inode_allocated(child) {
+                return Err(FsError::corrupted("directory entry",
+                    format!("entry in directory {} points to free inode {}", inode_number, child)).into());
+            }
+            let size = RECORD_HEADER_SIZE + name_len;
+            let mut record = Vec::with_capacity(size);
+            record.extend_from_slice(&entry[..4]);
+            record.extend_from_slice(&(size as u16).to_le_bytes());
+            record.push(name_len as u8);
+            record.push(metadata.load_inode(child)?.file_type as u8);
+            record.extend_from_slice(&entry[5..5 + name_len]);
+            match blocks.last_mut() {
+                Some((block, last)) if block.len() + size <= block_size => {
+                    *last = block.len();
+                    block.extend_from_slice(&record);
+                }
+                _ => blocks.push((record, 0)),
+            }
+        }
+
+        let mut data = Vec::with_capacity(blocks.len() * block_size);
+        for (mut block, last) in blocks {
+            // a whole block of the largest size wraps to 0, which is how it is stored
+            block[last + 4..last + 6].copy_from_slice(&((block_size - last) as u16).to_le_bytes());
+            block.resize(block_size, 0);
+            data.extend_from_slice(&block);
+        }
+        directories.push((inode, data));
+    }
+
+    metadata.set_version(FS_VERSION_0_11_0);
+    metadata.persist_super_block()?;
+
+    for (mut inode, data) in directories {
+        inode.truncate_data(0, metadata)?;
+        inode.write_data(0, &data, metadata)?;
+        metadata.persist_inode(&inode)?;
+    }
+    Ok(())
+}
+
+fn read_legacy_data<M: byte_compatible>(inode: &LegacyInode, metadata: &fs_metadata<M>) -> Result<Vec<u8>, Error> {
+    let block_size = metadata.super_block_get_block_size();
+    if inode.file_size > (LEGACY_INODE_BLOCKS * block_size) as u64 {
+        return Err(FsError::corrupted("inode",
+            format!("Inode {} has size {} which does not fit in its data blocks", inode.inode_number, inode.file_size)).into());
+    }
+
+    let mut data = vec![0_u8; inode.file_size as usize];
+    for (chunk, &block) in data.chunks_mut(block_size).zip(&inode.data_blocks) {
+        if block != 0 {
+            metadata.read_block_data(block, 0, chunk)?;
+        }
+    }
+    Ok(data)
+}
+
+/*
+    Replaces the inode's data with `data`, allocating blocks as it grows.
+    Directories never shrink when their entries get wider.
+*/
+fn write_legacy_data<M: byte_compatible>(inode: &mut LegacyInode, data: &[u8], metadata: &mut fs_metadata<M>) -> Result<(), Error> {
+    let block_size = metadata.super_block_get_block_size();
+    let chunks = data.chunks(block_size);
+    if chunks.len() > LEGACY_INODE_BLOCKS {
+        return Err(FsError::NoSpace.into());
+    }
+
+    for (chunk, block) in chunks.zip(inode.data_blocks.iter_mut()) {
+        if *block == 0 {
+            *block = metadata.allocate_blocks(1)?[0];
+        }
+        let mut buffer = chunk.to_vec();
+        buffer.resize(block_size, 0);
+        metadata.write_block_data(*block, 0, &buffer)?;
+    }
+    inode.file_size = data.len() as u64;
+    Ok(())
+}
+
+/*
+    inode_number (2) | name length (1) | name (LEGACY_NAME_SIZE, NUL padded)
+*/
+fn parse_legacy_entry(record: &[u8]) -> Result<(String, u32), Error> {
+    let corrupted = || Error::from(FsError::corrupted("directory entry", "malformed 0.0.2 entry"));
+    if record.len() < 3 {
+        return Err(corrupted());
+    }
+    let inode_number = u16::from_le_bytes([record[0], record[1]]) as u32;
+    let name_len = record[2] as usize;
+    let name = record.get(3..3 + name_len)
+        .filter(|_| name_len > 0)
+        .and_then(|bytes| String::from_utf8(bytes.to_vec()).ok())
+        .ok_or_else(corrupted)?;
+    Ok((name, inode_number))
+}
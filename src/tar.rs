@@ -0,0 +1,653 @@
+/*
+    Copies trees in and out of a filesystem as tar archives in the POSIX
+    ustar format. Regular files, directories, symbolic links, hard links,
+    FIFOs and device nodes round trip along with their mode, owner, group
+    and modification time. Sockets cannot be archived and are skipped, as
+    tar(1) does.
+
+    Names too long for the ustar fields are written to pax extended
+    headers. On import pax headers and GNU long name entries are both
+    understood, and numbers may be in GNU's base-256 form. Names are bytes
+    throughout; pax headers marked hdrcharset=BINARY carry those that are
+    not UTF-8.
+*/
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+
+use crate::access::MAY_READ;
+use crate::core::inode::{major, makedev, minor, FileType};
+use crate::fs::{ffs, FileStat};
+use crate::medium::types::byte_compatible;
+use crate::time::{SetTime, Timestamp};
+use crate::util::Path;
+
+const BLOCK_SIZE: usize = 512;
+// how much file data moves between archive and filesystem at a time
+const CHUNK_SIZE: usize = 64 << 10;
+
+const NAME_SIZE: usize = 100;
+const PREFIX_SIZE: usize = 155;
+const USTAR_MAGIC: &[u8; 6] = b"ustar\0";
+
+// typeflag values
+const REGULAR: u8 = b'0';
+// pre-POSIX archives mark regular files with a NUL
+const OLD_REGULAR: u8 = 0;
+const HARD_LINK: u8 = b'1';
+const SYMLINK: u8 = b'2';
+const CHAR_DEVICE: u8 = b'3';
+const BLOCK_DEVICE: u8 = b'4';
+const DIRECTORY: u8 = b'5';
+const FIFO: u8 = b'6';
+// a regular file that asked for contiguous storage
+const CONTIGUOUS: u8 = b'7';
+const PAX_HEADER: u8 = b'x';
+const PAX_GLOBAL_HEADER: u8 = b'g';
+const GNU_LONG_NAME: u8 = b'L';
+const GNU_LONG_LINK: u8 = b'K';
+
+fn invalid<M: Into<String>>(message: M) -> Error {
+    Error::new(ErrorKind::InvalidData, message.into())
+}
+
+/*
+    One archive member, after any pax or GNU headers before it were
+    applied.
+*/
+#[derive(Debug, Default)]
+struct Header {
+    path: Vec<u8>,
+    mode: u16,
+    uid: u32,
+    gid: u32,
+    size: u64,
+    mtime: i64,
+    typeflag: u8,
+    link: Vec<u8>,
+    rdev: u32,
+}
+
+/*
+    Header block layout (offset, size):
+        name (0, 100) | mode (100, 8) | uid (108, 8) | gid (116, 8)
+        | size (124, 12) | mtime (136, 12) | checksum (148, 8) | typeflag (156, 1)
+        | linkname (157, 100) | magic (257, 6) | version (263, 2)
+        | uname (265, 32) | gname (297, 32) | devmajor (329, 8)
+        | devminor (337, 8) | prefix (345, 155)
+    Numbers are octal text, or base-256 with the top bit of the first byte
+    set when they do not fit.
+*/
+impl Header {
+    fn decode(block: &[u8; BLOCK_SIZE]) -> Result<Self, Error> {
+        let stored = number(&block[148..156])?;
+        let sum: u64 = block.iter().enumerate()
+            .map(|(i, &b)| if (148..156).contains(&i) { b' ' as u64 } else { b as u64 })
+            .sum();
+        if stored != sum {
+            return Err(invalid("tar header checksum mismatch"));
+        }
+
+        let mut path = text(&block[..NAME_SIZE]).to_vec();
+        // GNU archives keep other fields where POSIX has the prefix
+        if &block[257..263] == USTAR_MAGIC {
+            let prefix = text(&block[345..345 + PREFIX_SIZE]);
+            if !prefix.is_empty() {
+                path = [prefix, b"/", &path].concat();
+            }
+        }
+        let field = |range: std::ops::Range<usize>| number(&block[range]);
+        let id = |value: u64| u32::try_from(value).map_err(|_| invalid("tar header id out of range"));
+        Ok(Self {
+            path,
+            mode: field(100..108)? as u16 & 0o7777,
+            uid: id(field(108..116)?)?,
+            gid: id(field(116..124)?)?,
+            size: field(124..136)?,
+            mtime: field(136..148)? as i64,
+            typeflag: block[156],
+            link: text(&block[157..157 + NAME_SIZE]).to_vec(),
+            rdev: makedev(field(329..337)? as u32, field(337..345)? as u32),
+        })
+    }
+
+    /*
+        The blocks that describe this member: a pax header first if the
+        names do not fit the ustar fields, then the ustar header itself.
+    */
+    fn encode(&self) -> Vec<u8> {
+        let mut records = Vec::new();
+        let (prefix, name) = split_path(&self.path).unwrap_or_else(|| {
+            records.extend_from_slice(&pax_record("path", &self.path));
+            (&[][..], &self.path[..NAME_SIZE])
+        });
+        let link = if self.link.len() > NAME_SIZE {
+            records.extend_from_slice(&pax_record("linkpath", &self.link));
+            &self.link[..NAME_SIZE]
+        } else {
+            &self.link[..]
+        };
+        if !records.is_empty() && [&self.path, &self.link].iter().any(|value| std::str::from_utf8(value).is_err()) {
+            records.splice(0..0, pax_record("hdrcharset", b"BINARY"));
+        }
+
+        let mut blocks = Vec::new();
+        if !records.is_empty() {
+            let trimmed = &self.path[..self.path.iter().rposition(|&b| b != b'/').map_or(0, |last| last + 1)];
+            let base = trimmed.rsplit(|&b| b == b'/').next().unwrap_or_default();
+            let mut path = [b"PaxHeader/", base].concat();
+            path.truncate(NAME_SIZE);
+            let pax = Header {
+                path,
+                mode: 0o644,
+                size: records.len() as u64,
+                typeflag: PAX_HEADER,
+                ..Header::default()
+            };
+            blocks.extend_from_slice(&pax.block(b"", &pax.path, b""));
+            blocks.extend_from_slice(&records);
+            blocks.resize(blocks.len().next_multiple_of(BLOCK_SIZE), 0);
+        }
+        blocks.extend_from_slice(&self.block(prefix, name, link));
+        blocks
+    }
+
+    fn block(&self, prefix: &[u8], name: &[u8], link: &[u8]) -> [u8; BLOCK_SIZE] {
+        let mut block = [0_u8; BLOCK_SIZE];
+        block[..name.len()].copy_from_slice(name);
+        put_number(&mut block[100..108], self.mode as u64);
+        put_number(&mut block[108..116], self.uid as u64);
+        put_number(&mut block[116..124], self.gid as u64);
+        put_number(&mut block[124..136], self.size);
+        // ustar has no room for times before the epoch
+        put_number(&mut block[136..148], self.mtime.max(0) as u64);
+        block[156] = self.typeflag;
+        block[157..157 + link.len()].copy_from_slice(link);
+        block[257..263].copy_from_slice(USTAR_MAGIC);
+        block[263..265].copy_from_slice(b"00");
+        put_number(&mut block[329..337], major(self.rdev) as u64);
+        put_number(&mut block[337..345], minor(self.rdev) as u64);
+        block[345..345 + prefix.len()].copy_from_slice(prefix);
+
+        block[148..156].fill(b' ');
+        let sum: u64 = block.iter().map(|&b| b as u64).sum();
+        block[148..156].copy_from_slice(format!("{:06o}\0 ", sum).as_bytes());
+        block
+    }
+}
+
+// a NUL terminated (or field filling) string
+fn text(field: &[u8]) -> &[u8] {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    &field[..end]
+}
+
+fn number(field: &[u8]) -> Result<u64, Error> {
+    if field[0] & 0x80 != 0 {
+        let mut value = (field[0] & 0x7f) as u64;
+        for &byte in &field[1..] {
+            value = value.checked_mul(256).ok_or_else(|| invalid("tar header number out of range"))? | byte as u64;
+        }
+        return Ok(value);
+    }
+    let digits = String::from_utf8_lossy(text(field));
+    let digits = digits.trim_matches(|c: char| c == ' ');
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(digits, 8).map_err(|_| invalid(format!("bad number {:?} in tar header", digits)))
+}
+
+fn put_number(field: &mut [u8], value: u64) {
+    let digits = field.len() - 1;
+    if value < 1 << (3 * digits) {
+        field.copy_from_slice(format!("{:0width$o}\0", value, width = digits).as_bytes());
+    } else {
+        let bytes = value.to_be_bytes();
+        let (len, kept) = (field.len(), bytes.len().min(field.len() - 1));
+        field.fill(0);
+        field[len - kept..].copy_from_slice(&bytes[bytes.len() - kept..]);
+        field[0] |= 0x80;
+    }
+}
+
+/*
+    Splits `path` into a ustar prefix and name, or None if it fits neither
+    way.
+*/
+fn split_path(path: &[u8]) -> Option<(&[u8], &[u8])> {
+    if path.len() <= NAME_SIZE {
+        return Some((&[], path));
+    }
+    // the trailing slash of a directory belongs to the name
+    let body = path.strip_suffix(b"/").unwrap_or(path);
+    (0..body.len()).filter(|&i| body[i] == b'/')
+        .map(|i| (&path[..i], &path[i + 1..]))
+        .find(|(prefix, name)| prefix.len() <= PREFIX_SIZE && name.len() <= NAME_SIZE && !name.is_empty())
+}
+
+/*
+    "<length> <key>=<value>\n", where the length counts the whole record
+    including its own digits.
+*/
+fn pax_record(key: &str, value: &[u8]) -> Vec<u8> {
+    let body = key.len() + value.len() + 3;
+    let mut length = body + body.to_string().len();
+    if length.to_string().len() + body > length {
+        length += 1;
+    }
+    [format!("{} {}=", length, key).as_bytes(), value, b"\n"].concat()
+}
+
+/*
+    What pax and GNU headers say about the member that follows them.
+*/
+#[derive(Debug, Default)]
+struct Overrides {
+    path: Option<Vec<u8>>,
+    link: Option<Vec<u8>>,
+    size: Option<u64>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    mtime: Option<i64>,
+}
+
+impl Overrides {
+    fn apply(self, header: &mut Header) {
+        header.path = self.path.unwrap_or(std::mem::take(&mut header.path));
+        header.link = self.link.unwrap_or(std::mem::take(&mut header.link));
+        header.size = self.size.unwrap_or(header.size);
+        header.uid = self.uid.unwrap_or(header.uid);
+        header.gid = self.gid.unwrap_or(header.gid);
+        header.mtime = self.mtime.unwrap_or(header.mtime);
+    }
+}
+
+fn apply_pax_records(records: &[u8], overrides: &mut Overrides) -> Result<(), Error> {
+    let mut rest = records;
+    while !rest.is_empty() {
+        let space = rest.iter().position(|&b| b == b' ').ok_or_else(|| invalid("bad pax record"))?;
+        let length: usize = std::str::from_utf8(&rest[..space]).ok()
+            .and_then(|digits| digits.parse().ok())
+            .filter(|&length| length > space + 1 && length <= rest.len())
+            .ok_or_else(|| invalid("bad pax record length"))?;
+        let record = &rest[space + 1..length - 1];
+        rest = &rest[length..];
+
+        let equals = record.iter().position(|&b| b == b'=').ok_or_else(|| invalid("bad pax record"))?;
+        let (key, value) = (String::from_utf8_lossy(&record[..equals]), &record[equals + 1..]);
+        let parse = |value: &[u8]| {
+            let value = String::from_utf8_lossy(value);
+            value.split('.').next().unwrap_or_default().parse::<i64>()
+                .map_err(|_| invalid(format!("bad pax value {}={}", key, value)))
+        };
+        match key.as_ref() {
+            "path" => overrides.path = Some(value.to_vec()),
+            "linkpath" => overrides.link = Some(value.to_vec()),
+            "size" => overrides.size = Some(parse(value)? as u64),
+            "uid" => overrides.uid = Some(parse(value)? as u32),
+            "gid" => overrides.gid = Some(parse(value)? as u32),
+            "mtime" => overrides.mtime = Some(parse(value)?),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/*
+    Reads one block. Returns false at a clean end of the archive.
+*/
+fn read_block<R: Read>(archive: &mut R, block: &mut [u8; BLOCK_SIZE]) -> Result<bool, Error> {
+    let mut filled = 0;
+    while filled < BLOCK_SIZE {
+        match archive.read(&mut block[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    match filled {
+        0 => Ok(false),
+        BLOCK_SIZE => Ok(true),
+        _ => Err(Error::new(ErrorKind::UnexpectedEof, "truncated tar archive")),
+    }
+}
+
+fn padding(size: u64) -> usize {
+    (size.next_multiple_of(BLOCK_SIZE as u64) - size) as usize
+}
+
+fn read_member_data<R: Read>(archive: &mut R, size: u64) -> Result<Vec<u8>, Error> {
+    let mut data = vec![0_u8; size as usize + padding(size)];
+    archive.read_exact(&mut data)?;
+    data.truncate(size as usize);
+    Ok(data)
+}
+
+fn skip_member_data<R: Read>(archive: &mut R, size: u64) -> Result<(), Error> {
+    let total = size + padding(size) as u64;
+    let copied = std::io::copy(&mut archive.take(total), &mut std::io::sink())?;
+    if copied != total {
+        return Err(Error::new(ErrorKind::UnexpectedEof, "truncated tar archive"));
+    }
+    Ok(())
+}
+
+/*
+    Writes everything below `directory` to `archive`, with paths relative
+    to it. Files with several names are stored once and linked to from
+    their other names.
+*/
+pub fn export<T: byte_compatible, P: Path, W: Write>(fs: &mut ffs<T>, directory: P, mut archive: W) -> Result<(), Error> {
+    let root = fs.lookup_path(directory)?;
+    let mut exported = HashMap::new();
+    export_directory(fs, root, b"", &mut archive, &mut exported)?;
+    archive.write_all(&[0_u8; 2 * BLOCK_SIZE])?;
+    archive.flush()
+}
+
+fn export_directory<T: byte_compatible, W: Write>(fs: &mut ffs<T>,
+                                                  directory: u32,
+                                                  prefix: &[u8],
+                                                  archive: &mut W,
+                                                  exported: &mut HashMap<u32, Vec<u8>>) -> Result<(), Error> {
+    for entry in fs.readdir(directory)? {
+        let path = [prefix, entry.name.as_bytes()].concat();
+        let stat = fs.stat(entry.inode_number)?;
+        let mut header = Header {
+            mode: stat.mode,
+            uid: stat.uid,
+            gid: stat.gid,
+            mtime: stat.mtime.seconds,
+            rdev: stat.rdev,
+            ..Header::default()
+        };
+        match stat.file_type {
+            FileType::Socket => continue,
+            FileType::Directory => {
+                header.path = [&path[..], b"/"].concat();
+                header.typeflag = DIRECTORY;
+                archive.write_all(&header.encode())?;
+                export_directory(fs, stat.inode_number, &header.path, archive, exported)?;
+                continue;
+            }
+            FileType::Symlink => {
+                header.typeflag = SYMLINK;
+                header.link = fs.readlink(stat.inode_number)?.into_vec();
+            }
+            FileType::Fifo => header.typeflag = FIFO,
+            FileType::CharDevice => header.typeflag = CHAR_DEVICE,
+            FileType::BlockDevice => header.typeflag = BLOCK_DEVICE,
+            FileType::File => match exported.get(&stat.inode_number) {
+                Some(first) => {
+                    header.typeflag = HARD_LINK;
+                    header.link = first.clone();
+                }
+                None => {
+                    header.typeflag = REGULAR;
+                    header.size = stat.size;
+                }
+            },
+        }
+        if stat.nlink > 1 {
+            exported.entry(stat.inode_number).or_insert_with(|| path.clone());
+        }
+        header.path = path;
+        archive.write_all(&header.encode())?;
+        if header.typeflag == REGULAR {
+            export_data(fs, &stat, archive)?;
+        }
+    }
+    Ok(())
+}
+
+fn export_data<T: byte_compatible, W: Write>(fs: &mut ffs<T>, stat: &FileStat, archive: &mut W) -> Result<(), Error> {
+    let mut handle = fs.open_file(stat.inode_number, MAY_READ)?;
+    let mut buffer = vec![0_u8; CHUNK_SIZE];
+    let mut offset = 0;
+    while offset < stat.size {
+        let wanted = (stat.size - offset).min(CHUNK_SIZE as u64) as usize;
+        let read = handle.read(&mut buffer[..wanted])?;
+        if read == 0 {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "file shrank while being archived"));
+        }
+        archive.write_all(&buffer[..read])?;
+        offset += read as u64;
+    }
+    archive.write_all(&vec![0_u8; padding(stat.size)])
+}
+
+/*
+    Unpacks `archive` into `directory`, creating directories the archive
+    leaves out along the way. Ownership is restored when running as root,
+    and directory times once everything inside them is in place. Entries
+    are never created outside `directory`: ".." is refused and symbolic
+    links are not followed while unpacking.
+*/
+pub fn import<T: byte_compatible, P: Path, R: Read>(fs: &mut ffs<T>, directory: P, mut archive: R) -> Result<(), Error> {
+    let root = fs.lookup_path(directory)?;
+    let mut directories = Vec::new();
+    let mut pending = Overrides::default();
+
+    let mut block = [0_u8; BLOCK_SIZE];
+    while read_block(&mut archive, &mut block)? {
+        if block.iter().all(|&b| b == 0) {
+            break;
+        }
+        let mut header = Header::decode(&block)?;
+        match header.typeflag {
+            PAX_HEADER => {
+                apply_pax_records(&read_member_data(&mut archive, header.size)?, &mut pending)?;
+                continue;
+            }
+            PAX_GLOBAL_HEADER => {
+                skip_member_data(&mut archive, header.size)?;
+                continue;
+            }
+            GNU_LONG_NAME | GNU_LONG_LINK => {
+                let data = read_member_data(&mut archive, header.size)?;
+                let value = text(&data).to_vec();
+                if header.typeflag == GNU_LONG_NAME {
+                    pending.path = Some(value);
+                } else {
+                    pending.link = Some(value);
+                }
+                continue;
+            }
+            _ => {}
+        }
+
+        std::mem::take(&mut pending).apply(&mut header);
+
+        if let Some(directory) = import_member(fs, root, &header, &mut archive)? {
+            directories.push((directory, header.mtime));
+        }
+    }
+
+    for (directory, mtime) in directories.into_iter().rev() {
+        let mtime = SetTime::To(Timestamp::new(mtime, 0));
+        fs.set_times(directory, Some(mtime), Some(mtime))?;
+    }
+    Ok(())
+}
+
+/*
+    The names along an archive path, without empty and "." components.
+*/
+fn components(path: &[u8]) -> Result<Vec<&[u8]>, Error> {
+    let components: Vec<&[u8]> = crate::util::components(path).filter(|&c| c != b".").collect();
+    if components.contains(&&b".."[..]) {
+        return Err(invalid(format!("{}: tar member leaves the target directory", String::from_utf8_lossy(path))));
+    }
+    Ok(components)
+}
+
+/*
+    Finds the directory that will hold `path` below `root`, creating the
+    missing ones, and returns it along with the final name.
+*/
+fn parent_of<'a, T: byte_compatible>(fs: &mut ffs<T>, root: u32, path: &'a [u8]) -> Result<Option<(u32, &'a [u8])>, Error> {
+    let mut components = components(path)?;
+    let Some(name) = components.pop() else {
+        return Ok(None);
+    };
+    let mut parent = root;
+    for component in components {
+        parent = match fs.lookup(parent, component) {
+            Ok(inode_number) => inode_number,
+            Err(e) if e.kind() == ErrorKind::NotFound => fs.mkdir_with_mode(parent, component, 0o755)?,
+            Err(e) => return Err(e),
+        };
+    }
+    Ok(Some((parent, name)))
+}
+
+/*
+    Creates one archive member and consumes its data. Returns the inode of
+    a directory, whose times are set last.
+*/
+fn import_member<T: byte_compatible, R: Read>(fs: &mut ffs<T>,
+                                             root: u32,
+                                             header: &Header,
+                                             archive: &mut R) -> Result<Option<u32>, Error> {
+    let Some((parent, name)) = parent_of(fs, root, &header.path)? else {
+        // the archive's own top directory, "./"
+        skip_member_data(archive, header.size)?;
+        return Ok(None);
+    };
+
+    let inode_number = match header.typeflag {
+        REGULAR | OLD_REGULAR | CONTIGUOUS => {
+            let inode_number = fs.create_with_mode(parent, name, header.mode)?;
+            let mut buffer = vec![0_u8; CHUNK_SIZE];
+            let mut offset = 0;
+            while offset < header.size {
+                let chunk = (header.size - offset).min(CHUNK_SIZE as u64) as usize;
+                archive.read_exact(&mut buffer[..chunk])?;
+                fs.write(inode_number, offset, &buffer[..chunk])?;
+                offset += chunk as u64;
+            }
+            archive.read_exact(&mut vec![0_u8; padding(header.size)])?;
+            inode_number
+        }
+        DIRECTORY => {
+            skip_member_data(archive, header.size)?;
+            match fs.lookup(parent, name) {
+                Ok(existing) if fs.stat(existing)?.file_type == FileType::Directory => existing,
+                Ok(_) => return Err(Error::new(ErrorKind::AlreadyExists,
+                    format!("{}: File exists", String::from_utf8_lossy(&header.path)))),
+                Err(e) if e.kind() == ErrorKind::NotFound => fs.mkdir_with_mode(parent, name, header.mode)?,
+                Err(e) => return Err(e),
+            }
+        }
+        SYMLINK => {
+            skip_member_data(archive, header.size)?;
+            fs.symlink(parent, name, &header.link[..])?
+        }
+        HARD_LINK => {
+            skip_member_data(archive, header.size)?;
+            let mut target = root;
+            for component in components(&header.link)? {
+                target = fs.lookup(target, component)?;
+            }
+            // a hard link shares the inode, and with it everything below
+            return fs.link(target, parent, name).map(|_| None);
+        }
+        CHAR_DEVICE | BLOCK_DEVICE | FIFO => {
+            skip_member_data(archive, header.size)?;
+            let file_type = match header.typeflag {
+                CHAR_DEVICE => FileType::CharDevice,
+                BLOCK_DEVICE => FileType::BlockDevice,
+                _ => FileType::Fifo,
+            };
+            fs.mknod(parent, name, file_type, header.mode, header.rdev)?
+        }
+        other => return Err(invalid(format!("{}: unsupported tar member type {:?}",
+                                            String::from_utf8_lossy(&header.path), other as char))),
+    };
+
+    if fs.credentials().is_root() {
+        fs.chown(inode_number, Some(header.uid), Some(header.gid))?;
+    }
+    if header.typeflag != SYMLINK {
+        // chown may have dropped setuid and setgid bits
+        fs.chmod(inode_number, header.mode)?;
+    }
+    if header.typeflag == DIRECTORY {
+        return Ok(Some(inode_number));
+    }
+    let mtime = SetTime::To(Timestamp::new(header.mtime, 0));
+    fs.set_times(inode_number, Some(mtime), Some(mtime))?;
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::medium::file::file_medium;
+
+    fn new_test_fs(name: &str) -> ffs<file_medium> {
+        let image = std::env::temp_dir().join(name).to_string_lossy().into_owned();
+        ffs::new(file_medium::new(image.as_str()).unwrap(), 4 << 20, 1024, 4096).unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut source = new_test_fs("test_tar_source.dat");
+        source.create_dir("/etc").unwrap();
+        source.create_file("/etc/passwd").unwrap().write_all(b"root:x:0:0::/root:/bin/sh\n").unwrap();
+        let big: Vec<u8> = (0..70_000_u32).map(|i| (i % 251) as u8).collect();
+        source.create_file("/etc/big").unwrap().write_all(&big).unwrap();
+        source.link_path("/etc/passwd", "/passwd").unwrap();
+        source.symlink_path("etc/passwd", "/link").unwrap();
+        source.create_dir("/dev").unwrap();
+        source.mknod_path("/dev/null", FileType::CharDevice, 0o666, makedev(1, 3)).unwrap();
+        source.mknod_path("/dev/sda", FileType::BlockDevice, 0o660, makedev(8, 0)).unwrap();
+        source.mknod_path("/dev/initctl", FileType::Fifo, 0o600, 0).unwrap();
+        source.mknod_path("/dev/log", FileType::Socket, 0o666, 0).unwrap();
+        // deep enough to need the ustar prefix, then a pax header
+        let mut deep = String::new();
+        for letter in ["d", "e", "f", "g"] {
+            deep = format!("{}/{}", deep, letter.repeat(60));
+            source.create_dir(deep.as_str()).unwrap();
+        }
+        let deeper = format!("{}/{}", deep, "h".repeat(60));
+        source.create_file(deeper.as_str()).unwrap();
+        let passwd = source.lookup_path("/etc/passwd").unwrap();
+        source.chown(passwd, Some(1000), Some(100)).unwrap();
+        source.chmod(passwd, 0o4640).unwrap();
+        source.set_times(passwd, None, Some(SetTime::To(Timestamp::new(1_700_000_000, 0)))).unwrap();
+
+        let mut archive = Vec::new();
+        export(&mut source, "/", &mut archive).unwrap();
+        assert_eq!(archive.len() % BLOCK_SIZE, 0);
+
+        let mut copy = new_test_fs("test_tar_copy.dat");
+        copy.create_dir("/restore").unwrap();
+        import(&mut copy, "/restore", archive.as_slice()).unwrap();
+
+        let passwd = copy.stat_path("/restore/etc/passwd").unwrap();
+        assert_eq!((passwd.mode, passwd.uid, passwd.gid, passwd.nlink), (0o4640, 1000, 100, 2));
+        assert_eq!(passwd.mtime, Timestamp::new(1_700_000_000, 0));
+        assert_eq!(copy.lookup_path("/restore/passwd").unwrap(), passwd.inode_number);
+        let mut contents = Vec::new();
+        copy.open("/restore/etc/big", MAY_READ).unwrap().read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, big);
+        assert_eq!(copy.readlink_path("/restore/link").unwrap(), "etc/passwd");
+        let null = copy.stat_path("/restore/dev/null").unwrap();
+        assert_eq!((null.file_type, null.rdev, null.mode), (FileType::CharDevice, makedev(1, 3), 0o644));
+        assert_eq!(copy.stat_path("/restore/dev/sda").unwrap().rdev, makedev(8, 0));
+        assert_eq!(copy.stat_path("/restore/dev/initctl").unwrap().file_type, FileType::Fifo);
+        assert_eq!(copy.lookup_path("/restore/dev/log").unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(copy.stat_path(format!("/restore{}", deeper)).unwrap().file_type, FileType::File);
+
+        // archives refuse to reach outside the target directory
+        let mut escape = Header { path: b"../outside".to_vec(), mode: 0o644, typeflag: REGULAR, ..Header::default() }.encode();
+        escape.extend_from_slice(&[0_u8; 2 * BLOCK_SIZE]);
+        assert_eq!(import(&mut copy, "/restore", escape.as_slice()).unwrap_err().kind(), ErrorKind::InvalidData);
+        archive[148] ^= 1;
+        assert_eq!(import(&mut copy, "/", archive.as_slice()).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+}
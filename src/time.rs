@@ -0,0 +1,96 @@
+/*
+    Inode timestamps and where they come from. The filesystem asks a Clock
+    for the current time, so that tests and tools replaying a script can
+    swap the system clock for one they control.
+*/
+
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/*
+    A point in time as seconds and nanoseconds since the Unix epoch. Times
+    before the epoch have negative seconds; nanoseconds always count
+    forward and stay below one second.
+*/
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp {
+    pub seconds: i64,
+    pub nanoseconds: u32,
+}
+
+impl Timestamp {
+    pub const fn new(seconds: i64, nanoseconds: u32) -> Self {
+        Self { seconds, nanoseconds }
+    }
+}
+
+impl From<SystemTime> for Timestamp {
+    fn from(time: SystemTime) -> Self {
+        match time.duration_since(UNIX_EPOCH) {
+            Ok(after) => Self::new(after.as_secs() as i64, after.subsec_nanos()),
+            Err(before) => {
+                let before = before.duration();
+                match before.subsec_nanos() {
+                    0 => Self::new(-(before.as_secs() as i64), 0),
+                    nanos => Self::new(-(before.as_secs() as i64) - 1, 1_000_000_000 - nanos),
+                }
+            }
+        }
+    }
+}
+
+impl From<Timestamp> for SystemTime {
+    fn from(time: Timestamp) -> Self {
+        let nanoseconds = Duration::from_nanos(time.nanoseconds as u64);
+        if time.seconds >= 0 {
+            UNIX_EPOCH + Duration::from_secs(time.seconds as u64) + nanoseconds
+        } else {
+            UNIX_EPOCH - Duration::from_secs(time.seconds.unsigned_abs()) + nanoseconds
+        }
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{:09}", self.seconds, self.nanoseconds)
+    }
+}
+
+/*
+    A time for set_times to put in place: a given one, or the current time,
+    which anybody who may write the file may set, as with utimensat.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetTime {
+    Now,
+    To(Timestamp),
+}
+
+pub trait Clock {
+    fn now(&self) -> Timestamp;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Timestamp {
+        SystemTime::now().into()
+    }
+}
+
+/*
+    When reading a file updates its access time, the choices mount(8)
+    offers:
+        Strict    on every read
+        Relatime  when the access time is not newer than the modification
+                  or change time, or is more than a day old (the default)
+        NoAtime   never
+*/
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AtimeMode {
+    Strict,
+    #[default]
+    Relatime,
+    NoAtime,
+}
@@ -1,62 +1,149 @@
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
 
-pub const NUM_RELEASED_VERSIONS: usize = 1;
+// 16 bit block and inode numbers, 32 bit file sizes
+pub const FS_VERSION_0_0_1: [u8; 3] = [0, 0, 1];
+// entries in directory blocks, the per-inode block bitmap after the fixed inode fields
+pub const FS_VERSION_0_0_2: [u8; 3] = [0, 0, 2];
+// a label in the superblock
+pub const FS_VERSION_0_0_3: [u8; 3] = [0, 0, 3];
+// the superblock starts with FS_MAGIC
+pub const FS_VERSION_0_0_4: [u8; 3] = [0, 0, 4];
+// 32 bit block and inode numbers, 64 bit file sizes
+pub const FS_VERSION_0_1_0: [u8; 3] = [0, 1, 0];
+// 12 direct block pointers plus single, double and triple indirect ones
+pub const FS_VERSION_0_2_0: [u8; 3] = [0, 2, 0];
+// superblock feature flags
+pub const FS_VERSION_0_3_0: [u8; 3] = [0, 3, 0];
+// inode slots without the per-inode block bitmap
+pub const FS_VERSION_0_4_0: [u8; 3] = [0, 4, 0];
+// access, modification, change and creation times in every inode
+pub const FS_VERSION_0_5_0: [u8; 3] = [0, 5, 0];
+// permission bits, owner and group in every inode
+pub const FS_VERSION_0_6_0: [u8; 3] = [0, 6, 0];
+// link counts in inodes, names only in directory entries
+pub const FS_VERSION_0_7_0: [u8; 3] = [0, 7, 0];
+// symbolic links
+pub const FS_VERSION_0_8_0: [u8; 3] = [0, 8, 0];
+// FIFOs, sockets and device nodes, with a device number in every inode
+pub const FS_VERSION_0_9_0: [u8; 3] = [0, 9, 0];
+// extended attributes, inline in the inode slot or in an xattr block
+pub const FS_VERSION_0_10_0: [u8; 3] = [0, 10, 0];
+// variable length directory records, names of up to 255 bytes
+pub const FS_VERSION_0_11_0: [u8; 3] = [0, 11, 0];
+// hashed indexes for directories larger than a block
+pub const FS_VERSION_0_12_0: [u8; 3] = [0, 12, 0];
+
+pub const NUM_RELEASED_VERSIONS: usize = 16;
 pub const VALID_FS_VERSIONS: [[u8; 3]; NUM_RELEASED_VERSIONS]  = [
-    [0, 0, 1]
+    FS_VERSION_0_0_1,
+    FS_VERSION_0_0_2,
+    FS_VERSION_0_0_3,
+    FS_VERSION_0_0_4,
+    FS_VERSION_0_1_0,
+    FS_VERSION_0_2_0,
+    FS_VERSION_0_3_0,
+    FS_VERSION_0_4_0,
+    FS_VERSION_0_5_0,
+    FS_VERSION_0_6_0,
+    FS_VERSION_0_7_0,
+    FS_VERSION_0_8_0,
+    FS_VERSION_0_9_0,
+    FS_VERSION_0_10_0,
+    FS_VERSION_0_11_0,
+    FS_VERSION_0_12_0,
 ];
-pub const CURRENT_FS_VERSION_IDX: usize = 0;
+pub const CURRENT_FS_VERSION_IDX: usize = 15;
 
 pub fn get_latest_version() -> [u8; 3] {
     VALID_FS_VERSIONS[CURRENT_FS_VERSION_IDX]
 }
 
-pub const MAX_FILE_NAME_SIZE: usize = 64;
-pub const MAX_CHILDREN_COUNT: usize = 64;
+pub const MAX_FILE_NAME_SIZE: usize = 255;
 
 pub const INODE_SIZE: usize = 256;
+pub const INODE_DIRECT_BLOCKS: usize = 12;
+// the direct pointers followed by the single, double and triple indirect ones
+pub const INODE_BLOCK_POINTERS: usize = INODE_DIRECT_BLOCKS + 3;
 pub const INODE_BITMAP_STARTING_BLOCK_NUMBER: usize = 1;
 
+pub const FS_MAGIC: [u8; 4] = *b"FiFS";
+
+/*
+    Superblock feature flags. A filesystem using a feature this build does
+    not know about is refused rather than misread.
+*/
+// files map their blocks through extent trees instead of block pointers
+pub const FEATURE_EXTENTS: u32 = 1 << 0;
+pub const SUPPORTED_FEATURES: u32 = FEATURE_EXTENTS;
+
 pub const SUPER_BLOCK_FILE_OFFSET: u64 = 0;
 pub const SUPER_BLOCK_SIZE: usize = 1 << 8;
 
+pub const MIN_BLOCK_SIZE: u32 = 1 << 9;
+pub const MAX_BLOCK_SIZE: u32 = 1 << 16;
+pub const MAX_LABEL_SIZE: usize = 16;
+
+pub const DEFAULT_BLOCK_SIZE: u32 = 4 << 10;
+pub const DEFAULT_BYTES_PER_INODE: u32 = 16 << 10;
+
+/*
+    Anything that can name a file. Names are raw bytes, like on Linux, and
+    need not be UTF-8.
+*/
 pub trait Path {
     fn to_le_bytes(&self) -> &[u8];
-    fn to_String(&self) -> String;
+
+    fn to_os_str(&self) -> &OsStr {
+        OsStr::from_bytes(self.to_le_bytes())
+    }
 }
 
 impl Path for String {
     fn to_le_bytes(&self) -> &[u8] {
         self.as_bytes()
     }
-    fn to_String(&self) -> String {
-        self.clone()
-    }
 }
 
 impl Path for &str {
     fn to_le_bytes(&self) -> &[u8] {
         self.as_bytes()
     }
-    fn to_String(&self) -> String {
-        self.to_string()
-    }
 }
 
 impl Path for [u8] {
     fn to_le_bytes(&self) -> &[u8] {
         self
     }
-    fn to_String(&self) -> String {
-        String::from_utf8(self.to_vec()).unwrap_or_default()
-    }
 }
 
 impl Path for &[u8] {
     fn to_le_bytes(&self) -> &[u8] {
         self
     }
-    fn to_String(&self) -> String {
-        String::from_utf8(self.to_vec()).unwrap_or_default()
+}
+
+impl Path for OsString {
+    fn to_le_bytes(&self) -> &[u8] {
+        self.as_bytes()
     }
 }
 
+impl Path for &OsStr {
+    fn to_le_bytes(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
 
+impl Path for &std::path::Path {
+    fn to_le_bytes(&self) -> &[u8] {
+        self.as_os_str().as_bytes()
+    }
+}
+
+/*
+    The names along `path`, without the empty ones between slashes.
+*/
+pub fn components(path: &[u8]) -> impl DoubleEndedIterator<Item = &[u8]> {
+    path.split(|&b| b == b'/').filter(|component| !component.is_empty())
+}